@@ -1,5 +1,5 @@
 use iai::black_box;
-use lila_openingexplorer::model::{LichessEntry, Speed};
+use lila_openingexplorer::model::{LichessEntry, Mode, Speed};
 use shakmaty::{uci::UciMove, Color, Outcome, Square};
 
 fn bench_lichess_write_single() -> Vec<u8> {
@@ -10,6 +10,8 @@ fn bench_lichess_write_single() -> Vec<u8> {
             promotion: None,
         }),
         black_box(Speed::Classical),
+        black_box(Mode::Rated),
+        black_box(0),
         black_box("abcdefgh".parse().expect("game id")),
         black_box(Outcome::Decisive {
             winner: Color::White,
@@ -0,0 +1,75 @@
+use std::{
+    env, fs,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+fn main() {
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    if let Some(sha) = output("git", &["rev-parse", "HEAD"]) {
+        println!("cargo:rustc-env=BUILD_GIT_SHA={sha}");
+    }
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    if let Some(version) = output(&rustc, &["--version"]) {
+        println!("cargo:rustc-env=BUILD_RUSTC_VERSION={version}");
+    }
+
+    // Reproducible builds set SOURCE_DATE_EPOCH.
+    let epoch = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("system time after unix epoch")
+                .as_secs()
+        });
+    println!("cargo:rustc-env=BUILD_DATE={}", date_from_unix(epoch));
+
+    // The version of the bundled RocksDB is the build metadata of the
+    // librocksdb-sys package, for example 0.17.1+9.7.4.
+    if let Some(version) = fs::read_to_string("Cargo.lock").ok().and_then(|lock| {
+        lock.split("[[package]]")
+            .find(|package| package.contains("name = \"librocksdb-sys\""))
+            .and_then(|package| {
+                package
+                    .lines()
+                    .find_map(|line| line.strip_prefix("version = \""))
+                    .and_then(|version| version.trim_end_matches('"').split_once('+'))
+                    .map(|(_, rocksdb)| rocksdb.to_owned())
+            })
+    }) {
+        println!("cargo:rustc-env=BUILD_ROCKSDB_VERSION={version}");
+    }
+}
+
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    Command::new(program)
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|stdout| stdout.trim().to_owned())
+        .filter(|stdout| !stdout.is_empty())
+}
+
+/// Calendar date (UTC) of a unix timestamp, as YYYY-MM-DD.
+fn date_from_unix(secs: u64) -> String {
+    // Civil from days, http://howardhinnant.github.io/date_algorithms.html
+    let z = (secs / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}")
+}
@@ -7,6 +7,13 @@ use serde::Serialize;
 use serde_with::{formats::SpaceSeparator, serde_as, DisplayFromStr, StringWithSeparator};
 use time::OffsetDateTime;
 
+#[derive(Debug, Serialize, Copy, Clone)]
+#[serde(rename_all = "camelCase")]
+enum Mode {
+    Rated,
+    Casual,
+}
+
 #[derive(Debug, Serialize, Copy, Clone)]
 #[serde(rename_all = "camelCase")]
 enum Speed {
@@ -52,6 +59,8 @@ impl Speed {
 struct Batch {
     filename: PathBuf,
     games: Vec<Game>,
+    /// Set on the last batch of a file, with the date of the last game.
+    completed_date: Option<String>,
 }
 
 impl Batch {
@@ -68,9 +77,11 @@ struct Importer<'a> {
     filename: PathBuf,
     batch_size: usize,
     progress: &'a ProgressBar,
+    players: &'a [String],
 
     current: Game,
     skip: bool,
+    last_date: Option<String>,
     batch: Vec<Game>,
 }
 
@@ -79,6 +90,7 @@ struct Importer<'a> {
 struct Game {
     variant: Option<String>,
     speed: Option<Speed>,
+    mode: Option<Mode>,
     fen: Option<String>,
     id: Option<String>,
     date: Option<String>,
@@ -88,6 +100,7 @@ struct Game {
     winner: Option<Color>,
     #[serde_as(as = "StringWithSeparator<SpaceSeparator, SanPlus>")]
     moves: Vec<SanPlus>,
+    source: Option<&'static str>,
 }
 
 #[derive(Default, Serialize, Debug)]
@@ -96,28 +109,44 @@ struct Player {
     rating: Option<u16>,
 }
 
-impl Importer<'_> {
+impl<'a> Importer<'a> {
     fn new(
         tx: crossbeam::channel::Sender<Batch>,
         filename: PathBuf,
         batch_size: usize,
-        progress: &ProgressBar,
-    ) -> Importer<'_> {
+        progress: &'a ProgressBar,
+        players: &'a [String],
+    ) -> Importer<'a> {
         Importer {
             tx,
             filename,
             batch_size,
+            players,
             current: Game::default(),
             skip: false,
+            last_date: None,
             batch: Vec::with_capacity(batch_size),
             progress,
         }
     }
 
     pub fn send(&mut self) {
+        self.send_batch(false);
+    }
+
+    pub fn send_final(&mut self) {
+        self.send_batch(true);
+    }
+
+    fn send_batch(&mut self, complete: bool) {
         let batch = Batch {
             filename: self.filename.clone(),
             games: mem::replace(&mut self.batch, Vec::with_capacity(self.batch_size)),
+            completed_date: if complete {
+                self.last_date.clone()
+            } else {
+                None
+            },
         };
         self.progress.set_message(batch.last_month().to_string());
         self.tx.send(batch).expect("send");
@@ -147,6 +176,20 @@ impl Visitor for Importer<'_> {
             }
         } else if key == b"TimeControl" {
             self.current.speed = Some(Speed::from_bytes(value.as_bytes()).expect("TimeControl"));
+        } else if key == b"Event" {
+            let event = value.as_bytes();
+            if event.starts_with(b"Rated ") {
+                self.current.mode = Some(Mode::Rated);
+            } else if event.starts_with(b"Casual ") {
+                self.current.mode = Some(Mode::Casual);
+            }
+            self.current.source = Some(if contains(event, b"/swiss/") {
+                "swiss"
+            } else if contains(event, b"/tournament/") {
+                "arena"
+            } else {
+                "pairing"
+            });
         } else if key == b"Variant" {
             self.current.variant = Some(value.decode_utf8().expect("Variant").into_owned());
         } else if key == b"Date" || key == b"UTCDate" {
@@ -184,6 +227,17 @@ impl Visitor for Importer<'_> {
 
     fn end_headers(&mut self) -> Skip {
         self.skip |= self.current.white.rating.is_none() || self.current.black.rating.is_none();
+        if !self.players.is_empty() {
+            self.skip |= ![&self.current.white, &self.current.black]
+                .into_iter()
+                .any(|p| {
+                    p.name.as_ref().map_or(false, |name| {
+                        self.players
+                            .iter()
+                            .any(|player| player.eq_ignore_ascii_case(name))
+                    })
+                });
+        }
         Skip(self.skip)
     }
 
@@ -197,6 +251,9 @@ impl Visitor for Importer<'_> {
 
     fn end_game(&mut self) {
         if !self.skip {
+            if self.current.date.is_some() {
+                self.last_date.clone_from(&self.current.date);
+            }
             self.batch.push(mem::take(&mut self.current));
         }
 
@@ -206,6 +263,12 @@ impl Visitor for Importer<'_> {
     }
 }
 
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
 #[derive(Parser)]
 struct Args {
     #[arg(long, default_value = "http://localhost:9002")]
@@ -214,6 +277,10 @@ struct Args {
     batch_size: usize,
     #[arg(long)]
     avoid_utc_hour: Vec<u8>,
+    /// Instead of the lichess explorer, backfill the personal explorer of
+    /// these players.
+    #[arg(long = "player")]
+    players: Vec<String>,
     pgns: Vec<PathBuf>,
 }
 
@@ -222,6 +289,24 @@ fn main() -> Result<(), io::Error> {
 
     let (tx, rx) = crossbeam::channel::bounded::<Batch>(50);
 
+    // Files from https://database.lichess.org/ contain exactly one month, so
+    // the month of the last game is marked as complete at the end of a file.
+    let complete_url = args
+        .players
+        .is_empty()
+        .then(|| format!("{}/import/lichess/complete", args.endpoint));
+
+    let url = if args.players.is_empty() {
+        format!("{}/import/lichess", args.endpoint)
+    } else {
+        format!(
+            "{}/import/player?players={}",
+            args.endpoint,
+            args.players.join(",")
+        )
+    };
+    let avoid_utc_hour = args.avoid_utc_hour;
+
     let bg = thread::spawn(move || {
         let client = reqwest::blocking::Client::builder()
             .timeout(None)
@@ -229,16 +314,13 @@ fn main() -> Result<(), io::Error> {
             .expect("client");
 
         while let Ok(batch) = rx.recv() {
-            while args
-                .avoid_utc_hour
-                .contains(&OffsetDateTime::now_utc().hour())
-            {
+            while avoid_utc_hour.contains(&OffsetDateTime::now_utc().hour()) {
                 println!("paused around this time ...");
                 thread::sleep(Duration::from_secs(10 * 60));
             }
 
             let res = client
-                .put(format!("{}/import/lichess", args.endpoint))
+                .put(&url)
                 .json(&batch.games)
                 .send()
                 .expect("send batch");
@@ -251,6 +333,17 @@ fn main() -> Result<(), io::Error> {
                     res.status(),
                     res.text().expect("decode response")
                 );
+            } else if let (Some(date), Some(complete_url)) = (&batch.completed_date, &complete_url)
+            {
+                let month = date.get(..7).unwrap_or(date).replace('.', "-");
+                let res = client
+                    .put(complete_url)
+                    .query(&[("month", &month)])
+                    .send()
+                    .expect("send completion");
+                if !res.status().is_success() {
+                    println!("{:?}: {}: {}", batch.filename, month, res.status());
+                }
             }
         }
     });
@@ -279,9 +372,10 @@ fn main() -> Result<(), io::Error> {
         };
 
         let mut reader = BufferedReader::new(uncompressed);
-        let mut importer = Importer::new(tx.clone(), arg, args.batch_size, &progress);
+        let mut importer =
+            Importer::new(tx.clone(), arg, args.batch_size, &progress, &args.players);
         reader.read_all(&mut importer)?;
-        importer.send();
+        importer.send_final();
 
         progress.finish();
     }
@@ -0,0 +1,392 @@
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fs,
+    fs::File,
+    io, mem,
+    path::{Path, PathBuf},
+    thread,
+};
+
+use clap::Parser;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use pgn_reader::{BufferedReader, RawHeader, SanPlus, Skip, Visitor};
+use serde::Serialize;
+use serde_with::{formats::SpaceSeparator, serde_as, DisplayFromStr, StringWithSeparator};
+use sha1::{Digest, Sha1};
+use shakmaty::{uci::UciMove, Chess, Color, Outcome, Position};
+
+/// Game in the format expected by `/import/masters`.
+#[serde_as]
+#[derive(Default, Serialize, Debug)]
+struct Game {
+    id: Option<String>,
+    event: String,
+    site: String,
+    date: String,
+    round: String,
+    white: Player,
+    black: Player,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    winner: Option<Color>,
+    #[serde_as(as = "StringWithSeparator<SpaceSeparator, UciMove>")]
+    moves: Vec<UciMove>,
+}
+
+#[derive(Default, Serialize, Debug)]
+struct Player {
+    name: String,
+    rating: u16,
+}
+
+impl Game {
+    /// Derive an id from the content of the game, so that importing the
+    /// same game twice is detected as a duplicate.
+    fn deterministic_id(&self) -> String {
+        let digest = Sha1::digest(serde_json::to_vec(self).expect("serialize game"));
+        let mut n = u64::from_le_bytes(digest[..8].try_into().expect("digest size"));
+        n %= 62u64.pow(8);
+        let mut id = String::with_capacity(8);
+        for _ in 0..8 {
+            let rem = (n % 62) as u8;
+            id.push(char::from(if rem >= 10 + 26 {
+                rem - (10 + 26) + b'a'
+            } else if rem >= 10 {
+                rem - 10 + b'A'
+            } else {
+                rem + b'0'
+            }));
+            n /= 62;
+        }
+        id
+    }
+
+    fn year(&self) -> Option<u16> {
+        self.date.split('.').next()?.parse().ok()
+    }
+}
+
+struct Batch {
+    filename: PathBuf,
+    games: Vec<Game>,
+    /// Number of games read from the file, including this batch.
+    read: u64,
+}
+
+impl Batch {
+    fn last_date(&self) -> &str {
+        self.games.last().map_or("", |g| g.date.as_str())
+    }
+}
+
+struct Filter {
+    min_rating: u16,
+    since: Option<u16>,
+    until: Option<u16>,
+}
+
+impl Filter {
+    fn accepts(&self, game: &Game) -> bool {
+        let avg_rating = (u32::from(game.white.rating) + u32::from(game.black.rating)) / 2;
+        if avg_rating < u32::from(self.min_rating) {
+            return false;
+        }
+        match game.year() {
+            Some(year) => {
+                self.since.map_or(true, |since| since <= year)
+                    && self.until.map_or(true, |until| year <= until)
+            }
+            None => self.since.is_none() && self.until.is_none(),
+        }
+    }
+}
+
+struct Importer<'a> {
+    tx: crossbeam::channel::Sender<Batch>,
+    filename: PathBuf,
+    batch_size: usize,
+    filter: &'a Filter,
+    /// Games at the start of the file that were already imported.
+    resume: u64,
+
+    read: u64,
+    current: Game,
+    pos: Chess,
+    has_ratings: [bool; 2],
+    skip: bool,
+    batch: Vec<Game>,
+}
+
+impl<'a> Importer<'a> {
+    fn new(
+        tx: crossbeam::channel::Sender<Batch>,
+        filename: PathBuf,
+        batch_size: usize,
+        filter: &'a Filter,
+        resume: u64,
+    ) -> Importer<'a> {
+        Importer {
+            tx,
+            filename,
+            batch_size,
+            filter,
+            resume,
+            read: 0,
+            current: Game::default(),
+            pos: Chess::default(),
+            has_ratings: [false; 2],
+            skip: false,
+            batch: Vec::with_capacity(batch_size),
+        }
+    }
+
+    fn send(&mut self) {
+        let batch = Batch {
+            filename: self.filename.clone(),
+            games: mem::replace(&mut self.batch, Vec::with_capacity(self.batch_size)),
+            read: self.read,
+        };
+        self.tx.send(batch).expect("send");
+    }
+}
+
+impl Visitor for Importer<'_> {
+    type Result = ();
+
+    fn begin_game(&mut self) {
+        self.read += 1;
+        self.current = Game::default();
+        self.pos = Chess::default();
+        self.has_ratings = [false; 2];
+        self.skip = self.read <= self.resume;
+    }
+
+    fn header(&mut self, key: &[u8], value: RawHeader<'_>) {
+        let text = || value.decode_utf8_lossy().into_owned();
+        match key {
+            b"LichessId" => self.current.id = Some(text()),
+            b"Event" => self.current.event = text(),
+            b"Site" => self.current.site = text(),
+            b"Date" => self.current.date = text(),
+            b"Round" => self.current.round = text(),
+            b"White" => self.current.white.name = text(),
+            b"Black" => self.current.black.name = text(),
+            b"WhiteElo" => match btoi::btou(value.as_bytes()) {
+                Ok(rating) => {
+                    self.current.white.rating = rating;
+                    self.has_ratings[0] = true;
+                }
+                Err(_) => self.skip = true,
+            },
+            b"BlackElo" => match btoi::btou(value.as_bytes()) {
+                Ok(rating) => {
+                    self.current.black.rating = rating;
+                    self.has_ratings[1] = true;
+                }
+                Err(_) => self.skip = true,
+            },
+            b"Result" => match Outcome::from_ascii(value.as_bytes()) {
+                Ok(outcome) => self.current.winner = outcome.winner(),
+                Err(_) => self.skip = true,
+            },
+            b"FEN" | b"SetUp" | b"Variant" => self.skip = true,
+            _ => (),
+        }
+    }
+
+    fn end_headers(&mut self) -> Skip {
+        self.skip |= self.has_ratings != [true; 2] || !self.filter.accepts(&self.current);
+        Skip(self.skip)
+    }
+
+    fn san(&mut self, san_plus: SanPlus) {
+        if self.skip {
+            return;
+        }
+        match san_plus.san.to_move(&self.pos) {
+            Ok(m) => {
+                self.current.moves.push(UciMove::from_standard(&m));
+                self.pos.play_unchecked(&m);
+            }
+            Err(_) => self.skip = true,
+        }
+    }
+
+    fn begin_variation(&mut self) -> Skip {
+        Skip(true) // stay in the mainline
+    }
+
+    fn end_game(&mut self) {
+        if !self.skip {
+            let mut game = mem::take(&mut self.current);
+            if game.id.is_none() {
+                game.id = Some(game.deterministic_id());
+            }
+            self.batch.push(game);
+        }
+
+        if self.batch.len() >= self.batch_size {
+            self.send();
+        }
+    }
+}
+
+/// Progress of previous runs, so that interrupted imports can be resumed.
+struct State {
+    path: Option<PathBuf>,
+    read: HashMap<String, u64>,
+}
+
+impl State {
+    fn open(path: Option<PathBuf>) -> io::Result<State> {
+        let read = match path {
+            Some(ref path) if path.exists() => {
+                serde_json::from_slice(&fs::read(path)?).map_err(io::Error::other)?
+            }
+            _ => HashMap::new(),
+        };
+        Ok(State { path, read })
+    }
+
+    fn resume(&self, filename: &Path) -> u64 {
+        self.read
+            .get(&filename.display().to_string())
+            .copied()
+            .unwrap_or(0)
+    }
+
+    fn record(&mut self, filename: &Path, read: u64) -> io::Result<()> {
+        self.read.insert(filename.display().to_string(), read);
+        if let Some(ref path) = self.path {
+            let tmp = path.with_extension("tmp");
+            fs::write(
+                &tmp,
+                serde_json::to_vec(&self.read).map_err(io::Error::other)?,
+            )?;
+            fs::rename(tmp, path)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Parser)]
+struct Args {
+    #[arg(long, default_value = "http://localhost:9002")]
+    endpoint: String,
+    #[arg(long, default_value = "200")]
+    batch_size: usize,
+    /// Skip games with a lower average rating.
+    #[arg(long, default_value = "2200")]
+    min_rating: u16,
+    /// Skip games played before this year.
+    #[arg(long)]
+    since: Option<u16>,
+    /// Skip games played after this year.
+    #[arg(long)]
+    until: Option<u16>,
+    /// Record progress in this file, and skip games that were already
+    /// imported when restarting.
+    #[arg(long)]
+    state: Option<PathBuf>,
+    pgns: Vec<PathBuf>,
+}
+
+fn main() -> Result<(), io::Error> {
+    let args = Args::parse();
+
+    let filter = Filter {
+        min_rating: args.min_rating,
+        since: args.since,
+        until: args.until,
+    };
+
+    let mut state = State::open(args.state)?;
+    let resume: Vec<u64> = args.pgns.iter().map(|pgn| state.resume(pgn)).collect();
+
+    let (tx, rx) = crossbeam::channel::bounded::<Batch>(50);
+
+    let url = format!("{}/import/masters", args.endpoint);
+
+    let bg = thread::spawn(move || {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(None)
+            .build()
+            .expect("client");
+
+        let (mut imported, mut duplicates, mut rejected) = (0u64, 0u64, 0u64);
+
+        while let Ok(batch) = rx.recv() {
+            for game in &batch.games {
+                let res = client.put(&url).json(game).send().expect("send game");
+                if res.status().is_success() {
+                    imported += 1;
+                } else {
+                    let status = res.status();
+                    let text = res.text().expect("decode response");
+                    if text.starts_with("duplicate game") {
+                        duplicates += 1;
+                    } else {
+                        rejected += 1;
+                        println!(
+                            "{:?}: {}: {} - {}",
+                            batch.filename,
+                            game.id.as_deref().unwrap_or("?"),
+                            status,
+                            text
+                        );
+                    }
+                }
+            }
+
+            state
+                .record(&batch.filename, batch.read)
+                .expect("record state");
+
+            if !batch.games.is_empty() {
+                println!(
+                    "{:?}: {}: {} imported, {} duplicates, {} rejected",
+                    batch.filename,
+                    batch.last_date(),
+                    imported,
+                    duplicates,
+                    rejected
+                );
+            }
+        }
+    });
+
+    for (arg, resume) in args.pgns.into_iter().zip(resume) {
+        let file = File::open(&arg)?;
+        let progress = ProgressBar::with_draw_target(
+            Some(file.metadata()?.len()),
+            ProgressDrawTarget::stdout_with_hz(4),
+        )
+        .with_style(
+            ProgressStyle::with_template(
+                "{spinner} {prefix} {wide_bar} {bytes_per_sec:>14} {eta:>7}",
+            )
+            .unwrap(),
+        )
+        .with_prefix(format!("{arg:?}"));
+        let file = progress.wrap_read(file);
+
+        let uncompressed: Box<dyn io::Read> = if arg.extension() == Some(OsStr::new("bz2")) {
+            Box::new(bzip2::read::MultiBzDecoder::new(file))
+        } else if arg.extension() == Some(OsStr::new("zst")) {
+            Box::new(zstd::Decoder::new(file)?)
+        } else {
+            Box::new(file)
+        };
+
+        let mut reader = BufferedReader::new(uncompressed);
+        let mut importer = Importer::new(tx.clone(), arg, args.batch_size, &filter, resume);
+        reader.read_all(&mut importer)?;
+        importer.send();
+
+        progress.finish();
+    }
+
+    drop(tx);
+    bg.join().expect("bg join");
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use std::{collections::HashMap, fs::File, io, path::PathBuf};
+
+use clap::Parser;
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Parser, Clone)]
+pub struct AliasOpt {
+    /// CSV file with columns `alias` and `name`, to normalize the spellings
+    /// of masters player names, for example `Carlsen, M.` to
+    /// `Carlsen, Magnus`. Applied to games imported afterwards, and when
+    /// looking up players.
+    #[arg(long = "masters-player-aliases")]
+    masters_player_aliases: Option<PathBuf>,
+}
+
+#[derive(Error, Debug)]
+pub enum AliasError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("csv error: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("alias {0:?} listed more than once")]
+    DuplicateAlias(String),
+}
+
+#[derive(Deserialize)]
+struct AliasRecord {
+    alias: String,
+    name: String,
+}
+
+/// Canonical names of masters players, by the lowercase spellings of their
+/// aliases.
+#[derive(Default, Debug)]
+pub struct PlayerAliases {
+    names: HashMap<String, String>,
+}
+
+impl PlayerAliases {
+    pub fn load(opt: AliasOpt) -> Result<PlayerAliases, AliasError> {
+        match opt.masters_player_aliases {
+            Some(path) => PlayerAliases::from_reader(File::open(path)?),
+            None => Ok(PlayerAliases::default()),
+        }
+    }
+
+    fn from_reader<R: io::Read>(reader: R) -> Result<PlayerAliases, AliasError> {
+        let mut names = HashMap::new();
+        for record in csv::Reader::from_reader(reader).deserialize() {
+            let AliasRecord { alias, name } = record?;
+            let alias = collapse_whitespace(&alias).to_lowercase();
+            if names
+                .insert(alias.clone(), collapse_whitespace(&name))
+                .is_some()
+            {
+                return Err(AliasError::DuplicateAlias(alias));
+            }
+        }
+        Ok(PlayerAliases { names })
+    }
+
+    /// The canonical spelling of a player name.
+    pub fn normalize(&self, name: &str) -> String {
+        let name = collapse_whitespace(name);
+        match self.names.get(&name.to_lowercase()) {
+            Some(canonical) => canonical.clone(),
+            None => name,
+        }
+    }
+
+    /// Whether two spellings refer to the same player.
+    pub fn same_player(&self, a: &str, b: &str) -> bool {
+        self.normalize(a).to_lowercase() == self.normalize(b).to_lowercase()
+    }
+
+    /// Aliases of the given canonical name, in lowercase.
+    pub fn aliases_of<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.names
+            .iter()
+            .filter(move |(_, canonical)| canonical.eq_ignore_ascii_case(name))
+            .map(|(alias, _)| alias.as_str())
+    }
+}
+
+fn collapse_whitespace(name: &str) -> String {
+    name.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_player_aliases() {
+        let aliases = PlayerAliases::from_reader(
+            "alias,name\nCarlsen  M.,\"Carlsen, Magnus\"\nMagnus Carlsen,\"Carlsen, Magnus\"\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(aliases.normalize("magnus  carlsen"), "Carlsen, Magnus");
+        assert_eq!(aliases.normalize(" Carlsen M. "), "Carlsen, Magnus");
+        assert_eq!(aliases.normalize("Caruana,  Fabiano"), "Caruana, Fabiano");
+        assert!(aliases.same_player("Carlsen, Magnus", "Magnus Carlsen"));
+        assert_eq!(aliases.aliases_of("Carlsen, Magnus").count(), 2);
+
+        assert!(PlayerAliases::from_reader("alias,name\na,b\nA,c\n".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,145 @@
+use std::cmp::Reverse;
+
+use clap::Parser;
+use shakmaty::Color;
+
+use crate::{
+    api::{ExplorerMove, MoveAnnotation},
+    model::Stats,
+};
+
+#[derive(Parser, Clone)]
+pub struct AnnotationOpt {
+    /// Moves need at least this many games to be annotated, and so does
+    /// the most popular move they are compared with.
+    #[arg(long = "annotation-min-games", default_value = "50")]
+    annotation_min_games: u64,
+    /// Annotate moves that score at least this many percentage points worse
+    /// than the most popular move as dubious (?!).
+    #[arg(long = "annotation-dubious", default_value = "5")]
+    annotation_dubious: f64,
+    /// Annotate moves that score at least this many percentage points worse
+    /// than the most popular move as mistakes (?).
+    #[arg(long = "annotation-mistake", default_value = "10")]
+    annotation_mistake: f64,
+    /// Annotate less popular moves that score at least this many percentage
+    /// points better than the most popular move as interesting (!?).
+    #[arg(long = "annotation-interesting", default_value = "5")]
+    annotation_interesting: f64,
+}
+
+/// Thresholds for annotating moves, comparing their scores with the score
+/// of the most popular move.
+#[derive(Debug, Clone)]
+pub struct MoveAnnotator {
+    min_games: u64,
+    dubious: f64,
+    mistake: f64,
+    interesting: f64,
+}
+
+impl Default for MoveAnnotator {
+    fn default() -> MoveAnnotator {
+        MoveAnnotator::new(AnnotationOpt::parse_from(["lila-openingexplorer"]))
+    }
+}
+
+impl MoveAnnotator {
+    pub fn new(opt: AnnotationOpt) -> MoveAnnotator {
+        MoveAnnotator {
+            min_games: opt.annotation_min_games,
+            dubious: opt.annotation_dubious,
+            mistake: opt.annotation_mistake,
+            interesting: opt.annotation_interesting,
+        }
+    }
+
+    /// Annotate moves of a position with the given side to move. The most
+    /// popular move itself is never annotated.
+    pub fn annotate(&self, moves: &mut [ExplorerMove], turn: Color) {
+        let Some((popular, popular_score)) = moves
+            .iter()
+            .enumerate()
+            .max_by_key(|(i, m)| (m.stats.total(), Reverse(*i)))
+            .filter(|(_, m)| m.stats.total() >= self.min_games)
+            .and_then(|(i, m)| Some((i, score(&m.stats, turn)?)))
+        else {
+            return;
+        };
+
+        for (i, m) in moves.iter_mut().enumerate() {
+            m.annotation = None;
+            if i == popular || m.stats.total() < self.min_games {
+                continue;
+            }
+            let Some(delta) = score(&m.stats, turn).map(|s| s - popular_score) else {
+                continue;
+            };
+            m.annotation = if delta <= -self.mistake {
+                Some(MoveAnnotation::Mistake)
+            } else if delta <= -self.dubious {
+                Some(MoveAnnotation::Dubious)
+            } else if delta >= self.interesting {
+                Some(MoveAnnotation::Interesting)
+            } else {
+                None
+            };
+        }
+    }
+}
+
+/// Score of the side to move in percent, counting draws as half a point.
+fn score(stats: &Stats, turn: Color) -> Option<f64> {
+    let total = stats.total();
+    (total > 0).then(|| {
+        (2 * turn.fold_wb(stats.white(), stats.black()) + stats.draws()) as f64 * 50.0
+            / total as f64
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn explorer_move(san: &str, white: u64, draws: u64, black: u64) -> ExplorerMove {
+        serde_json::from_value(json!({
+            "uci": "a2a3",
+            "san": san,
+            "white": white,
+            "draws": draws,
+            "black": black,
+            "game": null,
+            "opening": null,
+        }))
+        .expect("explorer move")
+    }
+
+    #[test]
+    fn test_annotate() {
+        let mut moves = vec![
+            explorer_move("e4", 500, 0, 500),
+            explorer_move("d4", 120, 0, 80),
+            explorer_move("c4", 90, 0, 110),
+            explorer_move("f3", 30, 0, 70),
+            explorer_move("a3", 0, 0, 10),
+        ];
+        MoveAnnotator::default().annotate(&mut moves, Color::White);
+        assert_eq!(
+            moves.iter().map(|m| m.annotation).collect::<Vec<_>>(),
+            [
+                None,
+                Some(MoveAnnotation::Interesting),
+                Some(MoveAnnotation::Dubious),
+                Some(MoveAnnotation::Mistake),
+                None,
+            ]
+        );
+
+        // Scores are from the perspective of the side to move.
+        MoveAnnotator::default().annotate(&mut moves, Color::Black);
+        assert_eq!(moves[1].annotation, Some(MoveAnnotation::Mistake));
+        assert_eq!(moves[3].annotation, Some(MoveAnnotation::Interesting));
+    }
+}
@@ -0,0 +1,251 @@
+use std::{convert::Infallible, fmt::Write as _, net::SocketAddr, str::FromStr};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRef, FromRequestParts},
+    http::{header::AUTHORIZATION, request::Parts, StatusCode},
+};
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AdminScope {
+    /// Only /import/* endpoints.
+    Import,
+    /// All administrative endpoints.
+    Full,
+}
+
+impl AdminScope {
+    fn allows(self, required: AdminScope) -> bool {
+        self == AdminScope::Full || self == required
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AdminToken {
+    scope: AdminScope,
+    secret: String,
+}
+
+impl AdminToken {
+    /// Identifies the token in the audit log, without revealing the secret.
+    fn fingerprint(&self) -> String {
+        let mut fingerprint = String::from(match self.scope {
+            AdminScope::Import => "import:",
+            AdminScope::Full => "full:",
+        });
+        for byte in &Sha1::digest(self.secret.as_bytes())[..4] {
+            let _ = write!(fingerprint, "{byte:02x}");
+        }
+        fingerprint
+    }
+}
+
+impl FromStr for AdminToken {
+    type Err = InvalidAdminToken;
+
+    fn from_str(s: &str) -> Result<AdminToken, InvalidAdminToken> {
+        let (scope, secret) = match s.split_once(':') {
+            Some(("import", secret)) => (AdminScope::Import, secret),
+            Some(("full", secret)) => (AdminScope::Full, secret),
+            Some(_) => return Err(InvalidAdminToken),
+            None => (AdminScope::Full, s),
+        };
+        if secret.is_empty() {
+            return Err(InvalidAdminToken);
+        }
+        Ok(AdminToken {
+            scope,
+            secret: secret.to_owned(),
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("invalid admin token, expected <secret>, full:<secret>, or import:<secret>")]
+pub struct InvalidAdminToken;
+
+#[derive(Default, Debug)]
+pub struct AdminTokens {
+    tokens: Vec<AdminToken>,
+}
+
+impl AdminTokens {
+    pub fn new(tokens: Vec<AdminToken>) -> AdminTokens {
+        AdminTokens { tokens }
+    }
+
+    fn authorize(&self, parts: &Parts, required: AdminScope) -> Result<AdminActor, StatusCode> {
+        if self.tokens.is_empty() {
+            // Rely on the reverse proxy.
+            return Ok(AdminActor::new(parts, None));
+        }
+
+        let secret = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+
+        // Compare digests of equal length, so that the time taken does not
+        // reveal the length of the secrets.
+        let digest = secret_digest(secret);
+        let token = self
+            .tokens
+            .iter()
+            .find(|token| constant_time_eq(&secret_digest(&token.secret), &digest))
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+
+        if token.scope.allows(required) {
+            Ok(AdminActor::new(parts, Some(token)))
+        } else {
+            Err(StatusCode::FORBIDDEN)
+        }
+    }
+}
+
+fn secret_digest(secret: &str) -> [u8; 20] {
+    Sha1::digest(secret.as_bytes()).into()
+}
+
+fn constant_time_eq(a: &[u8; 20], b: &[u8; 20]) -> bool {
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Who performed an administrative operation, for the audit log.
+#[derive(Debug, Clone)]
+pub struct AdminActor {
+    pub ip: Option<String>,
+    pub token: Option<String>,
+}
+
+impl AdminActor {
+    fn new(parts: &Parts, token: Option<&AdminToken>) -> AdminActor {
+        AdminActor {
+            ip: client_ip(parts),
+            token: token.map(AdminToken::fingerprint),
+        }
+    }
+}
+
+fn client_ip(parts: &Parts) -> Option<String> {
+    parts
+        .headers
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .map(|ip| ip.trim().to_owned())
+        .or_else(|| {
+            parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip().to_string())
+        })
+}
+
+/// Who requested an operation, for per-source quotas: The user reported by
+/// lila in the `X-Lichess-User` header, or else the client IP address.
+pub struct RequestSource(pub Option<String>);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for RequestSource
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Infallible> {
+        Ok(RequestSource(
+            parts
+                .headers
+                .get("x-lichess-user")
+                .and_then(|value| value.to_str().ok())
+                .filter(|user| !user.is_empty())
+                .map(|user| format!("user:{}", user.to_lowercase()))
+                .or_else(|| client_ip(parts).map(|ip| format!("ip:{ip}"))),
+        ))
+    }
+}
+
+/// Extractor that requires a token with full administrative permissions.
+pub struct RequireAdmin(pub AdminActor);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for RequireAdmin
+where
+    S: Send + Sync,
+    &'static AdminTokens: FromRef<S>,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, StatusCode> {
+        <&'static AdminTokens>::from_ref(state)
+            .authorize(parts, AdminScope::Full)
+            .map(RequireAdmin)
+    }
+}
+
+/// Extractor that requires a token that is at least allowed to import games.
+pub struct RequireImport(pub AdminActor);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for RequireImport
+where
+    S: Send + Sync,
+    &'static AdminTokens: FromRef<S>,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, StatusCode> {
+        <&'static AdminTokens>::from_ref(state)
+            .authorize(parts, AdminScope::Import)
+            .map(RequireImport)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admin_token_scope() {
+        let token: AdminToken = "secret".parse().unwrap();
+        assert_eq!(token.scope, AdminScope::Full);
+        assert_eq!(token.secret, "secret");
+
+        let token: AdminToken = "import:secret".parse().unwrap();
+        assert_eq!(token.scope, AdminScope::Import);
+        assert!(token.scope.allows(AdminScope::Import));
+        assert!(!token.scope.allows(AdminScope::Full));
+
+        assert!("import:".parse::<AdminToken>().is_err());
+        assert!("other:secret".parse::<AdminToken>().is_err());
+    }
+
+    #[test]
+    fn test_admin_token_fingerprint() {
+        let token: AdminToken = "import:secret".parse().unwrap();
+        let fingerprint = token.fingerprint();
+        assert!(fingerprint.starts_with("import:"));
+        assert_eq!(fingerprint.len(), "import:".len() + 8);
+        assert!(!fingerprint.contains("secret"));
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(
+            &secret_digest("secret"),
+            &secret_digest("secret")
+        ));
+        assert!(!constant_time_eq(
+            &secret_digest("secret"),
+            &secret_digest("secret2")
+        ));
+        assert!(!constant_time_eq(
+            &secret_digest("secret"),
+            &secret_digest("")
+        ));
+    }
+}
@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+use axum::http::{HeaderName, HeaderValue, Method};
+use clap::Parser;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+#[derive(Parser, Clone)]
+pub struct CorsOpt {
+    /// Allow access from all origins, without credentials.
+    #[arg(long, conflicts_with = "cors_origins")]
+    cors: bool,
+    /// Allow access only from this origin, for example
+    /// https://example.com. May be repeated.
+    #[arg(long = "cors-origin", value_delimiter = ',')]
+    cors_origins: Vec<HeaderValue>,
+    /// Allow requests with credentials (cookies, authorization headers) from
+    /// the origins given by --cors-origin.
+    #[arg(long, requires = "cors_origins")]
+    cors_allow_credentials: bool,
+    /// Request header that may be used in cross-origin requests, in addition
+    /// to the CORS-safelisted headers. May be repeated.
+    #[arg(long = "cors-allow-header", value_delimiter = ',')]
+    cors_allow_headers: Vec<HeaderName>,
+    /// Seconds for which browsers may cache the result of a preflight
+    /// request.
+    #[arg(long, default_value = "3600")]
+    cors_max_age: u64,
+}
+
+impl CorsOpt {
+    /// Layer answering preflight requests and adding CORS headers, unless
+    /// cross-origin access is disabled.
+    pub fn layer(&self) -> Option<CorsLayer> {
+        let allow_origin = if self.cors {
+            AllowOrigin::any()
+        } else if !self.cors_origins.is_empty() {
+            AllowOrigin::list(self.cors_origins.iter().cloned())
+        } else {
+            return None;
+        };
+
+        Some(
+            CorsLayer::new()
+                .allow_origin(allow_origin)
+                .allow_methods([Method::GET, Method::HEAD])
+                .allow_headers(self.cors_allow_headers.clone())
+                .allow_credentials(self.cors_allow_credentials)
+                .max_age(Duration::from_secs(self.cors_max_age)),
+        )
+    }
+}
@@ -1,10 +1,18 @@
-use std::sync::Arc;
+use std::{io, sync::Arc};
 
 use axum::{http::StatusCode, response::Response};
-use shakmaty::{san::SanError, uci::IllegalUciMoveError, variant::VariantPosition, PositionError};
+use shakmaty::{
+    san::SanError,
+    uci::{IllegalUciMoveError, UciMove},
+    variant::VariantPosition,
+    Color, PositionError,
+};
 use thiserror::Error;
 
-use crate::model::{GameId, LaxDate};
+use crate::{
+    model::{FormatVersion, GameId, LaxDate, ReadError},
+    opening::EcoRange,
+};
 
 #[derive(Error, Debug, Clone)]
 pub enum Error {
@@ -14,20 +22,69 @@ pub enum Error {
     IllegalUciMoveError(#[from] IllegalUciMoveError),
     #[error("bad request: {0}")]
     SanError(#[from] SanError),
+    #[error("bad request: illegal move at ply {ply}: {uci}")]
+    IllegalMove { ply: usize, uci: UciMove },
+    #[error("bad request: {count} {color} pieces on the board, at most {max} are supported")]
+    TooManyPieces {
+        color: Color,
+        count: usize,
+        max: usize,
+    },
     #[error("duplicate game {id}")]
     DuplicateGame { id: GameId },
+    #[error("game {id} duplicates the content of game {existing}")]
+    DuplicateGameContent { id: GameId, existing: GameId },
     #[error("rejected import of {id} due to average rating {rating}")]
     RejectedRating { id: GameId, rating: u16 },
     #[error("rejected import of {id} due to date {date}")]
     RejectedDate { id: GameId, date: LaxDate },
     #[error("indexer queue full")]
     IndexerQueueFull,
+    #[error("too many players queued for indexing, try again later")]
+    IndexerQuotaExhausted,
     #[error("duplicate opening position")]
     DuplicateOpening,
+    #[error("callback url must point to lila")]
+    InvalidCallbackUrl,
+    #[error("lease not found or expired")]
+    LeaseNotFound,
+    #[error("bad request: malformed lease batch: {0}")]
+    InvalidLeaseBatch(ReadError),
+    #[error("game search is not enabled")]
+    GameSearchDisabled,
+    #[error("bad request: {0}")]
+    InvalidGameSearch(&'static str),
+    #[error("bad request: {0}")]
+    InvalidPgn(&'static str),
+    #[error("bad request: {0}")]
+    InvalidTreeQuery(&'static str),
+    #[error("bad request: {0}")]
+    InvalidRepertoireQuery(&'static str),
+    #[error("bad request: {0}")]
+    InvalidBatchQuery(String),
+    #[error("bad request: {0}")]
+    InvalidVariants(String),
+    #[error("bad request: zobrist cannot be combined with fen or play")]
+    ZobristWithPosition,
+    #[error("bad request: zobrist is not supported by this endpoint")]
+    ZobristNotSupported,
+    #[error("bad request: eco cannot be combined with fen or variants other than standard")]
+    EcoWithPosition,
+    #[error("bad request: no opening in eco range {0}")]
+    UnknownEco(EcoRange),
+    #[error("bad request: standard entries are not stored separately")]
+    VariantNotSeparate,
+    #[error("bad request: {cf} can only be migrated to {version}, written by this release")]
+    MigrationVersion {
+        cf: &'static str,
+        version: FormatVersion,
+    },
     #[error("bad request: {0}")]
     CsvError(Arc<csv::Error>),
     #[error("internal request failed: {0}")]
     ReqwestError(Arc<reqwest::Error>),
+    #[error("io error: {0}")]
+    IoError(Arc<io::Error>),
 }
 
 impl From<PositionError<VariantPosition>> for Error {
@@ -48,20 +105,45 @@ impl From<reqwest::Error> for Error {
     }
 }
 
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::IoError(Arc::new(error))
+    }
+}
+
 impl axum::response::IntoResponse for Error {
     fn into_response(self) -> Response {
         (
             match self {
                 Error::IndexerQueueFull => StatusCode::SERVICE_UNAVAILABLE,
+                Error::IndexerQuotaExhausted => StatusCode::TOO_MANY_REQUESTS,
+                Error::LeaseNotFound | Error::GameSearchDisabled => StatusCode::NOT_FOUND,
                 Error::PositionError(_)
                 | Error::IllegalUciMoveError(_)
                 | Error::SanError(_)
+                | Error::IllegalMove { .. }
+                | Error::TooManyPieces { .. }
                 | Error::DuplicateGame { .. }
+                | Error::DuplicateGameContent { .. }
                 | Error::RejectedRating { .. }
                 | Error::RejectedDate { .. }
                 | Error::CsvError(_)
-                | Error::DuplicateOpening => StatusCode::BAD_REQUEST,
-                Error::ReqwestError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                | Error::DuplicateOpening
+                | Error::InvalidCallbackUrl
+                | Error::InvalidLeaseBatch(_)
+                | Error::InvalidGameSearch(_)
+                | Error::InvalidPgn(_)
+                | Error::InvalidTreeQuery(_)
+                | Error::InvalidRepertoireQuery(_)
+                | Error::InvalidBatchQuery(_)
+                | Error::InvalidVariants(_)
+                | Error::ZobristWithPosition
+                | Error::ZobristNotSupported
+                | Error::EcoWithPosition
+                | Error::UnknownEco(_)
+                | Error::VariantNotSeparate
+                | Error::MigrationVersion { .. } => StatusCode::BAD_REQUEST,
+                Error::ReqwestError(_) | Error::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             },
             self.to_string(),
         )
@@ -0,0 +1,110 @@
+use std::convert::Infallible;
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{
+        header::{ACCEPT, CONTENT_TYPE, VARY},
+        request::Parts,
+        HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+use crate::trace::Trace;
+
+const MSGPACK: &str = "application/x-msgpack";
+
+/// Serialization of a response, negotiated with the `Accept` header.
+/// MessagePack is cheaper to parse for clients that read millions of
+/// responses.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum ResponseFormat {
+    #[default]
+    Json,
+    MsgPack,
+}
+
+impl ResponseFormat {
+    fn from_accept(accept: &str) -> ResponseFormat {
+        if accept
+            .split(',')
+            .filter_map(|range| range.split(';').next())
+            .map(str::trim)
+            .any(|media_type| {
+                media_type.eq_ignore_ascii_case(MSGPACK)
+                    || media_type.eq_ignore_ascii_case("application/msgpack")
+            })
+        {
+            ResponseFormat::MsgPack
+        } else {
+            ResponseFormat::Json
+        }
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ResponseFormat
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Infallible> {
+        Ok(parts
+            .headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .map(ResponseFormat::from_accept)
+            .find(|format| *format != ResponseFormat::Json)
+            .unwrap_or_default())
+    }
+}
+
+/// Serializes a response in the negotiated format, as a stage of the
+/// current request.
+pub struct Formatted<T>(pub ResponseFormat, pub T);
+
+impl<T: Serialize> IntoResponse for Formatted<T> {
+    fn into_response(self) -> Response {
+        let Formatted(format, value) = self;
+        let mut res = Trace::current().stage("serialize", || match format {
+            ResponseFormat::Json => Json(value).into_response(),
+            ResponseFormat::MsgPack => match rmp_serde::to_vec_named(&value) {
+                Ok(buf) => ([(CONTENT_TYPE, MSGPACK)], buf).into_response(),
+                Err(err) => {
+                    log::error!("failed to serialize msgpack response: {err}");
+                    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                }
+            },
+        });
+        res.headers_mut()
+            .append(VARY, HeaderValue::from_static("accept"));
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_accept() {
+        assert_eq!(
+            ResponseFormat::from_accept("application/json"),
+            ResponseFormat::Json
+        );
+        assert_eq!(ResponseFormat::from_accept("*/*"), ResponseFormat::Json);
+        assert_eq!(
+            ResponseFormat::from_accept("application/json;q=0.5, application/x-msgpack"),
+            ResponseFormat::MsgPack
+        );
+        assert_eq!(
+            ResponseFormat::from_accept("Application/MsgPack; q=1"),
+            ResponseFormat::MsgPack
+        );
+    }
+}
@@ -0,0 +1,236 @@
+use std::{
+    future::Future,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{header::RETRY_AFTER, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures_util::{stream::Stream, StreamExt as _};
+
+use crate::metrics::Metrics;
+
+/// Limits the number of explorer queries in flight. Queries beyond the limit
+/// are rejected immediately, instead of queueing for the blocking pool with
+/// ever growing latency.
+pub struct LoadShedder {
+    /// Adjustable at runtime. `usize::MAX` if unlimited.
+    limit: AtomicUsize,
+    in_flight: AtomicUsize,
+    metrics: &'static Metrics,
+}
+
+impl LoadShedder {
+    pub fn new(limit: Option<usize>, metrics: &'static Metrics) -> LoadShedder {
+        LoadShedder {
+            limit: AtomicUsize::new(limit.unwrap_or(usize::MAX)),
+            in_flight: AtomicUsize::new(0),
+            metrics,
+        }
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        Some(self.limit.load(Ordering::Relaxed)).filter(|limit| *limit != usize::MAX)
+    }
+
+    pub fn set_limit(&self, limit: Option<usize>) {
+        self.limit
+            .store(limit.unwrap_or(usize::MAX), Ordering::Relaxed);
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Takes a slot for a query, unless the limit is reached. The slot is
+    /// released when the guard is dropped.
+    pub fn try_acquire(&self) -> Option<InFlightGuard<'_>> {
+        let in_flight = self.in_flight.fetch_add(1, Ordering::Relaxed);
+        let guard = InFlightGuard(&self.in_flight);
+
+        if in_flight >= self.limit.load(Ordering::Relaxed) {
+            self.metrics.inc_shed();
+            return None;
+        }
+
+        Some(guard)
+    }
+}
+
+pub struct InFlightGuard<'a>(&'a AtomicUsize);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub async fn shed_load(
+    State(shedder): State<&'static LoadShedder>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(_guard) = shedder.try_acquire() else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(RETRY_AFTER, "1")],
+            "too many queries in flight",
+        )
+            .into_response();
+    };
+
+    next.run(request).await
+}
+
+/// Answers the queries of a streamed response, up to `concurrency` at a
+/// time, each in a slot of its own. The slot taken by [`shed_load()`] is
+/// released as soon as the response headers exist, long before the body is
+/// complete. Yields `None` for queries over the limit.
+pub fn shed_each<S, F, Fut>(
+    shedder: &'static LoadShedder,
+    queries: S,
+    concurrency: usize,
+    mut f: F,
+) -> impl Stream<Item = Option<Fut::Output>>
+where
+    S: Stream,
+    F: FnMut(S::Item) -> Fut,
+    Fut: Future,
+{
+    queries
+        .map(move |query| {
+            let answer = f(query);
+            async move {
+                let _guard = shedder.try_acquire()?;
+                Some(answer.await)
+            }
+        })
+        .buffered(concurrency)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, sync::Arc};
+
+    use axum::{
+        body::{to_bytes, Body},
+        middleware,
+        routing::get,
+        Router,
+    };
+    use futures_util::stream;
+    use tokio::sync::Notify;
+    use tower::ServiceExt as _;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shed_load() {
+        let metrics: &'static Metrics = Box::leak(Box::default());
+        let shedder: &'static LoadShedder = Box::leak(Box::new(LoadShedder::new(Some(1), metrics)));
+
+        let entered = Arc::new(Notify::new());
+        let release = Arc::new(Notify::new());
+        let app = Router::new()
+            .route(
+                "/",
+                get({
+                    let entered = Arc::clone(&entered);
+                    let release = Arc::clone(&release);
+                    move || {
+                        let entered = Arc::clone(&entered);
+                        let release = Arc::clone(&release);
+                        async move {
+                            entered.notify_one();
+                            release.notified().await;
+                            "ok"
+                        }
+                    }
+                }),
+            )
+            .layer(middleware::from_fn_with_state(shedder, shed_load));
+        let request = || Request::builder().uri("/").body(Body::empty()).unwrap();
+
+        // Occupy the only slot.
+        let first = tokio::spawn(app.clone().oneshot(request()));
+        entered.notified().await;
+        assert_eq!(shedder.in_flight(), 1);
+
+        // Over the limit.
+        let res = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()[RETRY_AFTER], "1");
+        assert_eq!(shedder.in_flight(), 1);
+
+        // Slot released.
+        release.notify_one();
+        assert_eq!(first.await.unwrap().unwrap().status(), StatusCode::OK);
+        assert_eq!(shedder.in_flight(), 0);
+        release.notify_one();
+        assert_eq!(
+            app.oneshot(request()).await.unwrap().status(),
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shed_each() {
+        let metrics: &'static Metrics = Box::leak(Box::default());
+        let shedder: &'static LoadShedder = Box::leak(Box::new(LoadShedder::new(Some(1), metrics)));
+
+        let entered = Arc::new(Notify::new());
+        let release = Arc::new(Notify::new());
+        let app = Router::new()
+            .route(
+                "/",
+                get({
+                    let entered = Arc::clone(&entered);
+                    let release = Arc::clone(&release);
+                    move || {
+                        let entered = Arc::clone(&entered);
+                        let release = Arc::clone(&release);
+                        async move {
+                            let lines = shed_each(shedder, stream::iter([1, 2, 3]), 2, move |i| {
+                                let entered = Arc::clone(&entered);
+                                let release = Arc::clone(&release);
+                                async move {
+                                    if i == 1 {
+                                        entered.notify_one();
+                                        release.notified().await;
+                                    }
+                                    i
+                                }
+                            });
+                            Body::from_stream(lines.map(|res| {
+                                Ok::<_, Infallible>(match res {
+                                    Some(i) => format!("{i}\n"),
+                                    None => "shed\n".to_owned(),
+                                })
+                            }))
+                        }
+                    }
+                }),
+            )
+            .layer(middleware::from_fn_with_state(shedder, shed_load));
+
+        // The slot of the request is released once the headers exist.
+        let res = app
+            .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(shedder.in_flight(), 0);
+
+        // Queries still take slots while the body is streaming.
+        let body = tokio::spawn(to_bytes(res.into_body(), usize::MAX));
+        entered.notified().await;
+        assert_eq!(shedder.in_flight(), 1);
+
+        release.notify_one();
+        assert_eq!(body.await.unwrap().unwrap(), "1\nshed\n3\n");
+        assert_eq!(shedder.in_flight(), 0);
+    }
+}
@@ -1,12 +1,36 @@
+mod auth;
+mod cors;
 mod error;
+mod format;
+mod load_shed;
 mod nd_json;
 mod query;
+mod readiness;
 mod response;
 
+pub use auth::{AdminActor, AdminToken, AdminTokens, RequestSource, RequireAdmin, RequireImport};
+pub use cors::CorsOpt;
 pub use error::Error;
+pub use format::{Formatted, ResponseFormat};
+pub use load_shed::{shed_each, shed_load, LoadShedder};
 pub use nd_json::NdJson;
 pub use query::{
-    HistoryWanted, LichessHistoryQuery, LichessQuery, LichessQueryFilter, Limits, MastersQuery,
-    PlayPosition, PlayerLimits, PlayerQuery, PlayerQueryFilter, Source, WithSource,
+    query_from_json, AuditQuery, GameSearchQuery, GroupBy, HistoryWanted, HotPositionsQuery,
+    HotWindow, ImportCompleteQuery, LichessHistoryQuery, LichessQuery, LichessQueryFilter, Limits,
+    MastersGamesAtQuery, MastersImportQuery, MastersNoveltyQuery, MastersPlayerQuery, MastersQuery,
+    MastersStructureQuery, MigrateQuery, MoveOrder, Play, PlayPosition, PlayerExportQuery,
+    PlayerGamesQuery, PlayerImportQuery, PlayerLimits, PlayerQuery, PlayerQueryFilter,
+    PlayerRepertoireQuery, PlayerStatusQuery, ResponseFields, RuntimeConfig, Source,
+    TranspositionsQuery, TreeFormat, TreeQuery, VariantsQuery, WithSource,
+};
+pub use readiness::Readiness;
+pub use response::{
+    AuditLogEntry, AuditLogResponse, BatchResponse, CachesMonitorResponse, ExplorerGame,
+    ExplorerGameWithUciMove, ExplorerMove, ExplorerResponse, HotPosition, HotPositionsResponse,
+    ImportStatusResponse, IndexerMonitorResponse, IndexerQueueEntry, MastersGamesAtResponse,
+    MastersImportReport, MastersNoveltyResponse, MastersPgnImportResult, MastersPlayerMove,
+    MastersPlayerResponse, MastersStructureResponse, MonthSummary, MoveAnnotation, MoveConfidence,
+    Novelty, OpeningsStatusResponse, PlayerGame, PlayerGamesResponse, PlayerRepertoireResponse,
+    PlayerStatusResponse, RepertoireLine, RocksDbMonitorResponse, Selected, StructurePosition,
+    TimeBreakdown, Transposition, TranspositionsResponse, TreeRow, VersionResponse,
 };
-pub use response::{ExplorerGame, ExplorerGameWithUciMove, ExplorerMove, ExplorerResponse};
@@ -1,10 +1,16 @@
 use std::{
-    cmp::min,
     collections::BTreeSet,
+    fmt,
+    fmt::Write as _,
     hash::{Hash, Hasher},
+    num::NonZeroU64,
 };
 
-use serde::Deserialize;
+use serde::{
+    de::{self, value::MapDeserializer, DeserializeOwned},
+    Deserialize, Deserializer, Serialize,
+};
+use serde_json::Value;
 use serde_with::{
     formats::CommaSeparator, serde_as, DefaultOnError, DisplayFromStr, StringWithSeparator,
 };
@@ -17,8 +23,13 @@ use shakmaty::{
 
 use crate::{
     api::Error,
-    model::{Mode, Month, RatingGroup, Speed, UserName, Year},
-    opening::{Opening, Openings},
+    db::EntryColumn,
+    model::{
+        GameSource, Mode, Month, PlayerGamesCursor, PlayerRating, RatingGroup, SearchSource, Speed,
+        Title, TitleFilter, UserName, Year,
+    },
+    opening::{EcoRange, Opening, Openings},
+    zobrist::StableZobrist128,
 };
 
 #[serde_as]
@@ -45,8 +56,38 @@ pub struct MastersQuery {
     pub until: Year,
     #[serde(flatten)]
     pub limits: Limits,
+    /// Include result percentages and score confidence intervals.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub confidence: bool,
+    /// Annotate moves with !?, ?! or ?, compared with the most popular
+    /// move.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub annotate: bool,
+    #[serde(default, deserialize_with = "deserialize_fields")]
+    pub fields: Option<ResponseFields>,
+}
+
+impl MastersQuery {
+    /// Canonical form of the query, identifying its response in the
+    /// persistent response cache.
+    pub fn canonical(&self) -> String {
+        let mut canonical = Canonical::default();
+        self.play.write_canonical(&mut canonical);
+        canonical
+            .param("since", self.since)
+            .param("until", self.until);
+        self.limits.write_canonical(&mut canonical);
+        canonical
+            .param("confidence", self.confidence)
+            .param("annotate", self.annotate)
+            .opt("fields", self.fields.map(|fields| fields.0));
+        canonical.0
+    }
 }
 
+#[serde_as]
 #[derive(Deserialize, Clone, Debug, Hash, Eq, PartialEq)]
 pub struct LichessQuery {
     #[serde(flatten)]
@@ -57,6 +98,155 @@ pub struct LichessQuery {
     pub filter: LichessQueryFilter,
     #[serde(default)]
     pub history: HistoryWanted,
+    /// Also summarize each month of the range separately.
+    #[serde(default, rename = "groupBy")]
+    pub group_by: Option<GroupBy>,
+    /// Include game counts of each move for this many recent months.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub trend: Option<u16>,
+    /// Include result percentages and score confidence intervals.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub confidence: bool,
+    /// Annotate moves with !?, ?! or ?, compared with the most popular
+    /// move.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub annotate: bool,
+    #[serde(default, deserialize_with = "deserialize_fields")]
+    pub fields: Option<ResponseFields>,
+}
+
+impl LichessQuery {
+    /// Canonical form of the query, identifying its response in the
+    /// persistent response cache.
+    pub fn canonical(&self) -> String {
+        let mut canonical = Canonical::default();
+        self.play.write_canonical(&mut canonical);
+        self.limits.write_canonical(&mut canonical);
+        self.filter.write_canonical(&mut canonical);
+        canonical
+            .param("history", format_args!("{:?}", self.history))
+            .opt("groupBy", self.group_by.map(|g| format!("{g:?}")))
+            .opt("trend", self.trend)
+            .param("confidence", self.confidence)
+            .param("annotate", self.annotate)
+            .opt("fields", self.fields.map(|fields| fields.0));
+        canonical.0
+    }
+}
+
+/// Builds a canonical form of a query, with every parameter in a fixed
+/// order and spelling. Unlike `Hash`, it does not depend on the build, so
+/// that it can identify persisted data.
+#[derive(Default)]
+struct Canonical(String);
+
+impl Canonical {
+    fn param(&mut self, name: &str, value: impl fmt::Display) -> &mut Canonical {
+        let _ = write!(self.0, "{name}={value}&");
+        self
+    }
+
+    fn opt<T: fmt::Display>(&mut self, name: &str, value: Option<T>) -> &mut Canonical {
+        match value {
+            Some(value) => self.param(name, value),
+            None => self.param(name, ""),
+        }
+    }
+
+    fn set<T: fmt::Debug>(&mut self, name: &str, values: Option<&BTreeSet<T>>) -> &mut Canonical {
+        self.opt(
+            name,
+            values.map(|values| {
+                values
+                    .iter()
+                    .map(|value| format!("{value:?}"))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            }),
+        )
+    }
+}
+
+/// Top-level fields of an explorer response, selected with
+/// `fields=moves,total`. `truncated`, `truncatedAt`, `queuePosition` and
+/// `queueEta` are always included.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct ResponseFields(u16);
+
+impl ResponseFields {
+    const NAMES: [&'static str; 9] = [
+        "total",
+        "moves",
+        "topGames",
+        "recentGames",
+        "opening",
+        "history",
+        "indexedGames",
+        "uniquePlayers",
+        "months",
+    ];
+
+    fn contains(self, name: &str) -> bool {
+        ResponseFields::NAMES
+            .iter()
+            .position(|n| *n == name)
+            .is_some_and(|i| self.0 & (1 << i) != 0)
+    }
+
+    /// Whether a key of the JSON response is selected.
+    pub fn contains_key(self, key: &str) -> bool {
+        match key {
+            "white" | "draws" | "black" => self.contains("total"),
+            "truncated" | "truncatedAt" | "queuePosition" | "queueEta" => true,
+            key => self.contains(key),
+        }
+    }
+
+    /// Avoid reading moves and games that are not selected.
+    pub fn restrict(self, limits: &mut Limits) {
+        if !self.contains("moves") {
+            limits.moves = 0;
+            limits.coverage = None;
+        }
+        if !self.contains("topGames") {
+            limits.top_games = 0;
+        }
+        if !self.contains("recentGames") {
+            limits.recent_games = 0;
+        }
+    }
+}
+
+fn deserialize_fields<'de, D>(deserializer: D) -> Result<Option<ResponseFields>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let fields = String::deserialize(deserializer)?;
+    let mut bits = 0;
+    for field in fields.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+        match ResponseFields::NAMES.iter().position(|n| *n == field) {
+            Some(i) => bits |= 1 << i,
+            None => {
+                return Err(de::Error::custom(format!(
+                    "unknown field {field:?}, expected one of {}",
+                    ResponseFields::NAMES.join(", ")
+                )))
+            }
+        }
+    }
+    Ok(Some(ResponseFields(bits)))
+}
+
+/// Answer a /lichess query for each of the given variants at once.
+#[serde_as]
+#[derive(Deserialize, Debug, Default)]
+pub struct VariantsQuery {
+    #[serde_as(as = "Option<StringWithSeparator<CommaSeparator, String>>")]
+    #[serde(default)]
+    pub variants: Option<Vec<String>>,
 }
 
 #[derive(Deserialize, Clone, Debug, Hash, Eq, PartialEq)]
@@ -67,6 +257,80 @@ pub struct LichessHistoryQuery {
     pub filter: LichessQueryFilter,
 }
 
+#[derive(Deserialize, Debug, Copy, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum TreeFormat {
+    #[default]
+    Csv,
+    Json,
+}
+
+#[serde_as]
+#[derive(Deserialize, Debug)]
+pub struct TreeQuery {
+    #[serde(flatten)]
+    pub play: Play,
+    #[serde(default)]
+    pub db: SearchSource,
+    /// Only used for the lichess database.
+    #[serde(flatten)]
+    pub filter: LichessQueryFilter,
+    /// Number of plies to explore below the position.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "TreeQuery::default_depth")]
+    pub depth: u8,
+    /// Number of most played moves to explore in each position.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "TreeQuery::default_moves")]
+    pub moves: usize,
+    #[serde(default)]
+    pub format: TreeFormat,
+}
+
+impl TreeQuery {
+    fn default_depth() -> u8 {
+        3
+    }
+
+    fn default_moves() -> usize {
+        5
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TranspositionsQuery {
+    #[serde(flatten)]
+    pub play: Play,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MastersGamesAtQuery {
+    #[serde(flatten)]
+    pub play: Play,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MastersNoveltyQuery {
+    #[serde(flatten)]
+    pub play: Play,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MastersPlayerQuery {
+    #[serde(flatten)]
+    pub play: Play,
+}
+
+#[serde_as]
+#[derive(Deserialize, Debug)]
+pub struct MastersStructureQuery {
+    #[serde(flatten)]
+    pub play: Play,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
 #[serde_as]
 #[derive(Deserialize, Clone, Debug, Hash, Eq, PartialEq)]
 pub struct LichessQueryFilter {
@@ -76,6 +340,18 @@ pub struct LichessQueryFilter {
     #[serde_as(as = "Option<StringWithSeparator<CommaSeparator, RatingGroup>>")]
     #[serde(default)]
     pub ratings: Option<BTreeSet<RatingGroup>>,
+    #[serde_as(as = "Option<StringWithSeparator<CommaSeparator, Mode>>")]
+    #[serde(default)]
+    pub modes: Option<BTreeSet<Mode>>,
+    /// Only count games in which the position occurred at this ply or later.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default, rename = "minPly")]
+    pub min_ply: Option<u8>,
+    /// Only count games in which the position occurred at this ply or
+    /// earlier.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default, rename = "maxPly")]
+    pub max_ply: Option<u8>,
     #[serde_as(as = "Option<DisplayFromStr>")]
     #[serde(default)]
     pub since: Option<Month>,
@@ -85,15 +361,48 @@ pub struct LichessQueryFilter {
 }
 
 impl LichessQueryFilter {
+    fn write_canonical(&self, canonical: &mut Canonical) {
+        canonical
+            .set("speeds", self.speeds.as_ref())
+            .set("ratings", self.ratings.as_ref())
+            .set("modes", self.modes.as_ref())
+            .opt("minPly", self.min_ply)
+            .opt("maxPly", self.max_ply)
+            .opt("since", self.since)
+            .opt("until", self.until);
+    }
+
     pub fn contains_speed(&self, speed: Speed) -> bool {
         self.speeds
             .as_ref()
             .map_or(true, |speeds| speeds.contains(&speed))
     }
 
+    pub fn contains_mode(&self, mode: Mode) -> bool {
+        self.modes
+            .as_ref()
+            .map_or(true, |modes| modes.contains(&mode))
+    }
+
+    /// Groups without a recorded ply are only included if no ply range is
+    /// requested.
+    pub fn contains_ply(&self, ply: Option<u8>) -> bool {
+        match ply {
+            Some(ply) => {
+                self.min_ply.map_or(true, |min_ply| min_ply <= ply)
+                    && self.max_ply.map_or(true, |max_ply| ply <= max_ply)
+            }
+            None => self.min_ply.is_none() && self.max_ply.is_none(),
+        }
+    }
+
+    /// If 2500 is the highest requested group, it also includes all higher
+    /// groups, as it did before those could be requested separately.
     pub fn contains_rating_group(&self, rating_group: RatingGroup) -> bool {
         self.ratings.as_ref().map_or(true, |ratings| {
-            ratings.contains(&min(rating_group, RatingGroup::Group2500))
+            ratings.contains(&rating_group)
+                || (rating_group > RatingGroup::Group2500
+                    && ratings.last() == Some(&RatingGroup::Group2500))
         })
     }
 
@@ -122,6 +431,10 @@ pub struct PlayerQuery {
     pub filter: PlayerQueryFilter,
     #[serde(flatten)]
     pub limits: PlayerLimits,
+    /// Notify lila with a POST request once indexing is complete, and end the
+    /// stream after the first response.
+    #[serde(default, rename = "callbackUrl")]
+    pub callback_url: Option<String>,
 }
 
 #[serde_as]
@@ -134,6 +447,10 @@ pub struct PlayerLimits {
     #[serde_as(as = "DisplayFromStr")]
     #[serde(default = "usize::max_value")]
     pub recent_games: usize,
+    /// Include results by the weekday and hour at which games started.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub time_breakdown: bool,
 }
 
 #[serde_as]
@@ -151,6 +468,260 @@ pub struct PlayerQueryFilter {
     #[serde_as(as = "DisplayFromStr")]
     #[serde(default = "Month::max_value")]
     pub until: Month,
+    #[serde_as(as = "Option<StringWithSeparator<CommaSeparator, GameSource>>")]
+    #[serde(default)]
+    pub sources: Option<Vec<GameSource>>,
+    /// Only games in which the player was rated at least this much.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default, rename = "minRating")]
+    pub min_rating: Option<u16>,
+    /// Only games in which the player was rated less than this.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default, rename = "maxRating")]
+    pub max_rating: Option<u16>,
+    /// Only games against opponents with any of these titles.
+    #[serde_as(as = "Option<StringWithSeparator<CommaSeparator, TitleFilter>>")]
+    #[serde(default, rename = "opponentTitle")]
+    pub opponent_title: Option<Vec<TitleFilter>>,
+    /// No games against opponents with any of these titles.
+    #[serde_as(as = "Option<StringWithSeparator<CommaSeparator, TitleFilter>>")]
+    #[serde(default, rename = "excludeOpponentTitle")]
+    pub exclude_opponent_title: Option<Vec<TitleFilter>>,
+}
+
+impl PlayerQueryFilter {
+    /// Groups without a recorded source are only included if no sources are
+    /// requested.
+    pub fn contains_source(&self, source: Option<GameSource>) -> bool {
+        match (&self.sources, source) {
+            (None, _) => true,
+            (Some(sources), Some(source)) => sources.contains(&source),
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Ratings are recorded in steps of 100 points. Groups without a
+    /// recorded rating are only included if no rating range is requested.
+    pub fn contains_rating(&self, rating: Option<PlayerRating>) -> bool {
+        if self.min_rating.is_none() && self.max_rating.is_none() {
+            return true;
+        }
+        rating.is_some_and(|rating| {
+            self.min_rating.map_or(true, |min| min <= rating.rating())
+                && self.max_rating.map_or(true, |max| rating.rating() < max)
+        })
+    }
+
+    /// Groups without a recorded opponent title are only included if no
+    /// titles are requested or excluded.
+    pub fn contains_opponent_title(&self, title: Option<Title>) -> bool {
+        if self.opponent_title.is_none() && self.exclude_opponent_title.is_none() {
+            return true;
+        }
+        title.is_some_and(|title| {
+            self.opponent_title
+                .as_ref()
+                .map_or(true, |filters| filters.iter().any(|f| f.matches(title)))
+                && !self
+                    .exclude_opponent_title
+                    .as_ref()
+                    .is_some_and(|filters| filters.iter().any(|f| f.matches(title)))
+        })
+    }
+}
+
+#[serde_as]
+#[derive(Deserialize, Debug)]
+pub struct PlayerRepertoireQuery {
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub variant: Variant,
+    #[serde_as(as = "DisplayFromStr")]
+    pub player: UserName,
+    #[serde(flatten)]
+    pub filter: PlayerQueryFilter,
+    /// Number of plies to explore from the initial position.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "PlayerRepertoireQuery::default_depth")]
+    pub depth: u8,
+    /// Number of most played moves to explore in each position.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "PlayerRepertoireQuery::default_moves")]
+    pub moves: usize,
+    /// Number of lines to return for each color.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "PlayerRepertoireQuery::default_lines")]
+    pub lines: usize,
+}
+
+impl PlayerRepertoireQuery {
+    fn default_depth() -> u8 {
+        4
+    }
+
+    fn default_moves() -> usize {
+        3
+    }
+
+    fn default_lines() -> usize {
+        10
+    }
+}
+
+#[serde_as]
+#[derive(Deserialize, Debug)]
+pub struct PlayerExportQuery {
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub variant: Variant,
+    #[serde_as(as = "DisplayFromStr")]
+    pub player: UserName,
+    #[serde_as(as = "DisplayFromStr")]
+    pub color: Color,
+    #[serde(flatten)]
+    pub filter: PlayerQueryFilter,
+    /// Number of plies to explore from the initial position.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "PlayerExportQuery::default_depth")]
+    pub depth: u8,
+    /// Number of most played moves to explore in each position.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "PlayerExportQuery::default_moves")]
+    pub moves: usize,
+}
+
+impl PlayerExportQuery {
+    fn default_depth() -> u8 {
+        8
+    }
+
+    fn default_moves() -> usize {
+        3
+    }
+}
+
+#[serde_as]
+#[derive(Deserialize, Debug)]
+pub struct PlayerGamesQuery {
+    #[serde(flatten)]
+    pub play: Play,
+    #[serde_as(as = "DisplayFromStr")]
+    pub player: UserName,
+    #[serde_as(as = "DisplayFromStr")]
+    pub color: Color,
+    #[serde(flatten)]
+    pub filter: PlayerQueryFilter,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub max: Option<usize>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub cursor: Option<PlayerGamesCursor>,
+}
+
+#[serde_as]
+#[derive(Deserialize, Debug)]
+pub struct PlayerStatusQuery {
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub variant: Variant,
+    #[serde_as(as = "DisplayFromStr")]
+    pub player: UserName,
+    #[serde_as(as = "DisplayFromStr")]
+    pub color: Color,
+}
+
+#[serde_as]
+#[derive(Deserialize, Debug)]
+pub struct PlayerImportQuery {
+    #[serde_as(as = "StringWithSeparator<CommaSeparator, UserName>")]
+    pub players: Vec<UserName>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MastersImportQuery {
+    /// Validate games without importing them.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[serde_as]
+#[derive(Deserialize, Debug)]
+pub struct ImportCompleteQuery {
+    #[serde_as(as = "DisplayFromStr")]
+    pub month: Month,
+}
+
+/// Parameters that can be adjusted without restarting, via
+/// `POST /admin/config` or the file given by `--runtime-config`. Missing
+/// fields are left unchanged.
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct RuntimeConfig {
+    /// Replacing the cache drops all cached responses.
+    pub masters_cache: Option<u64>,
+    /// Replacing the cache drops all cached responses.
+    pub lichess_cache: Option<u64>,
+    /// Seconds, or 0 to disable the on-disk response cache.
+    pub response_cache_ttl: Option<u64>,
+    /// 0 for unlimited.
+    pub max_queries_in_flight: Option<usize>,
+    /// Seconds.
+    pub indexer_cooldown: Option<u64>,
+    /// Seconds.
+    pub indexer_revisit_cooldown: Option<u64>,
+}
+
+#[serde_as]
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MigrateQuery {
+    #[serde_as(as = "DisplayFromStr")]
+    pub cf: EntryColumn,
+    pub to_version: u16,
+    /// Maximum number of entries to rewrite per second.
+    pub rate: Option<NonZeroU64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AuditQuery {
+    /// Only entries older than this id, to fetch the next page.
+    pub before: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum HotWindow {
+    #[default]
+    Hour,
+    Day,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HotPositionsQuery {
+    #[serde(default)]
+    pub window: HotWindow,
+    pub limit: Option<usize>,
+}
+
+#[serde_as]
+#[derive(Deserialize, Debug)]
+pub struct GameSearchQuery {
+    #[serde(default)]
+    pub db: SearchSource,
+    pub white: Option<String>,
+    pub black: Option<String>,
+    pub event: Option<String>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub since: Option<Month>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub until: Option<Month>,
+    #[serde(default)]
+    pub max: Option<usize>,
 }
 
 #[serde_as]
@@ -159,12 +730,64 @@ pub struct Play {
     #[serde_as(as = "DisplayFromStr")]
     #[serde(default)]
     variant: Variant,
-    #[serde_as(as = "Option<DisplayFromStr>")]
-    #[serde(default)]
+    #[serde(default, deserialize_with = "deserialize_fen")]
     fen: Option<Fen>,
-    #[serde_as(as = "StringWithSeparator<CommaSeparator, UciMove>")]
-    #[serde(default)]
+    #[serde(default, deserialize_with = "deserialize_play")]
     play: Vec<UciMove>,
+    /// Stable hash of the position, precomputed by a trusted client, instead
+    /// of `fen` and `play`.
+    #[serde(default, deserialize_with = "deserialize_zobrist")]
+    zobrist: Option<StableZobrist128>,
+    /// Start from the position of an ECO code or range instead of `fen`.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    eco: Option<EcoRange>,
+}
+
+fn deserialize_zobrist<'de, D>(deserializer: D) -> Result<Option<StableZobrist128>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let zobrist = String::deserialize(deserializer)?;
+    u128::from_str_radix(&zobrist, 16)
+        .map(|hash| Some(StableZobrist128(hash)))
+        .map_err(|_| de::Error::custom(format!("invalid zobrist hash: {zobrist:?}")))
+}
+
+fn deserialize_fen<'de, D>(deserializer: D) -> Result<Option<Fen>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let fen = String::deserialize(deserializer)?;
+    fen.parse()
+        .map(Some)
+        .map_err(|err| de::Error::custom(format!("{err}: {fen:?}")))
+}
+
+fn deserialize_play<'de, D>(deserializer: D) -> Result<Vec<UciMove>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let play = String::deserialize(deserializer)?;
+    let mut moves = Vec::new();
+    for (i, token) in play
+        .split(',')
+        .filter(|token| !token.is_empty())
+        .enumerate()
+    {
+        if i >= Play::MAX_PLIES {
+            return Err(de::Error::custom(format!(
+                "too many moves in play, at most {} are supported",
+                Play::MAX_PLIES
+            )));
+        }
+        moves.push(
+            token.parse().map_err(|_| {
+                de::Error::custom(format!("invalid uci at ply {}: {token:?}", i + 1))
+            })?,
+        );
+    }
+    Ok(moves)
 }
 
 impl Hash for Play {
@@ -175,12 +798,24 @@ impl Hash for Play {
         self.variant.hash(state);
         self.setup().hash(state);
         self.play.hash(state);
+        // Only hashed if present, to keep keys of the persistent response
+        // cache stable.
+        if let Some(zobrist) = self.zobrist {
+            u128::from(zobrist).hash(state);
+        }
+        if let Some(eco) = self.eco {
+            eco.hash(state);
+        }
     }
 }
 
 impl PartialEq for Play {
     fn eq(&self, other: &Play) -> bool {
-        self.variant == other.variant && self.setup() == other.setup() && self.play == other.play
+        self.variant == other.variant
+            && self.setup() == other.setup()
+            && self.play == other.play
+            && self.zobrist == other.zobrist
+            && self.eco == other.eco
     }
 }
 
@@ -190,6 +825,10 @@ pub struct PlayPosition {
 }
 
 impl Play {
+    /// Bound on the work for replaying moves, far beyond the depth of any
+    /// meaningful explorer position.
+    pub const MAX_PLIES: usize = 600;
+
     fn setup(&self) -> Setup {
         match self.fen {
             Some(ref fen) => fen.as_setup().to_owned(),
@@ -197,9 +836,68 @@ impl Play {
         }
     }
 
+    pub fn variant(&self) -> Variant {
+        self.variant
+    }
+
+    fn write_canonical(&self, canonical: &mut Canonical) {
+        canonical
+            .param("variant", self.variant.uci())
+            .opt("fen", self.fen.as_ref())
+            .param(
+                "play",
+                self.play
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(","),
+            )
+            .opt("zobrist", self.zobrist.map(|z| format!("{z:032x}")))
+            .opt("eco", self.eco.as_ref());
+    }
+
+    pub fn set_variant(&mut self, variant: Variant) {
+        self.variant = variant;
+    }
+
+    /// The moves, if they are played from the initial position rather than
+    /// from a given `fen`.
+    pub fn moves_from_start(&self) -> Option<&[UciMove]> {
+        (self.fen.is_none() && self.eco.is_none()).then_some(self.play.as_slice())
+    }
+
+    /// Separate the moves from the position they are played from, so that
+    /// the positions along the line can be visited one by one.
+    pub fn split_line(self) -> (Play, Vec<UciMove>) {
+        let line = self.play;
+        (
+            Play {
+                play: Vec::new(),
+                ..self
+            },
+            line,
+        )
+    }
+
+    /// The precomputed hash, if the query names the position by `zobrist`
+    /// rather than by `fen` and `play`.
+    pub fn zobrist(&self) -> Result<Option<(Variant, StableZobrist128)>, Error> {
+        match self.zobrist {
+            Some(_) if self.fen.is_some() || !self.play.is_empty() || self.eco.is_some() => {
+                Err(Error::ZobristWithPosition)
+            }
+            Some(zobrist) => Ok(Some((self.variant, zobrist))),
+            None => Ok(None),
+        }
+    }
+
     pub fn position(self, openings: &Openings) -> Result<PlayPosition, Error> {
+        if self.zobrist.is_some() {
+            return Err(Error::ZobristNotSupported);
+        }
         let mut pos = match self.fen {
             Some(fen) => {
+                check_piece_counts(self.variant, fen.as_setup())?;
                 VariantPosition::from_setup(self.variant, fen.into_setup(), CastlingMode::Chess960)
                     .or_else(PositionError::ignore_invalid_castling_rights)
                     .or_else(PositionError::ignore_invalid_ep_square)
@@ -207,11 +905,38 @@ impl Play {
             }
             None => VariantPosition::new(self.variant),
         };
-        let opening = openings.classify_and_play(&mut pos, self.play)?;
+        let play = match self.eco {
+            Some(_) if self.fen.is_some() || self.variant != Variant::Chess => {
+                return Err(Error::EcoWithPosition);
+            }
+            Some(eco) => {
+                let mut line = openings.eco_line(eco).ok_or(Error::UnknownEco(eco))?;
+                line.extend(self.play);
+                line
+            }
+            None => self.play,
+        };
+        let opening = openings.classify_and_play(&mut pos, play)?;
         Ok(PlayPosition { pos, opening })
     }
 }
 
+/// Reject boards that could not possibly come from a game, before handing
+/// them to move generation. Otherwise, too much material is tolerated.
+fn check_piece_counts(variant: Variant, setup: &Setup) -> Result<(), Error> {
+    for color in Color::ALL {
+        let max = match (variant, color) {
+            (Variant::Horde, Color::White) => 36,
+            _ => 16,
+        };
+        let count = setup.board.by_color(color).count();
+        if count > max {
+            return Err(Error::TooManyPieces { color, count, max });
+        }
+    }
+    Ok(())
+}
+
 #[serde_as]
 #[derive(Deserialize, Clone, Debug, Eq, PartialEq, Hash)]
 #[serde(rename_all = "camelCase")]
@@ -225,6 +950,53 @@ pub struct Limits {
     #[serde_as(as = "DisplayFromStr")]
     #[serde(default = "Limits::default_moves")]
     pub moves: usize,
+    #[serde(default)]
+    pub order_by: MoveOrder,
+    /// Omit moves with fewer games, before limiting the number of moves.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub min_games: u64,
+    /// Instead of a fixed number of moves, return the fewest moves that
+    /// cover the given fraction of games.
+    #[serde(default, deserialize_with = "deserialize_coverage")]
+    pub coverage: Option<Coverage>,
+}
+
+fn deserialize_coverage<'de, D>(deserializer: D) -> Result<Option<Coverage>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let coverage = String::deserialize(deserializer)?;
+    match coverage.parse::<f64>() {
+        Ok(fraction) if fraction > 0.0 && fraction <= 1.0 => {
+            Ok(Some(Coverage((fraction * 1_000_000.0).round() as u32)))
+        }
+        _ => Err(de::Error::custom(format!(
+            "invalid coverage, expected a fraction in (0, 1]: {coverage:?}"
+        ))),
+    }
+}
+
+/// Fraction of games, in parts per million.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Coverage(u32);
+
+impl Coverage {
+    /// Number of leading moves needed to cover the fraction of `total`
+    /// games, given the number of games of each move in order.
+    pub fn num_moves<I: IntoIterator<Item = u64>>(self, games: I, total: u64) -> usize {
+        let needed = u128::from(total) * u128::from(self.0);
+        let mut covered = 0;
+        let mut num = 0;
+        for games in games {
+            if covered * 1_000_000 >= needed {
+                break;
+            }
+            covered += u128::from(games);
+            num += 1;
+        }
+        num
+    }
 }
 
 impl Limits {
@@ -232,11 +1004,47 @@ impl Limits {
         12
     }
 
+    fn write_canonical(&self, canonical: &mut Canonical) {
+        canonical
+            .param("topGames", self.top_games)
+            .param("recentGames", self.recent_games)
+            .param("moves", self.moves)
+            .param("orderBy", format_args!("{:?}", self.order_by))
+            .param("minGames", self.min_games)
+            .opt("coverage", self.coverage.map(|coverage| coverage.0));
+    }
+
+    /// Number of moves to keep after sorting, before applying `coverage`.
+    pub fn moves_wanted(&self) -> usize {
+        if self.coverage.is_some() {
+            usize::MAX
+        } else {
+            self.moves
+        }
+    }
+
     pub fn games_wanted(&self) -> bool {
         self.top_games > 0 || self.recent_games > 0
     }
 }
 
+/// Order of the moves of a position. Moves are truncated to the requested
+/// number of moves after ordering.
+#[derive(Deserialize, Default, Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum MoveOrder {
+    /// Most played moves first.
+    #[default]
+    Total,
+    /// Best scoring moves for the side to move first, then most played.
+    Score,
+    /// Best performing moves first, then most played. Moves without
+    /// performance last.
+    Performance,
+    /// Most recently played moves first, then most played.
+    RecentPopularity,
+}
+
 #[derive(Deserialize, Default, Debug, Copy, Clone, Eq, PartialEq, Hash)]
 #[serde(rename_all = "camelCase")]
 pub enum HistoryWanted {
@@ -253,6 +1061,12 @@ pub enum HistoryWanted {
     Yes,
 }
 
+#[derive(Deserialize, Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum GroupBy {
+    Month,
+}
+
 #[derive(Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub enum Source {
@@ -263,22 +1077,250 @@ pub enum Source {
     Mobile,
 }
 
+/// Deserialize a query from a JSON object with the same fields as the query
+/// string, for example `{"fen": "...", "play": "e2e4,e7e5", "moves": 5}`.
+/// Lists may also be given as arrays.
+pub fn query_from_json<T: DeserializeOwned>(json: &str) -> Result<T, Error> {
+    let object: serde_json::Map<String, Value> =
+        serde_json::from_str(json).map_err(|err| Error::InvalidBatchQuery(err.to_string()))?;
+    let mut params = Vec::with_capacity(object.len());
+    for (name, value) in object {
+        let value = match value {
+            Value::Null => continue,
+            Value::String(s) => s,
+            Value::Bool(b) => b.to_string(),
+            Value::Number(n) => n.to_string(),
+            Value::Array(items) => items
+                .into_iter()
+                .map(|item| match item {
+                    Value::String(s) => Ok(s),
+                    Value::Bool(_) | Value::Number(_) => Ok(item.to_string()),
+                    _ => Err(Error::InvalidBatchQuery(format!(
+                        "unsupported list item in {name}"
+                    ))),
+                })
+                .collect::<Result<Vec<_>, _>>()?
+                .join(","),
+            Value::Object(_) => {
+                return Err(Error::InvalidBatchQuery(format!(
+                    "unsupported value for {name}"
+                )))
+            }
+        };
+        params.push((name, value));
+    }
+    T::deserialize(MapDeserializer::<_, de::value::Error>::new(
+        params.into_iter(),
+    ))
+    .map_err(|err| Error::InvalidBatchQuery(err.to_string()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_query_from_json() {
+        let query: WithSource<LichessQuery> = query_from_json(
+            r#"{"play": ["e2e4", "e7e5"], "speeds": "blitz,rapid", "moves": 3, "source": "openingCrawler"}"#,
+        )
+        .unwrap();
+        assert_eq!(query.query.play.play.len(), 2);
+        assert_eq!(
+            query.query.filter.speeds,
+            Some([Speed::Blitz, Speed::Rapid].into())
+        );
+        assert_eq!(query.query.limits.moves, 3);
+        assert_eq!(query.source, Some(Source::OpeningCrawler));
+
+        assert!(query_from_json::<LichessQuery>(r#"{"fen": {}}"#).is_err());
+        assert!(query_from_json::<LichessQuery>("not json").is_err());
+    }
+
+    #[test]
+    fn test_canonical_query() {
+        let canonical = |json: &str| query_from_json::<LichessQuery>(json).unwrap().canonical();
+
+        // Order and spelling of the parameters do not matter.
+        assert_eq!(
+            canonical(r#"{"speeds": "rapid,blitz", "play": "e2e4,e7e5"}"#),
+            canonical(r#"{"play": ["e2e4", "e7e5"], "speeds": "blitz,rapid"}"#)
+        );
+        assert_eq!(
+            canonical(r#"{"history": "true"}"#),
+            canonical(r#"{"history": "on"}"#)
+        );
+
+        assert_ne!(canonical(r#"{"play": "e2e4"}"#), canonical("{}"));
+        assert_ne!(
+            canonical(r#"{"speeds": "blitz"}"#),
+            canonical(r#"{"modes": "casual"}"#)
+        );
+    }
+
+    #[test]
+    fn test_tree_query() {
+        let query: TreeQuery =
+            query_from_json(r#"{"play": "e2e4", "db": "lichess", "format": "json"}"#).unwrap();
+        assert_eq!(query.db, SearchSource::Lichess);
+        assert!(matches!(query.format, TreeFormat::Json));
+        assert_eq!(query.depth, 3);
+        assert_eq!(query.moves, 5);
+    }
+
+    #[test]
+    fn test_variants_query() {
+        let query: VariantsQuery =
+            query_from_json(r#"{"play": "e2e4", "variants": "chess,atomic"}"#).unwrap();
+        assert_eq!(
+            query.variants,
+            Some(vec!["chess".to_owned(), "atomic".to_owned()])
+        );
+
+        let query: VariantsQuery = query_from_json(r#"{"play": "e2e4"}"#).unwrap();
+        assert_eq!(query.variants, None);
+    }
+
+    #[test]
+    fn test_response_fields() {
+        let query: MastersQuery =
+            query_from_json(r#"{"play": "e2e4", "fields": "moves,total"}"#).unwrap();
+        let fields = query.fields.unwrap();
+        assert!(fields.contains_key("moves"));
+        assert!(fields.contains_key("white"));
+        assert!(fields.contains_key("truncated"));
+        assert!(!fields.contains_key("topGames"));
+        assert!(!fields.contains_key("opening"));
+
+        let mut limits = query.limits;
+        fields.restrict(&mut limits);
+        assert_eq!(limits.top_games, 0);
+        assert_eq!(limits.moves, Limits::default_moves());
+
+        assert!(query_from_json::<MastersQuery>(r#"{"fields": "moves,foo"}"#).is_err());
+    }
+
+    #[test]
+    fn test_player_rating_filter() {
+        let filter: PlayerQueryFilter =
+            query_from_json(r#"{"minRating": "1800", "maxRating": "2000"}"#).unwrap();
+        assert!(!filter.contains_rating(None));
+        assert!(!filter.contains_rating(Some(PlayerRating::from_rating(1799))));
+        assert!(filter.contains_rating(Some(PlayerRating::from_rating(1800))));
+        assert!(filter.contains_rating(Some(PlayerRating::from_rating(1999))));
+        assert!(!filter.contains_rating(Some(PlayerRating::from_rating(2000))));
+
+        let filter: PlayerQueryFilter = query_from_json("{}").unwrap();
+        assert!(filter.contains_rating(None));
+    }
+
+    #[test]
+    fn test_group_by() {
+        let query: LichessQuery = query_from_json(r#"{"groupBy": "month"}"#).unwrap();
+        assert_eq!(query.group_by, Some(GroupBy::Month));
+        assert!(query_from_json::<LichessQuery>(r#"{"groupBy": "year"}"#).is_err());
+    }
+
+    #[test]
+    fn test_coverage() {
+        let limits: Limits = serde_json::from_value(serde_json::json!({
+            "coverage": "0.95",
+        }))
+        .unwrap();
+        let coverage = limits.coverage.unwrap();
+        assert_eq!(coverage.num_moves([50, 40, 5, 3, 2], 100), 3);
+        assert_eq!(coverage.num_moves([50, 40, 4, 3, 3], 100), 4);
+        assert_eq!(coverage.num_moves([100], 100), 1);
+        assert_eq!(coverage.num_moves([], 0), 0);
+
+        assert!(serde_json::from_value::<Limits>(serde_json::json!({
+            "coverage": "1.5",
+        }))
+        .is_err());
+    }
+
     #[test]
     fn test_play_equality() {
         let a = Play {
             variant: Variant::Chess,
             fen: None,
             play: Vec::new(),
+            zobrist: None,
+            eco: None,
         };
         let b = Play {
             variant: Variant::Chess,
             fen: Some(Fen::default()),
             play: Vec::new(),
+            zobrist: None,
+            eco: None,
         };
         assert_eq!(a, b);
     }
+
+    #[test]
+    fn test_play_zobrist() {
+        let play: Play = serde_json::from_value(serde_json::json!({
+            "variant": "atomic",
+            "zobrist": "d1d06239bd7d2ae8ad6fa208133e1f9a",
+        }))
+        .unwrap();
+        assert_eq!(
+            play.zobrist().unwrap(),
+            Some((
+                Variant::Atomic,
+                StableZobrist128(0xd1d06239bd7d2ae8ad6fa208133e1f9a)
+            ))
+        );
+        assert!(play.position(&Openings::new()).is_err());
+
+        let play: Play = serde_json::from_value(serde_json::json!({
+            "zobrist": "d1d06239bd7d2ae8ad6fa208133e1f9a",
+            "play": "e2e4",
+        }))
+        .unwrap();
+        assert!(play.zobrist().is_err());
+
+        assert!(serde_json::from_value::<Play>(serde_json::json!({
+            "zobrist": "xyz",
+        }))
+        .is_err());
+    }
+
+    #[test]
+    fn test_play_validation() {
+        let play: Play = serde_json::from_value(serde_json::json!({
+            "play": "e2e4,e7e5",
+        }))
+        .unwrap();
+        assert_eq!(play.play.len(), 2);
+
+        let err = serde_json::from_value::<Play>(serde_json::json!({
+            "play": "e2e4,e7e9",
+        }))
+        .unwrap_err();
+        assert!(err.to_string().contains("ply 2: \"e7e9\""));
+
+        let too_long = vec!["g1f3,f6g8,f3g1,g8f6"; Play::MAX_PLIES / 4 + 1].join(",");
+        assert!(serde_json::from_value::<Play>(serde_json::json!({
+            "play": too_long,
+        }))
+        .is_err());
+    }
+
+    #[test]
+    fn test_piece_count_sanity() {
+        let fen: Fen = "qqqqqqqq/qqqqqqqq/qqqqqqqq/8/8/8/8/K6k w - - 0 1"
+            .parse()
+            .unwrap();
+        assert!(matches!(
+            check_piece_counts(Variant::Chess, fen.as_setup()),
+            Err(Error::TooManyPieces {
+                color: Color::Black,
+                count: 25,
+                max: 16
+            })
+        ));
+        assert!(check_piece_counts(Variant::Horde, Fen::default().as_setup()).is_ok());
+    }
 }
@@ -0,0 +1,141 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    OnceLock,
+};
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde::Serialize;
+use tower::ServiceExt as _;
+
+use crate::api::VersionResponse;
+
+/// Startup progress. The server starts listening before the database is
+/// opened, answering only health and readiness probes until the routes of
+/// the app are installed.
+#[derive(Default)]
+pub struct Readiness {
+    db: AtomicBool,
+    openings: AtomicBool,
+    indexer: AtomicBool,
+    app: OnceLock<Router>,
+    admin_app: OnceLock<Router>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ReadinessResponse {
+    ready: bool,
+    db: bool,
+    openings: bool,
+    indexer: bool,
+}
+
+impl Readiness {
+    pub fn set_db_opened(&self) {
+        self.db.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_openings_loaded(&self) {
+        self.openings.store(true, Ordering::Relaxed);
+    }
+
+    pub fn openings_loaded(&self) -> bool {
+        self.openings.load(Ordering::Relaxed)
+    }
+
+    pub fn set_indexer_spawned(&self) {
+        self.indexer.store(true, Ordering::Relaxed);
+    }
+
+    /// Start serving the routes of the app.
+    pub fn set_app(&self, app: Router) {
+        if self.app.set(app).is_err() {
+            log::error!("app routes installed more than once");
+        }
+    }
+
+    /// Start serving the administrative routes, if they are served on a
+    /// separate listener.
+    pub fn set_admin_app(&self, app: Router) {
+        if self.admin_app.set(app).is_err() {
+            log::error!("admin routes installed more than once");
+        }
+    }
+
+    fn response(&self) -> ReadinessResponse {
+        let db = self.db.load(Ordering::Relaxed);
+        let openings = self.openings_loaded();
+        let indexer = self.indexer.load(Ordering::Relaxed);
+        ReadinessResponse {
+            ready: db && openings && indexer && self.app.get().is_some(),
+            db,
+            openings,
+            indexer,
+        }
+    }
+
+    /// Probes, and the routes of the app once installed.
+    pub fn router(&'static self) -> Router {
+        Router::new()
+            .route("/health", get(health))
+            .route("/ready", get(ready))
+            .route("/version", get(version))
+            .fallback(app)
+            .with_state(self)
+    }
+
+    /// Probes, and the administrative routes once installed.
+    pub fn admin_router(&'static self) -> Router {
+        Router::new()
+            .route("/health", get(health))
+            .route("/ready", get(ready))
+            .route("/version", get(version))
+            .fallback(admin_app)
+            .with_state(self)
+    }
+}
+
+async fn health() -> &'static str {
+    "ok"
+}
+
+async fn version() -> Json<VersionResponse> {
+    Json(VersionResponse::current())
+}
+
+async fn ready(
+    State(readiness): State<&'static Readiness>,
+) -> (StatusCode, Json<ReadinessResponse>) {
+    let response = readiness.response();
+    let status = if response.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(response))
+}
+
+async fn app(State(readiness): State<&'static Readiness>, req: Request) -> Response {
+    serve_installed(&readiness.app, req).await
+}
+
+async fn admin_app(State(readiness): State<&'static Readiness>, req: Request) -> Response {
+    serve_installed(&readiness.admin_app, req).await
+}
+
+async fn serve_installed(app: &OnceLock<Router>, req: Request) -> Response {
+    match app.get() {
+        Some(app) => app
+            .clone()
+            .oneshot(req)
+            .await
+            .unwrap_or_else(|err| match err {}),
+        None => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    }
+}
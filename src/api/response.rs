@@ -1,17 +1,29 @@
-use serde::Serialize;
-use serde_with::{serde_as, DisplayFromStr, TryFromInto};
+use std::time::SystemTime;
+
+use serde::{ser, Deserialize, Serialize, Serializer};
+use serde_json::Value;
+use serde_with::{
+    formats::{CommaSeparator, SpaceSeparator},
+    serde_as, DisplayFromStr, StringWithSeparator, TimestampMilliSeconds, TryFromInto,
+};
 use shakmaty::{san::SanPlus, uci::UciMove, ByColor, Color};
 
 use crate::{
+    api::{HotWindow, ResponseFields},
+    cloud_eval::MoveEval,
+    db::{DbMetrics, LichessMetrics, MastersMetrics},
+    indexer::QueueEntry,
     model::{
-        GameId, GamePlayer, History, LichessGame, MastersGame, Mode, Month, Speed, Stats, Year,
+        AuditEntry, GameId, GamePlayer, History, ImportStatus, LastPlayed, LichessGame,
+        MastersGame, Mode, Month, PlayerGamesCursor, PlayerStatus, Speed, Stats, UserId, Year,
     },
-    opening::Opening,
+    opening::{Opening, OpeningsOrigin},
+    tablebase::TablebaseCategory,
     util::ByColorDef,
 };
 
 #[serde_as]
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct ExplorerResponse {
     #[serde(flatten)]
@@ -24,12 +36,71 @@ pub struct ExplorerResponse {
     pub opening: Option<Opening>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub queue_position: Option<u64>,
+    /// Estimated seconds until indexing starts, while waiting in the queue.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queue_eta: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub history: Option<History>,
+    /// Total number of games in the database, independent of the position.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub indexed_games: Option<u64>,
+    /// Estimated number of distinct players who reached the position.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unique_players: Option<u64>,
+    /// Summaries of each month, with `groupBy=month`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub months: Option<Vec<MonthSummary>>,
+    /// Results by time of play, with `timeBreakdown=true` on `/player`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_breakdown: Option<TimeBreakdown>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+    /// Month at which a truncated `/lichess` scan stopped. Earlier months
+    /// are complete, this month may be partial, later months are missing.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub truncated_at: Option<Month>,
+}
+
+/// Results by the weekday and hour (UTC) at which games started, Monday and
+/// midnight first.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TimeBreakdown {
+    pub weekdays: [Stats; 7],
+    pub hours: [Stats; 24],
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MonthSummary {
+    #[serde_as(as = "DisplayFromStr")]
+    pub month: Month,
+    #[serde(flatten)]
+    pub total: Stats,
+    pub moves: Vec<ExplorerMove>,
+}
+
+/// A response with only the selected top-level fields.
+pub struct Selected<T> {
+    pub response: T,
+    pub fields: Option<ResponseFields>,
+}
+
+impl<T: Serialize> Serialize for Selected<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let Some(fields) = self.fields else {
+            return self.response.serialize(serializer);
+        };
+        let mut value = serde_json::to_value(&self.response).map_err(ser::Error::custom)?;
+        if let Value::Object(ref mut map) = value {
+            map.retain(|key, _| fields.contains_key(key));
+        }
+        value.serialize(serializer)
+    }
 }
 
 #[serde_as]
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct ExplorerMove {
     #[serde_as(as = "DisplayFromStr")]
@@ -46,10 +117,64 @@ pub struct ExplorerMove {
     pub stats: Stats,
     pub game: Option<ExplorerGame>,
     pub opening: Option<Opening>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub eval: Option<MoveEval>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tablebase: Option<TablebaseCategory>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trend: Option<Vec<u64>>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_played: Option<LastPlayed>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<MoveConfidence>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotation: Option<MoveAnnotation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub score_delta: Option<f64>,
+}
+
+/// Annotation of a move, compared with the most popular move in the same
+/// position.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MoveAnnotation {
+    #[serde(rename = "!?")]
+    Interesting,
+    #[serde(rename = "?!")]
+    Dubious,
+    #[serde(rename = "?")]
+    Mistake,
+}
+
+/// Percentages of results, and a 95% confidence interval for the score of
+/// the player making the move.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MoveConfidence {
+    pub white: f64,
+    pub draws: f64,
+    pub black: f64,
+    pub score_low: f64,
+    pub score_high: f64,
+}
+
+impl MoveConfidence {
+    pub fn new(stats: &Stats, turn: Color) -> Option<MoveConfidence> {
+        let (low, high) = stats.score_interval(turn)?;
+        let percent = |fraction: f64| (fraction * 1000.0).round() / 10.0;
+        let total = stats.total() as f64;
+        Some(MoveConfidence {
+            white: percent(stats.white() as f64 / total),
+            draws: percent(stats.draws() as f64 / total),
+            black: percent(stats.black() as f64 / total),
+            score_low: percent(low),
+            score_high: percent(high),
+        })
+    }
 }
 
 #[serde_as]
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ExplorerGameWithUciMove {
     #[serde_as(as = "DisplayFromStr")]
     pub uci: UciMove,
@@ -58,7 +183,7 @@ pub struct ExplorerGameWithUciMove {
 }
 
 #[serde_as]
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ExplorerGame {
     #[serde_as(as = "DisplayFromStr")]
     pub id: GameId,
@@ -101,3 +226,372 @@ impl ExplorerGame {
         }
     }
 }
+
+#[serde_as]
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexerQueueEntry {
+    pub player: String,
+    pub ticket: u64,
+    #[serde_as(as = "TimestampMilliSeconds")]
+    pub submitted_at: SystemTime,
+    pub in_progress: bool,
+}
+
+impl From<QueueEntry<UserId>> for IndexerQueueEntry {
+    fn from(entry: QueueEntry<UserId>) -> IndexerQueueEntry {
+        IndexerQueueEntry {
+            player: entry.task.as_lowercase_str().to_owned(),
+            ticket: entry.number,
+            submitted_at: entry.submitted_at,
+            in_progress: entry.acquired,
+        }
+    }
+}
+
+#[serde_as]
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerStatusResponse {
+    pub latest_created_at: u64,
+    #[serde_as(as = "TimestampMilliSeconds")]
+    pub indexed_at: SystemTime,
+    #[serde_as(as = "TimestampMilliSeconds")]
+    pub revisited_at: SystemTime,
+    pub history: History,
+}
+
+impl PlayerStatusResponse {
+    pub fn new(status: PlayerStatus, history: History) -> PlayerStatusResponse {
+        PlayerStatusResponse {
+            latest_created_at: status.latest_created_at,
+            indexed_at: status.indexed_at,
+            revisited_at: status.revisited_at,
+            history,
+        }
+    }
+}
+
+#[serde_as]
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportStatusResponse {
+    #[serde_as(as = "DisplayFromStr")]
+    pub month: Month,
+    pub accepted: u64,
+    pub skipped: u64,
+    pub completed: bool,
+}
+
+impl ImportStatusResponse {
+    pub fn new(month: Month, status: ImportStatus) -> ImportStatusResponse {
+        ImportStatusResponse {
+            month,
+            accepted: status.accepted,
+            skipped: status.skipped,
+            completed: status.completed,
+        }
+    }
+}
+
+/// Response to a single query of a batch, in the same order as the queries.
+#[derive(Serialize, Debug)]
+pub struct BatchResponse<T = ExplorerResponse> {
+    #[serde(flatten)]
+    pub response: Option<T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Result of importing a single game of a masters PGN.
+#[serde_as]
+#[derive(Serialize, Debug)]
+pub struct MastersPgnImportResult {
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<GameId>,
+    /// Positions whose explorer entries are updated by the game.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub positions: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// What importing a masters game writes, or would write in a dry run.
+#[serde_as]
+#[derive(Serialize, Debug)]
+pub struct MastersImportReport {
+    #[serde_as(as = "DisplayFromStr")]
+    pub id: GameId,
+    /// Positions whose explorer entries are updated by the game.
+    pub positions: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AuditLogEntry {
+    pub id: u64,
+    #[serde(flatten)]
+    pub entry: AuditEntry,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AuditLogResponse {
+    pub entries: Vec<AuditLogEntry>,
+    /// Pass as `before` to fetch the next page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct HotPosition {
+    pub endpoint: &'static str,
+    pub variant: &'static str,
+    /// Not known if the position was only queried by its hash.
+    pub fen: Option<String>,
+    /// Estimated number of queries, possibly too high.
+    pub count: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct HotPositionsResponse {
+    pub window: HotWindow,
+    pub positions: Vec<HotPosition>,
+}
+
+/// A move order from the initial position that reaches the queried position.
+#[serde_as]
+#[derive(Serialize, Debug)]
+pub struct Transposition {
+    /// Suitable as `play` of other queries.
+    #[serde_as(as = "StringWithSeparator<CommaSeparator, UciMove>")]
+    pub uci: Vec<UciMove>,
+    #[serde_as(as = "StringWithSeparator<SpaceSeparator, SanPlus>")]
+    pub san: Vec<SanPlus>,
+    /// Number of sampled games that used this move order.
+    pub games: u64,
+    /// Whether an opening book line uses this move order.
+    pub book: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TranspositionsResponse {
+    pub transpositions: Vec<Transposition>,
+}
+
+/// A line of a player, starting from the initial position.
+#[serde_as]
+#[derive(Serialize, Debug)]
+pub struct RepertoireLine {
+    /// Suitable as `play` of other queries.
+    #[serde_as(as = "StringWithSeparator<CommaSeparator, UciMove>")]
+    pub uci: Vec<UciMove>,
+    #[serde_as(as = "StringWithSeparator<SpaceSeparator, SanPlus>")]
+    pub san: Vec<SanPlus>,
+    /// Games through the last move of the line.
+    #[serde(flatten)]
+    pub stats: Stats,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub performance: Option<i32>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PlayerRepertoireResponse {
+    pub white: Vec<RepertoireLine>,
+    pub black: Vec<RepertoireLine>,
+}
+
+#[serde_as]
+#[derive(Serialize, Debug)]
+pub struct PlayerGame {
+    #[serde_as(as = "DisplayFromStr")]
+    pub id: GameId,
+    #[serde_as(as = "DisplayFromStr")]
+    pub uci: UciMove,
+    #[serde_as(as = "DisplayFromStr")]
+    pub month: Month,
+}
+
+#[serde_as]
+#[derive(Serialize, Debug)]
+pub struct PlayerGamesResponse {
+    pub games: Vec<PlayerGame>,
+    /// Continue with this cursor to get older games.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub next: Option<PlayerGamesCursor>,
+}
+
+#[serde_as]
+#[derive(Serialize, Debug)]
+pub struct MastersGamesAtResponse {
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub games: Vec<GameId>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MastersNoveltyResponse {
+    /// Number of moves of the line that stay within masters games.
+    pub ply: usize,
+    /// Masters games that reached the position after `ply` moves.
+    pub games: u64,
+    /// The next move of the line, leaving all masters games, if any.
+    pub novelty: Option<Novelty>,
+}
+
+#[serde_as]
+#[derive(Serialize, Debug)]
+pub struct Novelty {
+    #[serde_as(as = "DisplayFromStr")]
+    pub uci: UciMove,
+    #[serde_as(as = "DisplayFromStr")]
+    pub san: SanPlus,
+    /// The position after the move.
+    pub fen: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MastersPlayerResponse {
+    /// The canonical spelling of the player name.
+    pub name: String,
+    /// Games of the player that reached the position.
+    #[serde(flatten)]
+    pub total: Stats,
+    pub moves: Vec<MastersPlayerMove>,
+}
+
+#[serde_as]
+#[derive(Serialize, Debug)]
+pub struct MastersPlayerMove {
+    #[serde_as(as = "DisplayFromStr")]
+    pub uci: UciMove,
+    #[serde_as(as = "DisplayFromStr")]
+    pub san: SanPlus,
+    #[serde(flatten)]
+    pub stats: Stats,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MastersStructureResponse {
+    /// Whether there were more positions with the pawn structure than
+    /// could be considered.
+    pub truncated: bool,
+    pub positions: Vec<StructurePosition>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StructurePosition {
+    pub fen: String,
+    /// Games in which the position occurred, since the structure index was
+    /// introduced.
+    pub indexed_games: u64,
+    #[serde(flatten)]
+    pub stats: Stats,
+}
+
+/// A position of an exported opening tree. Flat, so that it can also be
+/// written as CSV.
+#[serde_as]
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TreeRow {
+    /// Moves from the root of the tree.
+    #[serde_as(as = "StringWithSeparator<CommaSeparator, UciMove>")]
+    pub uci: Vec<UciMove>,
+    #[serde_as(as = "StringWithSeparator<SpaceSeparator, SanPlus>")]
+    pub san: Vec<SanPlus>,
+    pub white: u64,
+    pub draws: u64,
+    pub black: u64,
+    pub average_rating: Option<u16>,
+    pub eco: Option<String>,
+    pub opening: Option<String>,
+}
+
+#[serde_as]
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OpeningsStatusResponse {
+    /// The configured source.
+    pub source: String,
+    /// Where the current opening names were loaded from, if anywhere.
+    pub origin: Option<OpeningsOrigin>,
+    pub names: usize,
+    #[serde_as(as = "Option<TimestampMilliSeconds>")]
+    pub loaded_at: Option<SystemTime>,
+    /// Seconds since opening names were last loaded, or since startup.
+    pub stale_secs: u64,
+    /// Consecutive failures to load from the source.
+    pub failures: u32,
+    pub last_error: Option<String>,
+    #[serde_as(as = "Option<TimestampMilliSeconds>")]
+    pub last_failure_at: Option<SystemTime>,
+    #[serde_as(as = "Option<TimestampMilliSeconds>")]
+    pub next_attempt_at: Option<SystemTime>,
+}
+
+/// Entries of the in-memory and on-disk response caches.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CachesMonitorResponse {
+    pub lichess_cache: u64,
+    pub masters_cache: u64,
+    pub response_cache: u64,
+}
+
+/// Build information, recorded by `build.rs`. Fields are `null` if they
+/// could not be determined, for example when building outside of a git
+/// checkout.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionResponse {
+    pub version: &'static str,
+    pub git_sha: Option<&'static str>,
+    pub build_date: Option<&'static str>,
+    pub rustc: Option<&'static str>,
+    pub features: Vec<&'static str>,
+    pub rocksdb: Option<&'static str>,
+}
+
+impl VersionResponse {
+    pub fn current() -> VersionResponse {
+        VersionResponse {
+            version: env!("CARGO_PKG_VERSION"),
+            git_sha: option_env!("BUILD_GIT_SHA"),
+            build_date: option_env!("BUILD_DATE"),
+            rustc: option_env!("BUILD_RUSTC_VERSION"),
+            features: [
+                ("stable-keys", cfg!(feature = "stable-keys")),
+                ("golden-tests", cfg!(feature = "golden-tests")),
+            ]
+            .into_iter()
+            .filter_map(|(feature, enabled)| enabled.then_some(feature))
+            .collect(),
+            rocksdb: option_env!("BUILD_ROCKSDB_VERSION"),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexerMonitorResponse {
+    pub indexing: usize,
+    pub lichess_import_sampled_out: u64,
+    /// Bytes held by games in flight, in total and by indexing task.
+    pub memory: u64,
+    pub memory_by_indexer: Vec<u64>,
+    pub memory_limit: Option<u64>,
+    /// Players that can still be queued.
+    pub queue_remaining: usize,
+    /// Recent throughput of each indexer.
+    pub games_per_second: Option<f64>,
+    pub games_per_player: Option<f64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RocksDbMonitorResponse {
+    #[serde(flatten)]
+    pub db: DbMetrics,
+    pub masters: MastersMetrics,
+    pub lichess: LichessMetrics,
+}
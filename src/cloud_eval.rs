@@ -0,0 +1,182 @@
+use std::{sync::Arc, time::Duration};
+
+use clap::Parser;
+use moka::future::Cache;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_with::{formats::SpaceSeparator, serde_as, StringWithSeparator};
+use shakmaty::{fen::Fen, uci::UciMove, variant::VariantPosition, EnPassantMode, Move};
+use tokio::{task::JoinHandle, time::timeout};
+
+use crate::{api::ExplorerMove, util::ply};
+
+#[derive(Parser, Clone)]
+pub struct CloudEvalOpt {
+    /// Cloud evaluation endpoint, for example
+    /// https://lichess.org/api/cloud-eval. If configured, moves of shallow
+    /// positions are annotated with cached evaluations.
+    #[arg(long = "cloud-eval")]
+    cloud_eval: Option<String>,
+    /// Only annotate positions up to this ply.
+    #[arg(long = "cloud-eval-max-ply", default_value = "12")]
+    cloud_eval_max_ply: u32,
+    /// Number of principal variations to request.
+    #[arg(long = "cloud-eval-multi-pv", default_value = "5")]
+    cloud_eval_multi_pv: u32,
+    /// Milliseconds to wait for an evaluation before responding without
+    /// annotations. The evaluation is still fetched and cached in the
+    /// background.
+    #[arg(long = "cloud-eval-timeout", default_value = "100")]
+    cloud_eval_timeout: u64,
+}
+
+/// Evaluation of a move, from the point of view of white.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct MoveEval {
+    pub depth: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cp: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mate: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct CloudEvalResponse {
+    depth: u32,
+    pvs: Vec<CloudEvalPv>,
+}
+
+#[serde_as]
+#[derive(Deserialize)]
+struct CloudEvalPv {
+    #[serde_as(as = "StringWithSeparator<SpaceSeparator, UciMove>")]
+    moves: Vec<UciMove>,
+    #[serde(default)]
+    cp: Option<i32>,
+    #[serde(default)]
+    mate: Option<i32>,
+}
+
+type PvEvals = Arc<[(UciMove, MoveEval)]>;
+
+pub struct CloudEval {
+    client: reqwest::Client,
+    cache: Cache<String, PvEvals>,
+    opt: CloudEvalOpt,
+}
+
+/// Evaluation being fetched in the background.
+pub struct PendingEval {
+    pos: VariantPosition,
+    handle: JoinHandle<Option<PvEvals>>,
+}
+
+impl CloudEval {
+    pub fn new(opt: CloudEvalOpt) -> CloudEval {
+        CloudEval {
+            client: reqwest::Client::builder()
+                .user_agent("lila-openingexplorer")
+                .timeout(Duration::from_secs(10))
+                .build()
+                .expect("reqwest client"),
+            cache: Cache::builder()
+                .max_capacity(50_000)
+                .time_to_live(Duration::from_secs(60 * 60))
+                .build(),
+            opt,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.opt.cloud_eval.is_some()
+    }
+
+    /// Start fetching the evaluation of a position, if configured and the
+    /// position is shallow enough.
+    pub fn prefetch(&self, pos: &VariantPosition) -> Option<PendingEval> {
+        let url = self.opt.cloud_eval.clone()?;
+        if ply(pos) > self.opt.cloud_eval_max_ply {
+            return None;
+        }
+
+        let fen = Fen::from_position(pos.clone(), EnPassantMode::Legal).to_string();
+        let variant = pos.variant().uci();
+        let key = format!("{variant}:{fen}");
+        let client = self.client.clone();
+        let cache = self.cache.clone();
+        let multi_pv = self.opt.cloud_eval_multi_pv;
+
+        Some(PendingEval {
+            pos: pos.clone(),
+            handle: tokio::spawn(async move {
+                cache
+                    .optionally_get_with(key, async move {
+                        let res = client
+                            .get(url)
+                            .query(&[("fen", fen.as_str()), ("variant", variant)])
+                            .query(&[("multiPv", multi_pv)])
+                            .send()
+                            .await
+                            .map_err(|err| log::warn!("cloud eval: {err}"))
+                            .ok()?;
+                        if res.status() == StatusCode::NOT_FOUND {
+                            return Some(PvEvals::from(Vec::new()));
+                        }
+                        let res: CloudEvalResponse = res
+                            .error_for_status()
+                            .map_err(|err| log::warn!("cloud eval: {err}"))
+                            .ok()?
+                            .json()
+                            .await
+                            .map_err(|err| log::warn!("cloud eval: {err}"))
+                            .ok()?;
+                        Some(
+                            res.pvs
+                                .into_iter()
+                                .filter_map(|pv| {
+                                    pv.moves.into_iter().next().map(|uci| {
+                                        (
+                                            uci,
+                                            MoveEval {
+                                                depth: res.depth,
+                                                cp: pv.cp,
+                                                mate: pv.mate,
+                                            },
+                                        )
+                                    })
+                                })
+                                .collect(),
+                        )
+                    })
+                    .await
+            }),
+        })
+    }
+
+    /// Annotate moves with the evaluation, if it becomes available in time.
+    pub async fn annotate(&self, pending: PendingEval, moves: &mut [ExplorerMove]) {
+        let evals = match timeout(
+            Duration::from_millis(self.opt.cloud_eval_timeout),
+            pending.handle,
+        )
+        .await
+        {
+            Ok(Ok(Some(evals))) => evals,
+            _ => return,
+        };
+
+        let pv_moves: Vec<(Move, &MoveEval)> = evals
+            .iter()
+            .filter_map(|(uci, eval)| uci.to_move(&pending.pos).ok().map(|m| (m, eval)))
+            .collect();
+
+        for explorer_move in moves {
+            if let Ok(m) = explorer_move.uci.to_move(&pending.pos) {
+                explorer_move.eval = pv_moves
+                    .iter()
+                    .find(|(pv_move, _)| *pv_move == m)
+                    .map(|(_, eval)| (*eval).clone());
+            }
+        }
+    }
+}
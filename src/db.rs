@@ -1,18 +1,51 @@
-use std::{path::PathBuf, time::Instant};
+use std::{
+    cmp::min,
+    collections::{BTreeMap, HashSet},
+    iter, mem,
+    num::NonZeroU64,
+    path::PathBuf,
+    slice,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
 
+use bytes::{Buf, BufMut};
 use clap::Parser;
 use rocksdb::{
-    properties::{ESTIMATE_NUM_KEYS, OPTIONS_STATISTICS},
-    BlockBasedOptions, Cache, ColumnFamily, ColumnFamilyDescriptor, DBCompressionType,
-    MergeOperands, Options, ReadOptions, SliceTransform, WriteBatch, DB,
+    compaction_filter::Decision,
+    perf::{set_perf_stats, PerfContext, PerfMetric, PerfStatsLevel},
+    properties::{
+        ACTUAL_DELAYED_WRITE_RATE, COMPACTION_PENDING, CUR_SIZE_ALL_MEM_TABLES, ESTIMATE_NUM_KEYS,
+        ESTIMATE_PENDING_COMPACTION_BYTES, IS_WRITE_STOPPED, LEVELSTATS, NUM_IMMUTABLE_MEM_TABLE,
+        OPTIONS_STATISTICS, TOTAL_SST_FILES_SIZE,
+    },
+    BlockBasedOptions, Cache, ColumnFamily, ColumnFamilyDescriptor, DBCompressionType, DBPath,
+    MemtableFactory, MergeOperands, Options, ReadOptions, SliceTransform, WriteBatch, DB,
 };
+use serde::Serialize;
+use serde_with::{serde_as, Map, TimestampMilliSeconds};
+use sha1::{Digest, Sha1};
+use shakmaty::{uci::UciMove, variant::Variant, ByColor, Color};
+use thiserror::Error;
 
 use crate::{
-    api::{HistoryWanted, LichessQueryFilter, Limits},
+    api::{
+        ExplorerResponse, GroupBy, HistoryWanted, LichessQueryFilter, Limits, PlayerQueryFilter,
+    },
+    era::EraAdjustment,
     model::{
-        GameId, History, HistoryBuilder, Key, KeyPrefix, LichessEntry, LichessGame, MastersEntry,
-        MastersGame, Month, PlayerEntry, PlayerStatus, PreparedResponse, UserId, Year,
+        read_uint, search_tokens, write_uint, AuditEntry, AuditKey, FormatVersion, GameId, History,
+        HistoryBuilder, HistorySegment, Key, KeyPrefix, LichessEntry, LichessGame,
+        LichessGameMoves, MastersEntry, MastersGame, Month, MonthsBuilder, PawnStructure,
+        PlayerEntry, PlayerGamesCursor, PlayerStatus, PreparedResponse, ReadError, SearchField,
+        SearchKey, SearchSource, StructureEntry, TrendBuilder, UserId, Year,
     },
+    trace::Trace,
 };
 
 #[derive(Parser)]
@@ -33,9 +66,40 @@ pub struct DbOpt {
     /// rate that your disks can comfortably handle.
     #[arg(long, default_value = "10485760")]
     db_rate_limit: i64,
+    /// Store the indexed moves of games imported via /import/lichess, so
+    /// that they can be served by /lichess/pgn/<id>.
+    #[arg(long)]
+    db_lichess_game_moves: bool,
+    /// Maintain a secondary index of player names and events for
+    /// /games/search. Only games imported or indexed while enabled can be
+    /// found.
+    #[arg(long)]
+    db_game_search: bool,
+    /// Soft deadline in milliseconds for scans of /masters and /lichess
+    /// positions. Scans that take longer stop early and the response is
+    /// flagged as truncated. Unlimited by default.
+    #[arg(long)]
+    db_read_deadline: Option<u64>,
+    /// After startup, scan masters, lichess and player entries for values
+    /// written in older format versions, and schedule them to be rewritten
+    /// in the current format during the next compactions.
+    #[arg(long)]
+    pub db_format_upgrade: bool,
+    /// Store the SST files of a column family in a separate directory, as
+    /// `<cf>=<path>`, for example to put the huge and rarely read
+    /// lichess_game on cheaper storage. Can be given multiple times, and
+    /// must be given whenever the database is opened.
+    #[arg(long = "db-cf-path")]
+    db_cf_paths: Vec<CfPath>,
+    /// Tune for bulk ingestion: vector memtables and no automatic
+    /// compactions. Set by --import-only.
+    #[arg(skip)]
+    pub bulk_load: bool,
 }
 
-#[derive(Default)]
+#[serde_as]
+#[derive(Default, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct DbMetrics {
     pub block_index_miss: u64,
     pub block_index_hit: u64,
@@ -43,6 +107,89 @@ pub struct DbMetrics {
     pub block_filter_hit: u64,
     pub block_data_miss: u64,
     pub block_data_hit: u64,
+    pub stall_micros: u64,
+    pub write_stopped: bool,
+    pub quarantined: u64,
+    pub delayed_write_rate: u64,
+    pub cache_fill: Vec<CacheFillMetrics>,
+    #[serde_as(as = "Map<_, _>")]
+    pub column_families: Vec<(&'static str, ColumnFamilyMetrics)>,
+}
+
+/// Adaptive block cache fill policy for a band of plies.
+#[derive(Default, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheFillMetrics {
+    pub min_ply: u32,
+    pub percent: u32,
+    pub block_cache_hit: u64,
+    pub block_read: u64,
+}
+
+/// Compaction and memtable state of a column family, to see when writes
+/// are about to be stalled.
+#[serde_as]
+#[derive(Default, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ColumnFamilyMetrics {
+    pub pending_compaction_bytes: u64,
+    pub compaction_pending: bool,
+    pub mem_table_bytes: u64,
+    pub immutable_mem_tables: u64,
+    /// Number of files and size in MiB by level.
+    pub levels: Vec<(u64, u64)>,
+    /// Completion of the last manual or scheduled compaction since startup.
+    #[serde_as(as = "Option<TimestampMilliSeconds>")]
+    pub last_compaction: Option<SystemTime>,
+}
+
+impl ColumnFamilyMetrics {
+    /// Parse the table of `rocksdb.levelstats`.
+    fn read_levelstats(&mut self, s: &str) {
+        for line in s.lines() {
+            let mut parts = line.split_whitespace();
+            if let (Some(level), Some(files), Some(size), None) =
+                (parts.next(), parts.next(), parts.next(), parts.next())
+            {
+                if let (Ok(level), Ok(files), Ok(size)) =
+                    (level.parse::<usize>(), files.parse(), size.parse())
+                {
+                    if self.levels.len() <= level {
+                        self.levels.resize(level + 1, (0, 0));
+                    }
+                    self.levels[level] = (files, size);
+                }
+            }
+        }
+    }
+
+    fn to_influx_fields(&self, name: &str) -> Vec<String> {
+        let mut fields = vec![
+            format!(
+                "cf_{name}_pending_compaction_bytes={}u",
+                self.pending_compaction_bytes
+            ),
+            format!("cf_{name}_compaction_pending={}", self.compaction_pending),
+            format!("cf_{name}_mem_table_bytes={}u", self.mem_table_bytes),
+            format!(
+                "cf_{name}_immutable_mem_tables={}u",
+                self.immutable_mem_tables
+            ),
+        ];
+        for (level, (files, size)) in self.levels.iter().enumerate() {
+            fields.push(format!("cf_{name}_l{level}_files={files}u"));
+            fields.push(format!("cf_{name}_l{level}_mb={size}u"));
+        }
+        if let Some(last_compaction) = self.last_compaction {
+            fields.push(format!(
+                "cf_{name}_last_compaction={}u",
+                last_compaction
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs())
+            ));
+        }
+        fields
+    }
 }
 
 impl DbMetrics {
@@ -66,6 +213,8 @@ impl DbMetrics {
                 self.block_data_miss = c;
             } else if let Some(c) = count(line, "rocksdb.block.cache.data.hit") {
                 self.block_data_hit = c;
+            } else if let Some(c) = count(line, "rocksdb.stall.micros") {
+                self.stall_micros = c;
             }
         }
     }
@@ -78,7 +227,26 @@ impl DbMetrics {
             format!("block_filter_hit={}u", self.block_filter_hit),
             format!("block_data_miss={}u", self.block_data_miss),
             format!("block_data_hit={}u", self.block_data_hit),
+            format!("stall_micros={}u", self.stall_micros),
+            format!("write_stopped={}", self.write_stopped),
+            format!("quarantined={}u", self.quarantined),
+            format!("delayed_write_rate={}u", self.delayed_write_rate),
         ]
+        .into_iter()
+        .chain(self.cache_fill.iter().flat_map(|band| {
+            let ply = band.min_ply;
+            [
+                format!("cache_fill_ply{ply}_percent={}u", band.percent),
+                format!("cache_fill_ply{ply}_hit={}u", band.block_cache_hit),
+                format!("cache_fill_ply{ply}_read={}u", band.block_read),
+            ]
+        }))
+        .chain(
+            self.column_families
+                .iter()
+                .flat_map(|(name, cf)| cf.to_influx_fields(name)),
+        )
+        .collect::<Vec<_>>()
         .join(",")
     }
 }
@@ -96,19 +264,129 @@ impl CacheHint {
     pub fn always() -> CacheHint {
         CacheHint { ply: 0 }
     }
+}
 
-    pub fn should_fill_cache(&self) -> bool {
-        let percent = if self.ply < 15 {
-            return true;
-        } else if self.ply < 20 {
-            5
-        } else if self.ply < 25 {
-            2
-        } else {
-            1
-        };
+/// Lower bounds of the ply bands with separate fill probabilities.
+/// Positions before the first band always fill the block cache.
+const CACHE_FILL_BANDS: [(u32, u32); 3] = [(15, 5), (20, 2), (25, 1)];
+
+/// Number of block accesses in a band before its fill probability is
+/// adjusted.
+const CACHE_FILL_WINDOW: u64 = 10_000;
+
+const CACHE_FILL_MAX_PERCENT: u32 = 50;
+
+struct CacheFillBand {
+    min_ply: u32,
+    percent: AtomicU32,
+    block_cache_hit: AtomicU64,
+    block_read: AtomicU64,
+    window_hit: AtomicU64,
+    window_total: AtomicU64,
+}
+
+impl CacheFillBand {
+    fn new(min_ply: u32, percent: u32) -> CacheFillBand {
+        CacheFillBand {
+            min_ply,
+            percent: AtomicU32::new(percent),
+            block_cache_hit: AtomicU64::new(0),
+            block_read: AtomicU64::new(0),
+            window_hit: AtomicU64::new(0),
+            window_total: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, hit: u64, read: u64) {
+        self.block_cache_hit.fetch_add(hit, Ordering::Relaxed);
+        self.block_read.fetch_add(read, Ordering::Relaxed);
+        if self.min_ply == 0 {
+            return; // always fill
+        }
+
+        let window_hit = self.window_hit.fetch_add(hit, Ordering::Relaxed) + hit;
+        let window_total = self.window_total.fetch_add(hit + read, Ordering::Relaxed) + hit + read;
+        if window_total >= CACHE_FILL_WINDOW {
+            self.window_hit.store(0, Ordering::Relaxed);
+            self.window_total.store(0, Ordering::Relaxed);
+            let hit_percent = (window_hit * 100 / window_total) as u32;
+            let previous = self.percent.load(Ordering::Relaxed);
+            self.percent.store(
+                ((previous + hit_percent) / 2).clamp(1, CACHE_FILL_MAX_PERCENT),
+                Ordering::Relaxed,
+            );
+        }
+    }
+}
+
+/// Decides whether reads fill the block cache, depending on the ply of the
+/// queried position. Deep positions are rarely queried again, so their
+/// blocks would mostly evict more useful ones. The fill probability of each
+/// band follows the block cache hit rate observed for reads in the band:
+/// Blocks that are often found in the cache are worth keeping there, while
+/// bands that mostly miss only churn the cache.
+pub struct CacheFillPolicy {
+    always: CacheFillBand,
+    bands: [CacheFillBand; CACHE_FILL_BANDS.len()],
+}
+
+impl Default for CacheFillPolicy {
+    fn default() -> CacheFillPolicy {
+        CacheFillPolicy {
+            always: CacheFillBand::new(0, 100),
+            bands: CACHE_FILL_BANDS.map(|(min_ply, percent)| CacheFillBand::new(min_ply, percent)),
+        }
+    }
+}
+
+impl CacheFillPolicy {
+    fn band(&self, hint: CacheHint) -> &CacheFillBand {
+        self.bands
+            .iter()
+            .rev()
+            .find(|band| band.min_ply <= hint.ply)
+            .unwrap_or(&self.always)
+    }
 
-        fastrand::u32(0..100) < percent
+    fn should_fill_cache(&self, hint: CacheHint) -> bool {
+        fastrand::u32(0..100) < self.band(hint).percent.load(Ordering::Relaxed)
+    }
+
+    /// Start counting block cache accesses of a read on the current thread.
+    fn probe(&self, hint: CacheHint) -> CacheFillProbe<'_> {
+        set_perf_stats(PerfStatsLevel::EnableCount);
+        let mut context = PerfContext::default();
+        context.reset();
+        CacheFillProbe {
+            band: self.band(hint),
+            context,
+        }
+    }
+
+    fn metrics(&self) -> Vec<CacheFillMetrics> {
+        std::iter::once(&self.always)
+            .chain(&self.bands)
+            .map(|band| CacheFillMetrics {
+                min_ply: band.min_ply,
+                percent: band.percent.load(Ordering::Relaxed),
+                block_cache_hit: band.block_cache_hit.load(Ordering::Relaxed),
+                block_read: band.block_read.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+struct CacheFillProbe<'a> {
+    band: &'a CacheFillBand,
+    context: PerfContext,
+}
+
+impl CacheFillProbe<'_> {
+    fn finish(self) {
+        self.band.observe(
+            self.context.metric(PerfMetric::BlockCacheHitCount),
+            self.context.metric(PerfMetric::BlockReadCount),
+        );
     }
 }
 
@@ -117,15 +395,318 @@ impl CacheHint {
 // thread-pool to avoid blocking other requests.
 pub struct Database {
     pub inner: DB,
+    game_search: bool,
+    lichess_game_moves: bool,
+    read_deadline: Option<Duration>,
+    last_compaction: Mutex<Option<SystemTime>>,
+    last_cf_compactions: Mutex<BTreeMap<&'static str, SystemTime>>,
+    last_audit_key: AtomicU64,
+    quarantine: Arc<Quarantine>,
+    cache_fill: CacheFillPolicy,
+    player_games: Mutex<()>,
+}
+
+/// Malformed values found by merge operators. Merge operators run on
+/// compaction threads without access to the database, so values are
+/// collected here and moved into the corrupt column family later.
+#[derive(Default)]
+struct Quarantine {
+    pending: Mutex<Vec<CorruptValue>>,
+    total: AtomicU64,
 }
 
-type MergeFn = fn(key: &[u8], existing: Option<&[u8]>, operands: &MergeOperands) -> Option<Vec<u8>>;
+struct CorruptValue {
+    cf: &'static str,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    at: u64,
+}
+
+impl CorruptValue {
+    /// Key in the corrupt column family: | cf | 0 | key | micros |.
+    fn quarantine_key(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.cf.len() + 1 + self.key.len() + 8);
+        buf.put_slice(self.cf.as_bytes());
+        buf.put_u8(0);
+        buf.put_slice(&self.key);
+        buf.put_u64(self.at);
+        buf
+    }
+}
+
+impl Quarantine {
+    fn push(&self, cf: &'static str, key: &[u8], value: &[u8], err: ReadError) {
+        log::error!("quarantining corrupt {cf} value for key {key:02x?}: {err}");
+        self.total.fetch_add(1, Ordering::Relaxed);
+        self.pending
+            .lock()
+            .expect("lock quarantine")
+            .push(CorruptValue {
+                cf,
+                key: key.to_vec(),
+                value: value.to_vec(),
+                at: SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map_or(0, |d| d.as_micros() as u64),
+            });
+    }
+}
+
+const COLUMN_FAMILIES: [&str; 22] = [
+    "masters",
+    "masters_game",
+    "masters_game_hash",
+    "masters_position_game",
+    "masters_structure",
+    "lichess",
+    "lichess_antichess",
+    "lichess_atomic",
+    "lichess_crazyhouse",
+    "lichess_horde",
+    "lichess_kingofthehill",
+    "lichess_racingkings",
+    "lichess_3check",
+    "lichess_game",
+    "lichess_game_moves",
+    "player",
+    "player_status",
+    "meta",
+    "game_search",
+    "response_cache",
+    "audit",
+    "corrupt",
+];
+
+/// Column families compacted by manual and scheduled compactions.
+pub const COMPACTED_COLUMN_FAMILIES: [&str; 16] = [
+    "lichess",
+    "lichess_antichess",
+    "lichess_atomic",
+    "lichess_crazyhouse",
+    "lichess_horde",
+    "lichess_kingofthehill",
+    "lichess_racingkings",
+    "lichess_3check",
+    "lichess_game",
+    "player",
+    "player_status",
+    "masters",
+    "masters_game",
+    "masters_game_hash",
+    "masters_position_game",
+    "masters_structure",
+];
+
+/// Lichess explorer entries of variants other than standard chess, each in
+/// their own column family, so that they can be compacted or deleted
+/// separately. Entries imported before the split remain in `lichess`, and
+/// are still read.
+const LICHESS_VARIANT_COLUMN_FAMILIES: [(Variant, &str); 7] = [
+    (Variant::Antichess, "lichess_antichess"),
+    (Variant::Atomic, "lichess_atomic"),
+    (Variant::Crazyhouse, "lichess_crazyhouse"),
+    (Variant::Horde, "lichess_horde"),
+    (Variant::KingOfTheHill, "lichess_kingofthehill"),
+    (Variant::RacingKings, "lichess_racingkings"),
+    (Variant::ThreeCheck, "lichess_3check"),
+];
+
+/// Time of day (UTC) for scheduled compactions, as `HH:MM`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct CompactAt {
+    minutes: u32,
+}
+
+#[derive(Error, Debug)]
+#[error("expected time of day as HH:MM")]
+pub struct InvalidCompactAt;
+
+impl FromStr for CompactAt {
+    type Err = InvalidCompactAt;
+
+    fn from_str(s: &str) -> Result<CompactAt, InvalidCompactAt> {
+        let (hours, minutes) = s.split_once(':').ok_or(InvalidCompactAt)?;
+        let hours: u32 = hours.parse().map_err(|_| InvalidCompactAt)?;
+        let minutes: u32 = minutes.parse().map_err(|_| InvalidCompactAt)?;
+        if hours >= 24 || minutes >= 60 {
+            return Err(InvalidCompactAt);
+        }
+        Ok(CompactAt {
+            minutes: hours * 60 + minutes,
+        })
+    }
+}
+
+impl CompactAt {
+    /// Time to wait until the next occurrence, at least a minute from now.
+    pub fn duration_until_next(&self, now: SystemTime) -> Duration {
+        const DAY: u64 = 24 * 60 * 60;
+        let since_midnight = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs())
+            % DAY;
+        let at = u64::from(self.minutes) * 60;
+        let wait = (at + DAY - since_midnight) % DAY;
+        Duration::from_secs(if wait < 60 { wait + DAY } else { wait })
+    }
+}
+
+/// Column families of entries with versioned binary encodings.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EntryColumn {
+    Masters,
+    Lichess,
+    Player,
+}
+
+impl EntryColumn {
+    pub const ALL: [EntryColumn; 3] = [
+        EntryColumn::Masters,
+        EntryColumn::Lichess,
+        EntryColumn::Player,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EntryColumn::Masters => "masters",
+            EntryColumn::Lichess => "lichess",
+            EntryColumn::Player => "player",
+        }
+    }
+
+    /// Column families with entries of this kind.
+    fn column_families(self) -> Vec<&'static str> {
+        match self {
+            EntryColumn::Lichess => iter::once(self.name())
+                .chain(LICHESS_VARIANT_COLUMN_FAMILIES.map(|(_, name)| name))
+                .collect(),
+            EntryColumn::Masters | EntryColumn::Player => vec![self.name()],
+        }
+    }
+
+    /// Version written by this release.
+    pub fn format_version(self) -> FormatVersion {
+        match self {
+            EntryColumn::Masters => MastersEntry::FORMAT_VERSION,
+            EntryColumn::Lichess => LichessEntry::FORMAT_VERSION,
+            EntryColumn::Player => PlayerEntry::FORMAT_VERSION,
+        }
+    }
+
+    /// The version to migrate to, if `to_version` is the version written by
+    /// this release. Guards against starting a migration by accident with an
+    /// outdated binary.
+    pub fn migration_target(self, to_version: u16) -> Option<FormatVersion> {
+        let version = self.format_version();
+        (FormatVersion::new(to_version) == Some(version)).then_some(version)
+    }
+
+    /// Check that a value can be decoded with the reader for its version.
+    fn validate(self, mut value: &[u8]) -> Result<(), ReadError> {
+        match self {
+            EntryColumn::Masters => MastersEntry::default().extend_from_reader(&mut value),
+            EntryColumn::Lichess => LichessEntry::default().extend_from_reader(&mut value),
+            EntryColumn::Player => PlayerEntry::default().extend_from_reader(&mut value),
+        }
+    }
+}
+
+impl FromStr for EntryColumn {
+    type Err = InvalidEntryColumn;
+
+    fn from_str(s: &str) -> Result<EntryColumn, InvalidEntryColumn> {
+        EntryColumn::ALL
+            .into_iter()
+            .find(|column| column.name() == s)
+            .ok_or(InvalidEntryColumn)
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("expected masters, lichess or player")]
+pub struct InvalidEntryColumn;
+
+/// Separate directory for the SST files of a column family.
+#[derive(Debug, Clone)]
+pub struct CfPath {
+    cf: &'static str,
+    path: PathBuf,
+}
+
+#[derive(Error, Debug)]
+#[error("expected <cf>=<path> with a known column family")]
+pub struct InvalidCfPath;
+
+impl FromStr for CfPath {
+    type Err = InvalidCfPath;
+
+    fn from_str(s: &str) -> Result<CfPath, InvalidCfPath> {
+        let (cf, path) = s.split_once('=').ok_or(InvalidCfPath)?;
+        let cf = COLUMN_FAMILIES
+            .into_iter()
+            .find(|name| *name == cf)
+            .ok_or(InvalidCfPath)?;
+        if path.is_empty() {
+            return Err(InvalidCfPath);
+        }
+        Ok(CfPath {
+            cf,
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+#[derive(Default, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MigrationProgress {
+    pub scanned: u64,
+    /// Entries in an older format version, scheduled to be rewritten.
+    pub scheduled: u64,
+    /// Values that could not be decoded. The merge operator moves them into
+    /// the corrupt column family when they are rewritten.
+    pub failed: u64,
+    /// Estimated number of keys in the column family.
+    pub estimated_keys: u64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ColumnFamilyStats {
+    pub estimated_keys: u64,
+    pub size_on_disk: u64,
+}
+
+#[serde_as]
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DbStats {
+    pub column_families: BTreeMap<&'static str, ColumnFamilyStats>,
+    pub size_on_disk: u64,
+    /// Completion of the last manual compaction since startup.
+    #[serde_as(as = "Option<TimestampMilliSeconds>")]
+    pub last_compaction: Option<SystemTime>,
+    pub masters_games: u64,
+    pub lichess_games: u64,
+}
+
+type MergeFn = fn(
+    key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &MergeOperands,
+    quarantine: &Quarantine,
+) -> Option<Vec<u8>>;
+
+type FilterFn = fn(level: u32, key: &[u8], value: &[u8]) -> Decision;
 
 struct Column<'a> {
     name: &'a str,
     prefix: Option<usize>,
     merge: Option<(&'a str, MergeFn)>,
+    filter: Option<(&'a str, FilterFn)>,
     cache: &'a Cache,
+    quarantine: &'a Arc<Quarantine>,
+    bulk_load: bool,
+    cf_paths: &'a [(&'static str, DBPath)],
 }
 
 impl Column<'_> {
@@ -149,13 +730,39 @@ impl Column<'_> {
 
         cf_opts.set_use_direct_io_for_flush_and_compaction(true);
 
+        if self.bulk_load {
+            // Compactions are deferred until the database is opened
+            // normally again, so writes must not stall on the growing
+            // number of level 0 files.
+            cf_opts.set_memtable_factory(MemtableFactory::Vector);
+            cf_opts.set_disable_auto_compactions(true);
+            cf_opts.set_level_zero_slowdown_writes_trigger(1 << 30);
+            cf_opts.set_level_zero_stop_writes_trigger(1 << 30);
+            cf_opts.set_soft_pending_compaction_bytes_limit(0);
+            cf_opts.set_hard_pending_compaction_bytes_limit(0);
+        }
+
         cf_opts.set_prefix_extractor(match self.prefix {
             Some(prefix) => SliceTransform::create_fixed_prefix(prefix),
             None => SliceTransform::create_noop(),
         });
 
         if let Some((name, merge_fn)) = self.merge {
-            cf_opts.set_merge_operator_associative(name, merge_fn);
+            let quarantine = Arc::clone(self.quarantine);
+            cf_opts.set_merge_operator_associative(
+                name,
+                move |key: &[u8], existing: Option<&[u8]>, operands: &MergeOperands| {
+                    merge_fn(key, existing, operands, &quarantine)
+                },
+            );
+        }
+
+        if let Some((name, filter_fn)) = self.filter {
+            cf_opts.set_compaction_filter(name, filter_fn);
+        }
+
+        if let Some((_, path)) = self.cf_paths.iter().find(|(cf, _)| *cf == self.name) {
+            cf_opts.set_cf_paths(slice::from_ref(path));
         }
 
         ColumnFamilyDescriptor::new(self.name, cf_opts)
@@ -182,7 +789,21 @@ impl Database {
             db_opts.set_compaction_readahead_size(2 * 1024 * 1024);
         }
 
+        if opt.bulk_load {
+            // Required for vector memtables.
+            db_opts.set_allow_concurrent_memtable_write(false);
+        }
+
         let cache = Cache::new_lru_cache(opt.db_cache);
+        let quarantine = Arc::new(Quarantine::default());
+        let cf_paths = opt
+            .db_cf_paths
+            .iter()
+            .map(|cf_path| Ok((cf_path.cf, DBPath::new(&cf_path.path, u64::MAX)?)))
+            .collect::<Result<Vec<_>, rocksdb::Error>>()?;
+        for cf_path in &opt.db_cf_paths {
+            log::info!("storing {} in {}", cf_path.cf, cf_path.path.display());
+        }
 
         let inner = DB::open_cf_descriptors(
             &db_opts,
@@ -193,14 +814,58 @@ impl Database {
                     name: "masters",
                     prefix: Some(KeyPrefix::SIZE),
                     merge: Some(("masters_merge", masters_merge)),
+                    filter: None,
                     cache: &cache,
+                    quarantine: &quarantine,
+                    bulk_load: opt.bulk_load,
+                    cf_paths: &cf_paths,
                 }
                 .descriptor(),
                 Column {
                     name: "masters_game",
                     prefix: None,
                     merge: None,
+                    filter: None,
+                    cache: &cache,
+                    quarantine: &quarantine,
+                    bulk_load: opt.bulk_load,
+                    cf_paths: &cf_paths,
+                }
+                .descriptor(),
+                // Masters game ids by content hash
+                Column {
+                    name: "masters_game_hash",
+                    prefix: None,
+                    merge: None,
+                    filter: None,
+                    cache: &cache,
+                    quarantine: &quarantine,
+                    bulk_load: opt.bulk_load,
+                    cf_paths: &cf_paths,
+                }
+                .descriptor(),
+                // Posting lists of masters games by position
+                Column {
+                    name: "masters_position_game",
+                    prefix: Some(KeyPrefix::SIZE),
+                    merge: None,
+                    filter: None,
+                    cache: &cache,
+                    quarantine: &quarantine,
+                    bulk_load: opt.bulk_load,
+                    cf_paths: &cf_paths,
+                }
+                .descriptor(),
+                // Masters positions by pawn structure
+                Column {
+                    name: "masters_structure",
+                    prefix: Some(PawnStructure::SIZE),
+                    merge: Some(("masters_structure_merge", masters_structure_merge)),
+                    filter: None,
                     cache: &cache,
+                    quarantine: &quarantine,
+                    bulk_load: opt.bulk_load,
+                    cf_paths: &cf_paths,
                 }
                 .descriptor(),
                 // Lichess database
@@ -208,14 +873,33 @@ impl Database {
                     name: "lichess",
                     prefix: Some(KeyPrefix::SIZE),
                     merge: Some(("lichess_merge", lichess_merge)),
+                    filter: None,
                     cache: &cache,
+                    quarantine: &quarantine,
+                    bulk_load: opt.bulk_load,
+                    cf_paths: &cf_paths,
                 }
                 .descriptor(),
                 Column {
                     name: "lichess_game",
                     prefix: None,
                     merge: Some(("lichess_game_merge", lichess_game_merge)),
+                    filter: None,
                     cache: &cache,
+                    quarantine: &quarantine,
+                    bulk_load: opt.bulk_load,
+                    cf_paths: &cf_paths,
+                }
+                .descriptor(),
+                Column {
+                    name: "lichess_game_moves",
+                    prefix: None,
+                    merge: None,
+                    filter: None,
+                    cache: &cache,
+                    quarantine: &quarantine,
+                    bulk_load: opt.bulk_load,
+                    cf_paths: &cf_paths,
                 }
                 .descriptor(),
                 // Player database (also shares lichess_game)
@@ -223,23 +907,260 @@ impl Database {
                     name: "player",
                     prefix: Some(KeyPrefix::SIZE),
                     merge: Some(("player_merge", player_merge)),
+                    filter: None,
                     cache: &cache,
+                    quarantine: &quarantine,
+                    bulk_load: opt.bulk_load,
+                    cf_paths: &cf_paths,
                 }
                 .descriptor(),
                 Column {
                     name: "player_status",
                     prefix: None,
                     merge: None,
+                    filter: None,
+                    cache: &cache,
+                    quarantine: &quarantine,
+                    bulk_load: opt.bulk_load,
+                    cf_paths: &cf_paths,
+                }
+                .descriptor(),
+                // Bookkeeping, for example import progress
+                Column {
+                    name: "meta",
+                    prefix: None,
+                    merge: Some(("meta_merge", meta_merge)),
+                    filter: None,
+                    cache: &cache,
+                    quarantine: &quarantine,
+                    bulk_load: opt.bulk_load,
+                    cf_paths: &cf_paths,
+                }
+                .descriptor(),
+                // Secondary index for game search
+                Column {
+                    name: "game_search",
+                    prefix: None,
+                    merge: None,
+                    filter: None,
+                    cache: &cache,
+                    quarantine: &quarantine,
+                    bulk_load: opt.bulk_load,
+                    cf_paths: &cf_paths,
+                }
+                .descriptor(),
+                // Second tier response cache
+                Column {
+                    name: "response_cache",
+                    prefix: None,
+                    merge: None,
+                    filter: Some(("response_cache_filter", response_cache_filter)),
+                    cache: &cache,
+                    quarantine: &quarantine,
+                    bulk_load: opt.bulk_load,
+                    cf_paths: &cf_paths,
+                }
+                .descriptor(),
+                // Append-only log of administrative operations
+                Column {
+                    name: "audit",
+                    prefix: None,
+                    merge: None,
+                    filter: None,
+                    cache: &cache,
+                    quarantine: &quarantine,
+                    bulk_load: opt.bulk_load,
+                    cf_paths: &cf_paths,
+                }
+                .descriptor(),
+                // Values that could not be merged
+                Column {
+                    name: "corrupt",
+                    prefix: None,
+                    merge: None,
+                    filter: None,
                     cache: &cache,
+                    quarantine: &quarantine,
+                    bulk_load: opt.bulk_load,
+                    cf_paths: &cf_paths,
                 }
                 .descriptor(),
-            ],
+            ]
+            .into_iter()
+            .chain(LICHESS_VARIANT_COLUMN_FAMILIES.map(|(_, name)| {
+                Column {
+                    name,
+                    prefix: Some(KeyPrefix::SIZE),
+                    merge: Some(("lichess_merge", lichess_merge)),
+                    filter: None,
+                    cache: &cache,
+                    quarantine: &quarantine,
+                    bulk_load: opt.bulk_load,
+                    cf_paths: &cf_paths,
+                }
+                .descriptor()
+            })),
         )?;
 
         let elapsed = started_at.elapsed();
         log::info!("database opened in {elapsed:.3?}");
 
-        Ok(Database { inner })
+        Ok(Database {
+            inner,
+            game_search: opt.db_game_search,
+            lichess_game_moves: opt.db_lichess_game_moves,
+            read_deadline: opt.db_read_deadline.map(Duration::from_millis),
+            last_compaction: Mutex::new(None),
+            last_cf_compactions: Mutex::default(),
+            last_audit_key: AtomicU64::new(0),
+            quarantine,
+            cache_fill: CacheFillPolicy::default(),
+            player_games: Mutex::new(()),
+        })
+    }
+
+    /// Hold while checking whether a game has been indexed for a player,
+    /// until the entries of the game are committed. The player indexer and
+    /// lichess imports both index games for players, and must not index the
+    /// same game twice.
+    pub fn lock_player_games(&self) -> MutexGuard<'_, ()> {
+        self.player_games.lock().expect("lock player games")
+    }
+
+    /// Move values found to be corrupt during merges into the corrupt
+    /// column family, for later inspection.
+    pub fn flush_quarantine(&self) -> Result<usize, rocksdb::Error> {
+        let pending = mem::take(&mut *self.quarantine.pending.lock().expect("lock quarantine"));
+        if pending.is_empty() {
+            return Ok(0);
+        }
+        let cf = self.inner.cf_handle("corrupt").expect("cf corrupt");
+        let mut batch = WriteBatch::default();
+        for value in &pending {
+            batch.put_cf(cf, value.quarantine_key(), &value.value);
+        }
+        self.inner.write(batch)?;
+        Ok(pending.len())
+    }
+
+    /// Add an empty merge operand to all entries written in an older format
+    /// version, so that the merge operators rewrite them in the current
+    /// format during the next compaction (or when they are next read).
+    /// Returns the number of scheduled entries.
+    pub fn schedule_format_upgrade(&self) -> Result<u64, rocksdb::Error> {
+        const CHUNK_SIZE: usize = 10_000;
+
+        let mut scheduled = 0;
+        for (column, name) in EntryColumn::ALL.into_iter().flat_map(|column| {
+            column
+                .column_families()
+                .into_iter()
+                .map(move |name| (column, name))
+        }) {
+            let cf = self.inner.cf_handle(name).expect("cf");
+            let version = column.format_version();
+
+            let mut opt = ReadOptions::default();
+            opt.fill_cache(false);
+            opt.set_ignore_range_deletions(true);
+            opt.set_total_order_seek(true);
+            let mut iter = self.inner.raw_iterator_cf_opt(cf, opt);
+            iter.seek_to_first();
+
+            let mut batch = WriteBatch::default();
+            while let Some((key, value)) = iter.item() {
+                if FormatVersion::of(value) < version {
+                    batch.merge_cf(cf, key, b"");
+                    scheduled += 1;
+                    if batch.len() >= CHUNK_SIZE {
+                        self.inner.write(mem::take(&mut batch))?;
+                    }
+                }
+                iter.next();
+            }
+            iter.status()?;
+            self.inner.write(batch)?;
+        }
+        Ok(scheduled)
+    }
+
+    /// Rewrite all entries of the column family (and for lichess entries,
+    /// also the column families of variants) that were written in an older
+    /// format version. Like [`Database::schedule_format_upgrade()`],
+    /// adds an empty merge operand to each of them, at most `max_rate`
+    /// entries per second, and then compacts the column family, so that the
+    /// merge operator writes them in the current version. Progress is
+    /// reported after every chunk.
+    ///
+    /// Concurrent writes are merged as usual, so the server can keep
+    /// running.
+    pub fn migrate(
+        &self,
+        column: EntryColumn,
+        max_rate: NonZeroU64,
+        mut report: impl FnMut(&MigrationProgress),
+    ) -> Result<MigrationProgress, rocksdb::Error> {
+        const CHUNK_SIZE: u64 = 10_000;
+
+        let names = column.column_families();
+        let version = column.format_version();
+        let mut progress = MigrationProgress::default();
+        for name in &names {
+            let cf = self.inner.cf_handle(name).expect("cf");
+            progress.estimated_keys += self
+                .inner
+                .property_int_value_cf(cf, ESTIMATE_NUM_KEYS)?
+                .unwrap_or(0);
+        }
+
+        let started_at = Instant::now();
+        for name in names {
+            let cf = self.inner.cf_handle(name).expect("cf");
+            let scheduled_before = progress.scheduled;
+
+            let mut opt = ReadOptions::default();
+            opt.fill_cache(false);
+            opt.set_ignore_range_deletions(true);
+            opt.set_total_order_seek(true);
+            let mut iter = self.inner.raw_iterator_cf_opt(cf, opt);
+            iter.seek_to_first();
+
+            let mut batch = WriteBatch::default();
+            while let Some((key, value)) = iter.item() {
+                if FormatVersion::of(value) < version {
+                    if let Err(err) = column.validate(value) {
+                        log::warn!("{name} value for key {key:02x?} can not be decoded: {err}");
+                        progress.failed += 1;
+                    }
+                    batch.merge_cf(cf, key, b"");
+                    progress.scheduled += 1;
+                }
+
+                progress.scanned += 1;
+                if progress.scanned % CHUNK_SIZE == 0 {
+                    self.inner.write(mem::take(&mut batch))?;
+                    report(&progress);
+
+                    let due =
+                        Duration::from_secs_f64(progress.scheduled as f64 / max_rate.get() as f64);
+                    if let Some(ahead) = due.checked_sub(started_at.elapsed()) {
+                        thread::sleep(ahead);
+                    }
+                }
+
+                iter.next();
+            }
+            iter.status()?;
+            self.inner.write(batch)?;
+
+            if progress.scheduled > scheduled_before {
+                self.compact_cf(name);
+            }
+        }
+        report(&progress);
+
+        self.flush_quarantine()?;
+        Ok(progress)
     }
 
     pub fn metrics(&self) -> Result<DbMetrics, rocksdb::Error> {
@@ -247,15 +1168,113 @@ impl Database {
         if let Some(options_statistics) = self.inner.property_value(OPTIONS_STATISTICS)? {
             metrics.read_options_statistics(&options_statistics);
         }
+        metrics.write_stopped = self
+            .inner
+            .property_int_value(IS_WRITE_STOPPED)?
+            .unwrap_or(0)
+            > 0;
+        metrics.quarantined = self.quarantine.total.load(Ordering::Relaxed);
+        metrics.cache_fill = self.cache_fill.metrics();
+        metrics.delayed_write_rate = self
+            .inner
+            .property_int_value(ACTUAL_DELAYED_WRITE_RATE)?
+            .unwrap_or(0);
+        for name in COLUMN_FAMILIES {
+            let cf = self.inner.cf_handle(name).expect("cf");
+            let mut cf_metrics = ColumnFamilyMetrics {
+                pending_compaction_bytes: self
+                    .inner
+                    .property_int_value_cf(cf, ESTIMATE_PENDING_COMPACTION_BYTES)?
+                    .unwrap_or(0),
+                compaction_pending: self
+                    .inner
+                    .property_int_value_cf(cf, COMPACTION_PENDING)?
+                    .unwrap_or(0)
+                    > 0,
+                mem_table_bytes: self
+                    .inner
+                    .property_int_value_cf(cf, CUR_SIZE_ALL_MEM_TABLES)?
+                    .unwrap_or(0),
+                immutable_mem_tables: self
+                    .inner
+                    .property_int_value_cf(cf, NUM_IMMUTABLE_MEM_TABLE)?
+                    .unwrap_or(0),
+                levels: Vec::new(),
+                last_compaction: self.last_cf_compactions.lock().unwrap().get(name).copied(),
+            };
+            if let Some(levelstats) = self.inner.property_value_cf(cf, LEVELSTATS)? {
+                cf_metrics.read_levelstats(&levelstats);
+            }
+            metrics.column_families.push((name, cf_metrics));
+        }
         Ok(metrics)
     }
 
     pub fn compact(&self) {
-        self.lichess().compact();
-        self.masters().compact();
+        for name in COMPACTED_COLUMN_FAMILIES {
+            self.compact_cf(name);
+        }
+        self.finish_compaction();
+    }
+
+    pub fn compact_cf(&self, name: &'static str) {
+        log::info!("running manual compaction for {name} ...");
+        compact_column(&self.inner, self.inner.cf_handle(name).expect("cf"));
+        self.last_cf_compactions
+            .lock()
+            .unwrap()
+            .insert(name, SystemTime::now());
+    }
+
+    /// Record that all of [`COMPACTED_COLUMN_FAMILIES`] have been compacted.
+    pub fn finish_compaction(&self) {
+        *self.last_compaction.lock().unwrap() = Some(SystemTime::now());
         log::info!("finished manual compaction");
     }
 
+    /// Writes are currently stopped or delayed, because compactions are
+    /// not keeping up with imports.
+    pub fn is_write_pressured(&self) -> Result<bool, rocksdb::Error> {
+        Ok(self
+            .inner
+            .property_int_value(IS_WRITE_STOPPED)?
+            .unwrap_or(0)
+            > 0
+            || self
+                .inner
+                .property_int_value(ACTUAL_DELAYED_WRITE_RATE)?
+                .unwrap_or(0)
+                > 0)
+    }
+
+    pub fn stats(&self) -> Result<DbStats, rocksdb::Error> {
+        let mut column_families = BTreeMap::new();
+        for name in COLUMN_FAMILIES {
+            let cf = self.inner.cf_handle(name).expect("cf");
+            column_families.insert(
+                name,
+                ColumnFamilyStats {
+                    estimated_keys: self
+                        .inner
+                        .property_int_value_cf(cf, ESTIMATE_NUM_KEYS)?
+                        .unwrap_or(0),
+                    size_on_disk: self
+                        .inner
+                        .property_int_value_cf(cf, TOTAL_SST_FILES_SIZE)?
+                        .unwrap_or(0),
+                },
+            );
+        }
+
+        Ok(DbStats {
+            size_on_disk: column_families.values().map(|cf| cf.size_on_disk).sum(),
+            last_compaction: *self.last_compaction.lock().unwrap(),
+            masters_games: column_families["masters_game"].estimated_keys,
+            lichess_games: column_families["lichess_game"].estimated_keys,
+            column_families,
+        })
+    }
+
     pub fn masters(&self) -> MastersDatabase<'_> {
         MastersDatabase {
             inner: &self.inner,
@@ -264,36 +1283,109 @@ impl Database {
                 .inner
                 .cf_handle("masters_game")
                 .expect("cf masters_game"),
+            cf_masters_game_hash: self
+                .inner
+                .cf_handle("masters_game_hash")
+                .expect("cf masters_game_hash"),
+            cf_masters_position_game: self
+                .inner
+                .cf_handle("masters_position_game")
+                .expect("cf masters_position_game"),
+            cf_masters_structure: self
+                .inner
+                .cf_handle("masters_structure")
+                .expect("cf masters_structure"),
+            cf_meta: self.inner.cf_handle("meta").expect("cf meta"),
+            cf_game_search: self.cf_game_search(),
+            read_deadline: self.read_deadline,
+            cache_fill: &self.cache_fill,
         }
     }
 
     pub fn lichess(&self) -> LichessDatabase<'_> {
         LichessDatabase {
             inner: &self.inner,
+            read_deadline: self.read_deadline,
+            cache_fill: &self.cache_fill,
             cf_lichess: self.inner.cf_handle("lichess").expect("cf lichess"),
+            cf_lichess_variants: LICHESS_VARIANT_COLUMN_FAMILIES.map(|(variant, name)| {
+                (
+                    variant,
+                    self.inner.cf_handle(name).expect("cf lichess variant"),
+                )
+            }),
             cf_lichess_game: self
                 .inner
                 .cf_handle("lichess_game")
                 .expect("cf lichess_game"),
+            cf_lichess_game_moves: self
+                .inner
+                .cf_handle("lichess_game_moves")
+                .expect("cf lichess_game_moves"),
+            store_game_moves: self.lichess_game_moves,
 
             cf_player: self.inner.cf_handle("player").expect("cf player"),
             cf_player_status: self
                 .inner
                 .cf_handle("player_status")
                 .expect("cf player_status"),
+
+            cf_meta: self.inner.cf_handle("meta").expect("cf meta"),
+            cf_game_search: self.cf_game_search(),
         }
     }
-}
 
-pub struct MastersDatabase<'a> {
-    inner: &'a DB,
-    cf_masters: &'a ColumnFamily,
-    cf_masters_game: &'a ColumnFamily,
-}
+    fn cf_game_search(&self) -> Option<&ColumnFamily> {
+        self.game_search
+            .then(|| self.inner.cf_handle("game_search").expect("cf game_search"))
+    }
 
-pub struct MastersMetrics {
-    num_masters: u64,
-    num_masters_game: u64,
+    pub fn game_search(&self) -> Option<GameSearchDatabase<'_>> {
+        self.cf_game_search()
+            .map(|cf_game_search| GameSearchDatabase {
+                inner: &self.inner,
+                cf_game_search,
+            })
+    }
+
+    pub fn audit(&self) -> AuditLog<'_> {
+        AuditLog {
+            inner: &self.inner,
+            cf_audit: self.inner.cf_handle("audit").expect("cf audit"),
+            last_key: &self.last_audit_key,
+        }
+    }
+
+    pub fn response_cache(&self) -> ResponseCache<'_> {
+        ResponseCache {
+            inner: &self.inner,
+            cf_response_cache: self
+                .inner
+                .cf_handle("response_cache")
+                .expect("cf response_cache"),
+            cf_meta: self.inner.cf_handle("meta").expect("cf meta"),
+        }
+    }
+}
+
+pub struct MastersDatabase<'a> {
+    inner: &'a DB,
+    cf_masters: &'a ColumnFamily,
+    cf_masters_game: &'a ColumnFamily,
+    cf_masters_game_hash: &'a ColumnFamily,
+    cf_masters_position_game: &'a ColumnFamily,
+    cf_masters_structure: &'a ColumnFamily,
+    cf_meta: &'a ColumnFamily,
+    cf_game_search: Option<&'a ColumnFamily>,
+    read_deadline: Option<Duration>,
+    cache_fill: &'a CacheFillPolicy,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MastersMetrics {
+    num_masters: u64,
+    num_masters_game: u64,
 }
 
 impl MastersMetrics {
@@ -307,13 +1399,6 @@ impl MastersMetrics {
 }
 
 impl MastersDatabase<'_> {
-    pub fn compact(&self) {
-        log::info!("running manual compaction for masters ...");
-        compact_column(self.inner, self.cf_masters);
-        log::info!("running manual compaction for masters_game ...");
-        compact_column(self.inner, self.cf_masters_game);
-    }
-
     pub fn estimate_metrics(&self) -> Result<MastersMetrics, rocksdb::Error> {
         Ok(MastersMetrics {
             num_masters: self
@@ -333,6 +1418,24 @@ impl MastersDatabase<'_> {
             .map(|maybe_entry| maybe_entry.is_some())
     }
 
+    /// The id of a game with the same content hash, if any. Only covers games
+    /// imported since content hashes were introduced.
+    pub fn game_by_content(
+        &self,
+        hash: &[u8; MastersGame::CONTENT_HASH_SIZE],
+    ) -> Result<Option<GameId>, rocksdb::Error> {
+        Ok(self
+            .inner
+            .get_pinned_cf(self.cf_masters_game_hash, hash)?
+            .and_then(|buf| match GameId::read(&mut buf.as_ref()) {
+                Ok(id) => Some(id),
+                Err(err) => {
+                    log::error!("skipping corrupt masters content hash: {err}");
+                    None
+                }
+            }))
+    }
+
     pub fn game(&self, id: GameId) -> Result<Option<MastersGame>, rocksdb::Error> {
         Ok(self
             .inner
@@ -369,31 +1472,128 @@ impl MastersDatabase<'_> {
             .map(|maybe_entry| maybe_entry.is_some())
     }
 
+    /// Read the entry for a position. Also returns whether the scan was
+    /// stopped early, because the read deadline passed.
     pub fn read(
         &self,
         key: KeyPrefix,
         since: Year,
         until: Year,
+        era: &EraAdjustment,
         cache_hint: CacheHint,
-    ) -> Result<MastersEntry, rocksdb::Error> {
+    ) -> Result<(MastersEntry, bool), rocksdb::Error> {
+        let deadline = self.read_deadline.map(|d| Instant::now() + d);
+        let mut truncated = false;
         let mut entry = MastersEntry::default();
 
         let mut opt = ReadOptions::default();
-        opt.fill_cache(cache_hint.should_fill_cache());
+        opt.fill_cache(self.cache_fill.should_fill_cache(cache_hint));
         opt.set_ignore_range_deletions(true);
         opt.set_prefix_same_as_start(true);
         opt.set_iterate_lower_bound(key.with_year(since).into_bytes());
         opt.set_iterate_upper_bound(key.with_year(until.add_years_saturating(1)).into_bytes());
 
+        let probe = self.cache_fill.probe(cache_hint);
         let mut iter = self.inner.raw_iterator_cf_opt(self.cf_masters, opt);
         iter.seek_to_first();
 
-        while let Some(mut value) = iter.value() {
-            entry.extend_from_reader(&mut value);
+        let (mut scanned_keys, mut scanned_bytes) = (0, 0);
+        while let Some((key, mut value)) = iter.item() {
+            if is_past(deadline) {
+                truncated = true;
+                break;
+            }
+            scanned_keys += 1;
+            scanned_bytes += (key.len() + value.len()) as u64;
+            let year = Key::try_from(key)
+                .expect("masters key size")
+                .year()
+                .expect("read masters key suffix");
+            if let Err(err) = entry.extend_from_year(year, era.rating_delta(year), &mut value) {
+                log::error!("skipping corrupt masters value: {err}");
+            }
             iter.next();
         }
 
-        iter.status().map(|_| entry)
+        probe.finish();
+        Trace::current().add_scan(scanned_keys, scanned_bytes);
+        iter.status().map(|_| (entry, truncated))
+    }
+
+    /// All games in which the position occurred, in the order of their ids.
+    /// Only covers games imported since the posting lists were introduced.
+    pub fn games_at(&self, key: &KeyPrefix) -> Result<Vec<GameId>, rocksdb::Error> {
+        let mut opt = ReadOptions::default();
+        opt.set_ignore_range_deletions(true);
+        opt.set_prefix_same_as_start(true);
+        opt.fill_cache(false);
+
+        let mut iter = self
+            .inner
+            .raw_iterator_cf_opt(self.cf_masters_position_game, opt);
+        iter.seek(key.as_bytes());
+
+        let mut ids = Vec::new();
+        while let Some(posting) = iter.key() {
+            if !posting.starts_with(key.as_bytes()) {
+                break;
+            }
+            match GameId::read(&mut &posting[KeyPrefix::SIZE..]) {
+                Ok(id) => ids.push(id),
+                Err(err) => log::error!("skipping corrupt masters posting: {err}"),
+            }
+            iter.next();
+        }
+
+        iter.status().map(|_| ids)
+    }
+
+    /// Positions with the given pawn structure, scanning at most `max`
+    /// positions. Also returns whether there were more. Only covers games
+    /// imported since the structure index was introduced.
+    pub fn structure_positions(
+        &self,
+        structure: PawnStructure,
+        max: usize,
+    ) -> Result<(Vec<StructureEntry>, bool), rocksdb::Error> {
+        let mut opt = ReadOptions::default();
+        opt.set_ignore_range_deletions(true);
+        opt.set_prefix_same_as_start(true);
+        opt.fill_cache(false);
+
+        let prefix = structure.to_bytes();
+        let mut iter = self
+            .inner
+            .raw_iterator_cf_opt(self.cf_masters_structure, opt);
+        iter.seek(prefix);
+
+        let mut entries = Vec::new();
+        let mut truncated = false;
+        while let Some((key, mut value)) = iter.item() {
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            if entries.len() >= max {
+                truncated = true;
+                break;
+            }
+            let mut entry = StructureEntry::default();
+            match entry.extend_from_reader(&mut value) {
+                Ok(()) => entries.push(entry),
+                Err(err) => log::error!("skipping corrupt masters structure entry: {err}"),
+            }
+            iter.next();
+        }
+
+        iter.status().map(|_| (entries, truncated))
+    }
+
+    /// Number of games imported since games started being counted.
+    pub fn game_count(&self) -> Result<u64, rocksdb::Error> {
+        Ok(self
+            .inner
+            .get_pinned_cf(self.cf_meta, MASTERS_GAME_COUNT_KEY)?
+            .map_or(0, |buf| read_uint(&mut buf.as_ref())))
     }
 
     pub fn batch(&self) -> MastersBatch<'_> {
@@ -423,6 +1623,61 @@ impl MastersBatch<'_> {
             id.to_bytes(),
             serde_json::to_vec(game).expect("serialize masters game"),
         );
+
+        if let Some(cf_game_search) = self.db.cf_game_search {
+            let month = game
+                .date
+                .month()
+                .or_else(|| Month::try_from(u16::from(game.date.year()) * 12).ok())
+                .unwrap_or_else(Month::min_value);
+            for (field, text) in [
+                (SearchField::White, &game.players.white.name),
+                (SearchField::Black, &game.players.black.name),
+                (SearchField::Event, &game.event),
+            ] {
+                put_search_tokens(
+                    &mut self.batch,
+                    cf_game_search,
+                    SearchSource::Masters,
+                    field,
+                    text,
+                    month,
+                    id,
+                );
+            }
+        }
+    }
+
+    pub fn put_content_hash(&mut self, hash: &[u8; MastersGame::CONTENT_HASH_SIZE], id: GameId) {
+        self.batch
+            .put_cf(self.db.cf_masters_game_hash, hash, id.to_bytes());
+    }
+
+    pub fn put_position_game(&mut self, key: &KeyPrefix, id: GameId) {
+        self.batch
+            .put_cf(self.db.cf_masters_position_game, key.with_game(id), b"");
+    }
+
+    pub fn merge_structure(
+        &mut self,
+        structure: PawnStructure,
+        key: &KeyPrefix,
+        entry: StructureEntry,
+    ) {
+        let mut buf = Vec::new();
+        entry.write(&mut buf);
+        self.batch.merge_cf(
+            self.db.cf_masters_structure,
+            structure.with_position(key),
+            buf,
+        );
+    }
+
+    pub fn inc_game_count(&mut self) {
+        let mut buf = Vec::new();
+        write_uint(&mut buf, 1);
+        self.batch
+            .merge_cf(self.db.cf_meta, MASTERS_GAME_COUNT_KEY, buf);
     }
 
     pub fn commit(self) -> Result<(), rocksdb::Error> {
@@ -432,14 +1687,24 @@ impl MastersBatch<'_> {
 
 pub struct LichessDatabase<'a> {
     inner: &'a DB,
+    read_deadline: Option<Duration>,
+    cache_fill: &'a CacheFillPolicy,
 
     cf_lichess: &'a ColumnFamily,
+    cf_lichess_variants: [(Variant, &'a ColumnFamily); 7],
     cf_lichess_game: &'a ColumnFamily,
+    cf_lichess_game_moves: &'a ColumnFamily,
+    store_game_moves: bool,
 
     cf_player: &'a ColumnFamily,
     cf_player_status: &'a ColumnFamily,
+
+    cf_meta: &'a ColumnFamily,
+    cf_game_search: Option<&'a ColumnFamily>,
 }
 
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct LichessMetrics {
     num_lichess: u64,
     num_lichess_game: u64,
@@ -459,24 +1724,40 @@ impl LichessMetrics {
     }
 }
 
-impl LichessDatabase<'_> {
-    pub fn compact(&self) {
-        log::info!("running manual compaction for lichess ...");
-        compact_column(self.inner, self.cf_lichess);
-        log::info!("running manual compaction for lichess_game ...");
-        compact_column(self.inner, self.cf_lichess_game);
-        log::info!("running manual compaction for player ...");
-        compact_column(self.inner, self.cf_player);
-        log::info!("running manual compaction for player_status ...");
-        compact_column(self.inner, self.cf_player_status);
+impl<'a> LichessDatabase<'a> {
+    /// Column family that new entries of the variant are written to.
+    fn cf_lichess_variant(&self, variant: Variant) -> &'a ColumnFamily {
+        self.cf_lichess_variants
+            .iter()
+            .find(|(v, _)| *v == variant)
+            .map_or(self.cf_lichess, |&(_, cf)| cf)
+    }
+
+    /// All column families with lichess explorer entries.
+    fn cfs_lichess(&self) -> impl Iterator<Item = &'a ColumnFamily> {
+        iter::once(self.cf_lichess).chain(self.cf_lichess_variants.map(|(_, cf)| cf))
+    }
+
+    /// Column families that entries of the variant are read from.
+    fn cfs_lichess_read(&self, variant: Variant) -> Vec<&'a ColumnFamily> {
+        if variant == Variant::Chess {
+            vec![self.cf_lichess]
+        } else {
+            vec![self.cf_lichess_variant(variant), self.cf_lichess]
+        }
     }
 
     pub fn estimate_metrics(&self) -> Result<LichessMetrics, rocksdb::Error> {
         Ok(LichessMetrics {
             num_lichess: self
-                .inner
-                .property_int_value_cf(self.cf_lichess, ESTIMATE_NUM_KEYS)?
-                .unwrap_or(0),
+                .cfs_lichess()
+                .map(|cf| {
+                    Ok(self
+                        .inner
+                        .property_int_value_cf(cf, ESTIMATE_NUM_KEYS)?
+                        .unwrap_or(0))
+                })
+                .sum::<Result<u64, rocksdb::Error>>()?,
             num_lichess_game: self
                 .inner
                 .property_int_value_cf(self.cf_lichess_game, ESTIMATE_NUM_KEYS)?
@@ -499,6 +1780,13 @@ impl LichessDatabase<'_> {
             .map(|buf| LichessGame::read(&mut buf.as_ref())))
     }
 
+    pub fn game_moves(&self, id: GameId) -> Result<Option<LichessGameMoves>, rocksdb::Error> {
+        Ok(self
+            .inner
+            .get_pinned_cf(self.cf_lichess_game_moves, id.to_bytes())?
+            .map(|buf| LichessGameMoves::read(&mut buf.as_ref())))
+    }
+
     pub fn games<I: IntoIterator<Item = GameId>>(
         &self,
         ids: I,
@@ -520,60 +1808,142 @@ impl LichessDatabase<'_> {
             .collect()
     }
 
+    /// Read and prepare the entry for a position. Also returns whether the
+    /// scan was stopped early, because the read deadline passed.
+    #[allow(clippy::too_many_arguments)]
     pub fn read_lichess(
         &self,
+        variant: Variant,
         key: &KeyPrefix,
+        color: Color,
         filter: &LichessQueryFilter,
         limits: &Limits,
         history: HistoryWanted,
+        trend: Option<u16>,
+        group_by: Option<GroupBy>,
         cache_hint: CacheHint,
-    ) -> Result<(PreparedResponse, Option<History>), rocksdb::Error> {
+    ) -> Result<
+        (
+            PreparedResponse,
+            Option<History>,
+            Option<Vec<(Month, PreparedResponse)>>,
+            Option<Month>,
+        ),
+        rocksdb::Error,
+    > {
+        let deadline = self.read_deadline.map(|d| Instant::now() + d);
+        let mut truncated_at = None;
         let mut entry = LichessEntry::default();
         let mut history = match history {
             HistoryWanted::No => None,
             HistoryWanted::Yes => Some(HistoryBuilder::new_between(filter.since, filter.until)),
         };
+        let mut trend = trend.map(|months| TrendBuilder::new_until(filter.until, months));
+        let mut months = group_by.map(|GroupBy::Month| MonthsBuilder::default());
+
+        let fill_cache = self.cache_fill.should_fill_cache(cache_hint);
+        let probe = self.cache_fill.probe(cache_hint);
+        // Read all column families at the same point in time, so that
+        // entries moved by LichessDatabase::split_variants() are seen
+        // exactly once.
+        let snapshot = self.inner.snapshot();
+        let mut iters = self
+            .cfs_lichess_read(variant)
+            .into_iter()
+            .map(|cf| {
+                let mut opt = ReadOptions::default();
+                opt.set_snapshot(&snapshot);
+                opt.fill_cache(fill_cache);
+                opt.set_ignore_range_deletions(true);
+                opt.set_prefix_same_as_start(true);
+                opt.set_iterate_lower_bound(
+                    key.with_month(filter.since.unwrap_or_else(Month::min_value))
+                        .into_bytes(),
+                );
+                opt.set_iterate_upper_bound(
+                    key.with_month(
+                        filter
+                            .until
+                            .map_or(Month::max_value(), |m| m.add_months_saturating(1)),
+                    )
+                    .into_bytes(),
+                );
+                let mut iter = self.inner.raw_iterator_cf_opt(cf, opt);
+                iter.seek_to_first();
+                iter
+            })
+            .collect::<Vec<_>>();
+
+        // Merge the column families by month. History is recorded once all
+        // values of a month have been read. History and the selection of
+        // recent games depend on reading months in ascending order, so a
+        // scan that runs out of time reports the month at which it stopped.
+        let mut last_month = None;
+        let (mut scanned_keys, mut scanned_bytes) = (0, 0);
+        while let Some(iter) = iters
+            .iter_mut()
+            .filter(|iter| iter.valid())
+            .min_by(|a, b| a.key().cmp(&b.key()))
+        {
+            let (key, mut value) = iter.item().expect("valid iterator");
+            let month = Key::try_from(key)
+                .expect("lichess key size")
+                .month()
+                .expect("read lichess key suffix");
+
+            if is_past(deadline) {
+                truncated_at = Some(month);
+                break;
+            }
 
-        let mut opt = ReadOptions::default();
-        opt.fill_cache(cache_hint.should_fill_cache());
-        opt.set_ignore_range_deletions(true);
-        opt.set_prefix_same_as_start(true);
-        opt.set_iterate_lower_bound(
-            key.with_month(filter.since.unwrap_or_else(Month::min_value))
-                .into_bytes(),
-        );
-        opt.set_iterate_upper_bound(
-            key.with_month(
-                filter
-                    .until
-                    .map_or(Month::max_value(), |m| m.add_months_saturating(1)),
-            )
-            .into_bytes(),
-        );
+            scanned_keys += 1;
+            scanned_bytes += (key.len() + value.len()) as u64;
 
-        let mut iter = self.inner.raw_iterator_cf_opt(self.cf_lichess, opt);
-        iter.seek_to_first();
+            if let (Some(history), Some(last_month)) = (history.as_mut(), last_month) {
+                if last_month != month {
+                    history.record_difference(last_month, entry.total(filter));
+                }
+            }
+            last_month = Some(month);
+
+            if let Some(ref mut trend) = trend {
+                if trend.wants(month) {
+                    let mut month_entry = LichessEntry::default();
+                    if month_entry.extend_from_reader(&mut &value[..]).is_ok() {
+                        trend.record(month, &month_entry, filter);
+                    }
+                }
+            }
 
-        while let Some((key, mut value)) = iter.item() {
-            entry.extend_from_reader(&mut value);
-
-            if let Some(ref mut history) = history {
-                history.record_difference(
-                    Key::try_from(key)
-                        .expect("lichess key size")
-                        .month()
-                        .expect("read lichess key suffix"),
-                    entry.total(filter),
-                );
+            if let Some(ref mut months) = months {
+                if let Err(err) = months.record(month, &mut &value[..]) {
+                    log::error!("skipping corrupt lichess value of month: {err}");
+                }
+            }
+
+            if let Err(err) = entry.extend_from_month(month, &mut value) {
+                log::error!("skipping corrupt lichess value: {err}");
             }
 
             iter.next();
         }
 
-        iter.status().map(|_| {
+        if let (Some(history), Some(last_month)) = (history.as_mut(), last_month) {
+            history.record_difference(last_month, entry.total(filter));
+        }
+
+        probe.finish();
+        Trace::current().add_scan(scanned_keys, scanned_bytes);
+        iters.iter().try_for_each(|iter| iter.status()).map(|_| {
+            let mut prepared = entry.prepare(color, filter, limits);
+            if let Some(trend) = trend {
+                trend.annotate(&mut prepared.moves);
+            }
             (
-                entry.prepare(filter, limits),
+                prepared,
                 history.map(HistoryBuilder::build),
+                months.map(|months| months.build(color, filter, limits)),
+                truncated_at,
             )
         })
     }
@@ -588,23 +1958,113 @@ impl LichessDatabase<'_> {
         let mut entry = PlayerEntry::default();
 
         let mut opt = ReadOptions::default();
-        opt.fill_cache(cache_hint.should_fill_cache());
+        opt.fill_cache(self.cache_fill.should_fill_cache(cache_hint));
         opt.set_ignore_range_deletions(true);
         opt.set_prefix_same_as_start(true);
         opt.set_iterate_lower_bound(key.with_month(since).into_bytes());
         opt.set_iterate_upper_bound(key.with_month(until.add_months_saturating(1)).into_bytes());
 
+        let probe = self.cache_fill.probe(cache_hint);
         let mut iter = self.inner.raw_iterator_cf_opt(self.cf_player, opt);
         iter.seek_to_first();
 
-        while let Some(mut value) = iter.value() {
-            entry.extend_from_reader(&mut value);
+        while let Some((key, mut value)) = iter.item() {
+            let month = Key::try_from(key)
+                .expect("player key size")
+                .month()
+                .expect("read player key suffix");
+            if let Err(err) = entry.extend_from_month(month, &mut value) {
+                log::error!("skipping corrupt player value: {err}");
+            }
             iter.next();
         }
 
+        probe.finish();
         iter.status().map(|_| entry)
     }
 
+    /// Games of the player through `key`, most recent first, starting at
+    /// `cursor` (or the end of the filtered range). Returns the month of each
+    /// game, and a cursor to continue with, if there are more games.
+    pub fn player_games(
+        &self,
+        key: &KeyPrefix,
+        filter: &PlayerQueryFilter,
+        cursor: Option<PlayerGamesCursor>,
+        max_games: usize,
+    ) -> Result<(Vec<(Month, UciMove, GameId)>, Option<PlayerGamesCursor>), rocksdb::Error> {
+        let until = cursor.map_or(filter.until, |cursor| min(cursor.month, filter.until));
+
+        let mut opt = ReadOptions::default();
+        opt.fill_cache(false);
+        opt.set_ignore_range_deletions(true);
+        opt.set_prefix_same_as_start(true);
+        opt.set_iterate_lower_bound(key.with_month(filter.since).into_bytes());
+        opt.set_iterate_upper_bound(key.with_month(until.add_months_saturating(1)).into_bytes());
+
+        let mut iter = self.inner.raw_iterator_cf_opt(self.cf_player, opt);
+        iter.seek_to_last();
+
+        let mut games = Vec::new();
+        while let Some((key, mut value)) = iter.item() {
+            let month = Key::try_from(key)
+                .expect("player key size")
+                .month()
+                .expect("read player key suffix");
+            let mut entry = PlayerEntry::default();
+            if let Err(err) = entry.extend_from_reader(&mut value) {
+                log::error!("skipping corrupt player value: {err}");
+            }
+            let skip = cursor
+                .filter(|cursor| cursor.month == month)
+                .map_or(0, |cursor| cursor.skip);
+            for (i, (uci, id)) in entry.games(filter).into_iter().enumerate().skip(skip) {
+                if games.len() >= max_games {
+                    return Ok((games, Some(PlayerGamesCursor { month, skip: i })));
+                }
+                games.push((month, uci, id));
+            }
+            iter.prev();
+        }
+
+        iter.status().map(|_| (games, None))
+    }
+
+    /// Number of games through `key` in each month with at least one game.
+    pub fn player_history(&self, key: &KeyPrefix) -> Result<History, rocksdb::Error> {
+        let mut history = History::new();
+
+        let mut opt = ReadOptions::default();
+        opt.fill_cache(false);
+        opt.set_ignore_range_deletions(true);
+        opt.set_prefix_same_as_start(true);
+        opt.set_iterate_lower_bound(key.with_month(Month::min_value()).into_bytes());
+        opt.set_iterate_upper_bound(
+            key.with_month(Month::max_value().add_months_saturating(1))
+                .into_bytes(),
+        );
+
+        let mut iter = self.inner.raw_iterator_cf_opt(self.cf_player, opt);
+        iter.seek_to_first();
+
+        while let Some((key, mut value)) = iter.item() {
+            let mut entry = PlayerEntry::default();
+            if let Err(err) = entry.extend_from_reader(&mut value) {
+                log::error!("skipping corrupt player value: {err}");
+            }
+            history.push(HistorySegment {
+                month: Key::try_from(key)
+                    .expect("player key size")
+                    .month()
+                    .expect("read player key suffix"),
+                stats: entry.total(),
+            });
+            iter.next();
+        }
+
+        iter.status().map(|_| history)
+    }
+
     pub fn player_status(&self, id: &UserId) -> Result<Option<PlayerStatus>, rocksdb::Error> {
         Ok(self
             .inner
@@ -612,6 +2072,30 @@ impl LichessDatabase<'_> {
             .map(|buf| PlayerStatus::read(&mut buf.as_ref())))
     }
 
+    /// Whether each of the players has been indexed before, with a single
+    /// batched read.
+    pub fn players_indexed(
+        &self,
+        users: &ByColor<Option<UserId>>,
+    ) -> Result<ByColor<bool>, rocksdb::Error> {
+        let keys: Vec<_> = users
+            .iter()
+            .flatten()
+            .map(|user| user.as_lowercase_str())
+            .collect();
+        let mut found = self
+            .inner
+            .batched_multi_get_cf(self.cf_player_status, &keys, false)
+            .into_iter();
+        let mut indexed = ByColor::new_with(|_| false);
+        for color in Color::ALL {
+            if users.get(color).is_some() {
+                *indexed.get_mut(color) = found.next().expect("status of each user")?.is_some();
+            }
+        }
+        Ok(indexed)
+    }
+
     pub fn put_player_status(
         &self,
         id: &UserId,
@@ -623,6 +2107,299 @@ impl LichessDatabase<'_> {
             .put_cf(self.cf_player_status, id.as_lowercase_str(), buf)
     }
 
+    /// Delete all entries of `month` from the lichess column family, clear
+    /// the import flag of games from that month, and reset its import
+    /// status, so that the month can be imported again. Entries of a month
+    /// are spread over all positions, so this scans the entire lichess and
+    /// lichess_game column families.
+    pub fn delete_month(&self, month: Month) -> Result<MonthDeletion, rocksdb::Error> {
+        self.delete_months(|m| m == month, false)
+    }
+
+    /// Delete all entries of months before `cutoff`, like
+    /// [`LichessDatabase::delete_month()`]. Games of these months that are
+    /// not referenced by the player index are deleted entirely, including
+    /// their stored moves.
+    pub fn delete_months_before(&self, cutoff: Month) -> Result<MonthDeletion, rocksdb::Error> {
+        self.delete_months(|m| m < cutoff, true)
+    }
+
+    fn delete_months(
+        &self,
+        deleted: impl Fn(Month) -> bool,
+        prune_games: bool,
+    ) -> Result<MonthDeletion, rocksdb::Error> {
+        const CHUNK_SIZE: usize = 10_000;
+
+        let mut deletion = MonthDeletion::default();
+        let key_month = |key: &[u8]| {
+            key.get(key.len().saturating_sub(2)..)
+                .and_then(|bytes| bytes.try_into().ok())
+                .and_then(|bytes| Month::try_from(u16::from_be_bytes(bytes)).ok())
+                .is_some_and(&deleted)
+        };
+
+        let mut batch = WriteBatch::default();
+        for cf in self.cfs_lichess() {
+            let mut opt = ReadOptions::default();
+            opt.fill_cache(false);
+            opt.set_ignore_range_deletions(true);
+            opt.set_total_order_seek(true);
+            let mut iter = self.inner.raw_iterator_cf_opt(cf, opt);
+            iter.seek_to_first();
+
+            while let Some(key) = iter.key() {
+                if key.len() == Key::SIZE && key_month(key) {
+                    batch.delete_cf(cf, key);
+                    deletion.entries += 1;
+                    if batch.len() >= CHUNK_SIZE {
+                        self.inner.write(std::mem::take(&mut batch))?;
+                    }
+                }
+                iter.next();
+            }
+            iter.status()?;
+            self.inner.write(std::mem::take(&mut batch))?;
+        }
+
+        let mut opt = ReadOptions::default();
+        opt.fill_cache(false);
+        opt.set_ignore_range_deletions(true);
+        let mut iter = self.inner.raw_iterator_cf_opt(self.cf_lichess_game, opt);
+        iter.seek_to_first();
+
+        while let Some((key, mut value)) = iter.item() {
+            let info = LichessGame::read(&mut value);
+            if deleted(info.month) && info.indexed_lichess {
+                if prune_games && !info.indexed_player.white && !info.indexed_player.black {
+                    batch.delete_cf(self.cf_lichess_game, key);
+                    batch.delete_cf(self.cf_lichess_game_moves, key);
+                    deletion.pruned += 1;
+                } else {
+                    // Merge rather than put, so that player index flags set
+                    // concurrently are kept.
+                    let mut buf = Vec::with_capacity(LichessGame::SIZE_HINT);
+                    info.write_unindexed_lichess(&mut buf);
+                    batch.merge_cf(self.cf_lichess_game, key, buf);
+                }
+                deletion.games += 1;
+                if batch.len() >= CHUNK_SIZE {
+                    self.inner.write(std::mem::take(&mut batch))?;
+                }
+            }
+            iter.next();
+        }
+        iter.status()?;
+
+        for (month, _) in self.import_status()? {
+            if deleted(month) {
+                batch.delete_cf(self.cf_meta, import_status_key(month));
+            }
+        }
+
+        let mut opt = ReadOptions::default();
+        opt.set_ignore_range_deletions(true);
+        opt.set_iterate_lower_bound(LICHESS_GAME_COUNT_PREFIX);
+        opt.set_iterate_upper_bound(prefix_upper_bound(LICHESS_GAME_COUNT_PREFIX));
+        let mut iter = self.inner.raw_iterator_cf_opt(self.cf_meta, opt);
+        iter.seek_to_first();
+        while let Some(key) = iter.key() {
+            if key_month(key) {
+                batch.delete_cf(self.cf_meta, key);
+            }
+            iter.next();
+        }
+        iter.status()?;
+
+        self.inner.write(batch)?;
+
+        Ok(deletion)
+    }
+
+    /// Delete all entries of a variant other than standard chess, that have
+    /// been written to the column family of the variant, and the count of
+    /// its imported games. Returns `false` for standard chess.
+    pub fn delete_variant(&self, variant: Variant) -> Result<bool, rocksdb::Error> {
+        let Some(&(_, cf)) = self.cf_lichess_variants.iter().find(|(v, _)| *v == variant) else {
+            return Ok(false);
+        };
+        // Drop whole files first, which is cheap. Readers ignore range
+        // deletions, so delete the remaining keys one by one.
+        let from = [0u8; Key::SIZE];
+        let to = [0xffu8; Key::SIZE + 1];
+        self.inner.delete_file_in_range_cf(cf, from, to)?;
+        self.delete_keys(cf, None)?;
+        compact_column(self.inner, cf);
+
+        self.delete_keys(
+            self.cf_meta,
+            Some(lichess_game_count_prefix(variant).as_slice()),
+        )?;
+        Ok(true)
+    }
+
+    /// Delete all keys of the column family, or only those with `prefix`,
+    /// with point deletions.
+    fn delete_keys(&self, cf: &ColumnFamily, prefix: Option<&[u8]>) -> Result<(), rocksdb::Error> {
+        const CHUNK_SIZE: usize = 10_000;
+
+        let mut opt = ReadOptions::default();
+        opt.fill_cache(false);
+        opt.set_ignore_range_deletions(true);
+        opt.set_total_order_seek(true);
+        if let Some(prefix) = prefix {
+            opt.set_iterate_lower_bound(prefix);
+            opt.set_iterate_upper_bound(prefix_upper_bound(prefix));
+        }
+        let mut iter = self.inner.raw_iterator_cf_opt(cf, opt);
+        iter.seek_to_first();
+
+        let mut batch = WriteBatch::default();
+        while let Some(key) = iter.key() {
+            batch.delete_cf(cf, key);
+            if batch.len() >= CHUNK_SIZE {
+                self.inner.write(mem::take(&mut batch))?;
+            }
+            iter.next();
+        }
+        iter.status()?;
+        self.inner.write(batch)
+    }
+
+    /// Move entries of variants other than standard chess, that were
+    /// imported before variants were stored separately, from `lichess` into
+    /// the column family of their variant. Keys do not reveal the variant,
+    /// so games with stored moves are replayed with `keys`, which returns
+    /// the keys of the entries written when the game was imported, or
+    /// `None` if the game can not be replayed. Entries only reached by games
+    /// without stored moves stay in `lichess`, where they are still read.
+    ///
+    /// Values are moved rather than merged, so lichess imports and
+    /// deletions must not run concurrently. Can be interrupted and started
+    /// again.
+    pub fn split_variants(
+        &self,
+        keys: impl Fn(&LichessGameMoves, Month) -> Option<Vec<Key>>,
+    ) -> Result<VariantSplit, rocksdb::Error> {
+        const CHUNK_SIZE: usize = 10_000;
+
+        let mut split = VariantSplit::default();
+        let mut batch = WriteBatch::default();
+        // Keys moved in the pending batch, which reads do not see yet.
+        let mut moved = HashSet::new();
+
+        let mut opt = ReadOptions::default();
+        opt.fill_cache(false);
+        opt.set_ignore_range_deletions(true);
+        let mut iter = self
+            .inner
+            .raw_iterator_cf_opt(self.cf_lichess_game_moves, opt);
+        iter.seek_to_first();
+
+        while let Some((id, mut value)) = iter.item() {
+            let moves = LichessGameMoves::read(&mut value);
+            let info = match moves.variant {
+                Variant::Chess => None,
+                _ => self
+                    .inner
+                    .get_pinned_cf(self.cf_lichess_game, id)?
+                    .map(|buf| LichessGame::read(&mut buf.as_ref()))
+                    .filter(|info| info.indexed_lichess),
+            };
+            if let Some(info) = info {
+                let Some(keys) = keys(&moves, info.month) else {
+                    log::warn!("can not replay lichess game {id:02x?} to split variants");
+                    split.failed += 1;
+                    iter.next();
+                    continue;
+                };
+                let cf = self.cf_lichess_variant(moves.variant);
+                for key in keys {
+                    let key = key.into_bytes();
+                    if moved.contains(&key) {
+                        continue;
+                    }
+                    if let Some(value) = self.inner.get_pinned_cf(self.cf_lichess, key)? {
+                        batch.merge_cf(cf, key, value);
+                        batch.delete_cf(self.cf_lichess, key);
+                        moved.insert(key);
+                        split.entries += 1;
+                    }
+                }
+                split.games += 1;
+                if batch.len() >= CHUNK_SIZE {
+                    self.inner.write(mem::take(&mut batch))?;
+                    moved.clear();
+                }
+            }
+            iter.next();
+        }
+        iter.status()?;
+        self.inner.write(batch)?;
+
+        Ok(split)
+    }
+
+    pub fn merge_import_status(
+        &self,
+        month: Month,
+        status: &ImportStatus,
+    ) -> Result<(), rocksdb::Error> {
+        let mut buf = Vec::new();
+        status.write(&mut buf);
+        self.inner
+            .merge_cf(self.cf_meta, import_status_key(month), buf)
+    }
+
+    pub fn import_status(&self) -> Result<Vec<(Month, ImportStatus)>, rocksdb::Error> {
+        let mut opt = ReadOptions::default();
+        opt.set_ignore_range_deletions(true);
+        opt.set_iterate_lower_bound(import_status_key(Month::min_value()));
+        opt.set_iterate_upper_bound(import_status_key(
+            Month::max_value().add_months_saturating(1),
+        ));
+
+        let mut iter = self.inner.raw_iterator_cf_opt(self.cf_meta, opt);
+        iter.seek_to_first();
+
+        let mut statuses = Vec::new();
+        while let Some((key, mut value)) = iter.item() {
+            let month = u16::from_be_bytes(
+                key[IMPORT_STATUS_PREFIX.len()..]
+                    .try_into()
+                    .expect("import status key size"),
+            );
+            statuses.push((
+                Month::try_from(month).expect("import status month"),
+                ImportStatus::read(&mut value),
+            ));
+            iter.next();
+        }
+
+        iter.status().map(|_| statuses)
+    }
+
+    /// Number of games of the variant imported since games started being
+    /// counted.
+    pub fn game_count(&self, variant: Variant) -> Result<u64, rocksdb::Error> {
+        let mut opt = ReadOptions::default();
+        opt.set_ignore_range_deletions(true);
+        let prefix = lichess_game_count_prefix(variant);
+        opt.set_iterate_upper_bound(prefix_upper_bound(&prefix));
+        opt.set_iterate_lower_bound(prefix);
+
+        let mut iter = self.inner.raw_iterator_cf_opt(self.cf_meta, opt);
+        iter.seek_to_first();
+
+        let mut count = 0;
+        while let Some(mut value) = iter.value() {
+            count += read_uint(&mut value);
+            iter.next();
+        }
+
+        iter.status().map(|_| count)
+    }
+
     pub fn batch(&self) -> LichessBatch<'_> {
         LichessBatch {
             inner: self,
@@ -631,26 +2408,85 @@ impl LichessDatabase<'_> {
     }
 }
 
+#[derive(Debug, Default, Serialize)]
+pub struct VariantSplit {
+    /// Games of variants other than standard chess that were replayed.
+    pub games: u64,
+    /// Entries moved into the column family of their variant.
+    pub entries: u64,
+    /// Games whose stored moves could not be replayed.
+    pub failed: u64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct MonthDeletion {
+    pub entries: u64,
+    pub games: u64,
+    /// Games deleted entirely, because only the lichess explorer referenced
+    /// them.
+    pub pruned: u64,
+}
+
 pub struct LichessBatch<'a> {
     inner: &'a LichessDatabase<'a>,
     batch: WriteBatch,
 }
 
 impl LichessBatch<'_> {
-    pub fn merge_lichess(&mut self, key: Key, entry: LichessEntry) {
+    pub fn merge_lichess(&mut self, variant: Variant, key: Key, entry: LichessEntry) {
         let mut buf = Vec::with_capacity(LichessEntry::SIZE_HINT);
         entry.write(&mut buf);
-        self.batch
-            .merge_cf(self.inner.cf_lichess, key.into_bytes(), buf);
+        self.batch.merge_cf(
+            self.inner.cf_lichess_variant(variant),
+            key.into_bytes(),
+            buf,
+        );
     }
 
     pub fn merge_game(&mut self, id: GameId, info: LichessGame) {
+        if let Some(cf_game_search) = self.inner.cf_game_search {
+            for (field, player) in [
+                (SearchField::White, &info.players.white),
+                (SearchField::Black, &info.players.black),
+            ] {
+                put_search_tokens(
+                    &mut self.batch,
+                    cf_game_search,
+                    SearchSource::Lichess,
+                    field,
+                    &player.name,
+                    info.month,
+                    id,
+                );
+            }
+        }
+
         let mut buf = Vec::with_capacity(LichessGame::SIZE_HINT);
         info.write(&mut buf);
         self.batch
             .merge_cf(self.inner.cf_lichess_game, id.to_bytes(), buf);
     }
 
+    /// Store the moves of a game, unless disabled.
+    pub fn put_game_moves(&mut self, id: GameId, moves: &LichessGameMoves) {
+        if self.inner.store_game_moves {
+            let mut buf = Vec::new();
+            moves.write(&mut buf);
+            self.batch
+                .put_cf(self.inner.cf_lichess_game_moves, id.to_bytes(), buf);
+        }
+    }
+
+    pub fn inc_game_count(&mut self, variant: Variant, month: Month) {
+        let mut buf = Vec::new();
+        write_uint(&mut buf, 1);
+        self.batch.merge_cf(
+            self.inner.cf_meta,
+            lichess_game_count_key(variant, month),
+            buf,
+        );
+    }
+
     pub fn merge_player(&mut self, key: Key, entry: PlayerEntry) {
         let mut buf = Vec::with_capacity(PlayerEntry::SIZE_HINT);
         entry.write(&mut buf);
@@ -663,15 +2499,52 @@ impl LichessBatch<'_> {
     }
 }
 
+fn is_past(deadline: Option<Instant>) -> bool {
+    deadline.map_or(false, |deadline| deadline <= Instant::now())
+}
+
+/// Combine the existing value and all operands. Operands that turn out to
+/// be malformed are quarantined, and the merge is restarted without them, so
+/// that no partially read data is kept.
+fn merge_entries<T: Default>(
+    cf: &'static str,
+    key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &MergeOperands,
+    quarantine: &Quarantine,
+    extend: impl Fn(&mut T, &[u8]) -> Result<(), ReadError>,
+) -> T {
+    let mut skip = Vec::new();
+    'restart: loop {
+        let mut entry = T::default();
+        for (i, op) in existing.into_iter().chain(operands.into_iter()).enumerate() {
+            if skip.contains(&i) {
+                continue;
+            }
+            if let Err(err) = extend(&mut entry, op) {
+                quarantine.push(cf, key, op, err);
+                skip.push(i);
+                continue 'restart;
+            }
+        }
+        return entry;
+    }
+}
+
 fn lichess_merge(
-    _key: &[u8],
+    key: &[u8],
     existing: Option<&[u8]>,
     operands: &MergeOperands,
+    quarantine: &Quarantine,
 ) -> Option<Vec<u8>> {
-    let mut entry = LichessEntry::default();
-    for mut op in existing.into_iter().chain(operands.into_iter()) {
-        entry.extend_from_reader(&mut op);
-    }
+    let entry = merge_entries(
+        "lichess",
+        key,
+        existing,
+        operands,
+        quarantine,
+        |entry: &mut LichessEntry, mut op| entry.extend_from_reader(&mut op),
+    );
     let mut buf = Vec::new();
     entry.write(&mut buf);
     Some(buf)
@@ -681,51 +2554,708 @@ fn lichess_game_merge(
     _key: &[u8],
     existing: Option<&[u8]>,
     operands: &MergeOperands,
+    _quarantine: &Quarantine,
 ) -> Option<Vec<u8>> {
-    // Take latest game info, but merge index status.
+    merge_lichess_games(existing.into_iter().chain(operands.into_iter()))
+}
+
+/// Take latest game info, but merge index status. A value written by
+/// [`LichessGame::write_unindexed_lichess()`] clears the lichess flag of the
+/// values before it, and the result keeps clearing it until a later value
+/// sets the flag again, so that partial merges compose.
+fn merge_lichess_games<'a>(values: impl Iterator<Item = &'a [u8]>) -> Option<Vec<u8>> {
     let mut info: Option<LichessGame> = None;
+    let mut clears_indexed_lichess = false;
     let mut size_hint = 0;
-    for mut op in existing.into_iter().chain(operands.into_iter()) {
+    for mut op in values {
         size_hint = op.len();
+        let clears = LichessGame::clears_indexed_lichess(op);
         let mut new_info = LichessGame::read(&mut op);
         if let Some(old_info) = info {
             new_info.indexed_player.white |= old_info.indexed_player.white;
             new_info.indexed_player.black |= old_info.indexed_player.black;
-            new_info.indexed_lichess |= old_info.indexed_lichess;
+            if !clears {
+                new_info.indexed_lichess |= old_info.indexed_lichess;
+            }
         }
+        clears_indexed_lichess = clears || (clears_indexed_lichess && !new_info.indexed_lichess);
         info = Some(new_info);
     }
     info.map(|info| {
         let mut buf = Vec::with_capacity(size_hint);
-        info.write(&mut buf);
+        if clears_indexed_lichess {
+            info.write_unindexed_lichess(&mut buf);
+        } else {
+            info.write(&mut buf);
+        }
         buf
     })
 }
 
-fn player_merge(_key: &[u8], existing: Option<&[u8]>, operands: &MergeOperands) -> Option<Vec<u8>> {
-    let mut entry = PlayerEntry::default();
-    for mut op in existing.into_iter().chain(operands.into_iter()) {
-        entry.extend_from_reader(&mut op);
-    }
+fn player_merge(
+    key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &MergeOperands,
+    quarantine: &Quarantine,
+) -> Option<Vec<u8>> {
+    let entry = merge_entries(
+        "player",
+        key,
+        existing,
+        operands,
+        quarantine,
+        |entry: &mut PlayerEntry, mut op| entry.extend_from_reader(&mut op),
+    );
     let mut buf = Vec::new();
     entry.write(&mut buf);
     Some(buf)
 }
 
-fn masters_merge(
+const IMPORT_STATUS_PREFIX: &[u8] = b"lichess_import:";
+
+fn import_status_key(month: Month) -> Vec<u8> {
+    let mut key = IMPORT_STATUS_PREFIX.to_vec();
+    key.put_u16(u16::from(month));
+    key
+}
+
+const MASTERS_GAME_COUNT_KEY: &[u8] = b"game_count:masters";
+
+const LICHESS_GAME_COUNT_PREFIX: &[u8] = b"game_count:lichess:";
+
+/// Key of the number of lichess games of a variant imported for a month, so
+/// that counts can be removed along with the month.
+fn lichess_game_count_key(variant: Variant, month: Month) -> Vec<u8> {
+    let mut key = lichess_game_count_prefix(variant);
+    key.put_u16(u16::from(month));
+    key
+}
+
+fn lichess_game_count_prefix(variant: Variant) -> Vec<u8> {
+    let mut prefix = LICHESS_GAME_COUNT_PREFIX.to_vec();
+    prefix.put_slice(variant.uci().as_bytes());
+    prefix.put_u8(b':');
+    prefix
+}
+
+fn prefix_upper_bound(prefix: &[u8]) -> Vec<u8> {
+    let mut bound = prefix.to_vec();
+    *bound.last_mut().expect("non-empty prefix") += 1;
+    bound
+}
+
+/// Values in the meta column family are sequences of uints. Merging adds
+/// them componentwise.
+fn meta_merge(
     _key: &[u8],
     existing: Option<&[u8]>,
     operands: &MergeOperands,
+    _quarantine: &Quarantine,
 ) -> Option<Vec<u8>> {
-    let mut entry = MastersEntry::default();
+    let mut sums: Vec<u64> = Vec::new();
     for mut op in existing.into_iter().chain(operands.into_iter()) {
-        entry.extend_from_reader(&mut op);
+        let mut i = 0;
+        while op.has_remaining() {
+            let n = read_uint(&mut op);
+            match sums.get_mut(i) {
+                Some(sum) => *sum = sum.saturating_add(n),
+                None => sums.push(n),
+            }
+            i += 1;
+        }
     }
     let mut buf = Vec::new();
+    for sum in sums {
+        write_uint(&mut buf, sum);
+    }
+    Some(buf)
+}
+
+fn masters_merge(
+    key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &MergeOperands,
+    quarantine: &Quarantine,
+) -> Option<Vec<u8>> {
+    let entry = merge_entries(
+        "masters",
+        key,
+        existing,
+        operands,
+        quarantine,
+        |entry: &mut MastersEntry, mut op| entry.extend_from_reader(&mut op),
+    );
+    let mut buf = Vec::new();
     entry.write(&mut buf);
     Some(buf)
 }
 
+fn masters_structure_merge(
+    key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &MergeOperands,
+    quarantine: &Quarantine,
+) -> Option<Vec<u8>> {
+    let entry = merge_entries(
+        "masters_structure",
+        key,
+        existing,
+        operands,
+        quarantine,
+        |entry: &mut StructureEntry, mut op| entry.extend_from_reader(&mut op),
+    );
+    let mut buf = Vec::new();
+    entry.write(&mut buf);
+    Some(buf)
+}
+
+fn put_search_tokens(
+    batch: &mut WriteBatch,
+    cf_game_search: &ColumnFamily,
+    source: SearchSource,
+    field: SearchField,
+    text: &str,
+    month: Month,
+    id: GameId,
+) {
+    for token in search_tokens(text) {
+        batch.put_cf(
+            cf_game_search,
+            SearchKey {
+                source,
+                field,
+                token,
+                month,
+                id,
+            }
+            .into_bytes(),
+            b"",
+        );
+    }
+}
+
+pub struct GameSearchDatabase<'a> {
+    inner: &'a DB,
+    cf_game_search: &'a ColumnFamily,
+}
+
+impl GameSearchDatabase<'_> {
+    /// Find games where a token of the given field starts with `token`,
+    /// played between `since` and `until` (inclusive). Scans at most
+    /// `max_scan` index entries. Results are ordered by matching token, then
+    /// by month.
+    pub fn search(
+        &self,
+        source: SearchSource,
+        field: SearchField,
+        token: &str,
+        since: Month,
+        until: Month,
+        max_scan: usize,
+    ) -> Result<Vec<GameId>, rocksdb::Error> {
+        let prefix = SearchKey::prefix(source, field, token);
+
+        let mut opt = ReadOptions::default();
+        opt.set_ignore_range_deletions(true);
+        opt.set_iterate_lower_bound(prefix.clone());
+
+        let mut iter = self.inner.raw_iterator_cf_opt(self.cf_game_search, opt);
+        iter.seek_to_first();
+
+        let mut ids = Vec::new();
+        let mut seen = HashSet::new();
+        let mut scanned = 0;
+        while let Some(key) = iter.key() {
+            if !key.starts_with(&prefix) || scanned >= max_scan {
+                break;
+            }
+            scanned += 1;
+            if let Some((month, id)) = SearchKey::read_suffix(key) {
+                if since <= month && month <= until && seen.insert(id) {
+                    ids.push(id);
+                }
+            }
+            iter.next();
+        }
+
+        iter.status().map(|_| ids)
+    }
+}
+
+/// The namespace and its generation, so that all responses of a namespace
+/// can be invalidated at once, followed by a hash of the canonical query.
+pub struct ResponseCacheKey(Vec<u8>);
+
+fn response_cache_generation_key(namespace: &str) -> Vec<u8> {
+    let mut key = b"response_cache_generation:".to_vec();
+    key.extend_from_slice(namespace.as_bytes());
+    key
+}
+
+pub struct ResponseCache<'a> {
+    inner: &'a DB,
+    cf_response_cache: &'a ColumnFamily,
+    cf_meta: &'a ColumnFamily,
+}
+
+impl ResponseCache<'_> {
+    /// Key of a query in the current generation of its namespace.
+    pub fn key(
+        &self,
+        namespace: &str,
+        canonical_query: &str,
+    ) -> Result<ResponseCacheKey, rocksdb::Error> {
+        let generation = self
+            .inner
+            .get_pinned_cf(self.cf_meta, response_cache_generation_key(namespace))?
+            .map_or(0, |buf| read_uint(&mut buf.as_ref()));
+        let mut key = namespace.as_bytes().to_vec();
+        key.push(0);
+        key.put_u64(generation);
+        key.extend_from_slice(&Sha1::digest(canonical_query.as_bytes()));
+        Ok(ResponseCacheKey(key))
+    }
+
+    pub fn estimate_num_keys(&self) -> Result<u64, rocksdb::Error> {
+        Ok(self
+            .inner
+            .property_int_value_cf(self.cf_response_cache, ESTIMATE_NUM_KEYS)?
+            .unwrap_or(0))
+    }
+
+    /// Errors are logged and treated as misses.
+    pub fn get(&self, key: &ResponseCacheKey) -> Option<ExplorerResponse> {
+        self.inner
+            .get_pinned_cf(self.cf_response_cache, &key.0)
+            .inspect_err(|err| log::warn!("get cached response: {err}"))
+            .ok()
+            .flatten()
+            .and_then(|buf| {
+                let mut buf = buf.as_ref();
+                if response_cache_expired(buf) {
+                    return None;
+                }
+                buf.advance(8);
+                // Treat responses that were serialized in an incompatible
+                // format by a previous version as misses.
+                serde_json::from_slice(buf).ok()
+            })
+    }
+
+    /// Errors are logged, so that the response is served anyway.
+    pub fn put(&self, key: &ResponseCacheKey, response: &ExplorerResponse, ttl: Duration) {
+        let mut buf = Vec::new();
+        buf.put_u64(
+            (SystemTime::now() + ttl)
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("duration since unix epoch")
+                .as_secs(),
+        );
+        serde_json::to_writer(&mut buf, response).expect("serialize cached response");
+        if let Err(err) = self.inner.put_cf(self.cf_response_cache, &key.0, buf) {
+            log::warn!("put cached response: {err}");
+        }
+    }
+
+    /// Stop serving cached responses of a namespace, after changes to the
+    /// data they were computed from, by starting a new generation. Responses
+    /// of previous generations are removed by compactions once they expire.
+    pub fn invalidate(&self, namespace: &str) -> Result<(), rocksdb::Error> {
+        let mut buf = Vec::new();
+        write_uint(&mut buf, 1);
+        self.inner
+            .merge_cf(self.cf_meta, response_cache_generation_key(namespace), buf)
+    }
+}
+
+fn response_cache_expired(mut value: &[u8]) -> bool {
+    value.len() < 8
+        || SystemTime::UNIX_EPOCH + Duration::from_secs(value.get_u64()) <= SystemTime::now()
+}
+
+fn response_cache_filter(_level: u32, _key: &[u8], value: &[u8]) -> Decision {
+    if response_cache_expired(value) {
+        Decision::Remove
+    } else {
+        Decision::Keep
+    }
+}
+
+pub struct AuditLog<'a> {
+    inner: &'a DB,
+    cf_audit: &'a ColumnFamily,
+    last_key: &'a AtomicU64,
+}
+
+impl AuditLog<'_> {
+    pub fn record(&self, entry: &AuditEntry) -> Result<AuditKey, rocksdb::Error> {
+        // Keys are timestamps, bumped if necessary to keep them unique within
+        // this process.
+        let at = AuditKey::from_time(entry.at).0;
+        let prev = self
+            .last_key
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+                Some(at.max(last + 1))
+            })
+            .expect("fetch_update always succeeds");
+        let key = AuditKey(at.max(prev + 1));
+
+        self.inner.put_cf(
+            self.cf_audit,
+            key.to_bytes(),
+            serde_json::to_vec(entry).expect("serialize audit entry"),
+        )?;
+        Ok(key)
+    }
+
+    /// Newest entries first, strictly before `before` if given.
+    pub fn entries(
+        &self,
+        before: Option<AuditKey>,
+        limit: usize,
+    ) -> Result<Vec<(AuditKey, AuditEntry)>, rocksdb::Error> {
+        let mut opt = ReadOptions::default();
+        if let Some(before) = before {
+            opt.set_iterate_upper_bound(before.to_bytes());
+        }
+
+        let mut iter = self.inner.raw_iterator_cf_opt(self.cf_audit, opt);
+        iter.seek_to_last();
+
+        let mut entries = Vec::with_capacity(limit.min(1000));
+        while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+            if entries.len() >= limit {
+                break;
+            }
+            if let (Some(key), Ok(entry)) =
+                (AuditKey::from_bytes(key), serde_json::from_slice(value))
+            {
+                entries.push((key, entry));
+            }
+            iter.prev();
+        }
+
+        iter.status().map(|_| entries)
+    }
+}
+
 fn compact_column(db: &DB, cf: &ColumnFamily) {
     db.compact_range_cf(cf, None::<&[u8]>, None::<&[u8]>);
 }
+
+/// Directory for a database in tests, removed when dropped. Databases
+/// opened in it must be dropped first.
+#[cfg(test)]
+pub struct TempDb(PathBuf);
+
+#[cfg(test)]
+impl TempDb {
+    pub fn new(name: &str) -> TempDb {
+        let path = std::env::temp_dir().join(format!("explorer-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        TempDb(path)
+    }
+
+    pub fn open(&self, args: &[&str]) -> Database {
+        let path = self.0.to_str().expect("utf-8 path");
+        Database::open(DbOpt::parse_from(
+            ["lila-openingexplorer", "--db", path]
+                .into_iter()
+                .chain(args.iter().copied()),
+        ))
+        .expect("open temporary database")
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shakmaty::{Outcome, Square};
+
+    use super::*;
+    use crate::{
+        api::{query_from_json, LichessQuery},
+        model::{GamePlayer, KeyBuilder, Mode, Speed},
+        zobrist::StableZobrist128,
+    };
+
+    fn lichess_game(month: Month, indexed_white: bool, indexed_lichess: bool) -> LichessGame {
+        LichessGame {
+            outcome: Outcome::Draw,
+            speed: Speed::Blitz,
+            mode: Mode::Rated,
+            players: ByColor::new_with(|color| GamePlayer {
+                name: color.to_string(),
+                rating: 1500,
+            }),
+            month,
+            indexed_player: ByColor {
+                white: indexed_white,
+                black: false,
+            },
+            indexed_lichess,
+        }
+    }
+
+    #[test]
+    fn test_read_levelstats() {
+        let mut metrics = ColumnFamilyMetrics::default();
+        metrics.read_levelstats(
+            "Level Files Size(MB)\n\
+             --------------------\n  \
+               0        2        1\n  \
+               1        0        0\n  \
+               6      123     4567\n",
+        );
+        assert_eq!(
+            metrics.levels,
+            vec![(2, 1), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (123, 4567)]
+        );
+    }
+
+    #[test]
+    fn test_cache_fill_policy() {
+        let policy = CacheFillPolicy::default();
+        let percent = |ply| {
+            policy
+                .band(CacheHint::from_ply(ply))
+                .percent
+                .load(Ordering::Relaxed)
+        };
+        assert_eq!(percent(0), 100);
+        assert_eq!(percent(14), 100);
+        assert_eq!(percent(15), 5);
+        assert_eq!(percent(30), 1);
+
+        // Mostly hits: Fill more, up to the limit.
+        for _ in 0..20 {
+            policy
+                .band(CacheHint::from_ply(16))
+                .observe(CACHE_FILL_WINDOW, 0);
+        }
+        assert_eq!(percent(16), CACHE_FILL_MAX_PERCENT);
+
+        // Mostly misses: Fill less, but never stop entirely.
+        for _ in 0..20 {
+            policy
+                .band(CacheHint::from_ply(16))
+                .observe(0, CACHE_FILL_WINDOW);
+        }
+        assert_eq!(percent(16), 1);
+
+        // Positions before the first band are unaffected.
+        policy
+            .band(CacheHint::always())
+            .observe(0, CACHE_FILL_WINDOW);
+        assert_eq!(percent(0), 100);
+    }
+
+    #[test]
+    fn test_merge_lichess_games() {
+        let game = |indexed_white, indexed_lichess| {
+            lichess_game("2024-01".parse().unwrap(), indexed_white, indexed_lichess)
+        };
+        let write = |info: LichessGame| {
+            let mut buf = Vec::new();
+            info.write(&mut buf);
+            buf
+        };
+        let unindexed = {
+            let mut buf = Vec::new();
+            game(false, false).write_unindexed_lichess(&mut buf);
+            buf
+        };
+        let merge = |values: &[&[u8]]| merge_lichess_games(values.iter().copied()).unwrap();
+        let read = |value: Vec<u8>| LichessGame::read(&mut value.as_slice());
+
+        let imported = write(game(false, true));
+        let indexed_player = write(game(true, false));
+
+        // Clears the flag of earlier values, but keeps player index flags.
+        let info = read(merge(&[&imported, &unindexed, &indexed_player]));
+        assert!(!info.indexed_lichess);
+        assert!(info.indexed_player.white);
+
+        // Also when merged partially first.
+        let partial = merge(&[&unindexed, &indexed_player]);
+        let info = read(merge(&[&imported, &partial]));
+        assert!(!info.indexed_lichess);
+        assert!(info.indexed_player.white);
+
+        // Importing again sets the flag.
+        let partial = merge(&[&unindexed, &imported]);
+        assert!(read(merge(&[&imported, &partial])).indexed_lichess);
+    }
+
+    #[test]
+    fn test_response_cache_invalidate() {
+        let tmp = TempDb::new("response-cache");
+        let db = tmp.open(&[]);
+        let cache = db.response_cache();
+        let response: ExplorerResponse = serde_json::from_str(
+            r#"{"white": 1, "draws": 2, "black": 3, "moves": [], "opening": null}"#,
+        )
+        .unwrap();
+        let ttl = Duration::from_secs(60);
+
+        let lichess_key = cache.key("lichess", "play=e2e4").unwrap();
+        let masters_key = cache.key("masters", "play=e2e4").unwrap();
+        cache.put(&lichess_key, &response, ttl);
+        cache.put(&masters_key, &response, ttl);
+        assert!(cache.get(&lichess_key).is_some());
+
+        // Only the invalidated namespace starts a new generation.
+        cache.invalidate("lichess").unwrap();
+        assert!(cache
+            .get(&cache.key("lichess", "play=e2e4").unwrap())
+            .is_none());
+        assert!(cache
+            .get(&cache.key("masters", "play=e2e4").unwrap())
+            .is_some());
+    }
+
+    #[test]
+    fn test_lichess_variant_column_families() {
+        let tmp = TempDb::new("variants");
+        let db = tmp.open(&["--db-lichess-game-moves"]);
+        let lichess = db.lichess();
+        let cf_crazyhouse = lichess.cf_lichess_variant(Variant::Crazyhouse);
+
+        let prefix = KeyBuilder::lichess().with_zobrist(Variant::Crazyhouse, StableZobrist128(1));
+        let month: Month = "2020-01".parse().unwrap();
+        let next_month = month.add_months_saturating(1);
+        let entry = |id: &str| {
+            let mut buf = Vec::new();
+            LichessEntry::new_single(
+                UciMove::Normal {
+                    from: Square::E2,
+                    to: Square::E4,
+                    promotion: None,
+                },
+                Speed::Blitz,
+                Mode::Rated,
+                0,
+                id.parse().unwrap(),
+                Outcome::Draw,
+                1500,
+                1500,
+            )
+            .write(&mut buf);
+            buf
+        };
+
+        // Imported before and after variants were split.
+        let key = |month: Month| prefix.with_month(month).into_bytes();
+        db.inner
+            .merge_cf(lichess.cf_lichess, key(month), entry("aaaaaaaa"))
+            .unwrap();
+        db.inner
+            .merge_cf(cf_crazyhouse, key(month), entry("bbbbbbbb"))
+            .unwrap();
+        db.inner
+            .merge_cf(lichess.cf_lichess, key(next_month), entry("cccccccc"))
+            .unwrap();
+
+        let query: LichessQuery = query_from_json("{}").unwrap();
+        let read = |variant| {
+            let (prepared, _, months, truncated_at) = lichess
+                .read_lichess(
+                    variant,
+                    &prefix,
+                    Color::White,
+                    &query.filter,
+                    &query.limits,
+                    HistoryWanted::No,
+                    None,
+                    Some(GroupBy::Month),
+                    CacheHint::always(),
+                )
+                .unwrap();
+            assert_eq!(truncated_at, None);
+            (
+                prepared.total.total(),
+                months
+                    .unwrap()
+                    .into_iter()
+                    .map(|(month, prepared)| (month, prepared.total.total()))
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+        // Both column families are merged by month.
+        let expected = (3, vec![(month, 2), (next_month, 1)]);
+        assert_eq!(read(Variant::Crazyhouse), expected);
+        assert_eq!(read(Variant::Chess).0, 2);
+
+        // Split by replaying a stored game.
+        let id: GameId = "aaaaaaaa".parse().unwrap();
+        let mut batch = lichess.batch();
+        batch.merge_game(id, lichess_game(month, false, true));
+        batch.put_game_moves(
+            id,
+            &LichessGameMoves {
+                variant: Variant::Crazyhouse,
+                fen: None,
+                moves: Vec::new(),
+            },
+        );
+        batch.commit().unwrap();
+
+        let keys = |moves: &LichessGameMoves, month: Month| {
+            assert_eq!(moves.variant, Variant::Crazyhouse);
+            Some(vec![
+                prefix.with_month(month),
+                prefix.with_month(month.add_months_saturating(1)),
+            ])
+        };
+        let split = lichess.split_variants(&keys).unwrap();
+        assert_eq!((split.games, split.entries, split.failed), (1, 2, 0));
+        assert_eq!(read(Variant::Crazyhouse), expected);
+        assert_eq!(read(Variant::Chess).0, 0);
+
+        // Nothing left to move.
+        assert_eq!(lichess.split_variants(&keys).unwrap().entries, 0);
+        assert_eq!(read(Variant::Crazyhouse), expected);
+
+        // Deleted with point deletions, which reads do not ignore.
+        assert!(lichess.delete_variant(Variant::Crazyhouse).unwrap());
+        assert_eq!(read(Variant::Crazyhouse), (0, Vec::new()));
+        assert!(!lichess.delete_variant(Variant::Chess).unwrap());
+    }
+
+    #[test]
+    fn test_cf_path() {
+        let cf_path: CfPath = "lichess_game=/mnt/hdd/lichess_game".parse().unwrap();
+        assert_eq!(cf_path.cf, "lichess_game");
+        assert_eq!(cf_path.path, PathBuf::from("/mnt/hdd/lichess_game"));
+        assert!("lichess_games=/mnt/hdd".parse::<CfPath>().is_err());
+        assert!("lichess_game=".parse::<CfPath>().is_err());
+        assert!("lichess_game".parse::<CfPath>().is_err());
+    }
+
+    #[test]
+    fn test_compact_at() {
+        let at: CompactAt = "03:00".parse().unwrap();
+        let midnight = SystemTime::UNIX_EPOCH + Duration::from_secs(20_000 * 24 * 60 * 60);
+        assert_eq!(
+            at.duration_until_next(midnight),
+            Duration::from_secs(3 * 60 * 60)
+        );
+        assert_eq!(
+            at.duration_until_next(midnight + Duration::from_secs(3 * 60 * 60)),
+            Duration::from_secs(24 * 60 * 60)
+        );
+        assert_eq!(
+            at.duration_until_next(midnight + Duration::from_secs(4 * 60 * 60)),
+            Duration::from_secs(23 * 60 * 60)
+        );
+        assert!("24:00".parse::<CompactAt>().is_err());
+        assert!("3".parse::<CompactAt>().is_err());
+    }
+}
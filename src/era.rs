@@ -0,0 +1,111 @@
+use std::{fs::File, io, path::PathBuf};
+
+use clap::Parser;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::model::Year;
+
+#[derive(Parser, Clone)]
+pub struct EraOpt {
+    /// CSV file with columns `year` and `adjustment`, to normalize the
+    /// ratings of masters games across rating eras. The adjustment is added
+    /// to the ratings of games played in that year, interpolated linearly
+    /// between the listed years, and constant before the first and after the
+    /// last listed year. If configured, masters moves also report a
+    /// performance.
+    #[arg(long = "masters-era-adjustment")]
+    masters_era_adjustment: Option<PathBuf>,
+}
+
+#[derive(Error, Debug)]
+pub enum EraError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("csv error: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("year {0} listed more than once")]
+    DuplicateYear(u16),
+}
+
+#[derive(Deserialize)]
+struct EraRecord {
+    year: u16,
+    adjustment: i32,
+}
+
+/// Rating adjustments by year, sorted by year.
+#[derive(Default, Debug)]
+pub struct EraAdjustment {
+    points: Vec<(u16, i32)>,
+}
+
+impl EraAdjustment {
+    pub fn load(opt: EraOpt) -> Result<EraAdjustment, EraError> {
+        match opt.masters_era_adjustment {
+            Some(path) => EraAdjustment::from_reader(File::open(path)?),
+            None => Ok(EraAdjustment::default()),
+        }
+    }
+
+    fn from_reader<R: io::Read>(reader: R) -> Result<EraAdjustment, EraError> {
+        let mut points = Vec::new();
+        for record in csv::Reader::from_reader(reader).deserialize() {
+            let EraRecord { year, adjustment } = record?;
+            points.push((year, adjustment));
+        }
+        points.sort_unstable();
+        if let Some(w) = points.windows(2).find(|w| w[0].0 == w[1].0) {
+            return Err(EraError::DuplicateYear(w[0].0));
+        }
+        Ok(EraAdjustment { points })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.points.is_empty()
+    }
+
+    /// Rating points to add to the ratings of games played in the given
+    /// year.
+    pub fn rating_delta(&self, year: Year) -> i32 {
+        let year = u16::from(year);
+        let i = self.points.partition_point(|(y, _)| *y <= year);
+        match (
+            i.checked_sub(1).map(|i| self.points[i]),
+            self.points.get(i).copied(),
+        ) {
+            (None, None) => 0,
+            (Some((_, before)), None) => before,
+            (None, Some((_, after))) => after,
+            (Some((y0, a0)), Some((y1, a1))) => {
+                let t = f64::from(year - y0) / f64::from(y1 - y0);
+                (f64::from(a0) + t * f64::from(a1 - a0)).round() as i32
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn year(year: u16) -> Year {
+        Year::try_from(year).unwrap()
+    }
+
+    #[test]
+    fn test_rating_delta() {
+        let era =
+            EraAdjustment::from_reader("year,adjustment\n1990,0\n1970,100\n".as_bytes()).unwrap();
+        assert_eq!(era.rating_delta(year(1952)), 100);
+        assert_eq!(era.rating_delta(year(1970)), 100);
+        assert_eq!(era.rating_delta(year(1980)), 50);
+        assert_eq!(era.rating_delta(year(1990)), 0);
+        assert_eq!(era.rating_delta(year(2024)), 0);
+
+        assert_eq!(EraAdjustment::default().rating_delta(year(1970)), 0);
+        assert!(
+            EraAdjustment::from_reader("year,adjustment\n1970,1\n1970,2\n".as_bytes()).is_err()
+        );
+    }
+}
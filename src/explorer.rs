@@ -0,0 +1,447 @@
+use std::{collections::HashSet, time::Instant};
+
+use shakmaty::{
+    san::{San, SanPlus, Suffix},
+    uci::UciMove,
+    variant::VariantPosition,
+    zobrist::ZobristHash,
+    Color, EnPassantMode, MoveList, Position,
+};
+
+use crate::{
+    annotation::MoveAnnotator,
+    api::{
+        Error, ExplorerGame, ExplorerGameWithUciMove, ExplorerMove, ExplorerResponse, LichessQuery,
+        MastersQuery, MonthSummary, MoveConfidence, Play, PlayPosition, PlayerLimits,
+        PlayerQueryFilter,
+    },
+    db::{CacheHint, Database, LichessDatabase},
+    era::EraAdjustment,
+    model::{GameId, KeyBuilder, KeyPrefix, PreparedMove, UserId, UserName},
+    opening::{Opening, Openings},
+    trace::Trace,
+    util::ply,
+};
+
+/// Position of a lichess or masters query.
+pub(crate) struct QueriedPosition {
+    pub key: KeyPrefix,
+    /// Not available if the query named the position only by its hash.
+    pub pos: Option<VariantPosition>,
+    pub opening: Option<Opening>,
+}
+
+impl QueriedPosition {
+    pub fn new(
+        play: Play,
+        key_builder: KeyBuilder,
+        openings: &Openings,
+    ) -> Result<QueriedPosition, Error> {
+        Ok(match play.zobrist()? {
+            Some((variant, zobrist)) => QueriedPosition {
+                key: key_builder.with_zobrist(variant, zobrist),
+                pos: None,
+                opening: None,
+            },
+            None => {
+                let PlayPosition { pos, opening } = play.position(openings)?;
+                QueriedPosition {
+                    key: key_builder
+                        .with_zobrist(pos.variant(), pos.zobrist_hash(EnPassantMode::Legal)),
+                    pos: Some(pos),
+                    opening,
+                }
+            }
+        })
+    }
+}
+
+/// Computes SAN for all moves of a response. Legal moves are generated only
+/// once for disambiguation, and positions after each move are played on the
+/// same scratch board.
+pub(crate) struct SanContext<'a> {
+    pos: &'a VariantPosition,
+    legals: MoveList,
+    after: VariantPosition,
+}
+
+impl<'a> SanContext<'a> {
+    pub fn new(pos: &'a VariantPosition) -> SanContext<'a> {
+        SanContext {
+            pos,
+            legals: pos.legal_moves(),
+            after: pos.clone(),
+        }
+    }
+
+    /// SAN of a legal move. Leaves the position after the move in
+    /// `self.after`.
+    pub fn san_from_uci(&mut self, uci: &UciMove) -> Option<SanPlus> {
+        let m = uci.to_move(self.pos).ok()?;
+        let san = San::disambiguate(&m, &self.legals);
+        self.after.clone_from(self.pos);
+        self.after.play_unchecked(&m);
+        Some(SanPlus {
+            san,
+            suffix: Suffix::from_position(&self.after),
+        })
+    }
+}
+
+/// SAN of a move and the opening it leads to. Both are unknown if the query
+/// named the position only by its hash.
+pub(crate) fn san_and_opening(
+    context: Option<&mut SanContext<'_>>,
+    uci: &UciMove,
+    openings: &Openings,
+) -> (SanPlus, Option<Opening>) {
+    match context.and_then(|context| Some((context.san_from_uci(uci)?, context))) {
+        Some((san, context)) => (san, openings.classify_exact(&context.after).cloned()),
+        None => (
+            SanPlus {
+                san: San::Null,
+                suffix: None,
+            },
+            None,
+        ),
+    }
+}
+
+pub(crate) fn finalize_lichess_moves(
+    moves: Vec<PreparedMove>,
+    pos: Option<&VariantPosition>,
+    lichess_db: &LichessDatabase,
+    openings: &Openings,
+) -> Vec<ExplorerMove> {
+    let mut san_context = pos.map(SanContext::new);
+
+    // Fetch the exemplar games of all moves at once, in order.
+    let mut games = lichess_db
+        .games(moves.iter().filter_map(|p| p.game))
+        .expect("get games")
+        .into_iter();
+
+    moves
+        .into_iter()
+        .map(|p| {
+            let (san, opening) = san_and_opening(san_context.as_mut(), &p.uci, openings);
+            ExplorerMove {
+                stats: p.stats,
+                san,
+                uci: p.uci,
+                average_rating: p.average_rating,
+                average_opponent_rating: p.average_opponent_rating,
+                performance: p.performance,
+                game: p.game.and_then(|id| {
+                    games
+                        .next()
+                        .flatten()
+                        .map(|info| ExplorerGame::from_lichess(id, info))
+                }),
+                opening,
+                eval: None,
+                tablebase: None,
+                trend: p.trend,
+                last_played: p.last_played,
+                confidence: None,
+                annotation: None,
+                score_delta: p.score_delta,
+            }
+        })
+        .collect()
+}
+
+/// Annotate moves with result percentages and score confidence intervals,
+/// if the side to move is known.
+pub(crate) fn annotate_confidence(moves: &mut [ExplorerMove], pos: Option<&VariantPosition>) {
+    if let Some(pos) = pos {
+        for m in moves {
+            m.confidence = MoveConfidence::new(&m.stats, pos.turn());
+        }
+    }
+}
+
+/// Annotate moves with !?, ?! or ?, if the side to move is known.
+pub(crate) fn annotate_moves(
+    moves: &mut [ExplorerMove],
+    pos: Option<&VariantPosition>,
+    annotator: &MoveAnnotator,
+) {
+    if let Some(pos) = pos {
+        annotator.annotate(moves, pos.turn());
+    }
+}
+
+pub(crate) fn finalize_lichess_games(
+    games: Vec<(UciMove, GameId)>,
+    lichess_db: &LichessDatabase,
+    blacklist: &HashSet<UserId>,
+) -> Vec<ExplorerGameWithUciMove> {
+    lichess_db
+        .games(games.iter().map(|(_, id)| *id))
+        .expect("get games")
+        .into_iter()
+        .zip(games)
+        .filter_map(|(info, (uci, id))| {
+            info.filter(|info| {
+                info.players
+                    .iter()
+                    .filter_map(|player| player.name.parse::<UserName>().ok().map(UserId::from))
+                    .all(|player_id| !blacklist.contains(&player_id))
+            })
+            .map(|info| ExplorerGameWithUciMove {
+                uci,
+                row: ExplorerGame::from_lichess(id, info),
+            })
+        })
+        .collect()
+}
+
+/// Reads and annotates the response to a /masters query, also returning
+/// the queried position, if known. Must be called from a blocking context.
+pub(crate) fn masters_response(
+    db: &Database,
+    openings: &Openings,
+    era: &EraAdjustment,
+    annotator: &MoveAnnotator,
+    query: MastersQuery,
+) -> Result<(ExplorerResponse, Option<VariantPosition>), Error> {
+    let trace = Trace::current();
+    let QueriedPosition { key, pos, opening } = trace.stage("parse", || {
+        QueriedPosition::new(query.play, KeyBuilder::masters(), openings)
+    })?;
+    let cache_hint = pos
+        .as_ref()
+        .map_or(CacheHint::always(), |pos| CacheHint::from_ply(ply(pos)));
+    let masters_db = db.masters();
+    let (entry, truncated) = trace
+        .stage("scan", || {
+            masters_db.read(key, query.since, query.until, era, cache_hint)
+        })
+        .expect("get masters");
+    let games_started_at = Instant::now();
+    let entry = entry.prepare(&query.limits);
+    // The opponents of masters games are not recorded, so the
+    // performance is estimated from the era-adjusted ratings of
+    // the players making the move.
+    let turn = pos
+        .as_ref()
+        .filter(|_| era.is_enabled())
+        .map(|pos| pos.turn());
+    let mut san_context = pos.as_ref().map(SanContext::new);
+
+    let mut response = ExplorerResponse {
+        total: entry.total,
+        moves: entry
+            .moves
+            .into_iter()
+            .map(|p| {
+                let (san, opening) = san_and_opening(san_context.as_mut(), &p.uci, openings);
+                ExplorerMove {
+                    san,
+                    uci: p.uci,
+                    average_rating: p.average_rating,
+                    average_opponent_rating: p.average_opponent_rating,
+                    performance: turn.and_then(|turn| p.stats.performance(turn)),
+                    stats: p.stats,
+                    game: p.game.and_then(|id| {
+                        masters_db
+                            .game(id)
+                            .expect("get masters game")
+                            .map(|info| ExplorerGame::from_masters(id, info))
+                    }),
+                    opening,
+                    eval: None,
+                    tablebase: None,
+                    trend: p.trend,
+                    last_played: p.last_played,
+                    confidence: None,
+                    annotation: None,
+                    score_delta: None,
+                }
+            })
+            .collect(),
+        top_games: Some(
+            masters_db
+                .games(entry.top_games.iter().map(|(_, id)| *id))
+                .expect("get masters games")
+                .into_iter()
+                .zip(entry.top_games.into_iter())
+                .filter_map(|(info, (uci, id))| {
+                    info.map(|info| ExplorerGameWithUciMove {
+                        uci: uci.clone(),
+                        row: ExplorerGame::from_masters(id, info),
+                    })
+                })
+                .collect(),
+        ),
+        opening,
+        recent_games: None,
+        queue_position: None,
+        queue_eta: None,
+        history: None,
+        indexed_games: Some(masters_db.game_count().expect("get masters game count")),
+        unique_players: None,
+        months: None,
+        time_breakdown: None,
+        truncated,
+        truncated_at: None,
+    };
+    trace.record("games", games_started_at);
+    if query.confidence {
+        annotate_confidence(&mut response.moves, pos.as_ref());
+    }
+    if query.annotate {
+        annotate_moves(&mut response.moves, pos.as_ref(), annotator);
+    }
+    Ok((response, pos))
+}
+
+/// Reads and annotates the response to a /lichess query, also returning
+/// the queried position, if known. Must be called from a blocking context.
+pub(crate) fn lichess_response(
+    db: &Database,
+    openings: &Openings,
+    blacklist: &HashSet<UserId>,
+    annotator: &MoveAnnotator,
+    query: LichessQuery,
+) -> Result<(ExplorerResponse, Option<VariantPosition>), Error> {
+    let trace = Trace::current();
+    let variant = query.play.variant();
+    let QueriedPosition { key, pos, opening } = trace.stage("parse", || {
+        QueriedPosition::new(query.play, KeyBuilder::lichess(), openings)
+    })?;
+    let cache_hint = pos
+        .as_ref()
+        .map_or(CacheHint::always(), |pos| CacheHint::from_ply(ply(pos)));
+    let lichess_db = db.lichess();
+    let (mut filtered, history, months, truncated_at) = trace
+        .stage("scan", || {
+            lichess_db.read_lichess(
+                variant,
+                &key,
+                pos.as_ref().map_or(Color::White, |pos| pos.turn()),
+                &query.filter,
+                &query.limits,
+                query.history,
+                query.trend,
+                query.group_by,
+                cache_hint,
+            )
+        })
+        .expect("get lichess");
+    if pos.is_none() {
+        // Performance depends on the side to move, which is not
+        // known from the hash alone.
+        for m in &mut filtered.moves {
+            m.performance = None;
+        }
+    }
+
+    let games_started_at = Instant::now();
+    let mut response = ExplorerResponse {
+        total: filtered.total,
+        moves: finalize_lichess_moves(filtered.moves, pos.as_ref(), &lichess_db, openings),
+        recent_games: Some(finalize_lichess_games(
+            filtered.recent_games,
+            &lichess_db,
+            blacklist,
+        )),
+        top_games: Some(finalize_lichess_games(
+            filtered.top_games,
+            &lichess_db,
+            blacklist,
+        )),
+        opening,
+        history,
+        queue_position: None,
+        queue_eta: None,
+        indexed_games: Some(
+            lichess_db
+                .game_count(variant)
+                .expect("get lichess game count"),
+        ),
+        unique_players: filtered.unique_players,
+        months: months.map(|months| {
+            months
+                .into_iter()
+                .map(|(month, mut prepared)| {
+                    for m in &mut prepared.moves {
+                        // Summaries do not include games.
+                        m.game = None;
+                        if pos.is_none() {
+                            m.performance = None;
+                        }
+                    }
+                    MonthSummary {
+                        month,
+                        total: prepared.total,
+                        moves: finalize_lichess_moves(
+                            prepared.moves,
+                            pos.as_ref(),
+                            &lichess_db,
+                            openings,
+                        ),
+                    }
+                })
+                .collect()
+        }),
+        time_breakdown: None,
+        truncated: truncated_at.is_some(),
+        truncated_at,
+    };
+    trace.record("games", games_started_at);
+    if query.confidence {
+        annotate_confidence(&mut response.moves, pos.as_ref());
+    }
+    if query.annotate {
+        annotate_moves(&mut response.moves, pos.as_ref(), annotator);
+    }
+    Ok((response, pos))
+}
+
+/// Reads the games of a player that have been indexed so far. Must be
+/// called from a blocking context.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn player_response(
+    lichess_db: &LichessDatabase,
+    openings: &Openings,
+    key: &KeyPrefix,
+    pos: &VariantPosition,
+    opening: Option<Opening>,
+    color: Color,
+    filter: &PlayerQueryFilter,
+    limits: &PlayerLimits,
+) -> ExplorerResponse {
+    let entry = lichess_db
+        .read_player(
+            key,
+            filter.since,
+            filter.until,
+            CacheHint::from_ply(ply(pos)),
+        )
+        .expect("read player");
+    let time_breakdown = limits.time_breakdown.then(|| entry.time_breakdown(filter));
+    let filtered = entry.prepare(color, filter, limits);
+
+    ExplorerResponse {
+        total: filtered.total,
+        moves: finalize_lichess_moves(filtered.moves, Some(pos), lichess_db, openings),
+        recent_games: Some(finalize_lichess_games(
+            filtered.recent_games,
+            lichess_db,
+            &HashSet::new(),
+        )),
+        top_games: None,
+        history: None,
+        opening,
+        queue_position: None,
+        queue_eta: None,
+        indexed_games: None,
+        unique_players: None,
+        months: None,
+        time_breakdown,
+        truncated: false,
+        truncated_at: None,
+    }
+}
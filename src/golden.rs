@@ -0,0 +1,261 @@
+//! Golden response tests, enabled by the `golden-tests` feature. Imports the
+//! fixed set of games in `tests/golden` into a temporary database, and
+//! compares responses of `/masters`, `/lichess` and `/player` with the
+//! recorded JSON files next to them. After intended changes, record new
+//! responses with `UPDATE_GOLDEN=1 cargo test --features golden-tests`.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    sync::{atomic::AtomicU64, Arc},
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    body::{to_bytes, Body},
+    http::{header::CONTENT_TYPE, Method, Request, StatusCode},
+    Router,
+};
+use clap::Parser;
+use serde_json::Value;
+use tokio::{sync::Semaphore, task::JoinSet};
+use tower::ServiceExt as _;
+
+use crate::{
+    aliases::PlayerAliases,
+    api::LoadShedder,
+    cloud_eval::CloudEval,
+    db::Database,
+    indexer::{LichessImporter, MastersImporter, PlayerIndexerStub},
+    metrics::Metrics,
+    model::{PlayerStatus, UserId, UserName},
+    opening::OpeningsStatus,
+    query_log::QueryLog,
+    routes,
+    tablebase::Tablebase,
+    trace::TraceExporter,
+    AppState, Opt, ReloadableCache,
+};
+
+const FIXTURES: &str = "tests/golden";
+
+/// Golden responses compared by [`test_golden_responses`], all of which
+/// must be committed next to the fixtures.
+const GOLDEN: [&str; 7] = [
+    "masters_initial",
+    "masters_e4",
+    "lichess_initial",
+    "lichess_e4_blitz",
+    "lichess_history",
+    "player_white",
+    "player_black_e4",
+];
+
+/// Removes the temporary database when dropped.
+struct TempDb(PathBuf);
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn app(join_set: &mut JoinSet<()>, path: &Path) -> (Router, Arc<Database>) {
+    let opt = Opt::parse_from([
+        "lila-openingexplorer",
+        "--db",
+        path.to_str().expect("utf-8 path"),
+    ]);
+
+    let db = Arc::new(Database::open(opt.db).expect("db"));
+    let metrics: &'static Metrics = Box::leak(Box::default());
+    let player_aliases: &'static PlayerAliases = Box::leak(Box::default());
+    let semaphore: &'static Semaphore = Box::leak(Box::new(Semaphore::new(4)));
+    let state = AppState {
+        admin_tokens: Box::leak(Box::default()),
+        openings: Box::leak(Box::default()),
+        openings_status: Box::leak(Box::new(OpeningsStatus::new(&opt.openings))),
+        openings_opt: Box::leak(Box::new(opt.openings)),
+        readiness: Box::leak(Box::default()),
+        blacklist: Box::leak(Box::default()),
+        lichess_cache: Box::leak(Box::new(ReloadableCache::new(
+            0,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+        ))),
+        masters_cache: Box::leak(Box::new(ReloadableCache::new(
+            0,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+        ))),
+        response_cache_ttl: Box::leak(Box::new(AtomicU64::new(0))),
+        metrics,
+        load_shedder: Box::leak(Box::new(LoadShedder::new(None, metrics))),
+        cloud_eval: Box::leak(Box::new(CloudEval::new(opt.cloud_eval))),
+        era: Box::leak(Box::default()),
+        annotator: Box::leak(Box::default()),
+        player_aliases,
+        tablebase: Box::leak(Box::new(Tablebase::new(opt.tablebase))),
+        query_log: Box::leak(Box::new(QueryLog::new(opt.query_log).expect("query log"))),
+        hot_positions: Box::leak(Box::default()),
+        trace_exporter: Box::leak(Box::new(TraceExporter::new(opt.trace))),
+        lichess_importer: LichessImporter::new(Arc::clone(&db), opt.lichess_importer),
+        masters_importer: MastersImporter::new(
+            Arc::clone(&db),
+            opt.masters_importer,
+            player_aliases,
+        ),
+        player_indexer: PlayerIndexerStub::spawn(
+            join_set,
+            Arc::clone(&db),
+            opt.player_indexer,
+            opt.lila,
+        ),
+        db: Arc::clone(&db),
+        semaphore,
+    };
+    (routes(state, false), db)
+}
+
+async fn request(app: &Router, method: Method, uri: &str, body: Option<Value>) -> Vec<u8> {
+    let mut req = Request::builder().method(method).uri(uri);
+    if body.is_some() {
+        req = req.header(CONTENT_TYPE, "application/json");
+    }
+    let req = req
+        .body(body.map_or_else(Body::empty, |body| Body::from(body.to_string())))
+        .expect("request");
+    let res = app.clone().oneshot(req).await.expect("infallible");
+    assert_eq!(res.status(), StatusCode::OK, "{uri}");
+    to_bytes(res.into_body(), usize::MAX)
+        .await
+        .expect("body")
+        .to_vec()
+}
+
+fn fixture(name: &str) -> Value {
+    let path = Path::new(FIXTURES).join(name);
+    serde_json::from_slice(&fs::read(&path).expect("read fixture")).expect("parse fixture")
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    Path::new(FIXTURES).join(format!("{name}.golden.json"))
+}
+
+fn updating() -> bool {
+    env::var_os("UPDATE_GOLDEN").is_some()
+}
+
+fn assert_golden(name: &str, body: &[u8]) {
+    assert!(GOLDEN.contains(&name), "{name} is not listed in GOLDEN");
+    let actual: Value = serde_json::from_slice(body).expect("json response");
+    let path = golden_path(name);
+    if updating() {
+        let mut pretty = serde_json::to_string_pretty(&actual).expect("serialize");
+        pretty.push('\n');
+        fs::write(&path, pretty).expect("write golden response");
+        return;
+    }
+    let expected: Value = serde_json::from_slice(&fs::read(&path).expect("read golden response"))
+        .expect("parse golden response");
+    assert_eq!(actual, expected, "{name} differs from {}", path.display());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_golden_responses() {
+    // Fail before doing any work, and name every missing file, rather than
+    // stopping at the first one.
+    let missing: Vec<_> = GOLDEN
+        .iter()
+        .map(|name| golden_path(name))
+        .filter(|path| !path.exists())
+        .collect();
+    assert!(
+        updating() || missing.is_empty(),
+        "missing golden responses {missing:?}, record them with UPDATE_GOLDEN=1, review and commit"
+    );
+
+    let tmp = TempDb(env::temp_dir().join(format!("explorer-golden-{}", process::id())));
+    let _ = fs::remove_dir_all(&tmp.0);
+    let mut join_set = JoinSet::new();
+    let (app, db) = app(&mut join_set, &tmp.0);
+
+    for game in fixture("masters.json").as_array().expect("masters games") {
+        request(&app, Method::PUT, "/import/masters", Some(game.clone())).await;
+    }
+    let lichess = fixture("lichess.json");
+    request(&app, Method::PUT, "/import/lichess", Some(lichess.clone())).await;
+    request(
+        &app,
+        Method::PUT,
+        "/import/player?players=alice",
+        Some(lichess),
+    )
+    .await;
+
+    // Pretend alice was just indexed, so that /player does not try to
+    // fetch games from lila.
+    let now = SystemTime::now();
+    let alice = UserId::from("alice".parse::<UserName>().expect("user name"));
+    db.lichess()
+        .put_player_status(
+            &alice,
+            &PlayerStatus {
+                indexed_at: now,
+                revisited_at: now,
+                ..PlayerStatus::default()
+            },
+        )
+        .expect("put player status");
+
+    for (name, uri) in [
+        ("masters_initial", "/masters"),
+        ("masters_e4", "/masters?play=e2e4"),
+        ("lichess_initial", "/lichess"),
+        ("lichess_e4_blitz", "/lichess?play=e2e4&speeds=blitz"),
+        (
+            "lichess_history",
+            "/lichess?play=e2e4&history=true&until=2023-02",
+        ),
+    ] {
+        assert_golden(name, &request(&app, Method::GET, uri, None).await);
+    }
+
+    // Selected fields apply to each variant.
+    let variants: Value = serde_json::from_slice(
+        &request(
+            &app,
+            Method::GET,
+            "/lichess?variants=chess,atomic&fields=moves",
+            None,
+        )
+        .await,
+    )
+    .expect("json response");
+    for variant in ["chess", "atomic"] {
+        let response = variants[variant].as_object().expect("variant response");
+        assert!(response.contains_key("moves"), "{variant}");
+        for key in ["white", "topGames", "recentGames", "opening"] {
+            assert!(!response.contains_key(key), "{variant} has {key}");
+        }
+    }
+
+    for (name, uri) in [
+        ("player_white", "/player?player=alice&color=white"),
+        (
+            "player_black_e4",
+            "/player?player=alice&color=black&play=e2e4",
+        ),
+    ] {
+        let body = request(&app, Method::GET, uri, None).await;
+        let last_line = body
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .last()
+            .expect("ndjson line");
+        assert_golden(name, last_line);
+    }
+
+    join_set.abort_all();
+}
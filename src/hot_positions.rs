@@ -0,0 +1,281 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use shakmaty::{
+    fen::Fen,
+    variant::{Variant, VariantPosition},
+    EnPassantMode,
+};
+
+use crate::{
+    api::{HotPosition, HotWindow},
+    model::KeyPrefix,
+};
+
+/// Queries are counted in hourly slots.
+const SLOT_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// Slots covering the last day, including the current one.
+const SLOTS: usize = 24;
+
+const SKETCH_DEPTH: usize = 4;
+const SKETCH_WIDTH: usize = 1 << 13;
+
+/// Positions tracked per slot, as candidates for the most queried ones.
+const CANDIDATES: usize = 256;
+
+/// Approximate query counts of positions over the last hour and day, using
+/// a count-min sketch per hour. Only positions that were among the most
+/// queried within a slot can be listed.
+#[derive(Default)]
+pub struct HotPositions {
+    slots: Mutex<VecDeque<Slot>>,
+}
+
+struct Slot {
+    index: u64,
+    sketch: CountMinSketch,
+    candidates: HashMap<PositionId, Candidate>,
+    /// Smallest estimate among full candidates, if known.
+    min_candidate: Option<u32>,
+}
+
+/// Keys of masters and lichess positions are the same.
+type PositionId = (&'static str, [u8; KeyPrefix::SIZE]);
+
+struct Candidate {
+    variant: Variant,
+    fen: Option<String>,
+    estimate: u32,
+}
+
+impl HotPositions {
+    /// Count a query of the position with the given key. The position is
+    /// not known if the query named it only by its hash.
+    pub fn record(
+        &self,
+        endpoint: &'static str,
+        variant: Variant,
+        key: &KeyPrefix,
+        pos: Option<&VariantPosition>,
+    ) {
+        self.record_at(SystemTime::now(), endpoint, variant, key, pos);
+    }
+
+    fn record_at(
+        &self,
+        now: SystemTime,
+        endpoint: &'static str,
+        variant: Variant,
+        key: &KeyPrefix,
+        pos: Option<&VariantPosition>,
+    ) {
+        let id = (
+            endpoint,
+            key.as_bytes().try_into().expect("key prefix size"),
+        );
+        let (index, _) = slot_index(now);
+
+        let mut slots = self.slots.lock().expect("lock hot positions");
+        rotate(&mut slots, index);
+        let slot = slots.back_mut().expect("current slot");
+
+        let estimate = slot.sketch.add(&id);
+        if let Some(candidate) = slot.candidates.get_mut(&id) {
+            candidate.estimate = estimate;
+            return;
+        }
+        if slot.candidates.len() >= CANDIDATES {
+            if slot.min_candidate.is_some_and(|min| estimate <= min) {
+                return;
+            }
+            let min_key = *slot
+                .candidates
+                .iter()
+                .min_by_key(|(_, candidate)| candidate.estimate)
+                .map(|(key, _)| key)
+                .expect("candidates");
+            if slot.candidates[&min_key].estimate >= estimate {
+                slot.min_candidate = Some(slot.candidates[&min_key].estimate);
+                return;
+            }
+            slot.candidates.remove(&min_key);
+            slot.min_candidate = None;
+        }
+        // Only new candidates need the FEN.
+        slot.candidates.insert(
+            id,
+            Candidate {
+                variant,
+                fen: pos
+                    .map(|pos| Fen::from_position(pos.clone(), EnPassantMode::Legal).to_string()),
+                estimate,
+            },
+        );
+    }
+
+    /// The most queried positions in the window, with estimated counts.
+    pub fn top(&self, window: HotWindow, limit: usize) -> Vec<HotPosition> {
+        self.top_at(SystemTime::now(), window, limit)
+    }
+
+    fn top_at(&self, now: SystemTime, window: HotWindow, limit: usize) -> Vec<HotPosition> {
+        let mut slots = self.slots.lock().expect("lock hot positions");
+        let (index, elapsed) = slot_index(now);
+        rotate(&mut slots, index);
+
+        // Weights of the slots in the window. The last hour includes the
+        // part of the previous slot that has not passed yet.
+        let weights: Vec<(&Slot, f64)> = slots
+            .iter()
+            .filter_map(|slot| {
+                let age = index.saturating_sub(slot.index);
+                let weight = match window {
+                    HotWindow::Hour => match age {
+                        0 => 1.0,
+                        1 => 1.0 - elapsed,
+                        _ => 0.0,
+                    },
+                    HotWindow::Day => 1.0,
+                };
+                (weight > 0.0).then_some((slot, weight))
+            })
+            .collect();
+
+        let mut positions: HashMap<PositionId, HotPosition> = HashMap::new();
+        for (slot, _) in &weights {
+            for (id, candidate) in &slot.candidates {
+                positions.entry(*id).or_insert_with(|| HotPosition {
+                    endpoint: id.0,
+                    variant: candidate.variant.uci(),
+                    fen: candidate.fen.clone(),
+                    count: 0,
+                });
+            }
+        }
+
+        let mut positions: Vec<HotPosition> = positions
+            .into_iter()
+            .map(|(id, mut position)| {
+                position.count = weights
+                    .iter()
+                    .map(|(slot, weight)| f64::from(slot.sketch.estimate(&id)) * weight)
+                    .sum::<f64>()
+                    .round() as u64;
+                position
+            })
+            .filter(|position| position.count > 0)
+            .collect();
+        positions.sort_by(|a, b| b.count.cmp(&a.count));
+        positions.truncate(limit);
+        positions
+    }
+}
+
+/// Index of the slot, and the fraction of it that has passed.
+fn slot_index(now: SystemTime) -> (u64, f64) {
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let index = since_epoch.as_secs() / SLOT_DURATION.as_secs();
+    let elapsed = (since_epoch.as_secs_f64() - (index * SLOT_DURATION.as_secs()) as f64)
+        / SLOT_DURATION.as_secs_f64();
+    (index, elapsed)
+}
+
+/// Make the slot with the given index the current one, dropping slots that
+/// are older than a day.
+fn rotate(slots: &mut VecDeque<Slot>, index: u64) {
+    if slots.back().map_or(true, |slot| slot.index < index) {
+        slots.push_back(Slot {
+            index,
+            sketch: CountMinSketch::default(),
+            candidates: HashMap::new(),
+            min_candidate: None,
+        });
+    }
+    while slots
+        .front()
+        .is_some_and(|slot| slot.index + SLOTS as u64 <= index)
+    {
+        slots.pop_front();
+    }
+}
+
+struct CountMinSketch {
+    counters: Box<[[u32; SKETCH_WIDTH]; SKETCH_DEPTH]>,
+}
+
+impl Default for CountMinSketch {
+    fn default() -> CountMinSketch {
+        CountMinSketch {
+            counters: vec![[0; SKETCH_WIDTH]; SKETCH_DEPTH]
+                .into_boxed_slice()
+                .try_into()
+                .expect("sketch depth"),
+        }
+    }
+}
+
+impl CountMinSketch {
+    fn column(row: usize, id: &PositionId) -> usize {
+        let mut hasher = DefaultHasher::new();
+        row.hash(&mut hasher);
+        id.hash(&mut hasher);
+        hasher.finish() as usize % SKETCH_WIDTH
+    }
+
+    /// Count the key, returning the new estimate.
+    fn add(&mut self, id: &PositionId) -> u32 {
+        let mut estimate = u32::MAX;
+        for (row, counters) in self.counters.iter_mut().enumerate() {
+            let counter = &mut counters[CountMinSketch::column(row, id)];
+            *counter = counter.saturating_add(1);
+            estimate = estimate.min(*counter);
+        }
+        estimate
+    }
+
+    fn estimate(&self, id: &PositionId) -> u32 {
+        self.counters
+            .iter()
+            .enumerate()
+            .map(|(row, counters)| counters[CountMinSketch::column(row, id)])
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::KeyBuilder;
+
+    fn key(n: u64) -> KeyPrefix {
+        KeyBuilder::lichess().with_zobrist(Variant::Chess, u128::from(n).into())
+    }
+
+    #[test]
+    fn test_hot_positions() {
+        let hot = HotPositions::default();
+        let start = UNIX_EPOCH + Duration::from_secs(1_000 * 60 * 60);
+        for n in 0..10 {
+            for _ in 0..=n {
+                hot.record_at(start, "lichess", Variant::Chess, &key(n), None);
+            }
+        }
+
+        let top = hot.top_at(start, HotWindow::Hour, 3);
+        assert_eq!(top.iter().map(|p| p.count).collect::<Vec<_>>(), [10, 9, 8]);
+
+        // Half of the previous hour still counts.
+        let later = start + SLOT_DURATION + SLOT_DURATION / 2;
+        assert_eq!(hot.top_at(later, HotWindow::Hour, 1)[0].count, 5);
+        assert_eq!(hot.top_at(later, HotWindow::Day, 1)[0].count, 10);
+
+        let next_day = start + SLOT_DURATION * SLOTS as u32;
+        assert!(hot.top_at(next_day, HotWindow::Day, 1).is_empty());
+    }
+}
@@ -0,0 +1,213 @@
+use bytes::{Buf, BufMut};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use shakmaty::Color;
+
+use crate::{
+    db::LichessBatch,
+    model::{
+        ensure_remaining, try_read_uint, write_uint, GameId, Key, LichessGame, PlayerEntry,
+        ReadError,
+    },
+};
+
+/// Indexing run handed out to a remote worker.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Lease {
+    pub id: u64,
+    pub player: String,
+    pub since: u64,
+}
+
+/// Records for a single game, indexed from the side of one player.
+pub struct IndexedGame {
+    pub id: GameId,
+    pub info: LichessGame,
+    pub entries: Vec<(Key, PlayerEntry)>,
+}
+
+impl IndexedGame {
+    pub fn color(&self) -> Color {
+        if self.info.indexed_player.white {
+            Color::White
+        } else {
+            Color::Black
+        }
+    }
+
+    pub fn merge_into(self, batch: &mut LichessBatch<'_>) {
+        batch.merge_game(self.id, self.info);
+        for (key, entry) in self.entries {
+            batch.merge_player(key, entry);
+        }
+    }
+
+    fn write<B: BufMut>(&self, buf: &mut B) {
+        self.id.write(buf);
+        self.info.write(buf);
+        write_uint(buf, self.entries.len() as u64);
+        for (key, entry) in &self.entries {
+            buf.put_slice(&key.clone().into_bytes());
+            let mut entry_buf = Vec::with_capacity(PlayerEntry::SIZE_HINT);
+            entry.write(&mut entry_buf);
+            write_uint(buf, entry_buf.len() as u64);
+            buf.put_slice(&entry_buf);
+        }
+    }
+
+    fn read<B: Buf>(buf: &mut B) -> Result<IndexedGame, ReadError> {
+        let id = GameId::read(buf)?;
+        let info = LichessGame::try_read(buf)?;
+        let num_entries = try_read_uint(buf)?;
+        let mut entries = Vec::new();
+        for _ in 0..num_entries {
+            ensure_remaining(buf, Key::SIZE)?;
+            let mut key = [0; Key::SIZE];
+            buf.copy_to_slice(&mut key);
+            let len = usize::try_from(try_read_uint(buf)?)
+                .map_err(|_| ReadError::Invalid("player entry len"))?;
+            ensure_remaining(buf, len)?;
+            let mut entry = PlayerEntry::default();
+            entry.extend_from_reader(&mut buf.copy_to_bytes(len))?;
+            entries.push((Key::try_from(&key[..]).expect("key size"), entry));
+        }
+        Ok(IndexedGame { id, info, entries })
+    }
+}
+
+/// Results of a remote worker, posted back to the coordinator.
+#[derive(Default)]
+pub struct LeaseBatch {
+    pub latest_created_at: u64,
+    pub revisit_ongoing_created_at: Option<u64>,
+    pub games: Vec<IndexedGame>,
+}
+
+impl LeaseBatch {
+    pub fn write<B: BufMut>(&self, buf: &mut B) {
+        write_uint(buf, self.latest_created_at);
+        write_uint(buf, self.revisit_ongoing_created_at.unwrap_or(0));
+        for game in &self.games {
+            game.write(buf);
+        }
+    }
+
+    pub fn read<B: Buf>(buf: &mut B) -> Result<LeaseBatch, ReadError> {
+        let latest_created_at = try_read_uint(buf)?;
+        let revisit_ongoing_created_at = Some(try_read_uint(buf)?).filter(|t| *t != 0);
+        let mut games = Vec::new();
+        while buf.has_remaining() {
+            games.push(IndexedGame::read(buf)?);
+        }
+        Ok(LeaseBatch {
+            latest_created_at,
+            revisit_ongoing_created_at,
+            games,
+        })
+    }
+}
+
+/// Client used by remote workers to talk to the coordinator.
+pub struct CoordinatorClient {
+    client: reqwest::Client,
+    url: String,
+    token: Option<String>,
+}
+
+impl CoordinatorClient {
+    pub fn new(url: String, token: Option<String>) -> CoordinatorClient {
+        CoordinatorClient {
+            client: reqwest::Client::builder()
+                .user_agent("lila-openingexplorer")
+                .build()
+                .expect("reqwest client"),
+            url,
+            token,
+        }
+    }
+
+    pub async fn lease(&self) -> Result<Option<Lease>, reqwest::Error> {
+        let mut builder = self.client.get(format!("{}/admin/indexer/lease", self.url));
+
+        if let Some(ref token) = self.token {
+            builder = builder.bearer_auth(token);
+        }
+
+        let res = builder.send().await?.error_for_status()?;
+        if res.status() == StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+        res.json().await.map(Some)
+    }
+
+    pub async fn submit(
+        &self,
+        id: u64,
+        batch: &LeaseBatch,
+        done: bool,
+    ) -> Result<(), reqwest::Error> {
+        let mut body = Vec::new();
+        batch.write(&mut body);
+
+        let mut builder = self
+            .client
+            .post(format!("{}/admin/indexer/lease/{}", self.url, id))
+            .query(&[("done", done)])
+            .body(body);
+
+        if let Some(ref token) = self.token {
+            builder = builder.bearer_auth(token);
+        }
+
+        builder.send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shakmaty::{ByColor, Outcome};
+
+    use super::*;
+    use crate::model::{GamePlayer, Mode, Month, Speed};
+
+    #[test]
+    fn test_lease_batch_malformed() {
+        let batch = LeaseBatch {
+            latest_created_at: 1234,
+            revisit_ongoing_created_at: None,
+            games: vec![IndexedGame {
+                id: "1C0RyMje".parse().unwrap(),
+                info: LichessGame {
+                    outcome: Outcome::Draw,
+                    speed: Speed::Blitz,
+                    mode: Mode::Rated,
+                    players: ByColor::new_with(|_| GamePlayer {
+                        name: "revoof".to_owned(),
+                        rating: 1500,
+                    }),
+                    month: Month::min_value(),
+                    indexed_player: ByColor {
+                        white: true,
+                        black: false,
+                    },
+                    indexed_lichess: false,
+                },
+                entries: Vec::new(),
+            }],
+        };
+        let mut buf = Vec::new();
+        batch.write(&mut buf);
+
+        let read = LeaseBatch::read(&mut &buf[..]).expect("roundtrip");
+        assert_eq!(read.latest_created_at, 1234);
+        assert_eq!(read.games.len(), 1);
+
+        // Truncated bodies are rejected instead of panicking, unless they
+        // happen to end right after the header.
+        let header_len = 3;
+        for len in (0..buf.len()).filter(|len| *len != header_len) {
+            assert!(LeaseBatch::read(&mut &buf[..len]).is_err());
+        }
+    }
+}
@@ -1,8 +1,14 @@
 use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
+    cmp::min,
+    collections::{BTreeMap, HashMap, HashSet},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
 };
 
+use clap::Parser;
 use nohash_hasher::IntMap;
 use serde::Deserialize;
 use serde_with::{
@@ -16,11 +22,16 @@ use shakmaty::{
     zobrist::ZobristHash,
     ByColor, CastlingMode, Color, EnPassantMode, Outcome, Position,
 };
+use thiserror::Error;
 
 use crate::{
     api::Error,
-    db::Database,
-    model::{GameId, GamePlayer, KeyBuilder, LaxDate, LichessEntry, LichessGame, Mode, Speed},
+    db::{Database, LichessBatch, MonthDeletion, VariantSplit},
+    model::{
+        GameId, GamePlayer, GameSource, ImportStatus, Key, KeyBuilder, LaxDate, LichessEntry,
+        LichessGame, LichessGameMoves, Mode, Month, PlayerEntry, PlayersSketch, Speed, UserId,
+        UserName,
+    },
     util::ByColorDef,
     zobrist::StableZobrist128,
 };
@@ -33,6 +44,8 @@ pub struct LichessGameImport {
     #[serde_as(as = "DefaultOnNull<DisplayFromStr>")]
     variant: Variant,
     speed: Speed,
+    #[serde(default)]
+    mode: Option<Mode>,
     #[serde_as(as = "Option<DisplayFromStr>")]
     fen: Option<Fen>,
     #[serde_as(as = "DisplayFromStr")]
@@ -45,93 +58,348 @@ pub struct LichessGameImport {
     winner: Option<Color>,
     #[serde_as(as = "StringWithSeparator<SpaceSeparator, San>")]
     moves: Vec<San>,
+    #[serde(default)]
+    source: Option<GameSource>,
+}
+
+impl LichessGameImport {
+    fn month(&self) -> Result<Month, Error> {
+        self.date.month().ok_or_else(|| {
+            log::error!("lichess game {} missing month", self.id);
+            Error::RejectedDate {
+                id: self.id,
+                date: self.date,
+            }
+        })
+    }
+}
+
+#[derive(Parser, Clone)]
+pub struct LichessImporterOpt {
+    /// Sampling rule for /import/lichess, in the form
+    /// `<speed>:<min-average-rating>:<percent>`, where speed may be `*` to
+    /// match all speeds. Each game is accepted with the percentage of the
+    /// matching rule with the highest minimum rating, preferring rules for
+    /// a specific speed. Games not matched by any rule are always accepted.
+    /// May be repeated.
+    #[arg(long = "import-sampling", value_delimiter = ',')]
+    sampling: Vec<SamplingRule>,
+    /// Record a sketch of the players of games imported via /import/lichess,
+    /// to estimate the number of distinct players who reached a position
+    /// (`uniquePlayers`). Adds a few bytes to each position of each game,
+    /// and up to 1 KiB to each position and month.
+    #[arg(long = "import-unique-players")]
+    unique_players: bool,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SamplingRule {
+    speed: Option<Speed>,
+    min_rating: u16,
+    percent: u8,
+}
+
+#[derive(Error, Debug)]
+#[error("invalid sampling rule, expected <speed>:<min-average-rating>:<percent>")]
+pub struct InvalidSamplingRule;
+
+impl FromStr for SamplingRule {
+    type Err = InvalidSamplingRule;
+
+    fn from_str(s: &str) -> Result<SamplingRule, InvalidSamplingRule> {
+        let mut parts = s.split(':');
+        let (Some(speed), Some(min_rating), Some(percent), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(InvalidSamplingRule);
+        };
+        let percent = percent.parse().map_err(|_| InvalidSamplingRule)?;
+        if percent > 100 {
+            return Err(InvalidSamplingRule);
+        }
+        Ok(SamplingRule {
+            speed: match speed {
+                "*" => None,
+                speed => Some(speed.parse().map_err(|_| InvalidSamplingRule)?),
+            },
+            min_rating: min_rating.parse().map_err(|_| InvalidSamplingRule)?,
+            percent,
+        })
+    }
+}
+
+fn sampling_percent(rules: &[SamplingRule], speed: Speed, average_rating: u16) -> u8 {
+    rules
+        .iter()
+        .filter(|rule| rule.speed.map_or(true, |s| s == speed) && rule.min_rating <= average_rating)
+        .max_by_key(|rule| (rule.min_rating, rule.speed.is_some()))
+        .map_or(100, |rule| rule.percent)
 }
 
 #[derive(Clone)]
 pub struct LichessImporter {
     db: Arc<Database>,
     mutex: Arc<Mutex<()>>,
+    sampling: Arc<[SamplingRule]>,
+    sampled_out: Arc<AtomicU64>,
+    unique_players: bool,
 }
 
 impl LichessImporter {
-    pub fn new(db: Arc<Database>) -> LichessImporter {
+    pub fn new(db: Arc<Database>, opt: LichessImporterOpt) -> LichessImporter {
         LichessImporter {
             db,
             mutex: Arc::new(Mutex::new(())),
+            sampling: opt.sampling.into(),
+            sampled_out: Arc::default(),
+            unique_players: opt.unique_players,
         }
     }
 
+    /// Number of games rejected by the sampling policy since startup.
+    pub fn num_sampled_out(&self) -> u64 {
+        self.sampled_out.load(Ordering::Relaxed)
+    }
+
     pub fn import_many(&self, games: Vec<LichessGameImport>) -> Result<(), Error> {
+        let mut statuses: BTreeMap<Month, ImportStatus> = BTreeMap::new();
+
+        let result = games.into_iter().try_for_each(|game| {
+            let month = game.date.month();
+            let accepted = if self.accepts(&game) {
+                self.import(game)?
+            } else {
+                self.sampled_out.fetch_add(1, Ordering::Relaxed);
+                false
+            };
+            if let Some(month) = month {
+                let status = statuses.entry(month).or_default();
+                if accepted {
+                    status.accepted += 1;
+                } else {
+                    status.skipped += 1;
+                }
+            }
+            Ok(())
+        });
+
+        // Record progress even if the batch was aborted halfway. Cached
+        // responses are not invalidated by imports, which arrive
+        // continuously. They expire after their time to live instead.
+        let lichess_db = self.db.lichess();
+        for (month, status) in statuses {
+            lichess_db
+                .merge_import_status(month, &status)
+                .expect("merge import status");
+        }
+
+        result
+    }
+
+    /// Delete everything imported for `month`, so that it can be imported
+    /// again from scratch.
+    pub fn delete_month(&self, month: Month) -> MonthDeletion {
+        let _guard = self.mutex.lock().expect("lock lichess db");
+        log::warn!("deleting lichess games of {month} ...");
+        let deletion = self
+            .db
+            .lichess()
+            .delete_month(month)
+            .expect("delete lichess month");
+        self.invalidate_response_cache();
+        log::warn!(
+            "deleted {} entries of {} and reset {} games",
+            deletion.entries,
+            month,
+            deletion.games
+        );
+        deletion
+    }
+
+    /// Delete all entries of a variant other than standard chess, that
+    /// have been imported since variants are stored separately.
+    pub fn delete_variant(&self, variant: Variant) -> bool {
+        let _guard = self.mutex.lock().expect("lock lichess db");
+        log::warn!("deleting lichess entries of {} ...", variant.uci());
+        let deleted = self
+            .db
+            .lichess()
+            .delete_variant(variant)
+            .expect("delete lichess variant");
+        if deleted {
+            self.invalidate_response_cache();
+            log::warn!("deleted lichess entries of {}", variant.uci());
+        }
+        deleted
+    }
+
+    /// Move entries of variants that were imported before variants were
+    /// stored separately into their own column families, by replaying the
+    /// stored moves of their games.
+    pub fn split_variants(&self) -> VariantSplit {
+        let _guard = self.mutex.lock().expect("lock lichess db");
+        log::warn!("splitting lichess entries by variant ...");
+        let split = self
+            .db
+            .lichess()
+            .split_variants(stored_game_keys)
+            .expect("split lichess variants");
+        log::warn!(
+            "moved {} entries of {} games into variant column families, {} games failed",
+            split.entries,
+            split.games,
+            split.failed
+        );
+        split
+    }
+
+    /// Delete everything imported for months before `cutoff`, and games
+    /// that are no longer referenced.
+    pub fn delete_months_before(&self, cutoff: Month) -> MonthDeletion {
+        let _guard = self.mutex.lock().expect("lock lichess db");
+        log::warn!("deleting lichess games before {cutoff} ...");
+        let deletion = self
+            .db
+            .lichess()
+            .delete_months_before(cutoff)
+            .expect("delete lichess months");
+        self.invalidate_response_cache();
+        log::warn!(
+            "deleted {} entries before {}, reset {} games and pruned {} of them",
+            deletion.entries,
+            cutoff,
+            deletion.games,
+            deletion.pruned
+        );
+        deletion
+    }
+
+    fn invalidate_response_cache(&self) {
+        self.db
+            .response_cache()
+            .invalidate("lichess")
+            .expect("invalidate lichess response cache");
+    }
+
+    /// Mark all games of `month` as imported.
+    pub fn complete_month(&self, month: Month) {
+        self.db
+            .lichess()
+            .merge_import_status(
+                month,
+                &ImportStatus {
+                    completed: true,
+                    ..ImportStatus::default()
+                },
+            )
+            .expect("merge import status");
+    }
+
+    fn accepts(&self, game: &LichessGameImport) -> bool {
+        let average_rating =
+            (u32::from(game.players.white.rating) + u32::from(game.players.black.rating)) / 2;
+        let percent = sampling_percent(&self.sampling, game.speed, average_rating as u16);
+        // Decide by game id rather than randomly, so that repeated imports
+        // of the same games sample consistently.
+        let mut id = [0; 8];
+        id[..GameId::SIZE].copy_from_slice(&game.id.to_bytes());
+        u64::from_le_bytes(id) % 100 < u64::from(percent)
+    }
+
+    /// Index games into the player column family for those of `players` who
+    /// played in them, bypassing the lila API. Player statuses are not
+    /// touched, so a later regular indexing run skips these games.
+    pub fn import_players_many(
+        &self,
+        games: Vec<LichessGameImport>,
+        players: &HashSet<UserId>,
+    ) -> Result<(), Error> {
         for game in games {
-            self.import(game)?;
+            self.import_players(game, players)?;
         }
         Ok(())
     }
 
-    fn import(&self, game: LichessGameImport) -> Result<(), Error> {
+    /// Returns whether the game was newly imported. Also indexes the game
+    /// for participants who have been indexed before, so that their
+    /// personal explorers include it without waiting for the next index
+    /// run, which then skips it.
+    fn import(&self, game: LichessGameImport) -> Result<bool, Error> {
         let _guard = self.mutex.lock().expect("lock lichess db");
+        let _player_games = self.db.lock_player_games();
 
         let lichess_db = self.db.lichess();
-        if lichess_db
-            .game(game.id)
-            .expect("get game info")
-            .map_or(false, |info| info.indexed_lichess)
-        {
+        let info = lichess_db.game(game.id).expect("get game info");
+        if info.as_ref().map_or(false, |info| info.indexed_lichess) {
             log::debug!("lichess game {} already imported", game.id);
-            return Ok(());
+            return Ok(false);
         }
 
-        let month = match game.date.month() {
-            Some(month) => month,
-            None => {
-                log::error!("lichess game {} missing month", game.id);
-                return Err(Error::RejectedDate {
-                    id: game.id,
-                    date: game.date,
-                });
-            }
-        };
+        let month = game.month()?;
         let outcome = Outcome::from_winner(game.winner);
-
-        let mut pos = match game.fen {
-            Some(fen) => {
-                VariantPosition::from_setup(game.variant, fen.into_setup(), CastlingMode::Chess960)?
-            }
-            None => VariantPosition::new(game.variant),
-        };
-
-        let mut without_loops: IntMap<StableZobrist128, (UciMove, Color)> =
-            HashMap::with_capacity_and_hasher(game.moves.len(), Default::default());
-        for san in game.moves.into_iter().take(MAX_PLIES) {
-            let m = san.to_move(&pos)?;
-            without_loops.insert(
-                pos.zobrist_hash(EnPassantMode::Legal),
-                (UciMove::from_chess960(&m), pos.turn()),
-            );
-            pos.play_unchecked(&m);
-        }
+        let mode = game.mode.unwrap_or(Mode::Rated);
+        let (without_loops, line) = without_loops(game.variant, game.fen.as_ref(), &game.moves)?;
+        // Participants who have been indexed before, unless the player
+        // indexer already wrote the game for them.
+        let users = ByColor::new_with(|color| {
+            game.players
+                .get(color)
+                .name
+                .parse::<UserName>()
+                .ok()
+                .map(UserId::from)
+                .filter(|_| {
+                    !info
+                        .as_ref()
+                        .map_or(false, |info| *info.indexed_player.get(color))
+                })
+        });
+        let indexed = lichess_db
+            .players_indexed(&users)
+            .expect("get player statuses");
+        let users = ByColor::new_with(|color| {
+            users
+                .get(color)
+                .as_ref()
+                .filter(|_| *indexed.get(color))
+                .cloned()
+        });
+        let players = self.unique_players.then(|| {
+            let mut players = PlayersSketch::default();
+            players.insert(&game.players.white.name);
+            players.insert(&game.players.black.name);
+            players
+        });
 
         let mut batch = lichess_db.batch();
-        for (key, (uci, turn)) in without_loops {
+        for (key, (uci, turn, ply)) in without_loops {
+            let mut entry = LichessEntry::new_single(
+                uci,
+                game.speed,
+                mode,
+                ply,
+                game.id,
+                outcome,
+                game.players.get(turn).rating,
+                game.players.get(!turn).rating,
+            );
+            if let Some(ref players) = players {
+                entry = entry.with_players(players.clone());
+            }
             batch.merge_lichess(
+                game.variant,
                 KeyBuilder::lichess()
                     .with_zobrist(game.variant, key)
                     .with_month(month),
-                LichessEntry::new_single(
-                    uci,
-                    game.speed,
-                    game.id,
-                    outcome,
-                    game.players.get(turn).rating,
-                    game.players.get(!turn).rating,
-                ),
+                entry,
             );
         }
+        merge_players(&mut batch, &game, &users, month, &without_loops);
         batch.merge_game(
             game.id,
             LichessGame {
-                mode: Mode::Rated,
-                indexed_player: Default::default(),
+                mode,
+                indexed_player: users.map(|user| user.is_some()),
                 indexed_lichess: true,
                 outcome,
                 players: game.players,
@@ -139,8 +407,259 @@ impl LichessImporter {
                 speed: game.speed,
             },
         );
+        batch.put_game_moves(
+            game.id,
+            &LichessGameMoves {
+                variant: game.variant,
+                fen: game.fen,
+                moves: line,
+            },
+        );
+        batch.inc_game_count(game.variant, month);
 
         batch.commit().expect("commit lichess game");
+        Ok(true)
+    }
+
+    fn import_players(
+        &self,
+        game: LichessGameImport,
+        players: &HashSet<UserId>,
+    ) -> Result<(), Error> {
+        let _guard = self.mutex.lock().expect("lock lichess db");
+        let _player_games = self.db.lock_player_games();
+
+        let lichess_db = self.db.lichess();
+        let info = lichess_db.game(game.id).expect("get game info");
+        let users = ByColor::new_with(|color| {
+            game.players
+                .get(color)
+                .name
+                .parse::<UserName>()
+                .ok()
+                .map(UserId::from)
+                .filter(|user| players.contains(user))
+                .filter(|_| {
+                    !info
+                        .as_ref()
+                        .map_or(false, |info| *info.indexed_player.get(color))
+                })
+        });
+        if users.iter().all(Option::is_none) {
+            log::debug!("lichess game {} already indexed for players", game.id);
+            return Ok(());
+        }
+
+        let month = game.month()?;
+        let outcome = Outcome::from_winner(game.winner);
+        let mode = game.mode.unwrap_or(Mode::Rated);
+        let (without_loops, _) = without_loops(game.variant, game.fen.as_ref(), &game.moves)?;
+
+        let mut batch = lichess_db.batch();
+        merge_players(&mut batch, &game, &users, month, &without_loops);
+        batch.merge_game(
+            game.id,
+            LichessGame {
+                mode,
+                indexed_player: users.map(|user| user.is_some()),
+                indexed_lichess: false,
+                outcome,
+                players: game.players,
+                month,
+                speed: game.speed,
+            },
+        );
+
+        batch.commit().expect("commit player games");
         Ok(())
     }
 }
+
+type WithoutLoops = IntMap<StableZobrist128, (UciMove, Color, u8)>;
+
+/// Merge entries of the game into the player column family, for each
+/// participant in `users`.
+fn merge_players(
+    batch: &mut LichessBatch<'_>,
+    game: &LichessGameImport,
+    users: &ByColor<Option<UserId>>,
+    month: Month,
+    without_loops: &WithoutLoops,
+) {
+    let outcome = Outcome::from_winner(game.winner);
+    let mode = game.mode.unwrap_or(Mode::Rated);
+    for color in Color::ALL {
+        if let Some(user) = users.get(color) {
+            let key = KeyBuilder::player(user, color);
+            for (zobrist, (uci, _, _)) in without_loops {
+                batch.merge_player(
+                    key.with_zobrist(game.variant, *zobrist).with_month(month),
+                    PlayerEntry::new_single(
+                        uci.clone(),
+                        game.speed,
+                        mode,
+                        game.source,
+                        None,
+                        game.id,
+                        outcome,
+                        Some(game.players.get(color).rating),
+                        game.players.get(!color).rating,
+                        None,
+                    ),
+                );
+            }
+        }
+    }
+}
+
+/// Replay the game, returning the last move played from each position,
+/// and the truncated line.
+fn without_loops(
+    variant: Variant,
+    fen: Option<&Fen>,
+    moves: &[San],
+) -> Result<(WithoutLoops, Vec<UciMove>), Error> {
+    let mut pos = match fen {
+        Some(fen) => {
+            VariantPosition::from_setup(variant, fen.as_setup().to_owned(), CastlingMode::Chess960)?
+        }
+        None => VariantPosition::new(variant),
+    };
+
+    let mut without_loops: WithoutLoops =
+        HashMap::with_capacity_and_hasher(moves.len(), Default::default());
+    let mut line = Vec::with_capacity(min(moves.len(), MAX_PLIES));
+    for (ply, san) in moves.iter().take(MAX_PLIES).enumerate() {
+        let m = san.to_move(&pos)?;
+        let uci = UciMove::from_chess960(&m);
+        line.push(uci.clone());
+        without_loops.insert(
+            pos.zobrist_hash(EnPassantMode::Legal),
+            (uci, pos.turn(), ply as u8),
+        );
+        pos.play_unchecked(&m);
+    }
+    Ok((without_loops, line))
+}
+
+/// Keys of the entries written by [`LichessImporter::import()`] for a game,
+/// replayed from its stored moves.
+fn stored_game_keys(moves: &LichessGameMoves, month: Month) -> Option<Vec<Key>> {
+    let mut pos = match moves.fen {
+        Some(ref fen) => VariantPosition::from_setup(
+            moves.variant,
+            fen.as_setup().to_owned(),
+            CastlingMode::Chess960,
+        )
+        .ok()?,
+        None => VariantPosition::new(moves.variant),
+    };
+    let mut keys = Vec::with_capacity(moves.moves.len());
+    for uci in &moves.moves {
+        let m = uci.to_move(&pos).ok()?;
+        keys.push(
+            KeyBuilder::lichess()
+                .with_zobrist(moves.variant, pos.zobrist_hash(EnPassantMode::Legal))
+                .with_month(month),
+        );
+        pos.play_unchecked(&m);
+    }
+    Some(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::{query_from_json, PlayerQueryFilter},
+        db::TempDb,
+        model::PlayerStatus,
+    };
+
+    #[test]
+    fn test_import_indexes_known_players() {
+        let tmp = TempDb::new("import-players");
+        let db = Arc::new(tmp.open(&[]));
+        let importer = LichessImporter::new(
+            Arc::clone(&db),
+            LichessImporterOpt::parse_from(["lila-openingexplorer"]),
+        );
+
+        let alice = UserId::from("alice".parse::<UserName>().unwrap());
+        let bob = UserId::from("bob".parse::<UserName>().unwrap());
+        db.lichess()
+            .put_player_status(&alice, &PlayerStatus::default())
+            .unwrap();
+
+        let game = || -> LichessGameImport {
+            serde_json::from_str(
+                r#"{
+                    "variant": "chess",
+                    "speed": "blitz",
+                    "id": "AAAAAAAA",
+                    "date": "2023.01.15",
+                    "white": { "name": "alice", "rating": 1800 },
+                    "black": { "name": "bob", "rating": 1750 },
+                    "winner": "white",
+                    "moves": "e4 e5 Nf3",
+                    "source": "arena"
+                }"#,
+            )
+            .unwrap()
+        };
+        let games = |user: &UserId, color: Color, filter: &str| {
+            let filter: PlayerQueryFilter = query_from_json(filter).unwrap();
+            let key = KeyBuilder::player(user, color).with_zobrist(
+                Variant::Chess,
+                VariantPosition::new(Variant::Chess).zobrist_hash(EnPassantMode::Legal),
+            );
+            db.lichess()
+                .player_games(&key, &filter, None, 10)
+                .unwrap()
+                .0
+                .into_iter()
+                .map(|(_, _, id)| id)
+                .collect::<Vec<_>>()
+        };
+        let id: GameId = "AAAAAAAA".parse().unwrap();
+
+        // Indexed only for the participant who was indexed before, with the
+        // source of the game.
+        importer.import_many(vec![game()]).unwrap();
+        assert_eq!(games(&alice, Color::White, "{}"), vec![id]);
+        assert_eq!(
+            games(&alice, Color::White, r#"{"sources": "arena"}"#),
+            vec![id]
+        );
+        assert!(games(&bob, Color::Black, "{}").is_empty());
+        let info = db.lichess().game(id).unwrap().unwrap();
+        assert!(info.indexed_lichess);
+        assert!(info.indexed_player.white);
+        assert!(!info.indexed_player.black);
+
+        // Not counted again.
+        importer.import_many(vec![game()]).unwrap();
+        importer
+            .import_players_many(vec![game()], &HashSet::from([alice.clone()]))
+            .unwrap();
+        assert_eq!(games(&alice, Color::White, "{}"), vec![id]);
+    }
+
+    #[test]
+    fn test_sampling_percent() {
+        let rules: Vec<SamplingRule> = ["rapid:0:20", "*:0:10", "*:2000:50", "classical:1800:100"]
+            .into_iter()
+            .map(|rule| rule.parse().unwrap())
+            .collect();
+        assert_eq!(sampling_percent(&rules, Speed::Blitz, 1500), 10);
+        assert_eq!(sampling_percent(&rules, Speed::Rapid, 1500), 20);
+        assert_eq!(sampling_percent(&rules, Speed::Blitz, 2100), 50);
+        assert_eq!(sampling_percent(&rules, Speed::Classical, 1900), 100);
+        assert_eq!(sampling_percent(&rules, Speed::Classical, 2100), 50);
+        assert_eq!(sampling_percent(&[], Speed::Bullet, 800), 100);
+
+        assert!("blitz:2000".parse::<SamplingRule>().is_err());
+        assert!("blitz:2000:101".parse::<SamplingRule>().is_err());
+        assert!("fast:2000:50".parse::<SamplingRule>().is_err());
+    }
+}
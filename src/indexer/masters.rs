@@ -1,37 +1,77 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     sync::{Arc, Mutex},
 };
 
+use clap::Parser;
 use nohash_hasher::IntMap;
+use pgn_reader::{BufferedReader, RawHeader, SanPlus, Skip, Visitor};
 use shakmaty::{
-    uci::UciMove, variant::Variant, zobrist::ZobristHash, Chess, Color, EnPassantMode, Outcome,
-    Position,
+    fen::Fen, uci::UciMove, variant::Variant, zobrist::ZobristHash, ByColor, Chess, Color,
+    EnPassantMode, Outcome, Position,
 };
 
 use crate::{
-    api::Error,
+    aliases::PlayerAliases,
+    api::{Error, MastersImportReport, MastersPgnImportResult},
     db::Database,
-    model::{KeyBuilder, LaxDate, MastersEntry, MastersGameWithId},
+    model::{
+        GameId, GamePlayer, KeyBuilder, LaxDate, MastersEntry, MastersGame, MastersGameWithId,
+        PawnStructure, StructureEntry, Year,
+    },
     util::midpoint,
     zobrist::StableZobrist128,
 };
 
+#[derive(Parser, Clone)]
+pub struct MastersImporterOpt {
+    /// Rating points per year since 1952 added to the combined rating of
+    /// masters games when selecting top games, so that modern games are not
+    /// crowded out by older games of similar strength. Only applies to games
+    /// imported afterwards.
+    #[arg(long = "masters-recency-weight", default_value = "4")]
+    recency_weight: u16,
+}
+
+impl MastersImporterOpt {
+    fn recency_bonus(&self, year: Year) -> u16 {
+        u16::from(year)
+            .saturating_sub(u16::from(Year::min_value()))
+            .saturating_mul(self.recency_weight)
+    }
+}
+
 #[derive(Clone)]
 pub struct MastersImporter {
     db: Arc<Database>,
     mutex: Arc<Mutex<()>>,
+    opt: Arc<MastersImporterOpt>,
+    aliases: &'static PlayerAliases,
 }
 
 impl MastersImporter {
-    pub fn new(db: Arc<Database>) -> MastersImporter {
+    pub fn new(
+        db: Arc<Database>,
+        opt: MastersImporterOpt,
+        aliases: &'static PlayerAliases,
+    ) -> MastersImporter {
         MastersImporter {
             db,
             mutex: Arc::new(Mutex::new(())),
+            opt: Arc::new(opt),
+            aliases,
         }
     }
 
-    pub fn import(&self, body: MastersGameWithId) -> Result<(), Error> {
+    /// Validate and import a game. A dry run performs all checks, but
+    /// writes nothing.
+    pub fn import(
+        &self,
+        mut body: MastersGameWithId,
+        dry_run: bool,
+    ) -> Result<MastersImportReport, Error> {
+        self.normalize_players(&mut body);
+
         let avg_rating = midpoint(
             body.game.players.white.rating,
             body.game.players.black.rating,
@@ -60,7 +100,18 @@ impl MastersImporter {
             return Err(Error::DuplicateGame { id: body.id });
         }
 
-        let mut without_loops: IntMap<StableZobrist128, (UciMove, Color)> =
+        let content_hash = body.game.content_hash();
+        if let Some(existing) = masters_db
+            .game_by_content(&content_hash)
+            .expect("check for masters game content")
+        {
+            return Err(Error::DuplicateGameContent {
+                id: body.id,
+                existing,
+            });
+        }
+
+        let mut without_loops: IntMap<StableZobrist128, (UciMove, Color, PawnStructure, Fen)> =
             HashMap::with_capacity_and_hasher(body.game.moves.len(), Default::default());
         let mut pos = Chess::default();
         let mut final_key = None;
@@ -68,7 +119,15 @@ impl MastersImporter {
             let key = pos.zobrist_hash(EnPassantMode::Legal);
             final_key = Some(key);
             let m = uci.to_move(&pos)?;
-            without_loops.insert(key, (UciMove::from_chess960(&m), pos.turn()));
+            without_loops.insert(
+                key,
+                (
+                    UciMove::from_chess960(&m),
+                    pos.turn(),
+                    PawnStructure::from_board(pos.board()),
+                    Fen::from_position(pos.clone(), EnPassantMode::Legal),
+                ),
+            );
             pos.play_unchecked(&m);
         }
 
@@ -85,24 +144,278 @@ impl MastersImporter {
             }
         }
 
+        let report = MastersImportReport {
+            id: body.id,
+            positions: without_loops.len(),
+        };
+        if dry_run {
+            return Ok(report);
+        }
+
+        let recency_bonus = self.opt.recency_bonus(body.game.date.year());
+
         let mut batch = masters_db.batch();
         batch.put_game(body.id, &body.game);
-        for (key, (uci, turn)) in without_loops {
+        batch.put_content_hash(&content_hash, body.id);
+        for (key, (uci, turn, structure, fen)) in without_loops {
+            let key = KeyBuilder::masters().with_zobrist(Variant::Chess, key);
+            batch.put_position_game(&key, body.id);
+            batch.merge_structure(structure, &key, StructureEntry::new_single(fen.to_string()));
             batch.merge(
-                KeyBuilder::masters()
-                    .with_zobrist(Variant::Chess, key)
-                    .with_year(body.game.date.year()),
+                key.with_year(body.game.date.year()),
                 MastersEntry::new_single(
                     uci,
                     body.id,
                     Outcome::from_winner(body.game.winner),
                     body.game.players.get(turn).rating,
                     body.game.players.get(!turn).rating,
+                    recency_bonus,
                 ),
             );
         }
 
+        batch.inc_game_count();
+
         batch.commit().expect("commit masters game");
-        Ok(())
+        Ok(report)
+    }
+
+    fn normalize_players(&self, body: &mut MastersGameWithId) {
+        for player in body.game.players.iter_mut() {
+            player.name = self.aliases.normalize(&player.name);
+        }
+    }
+
+    /// Import all games of a PGN, reporting the result of each game. A dry
+    /// run also reports games that duplicate earlier games of the same PGN.
+    pub fn import_pgn(
+        &self,
+        pgn: &[u8],
+        dry_run: bool,
+    ) -> Result<Vec<MastersPgnImportResult>, Error> {
+        let mut reader = BufferedReader::new_cursor(pgn);
+        let mut visitor = MastersPgnVisitor::default();
+        let mut results = Vec::new();
+        let mut seen_ids = HashSet::new();
+        let mut seen_content = HashMap::new();
+        while let Some(game) = reader.read_game(&mut visitor)? {
+            results.push(
+                match game.and_then(|mut game| {
+                    if !dry_run {
+                        return self.import(game, false);
+                    }
+                    // Nothing is written, so keep track of the games that
+                    // would have been imported.
+                    self.normalize_players(&mut game);
+                    if seen_ids.contains(&game.id) {
+                        return Err(Error::DuplicateGame { id: game.id });
+                    }
+                    let content_hash = game.game.content_hash();
+                    if let Some(existing) = seen_content.get(&content_hash) {
+                        return Err(Error::DuplicateGameContent {
+                            id: game.id,
+                            existing: *existing,
+                        });
+                    }
+                    let report = self.import(game, true)?;
+                    seen_ids.insert(report.id);
+                    seen_content.insert(content_hash, report.id);
+                    Ok(report)
+                }) {
+                    Ok(report) => MastersPgnImportResult {
+                        id: Some(report.id),
+                        positions: Some(report.positions),
+                        error: None,
+                    },
+                    Err(err) => MastersPgnImportResult {
+                        id: None,
+                        positions: None,
+                        error: Some(err.to_string()),
+                    },
+                },
+            );
+        }
+        Ok(results)
+    }
+}
+
+#[derive(Default)]
+struct MastersPgnVisitor {
+    id: Option<GameId>,
+    event: Option<String>,
+    site: Option<String>,
+    date: Option<LaxDate>,
+    round: Option<String>,
+    names: ByColor<Option<String>>,
+    ratings: ByColor<Option<u16>>,
+    outcome: Option<Outcome>,
+    pos: Chess,
+    moves: Vec<UciMove>,
+    error: Option<Error>,
+}
+
+impl MastersPgnVisitor {
+    fn finish(&mut self) -> Result<MastersGameWithId, Error> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+
+        let game = MastersGame {
+            event: self
+                .event
+                .take()
+                .ok_or(Error::InvalidPgn("missing Event"))?,
+            site: self.site.take().ok_or(Error::InvalidPgn("missing Site"))?,
+            date: self
+                .date
+                .ok_or(Error::InvalidPgn("missing or invalid Date"))?,
+            round: self
+                .round
+                .take()
+                .ok_or(Error::InvalidPgn("missing Round"))?,
+            players: ByColor {
+                white: GamePlayer {
+                    name: self
+                        .names
+                        .white
+                        .take()
+                        .ok_or(Error::InvalidPgn("missing White"))?,
+                    rating: self
+                        .ratings
+                        .white
+                        .ok_or(Error::InvalidPgn("missing or invalid WhiteElo"))?,
+                },
+                black: GamePlayer {
+                    name: self
+                        .names
+                        .black
+                        .take()
+                        .ok_or(Error::InvalidPgn("missing Black"))?,
+                    rating: self
+                        .ratings
+                        .black
+                        .ok_or(Error::InvalidPgn("missing or invalid BlackElo"))?,
+                },
+            },
+            winner: self
+                .outcome
+                .ok_or(Error::InvalidPgn("missing or unfinished Result"))?
+                .winner(),
+            moves: std::mem::take(&mut self.moves),
+        };
+
+        Ok(MastersGameWithId {
+            id: self.id.unwrap_or_else(|| {
+                GameId::from_content(&serde_json::to_vec(&game).expect("serialize masters game"))
+            }),
+            game,
+        })
+    }
+}
+
+impl Visitor for MastersPgnVisitor {
+    type Result = Result<MastersGameWithId, Error>;
+
+    fn begin_game(&mut self) {
+        *self = MastersPgnVisitor::default();
+    }
+
+    fn header(&mut self, key: &[u8], value: RawHeader<'_>) {
+        let value = value.decode_utf8_lossy().into_owned();
+        match key {
+            b"LichessId" => self.id = value.parse().ok(),
+            b"Event" => self.event = Some(value),
+            b"Site" => self.site = Some(value),
+            b"Date" => self.date = value.parse().ok(),
+            b"Round" => self.round = Some(value),
+            b"White" => self.names.white = Some(value),
+            b"Black" => self.names.black = Some(value),
+            b"WhiteElo" => self.ratings.white = value.parse().ok(),
+            b"BlackElo" => self.ratings.black = value.parse().ok(),
+            b"Result" => self.outcome = Outcome::from_ascii(value.as_bytes()).ok(),
+            b"FEN" | b"Variant" => {
+                self.error = Some(Error::InvalidPgn(
+                    "masters games must be standard chess from the initial position",
+                ))
+            }
+            _ => (),
+        }
+    }
+
+    fn end_headers(&mut self) -> Skip {
+        Skip(self.error.is_some())
+    }
+
+    fn san(&mut self, san_plus: SanPlus) {
+        if self.error.is_none() {
+            match san_plus.san.to_move(&self.pos) {
+                Ok(m) => {
+                    self.moves.push(UciMove::from_standard(&m));
+                    self.pos.play_unchecked(&m);
+                }
+                Err(err) => self.error = Some(err.into()),
+            }
+        }
+    }
+
+    fn begin_variation(&mut self) -> Skip {
+        Skip(true) // stay in the mainline
+    }
+
+    fn end_game(&mut self) -> Self::Result {
+        self.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recency_bonus() {
+        let opt = MastersImporterOpt { recency_weight: 4 };
+        assert_eq!(opt.recency_bonus(Year::min_value()), 0);
+        assert_eq!(opt.recency_bonus(Year::try_from(2022).unwrap()), 280);
+    }
+
+    #[test]
+    fn test_masters_pgn_visitor() {
+        let pgn = b"[Event \"Tata Steel Masters\"]\n\
+            [Site \"Wijk aan Zee NED\"]\n\
+            [Date \"2023.01.14\"]\n\
+            [Round \"1\"]\n\
+            [White \"Carlsen, Magnus\"]\n\
+            [Black \"Rapport, Richard\"]\n\
+            [Result \"1/2-1/2\"]\n\
+            [WhiteElo \"2859\"]\n\
+            [BlackElo \"2745\"]\n\
+            \n\
+            1. e4 ( 1. d4 ) e5 2. Nf3 1/2-1/2\n\
+            \n\
+            [Event \"?\"]\n\
+            [Result \"1-0\"]\n\
+            \n\
+            1. e4 e5 2. Ke3 1-0\n";
+
+        let mut reader = BufferedReader::new_cursor(&pgn[..]);
+        let mut visitor = MastersPgnVisitor::default();
+
+        let game = reader.read_game(&mut visitor).unwrap().unwrap().unwrap();
+        assert_eq!(game.game.players.white.rating, 2859);
+        assert_eq!(game.game.winner, None);
+        assert_eq!(
+            game.game.moves,
+            vec![
+                "e2e4".parse::<UciMove>().unwrap(),
+                "e7e5".parse().unwrap(),
+                "g1f3".parse().unwrap()
+            ]
+        );
+
+        assert!(matches!(
+            reader.read_game(&mut visitor).unwrap().unwrap(),
+            Err(Error::SanError(_))
+        ));
+        assert!(reader.read_game(&mut visitor).unwrap().is_none());
     }
 }
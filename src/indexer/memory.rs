@@ -0,0 +1,171 @@
+use std::{
+    mem,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use shakmaty::san::San;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{indexer::player::MAX_PLIES, lila::Game};
+
+/// Estimated size of the entries that a single ply of a game produces, as
+/// retained by batches of remote workers.
+const ENTRY_SIZE: usize = 128;
+
+/// Memory held by games between the lila stream and the indexers, and by
+/// batches of remote workers waiting to be submitted. With a limit, feeding
+/// games waits for memory to be released, so that the stream from lila is
+/// read no faster than games are indexed.
+pub struct IndexerMemory {
+    /// Permits in KiB, if limited.
+    limit: Option<Arc<Semaphore>>,
+    limit_kib: u32,
+    actors: Vec<AtomicU64>,
+    waiting: AtomicUsize,
+}
+
+impl IndexerMemory {
+    pub fn new(actors: usize, limit_mib: Option<u32>) -> IndexerMemory {
+        let limit_kib = limit_mib.map_or(0, |mib| mib.saturating_mul(1024).max(1));
+        IndexerMemory {
+            limit: limit_mib.map(|_| Arc::new(Semaphore::new(limit_kib as usize))),
+            limit_kib,
+            actors: (0..actors).map(|_| AtomicU64::new(0)).collect(),
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    /// Reserve memory on behalf of the actor `idx`, waiting until enough is
+    /// available. Reservations larger than the limit wait for all other
+    /// reservations to be released.
+    pub async fn reserve(self: &Arc<Self>, idx: usize, bytes: u64) -> MemoryReservation {
+        let permit = match self.limit {
+            Some(ref semaphore) => {
+                let kib = bytes.div_ceil(1024).clamp(1, u64::from(self.limit_kib)) as u32;
+                Some(match Arc::clone(semaphore).try_acquire_many_owned(kib) {
+                    Ok(permit) => permit,
+                    Err(_) => {
+                        let _waiting = Waiting::new(&self.waiting);
+                        Arc::clone(semaphore)
+                            .acquire_many_owned(kib)
+                            .await
+                            .expect("indexer memory semaphore never closed")
+                    }
+                })
+            }
+            None => None,
+        };
+        self.actors[idx].fetch_add(bytes, Ordering::Relaxed);
+        MemoryReservation {
+            memory: Arc::clone(self),
+            idx,
+            bytes,
+            _permit: permit,
+        }
+    }
+
+    /// Whether reservations are waiting for memory to be released.
+    pub fn is_contended(&self) -> bool {
+        self.waiting.load(Ordering::Relaxed) > 0
+    }
+
+    /// Bytes currently reserved by each actor.
+    pub fn usage_by_actor(&self) -> Vec<u64> {
+        self.actors
+            .iter()
+            .map(|bytes| bytes.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// Bytes currently reserved in total.
+    pub fn usage(&self) -> u64 {
+        self.usage_by_actor().into_iter().sum()
+    }
+
+    /// The limit in bytes, if any.
+    pub fn limit(&self) -> Option<u64> {
+        self.limit
+            .as_ref()
+            .map(|_| u64::from(self.limit_kib) * 1024)
+    }
+}
+
+/// Counts a waiting reservation, also if it is cancelled.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Waiting<'_> {
+    fn new(waiting: &AtomicUsize) -> Waiting<'_> {
+        waiting.fetch_add(1, Ordering::Relaxed);
+        Waiting(waiting)
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Memory reserved for a game, until dropped.
+pub struct MemoryReservation {
+    memory: Arc<IndexerMemory>,
+    idx: usize,
+    bytes: u64,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        self.memory.actors[self.idx].fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+/// Estimated memory held by a game and its indexed entries.
+pub fn estimate_game_size(game: &Game) -> u64 {
+    // User names are short, and not counted.
+    (mem::size_of::<Game>()
+        + game.moves.capacity() * mem::size_of::<San>()
+        + game.tournament.as_ref().map_or(0, String::len)
+        + game.swiss.as_ref().map_or(0, String::len)
+        + game.moves.len().min(MAX_PLIES) * ENTRY_SIZE) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reserve() {
+        let memory = Arc::new(IndexerMemory::new(2, Some(1)));
+        assert_eq!(memory.limit(), Some(1024 * 1024));
+
+        let a = memory.reserve(0, 600 * 1024).await;
+        let b = memory.reserve(1, 300 * 1024).await;
+        assert_eq!(memory.usage_by_actor(), [600 * 1024, 300 * 1024]);
+
+        // Waits until enough memory is released.
+        assert!(
+            timeout(Duration::from_millis(10), memory.reserve(1, 200 * 1024))
+                .await
+                .is_err()
+        );
+        assert!(!memory.is_contended());
+        drop(a);
+        let c = memory.reserve(1, 200 * 1024).await;
+        assert_eq!(memory.usage(), 500 * 1024);
+
+        // Oversized reservations take the entire limit.
+        drop((b, c));
+        let d = memory.reserve(0, 5 * 1024 * 1024).await;
+        assert_eq!(memory.usage(), 5 * 1024 * 1024);
+        drop(d);
+        assert_eq!(memory.usage(), 0);
+    }
+}
@@ -1,9 +1,15 @@
+mod lease;
 mod lichess;
 mod masters;
+mod memory;
 mod player;
 mod player_queue;
+mod throughput;
 
-pub use lichess::{LichessGameImport, LichessImporter};
-pub use masters::MastersImporter;
-pub use player::{PlayerIndexerOpt, PlayerIndexerStub};
-pub use player_queue::{Queue, QueueFull, Ticket};
+pub use lease::{CoordinatorClient, IndexedGame, Lease, LeaseBatch};
+pub use lichess::{LichessGameImport, LichessImporter, LichessImporterOpt};
+pub use masters::{MastersImporter, MastersImporterOpt};
+pub use memory::IndexerMemory;
+pub use player::{LeaseNotFound, PlayerIndexerOpt, PlayerIndexerStub, PlayerIndexerWorker};
+pub use player_queue::{Queue, QueueEntry, SubmitError, Ticket};
+pub use throughput::IndexerThroughput;
@@ -1,17 +1,19 @@
 use std::{
     collections::HashMap,
-    sync::Arc,
+    sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
 
 use clap::Parser;
 use futures_util::StreamExt;
+use moka::sync::Cache;
 use nohash_hasher::IntMap;
 use reqwest::StatusCode;
 use shakmaty::{
-    uci::UciMove, variant::VariantPosition, zobrist::ZobristHash, ByColor, CastlingMode, Outcome,
-    Position,
+    uci::UciMove, variant::VariantPosition, zobrist::ZobristHash, ByColor, CastlingMode, Color,
+    Outcome, Position,
 };
+use thiserror::Error;
 use tokio::{
     sync::{mpsc, Semaphore},
     task,
@@ -21,26 +23,100 @@ use tokio::{
 
 use crate::{
     db::Database,
-    indexer::{Queue, QueueFull, Ticket},
+    indexer::{
+        memory::{estimate_game_size, IndexerMemory, MemoryReservation},
+        throughput::IndexerThroughput,
+        CoordinatorClient, IndexedGame, Lease, LeaseBatch, Queue, QueueEntry, SubmitError, Ticket,
+    },
     lila::{Game, Lila, LilaOpt},
-    model::{GamePlayer, KeyBuilder, LichessGame, Mode, Month, PlayerEntry, PlayerStatus, UserId},
+    model::{
+        GameId, GamePlayer, IndexCooldowns, IndexRun, KeyBuilder, LichessGame, Mode, Month,
+        PlayerEntry, PlayerStatus, TimeBucket, Title, UserId, UserName,
+    },
     util::spawn_blocking,
     zobrist::StableZobrist128,
 };
 
-const MAX_PLIES: usize = 50;
+pub(crate) const MAX_PLIES: usize = 50;
 
 #[derive(Parser, Clone)]
 pub struct PlayerIndexerOpt {
     /// Number of parallel indexing tasks.
     #[arg(long = "indexers", default_value = "8")]
     indexers: usize,
+    /// Number of recently replayed games to keep, so that games do not have
+    /// to be replayed again when indexing the opponent.
+    #[arg(long = "indexer-parse-cache", default_value = "50000")]
+    parse_cache: u64,
+    /// Minimum number of seconds between index runs of the same player.
+    #[arg(long = "indexer-cooldown", default_value = "120")]
+    cooldown: u64,
+    /// Minimum number of seconds before revisiting games that were still
+    /// ongoing when a player was last indexed.
+    #[arg(long = "indexer-revisit-cooldown", default_value = "86400")]
+    revisit_cooldown: u64,
+    /// Maximum number of players waiting in the indexing queue on behalf of
+    /// the same source (user reported by lila, or client IP address).
+    /// Unlimited by default.
+    #[arg(long = "indexer-source-quota")]
+    source_quota: Option<usize>,
+    /// Maximum memory in MiB held by games waiting to be indexed, and by
+    /// batches waiting to be submitted to the coordinator, across all
+    /// indexing tasks. Reading games from lila pauses while the limit is
+    /// reached. Unlimited by default.
+    #[arg(long = "indexer-memory-limit")]
+    memory_limit: Option<u32>,
+    /// Run as a remote indexing worker, leasing players from the given
+    /// coordinator instead of serving requests.
+    #[arg(long = "indexer-coordinator")]
+    pub coordinator: Option<String>,
+    /// Admin token for the coordinator.
+    #[arg(
+        long = "indexer-coordinator-token",
+        env = "EXPLORER_INDEXER_COORDINATOR_TOKEN"
+    )]
+    coordinator_token: Option<String>,
 }
 
+/// Leases that are not renewed within this time are considered abandoned.
+const LEASE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Number of games a remote worker indexes before posting a batch.
+const LEASE_BATCH_SIZE: usize = 256;
+
+#[derive(Error, Debug)]
+#[error("lease not found or expired")]
+pub struct LeaseNotFound;
+
+#[derive(Default)]
+struct Leases {
+    next_id: u64,
+    active: HashMap<u64, ActiveLease>,
+}
+
+struct ActiveLease {
+    player: UserId,
+    renewed_at: Instant,
+    run: Arc<Mutex<LeaseRun>>,
+}
+
+/// State of a leased index run, locked while a batch is applied.
+struct LeaseRun {
+    index_run: IndexRun,
+    status: PlayerStatus,
+}
+
+type ParseCache = Cache<GameId, Arc<IntMap<StableZobrist128, UciMove>>>;
+
 #[derive(Clone)]
 pub struct PlayerIndexerStub {
     queue: Arc<Queue<UserId>>,
     db: Arc<Database>,
+    lila: Arc<Lila>,
+    leases: Arc<Mutex<Leases>>,
+    cooldowns: Arc<IndexCooldowns>,
+    memory: Arc<IndexerMemory>,
+    throughput: Arc<IndexerThroughput>,
 }
 
 impl PlayerIndexerStub {
@@ -50,7 +126,15 @@ impl PlayerIndexerStub {
         opt: PlayerIndexerOpt,
         lila_opt: LilaOpt,
     ) -> PlayerIndexerStub {
-        let queue = Arc::new(Queue::with_capacity(2000));
+        let queue = Arc::new(Queue::with_capacity(2000, opt.source_quota));
+        let parse_cache = ParseCache::new(opt.parse_cache);
+        let lila = Lila::new(lila_opt);
+        let memory = Arc::new(IndexerMemory::new(opt.indexers, opt.memory_limit));
+        let throughput = Arc::new(IndexerThroughput::new(opt.indexers));
+        let cooldowns = Arc::new(IndexCooldowns::new(
+            Duration::from_secs(opt.cooldown),
+            Duration::from_secs(opt.revisit_cooldown),
+        ));
 
         for idx in 0..opt.indexers {
             join_set.spawn(
@@ -58,28 +142,62 @@ impl PlayerIndexerStub {
                     idx,
                     queue: Arc::clone(&queue),
                     db: Arc::clone(&db),
-                    lila: Lila::new(lila_opt.clone()),
+                    lila: lila.clone(),
+                    parse_cache: parse_cache.clone(),
+                    cooldowns: Arc::clone(&cooldowns),
+                    memory: Arc::clone(&memory),
+                    throughput: Arc::clone(&throughput),
                 }
                 .run(),
             );
         }
 
-        PlayerIndexerStub { queue, db }
+        PlayerIndexerStub {
+            queue,
+            db,
+            lila: Arc::new(lila),
+            leases: Arc::default(),
+            cooldowns,
+            memory,
+            throughput,
+        }
+    }
+
+    pub fn cooldowns(&self) -> &IndexCooldowns {
+        &self.cooldowns
+    }
+
+    pub fn memory(&self) -> &IndexerMemory {
+        &self.memory
+    }
+
+    pub fn throughput(&self) -> &IndexerThroughput {
+        &self.throughput
     }
 
     pub fn num_indexing(&self) -> usize {
         self.queue.estimate_len()
     }
 
+    pub fn queue_snapshot(&self) -> Vec<QueueEntry<UserId>> {
+        self.queue.snapshot()
+    }
+
     pub fn preceding_tickets(&self, ticket: &Ticket) -> u64 {
         self.queue.preceding_tickets(ticket)
     }
 
+    /// Number of players that can still be queued.
+    pub fn remaining_capacity(&self) -> usize {
+        self.queue.remaining_capacity()
+    }
+
     pub async fn index_player(
         &self,
         player: UserId,
+        source: Option<String>,
         semaphore: &Semaphore,
-    ) -> Result<Ticket, QueueFull<UserId>> {
+    ) -> Result<Ticket, SubmitError<UserId>> {
         if let Some(ticket) = self.queue.watch(&player) {
             return Ok(ticket);
         }
@@ -96,11 +214,160 @@ impl PlayerIndexerStub {
             .await
         };
 
-        if status.maybe_start_index_run().is_none() {
+        if status.maybe_start_index_run(&self.cooldowns).is_none() {
             return Ok(Ticket::new_completed()); // Do not reindex so soon!
         }
 
-        self.queue.submit(player)
+        self.queue.submit(player, source)
+    }
+
+    /// Hand out the next queued player to a remote worker. Must be called
+    /// from a blocking context.
+    pub fn lease(&self) -> Option<Lease> {
+        self.expire_leases();
+
+        while let Some(player) = self.queue.try_acquire() {
+            let status = self
+                .db
+                .lichess()
+                .player_status(&player)
+                .expect("get player status")
+                .unwrap_or_default();
+
+            let index_run = match status.maybe_start_index_run(&self.cooldowns) {
+                Some(index_run) => index_run,
+                None => {
+                    self.queue.release(&player);
+                    continue; // Do not reindex so soon!
+                }
+            };
+
+            let mut leases = self.leases.lock().unwrap();
+            let id = leases.next_id;
+            leases.next_id += 1;
+            let lease = Lease {
+                id,
+                player: player.as_lowercase_str().to_owned(),
+                since: index_run.since(),
+            };
+            log::info!(
+                "leasing {} ({}) as {}",
+                player.as_lowercase_str(),
+                index_run,
+                id
+            );
+            leases.active.insert(
+                id,
+                ActiveLease {
+                    player,
+                    renewed_at: Instant::now(),
+                    run: Arc::new(Mutex::new(LeaseRun { index_run, status })),
+                },
+            );
+            return Some(lease);
+        }
+
+        None
+    }
+
+    /// Apply a batch posted by a remote worker. Must be called from a
+    /// blocking context.
+    pub fn submit_lease(
+        &self,
+        id: u64,
+        batch: LeaseBatch,
+        done: bool,
+    ) -> Result<(), LeaseNotFound> {
+        let (player, run) = {
+            let mut leases = self.leases.lock().unwrap();
+            let lease = leases.active.get_mut(&id).ok_or(LeaseNotFound)?;
+            lease.renewed_at = Instant::now();
+            (lease.player.clone(), Arc::clone(&lease.run))
+        };
+
+        // Writes for the leased player are sequenced by holding the lock of
+        // the run, just like a local actor sequences writes for its player.
+        // Other leases can be handed out and submitted in the meantime.
+        let mut run = run.lock().unwrap();
+
+        let lichess_db = self.db.lichess();
+        for game in batch.games {
+            let _player_games = self.db.lock_player_games();
+            let already_indexed = lichess_db
+                .game(game.id)
+                .expect("get game info")
+                .map_or(false, |info| *info.indexed_player.get(game.color()));
+            if !already_indexed {
+                let mut write_batch = lichess_db.batch();
+                game.merge_into(&mut write_batch);
+                write_batch
+                    .commit()
+                    .expect("atomically commit game and moves");
+            }
+        }
+
+        run.status.latest_created_at = run.status.latest_created_at.max(batch.latest_created_at);
+        if run.status.revisit_ongoing_created_at.is_none() {
+            run.status.revisit_ongoing_created_at = batch.revisit_ongoing_created_at;
+        }
+
+        if done {
+            let index_run = run.index_run;
+            run.status.finish_index_run(index_run);
+        }
+        lichess_db
+            .put_player_status(&player, &run.status)
+            .expect("put player status");
+
+        if done {
+            // Only release the player once its status is written, so that
+            // it is not leased again right away.
+            if self.leases.lock().unwrap().active.remove(&id).is_none() {
+                // Expired while the batch was applied, and already released.
+                return Err(LeaseNotFound);
+            }
+            self.queue.release(&player);
+            log::info!("lease {} for {} done", id, player.as_lowercase_str());
+        }
+
+        Ok(())
+    }
+
+    fn expire_leases(&self) {
+        let mut leases = self.leases.lock().unwrap();
+        leases.active.retain(|id, lease| {
+            let alive = lease.renewed_at.elapsed() < LEASE_TIMEOUT;
+            if !alive {
+                log::warn!(
+                    "lease {} for {} expired",
+                    id,
+                    lease.player.as_lowercase_str()
+                );
+                self.queue.release(&lease.player);
+            }
+            alive
+        });
+    }
+
+    pub fn is_callback_url(&self, url: &str) -> bool {
+        self.lila.is_callback_url(url)
+    }
+
+    /// Notify `callback_url` with a POST request once the indexing run
+    /// associated with `ticket` is complete.
+    pub fn notify_on_completion(&self, player: UserId, mut ticket: Ticket, callback_url: String) {
+        let lila = Arc::clone(&self.lila);
+        task::spawn(async move {
+            ticket.completed().await;
+            if let Err(err) = lila.notify_indexed(&callback_url, &player).await {
+                log::error!(
+                    "failed to notify {} about {}: {}",
+                    callback_url,
+                    player.as_lowercase_str(),
+                    err
+                );
+            }
+        });
     }
 }
 
@@ -109,6 +376,10 @@ struct PlayerIndexerActor {
     queue: Arc<Queue<UserId>>,
     db: Arc<Database>,
     lila: Lila,
+    parse_cache: ParseCache,
+    cooldowns: Arc<IndexCooldowns>,
+    memory: Arc<IndexerMemory>,
+    throughput: Arc<IndexerThroughput>,
 }
 
 impl PlayerIndexerActor {
@@ -119,48 +390,13 @@ impl PlayerIndexerActor {
         }
     }
 
-    async fn feed_games(&self, player: &UserId, since: u64, tx: mpsc::Sender<Game>) {
-        let mut games =
-            match timeout(Duration::from_secs(60), self.lila.user_games(player, since)).await {
-                Ok(Ok(games)) => games,
-                Ok(Err(err)) if err.status() == Some(StatusCode::NOT_FOUND) => {
-                    log::warn!(
-                        "indexer {:02}: did not find player {}",
-                        self.idx,
-                        player.as_lowercase_str()
-                    );
-                    return;
-                }
-                Ok(Err(err)) => {
-                    log::error!("indexer {:02}: request failed: {}", self.idx, err);
-                    sleep(Duration::from_secs(5)).await;
-                    return;
-                }
-                Err(timed_out) => {
-                    log::error!("indexer {:02}: request to lila: {}", self.idx, timed_out);
-                    return;
-                }
-            };
-
-        loop {
-            let game = match timeout(Duration::from_secs(60), games.next()).await {
-                Ok(Some(Ok(game))) => game,
-                Ok(Some(Err(err))) => {
-                    log::error!("indexer {:02}: {}", self.idx, err);
-                    continue;
-                }
-                Ok(None) => break,
-                Err(timed_out) => {
-                    log::error!("indexer {:02}: stream from lila: {}", self.idx, timed_out);
-                    break;
-                }
-            };
-
-            if tx.send(game).await.is_err() {
-                log::error!("indexer {:02}: game receiver dropped", self.idx);
-                break;
-            }
-        }
+    async fn feed_games(
+        &self,
+        player: &UserId,
+        since: u64,
+        tx: mpsc::Sender<(Game, MemoryReservation)>,
+    ) {
+        feed_games(self.idx, &self.lila, &self.memory, player, since, tx).await;
     }
 
     async fn index_player(&self, player: &UserId) {
@@ -177,7 +413,7 @@ impl PlayerIndexerActor {
             .expect("join get player status")
         };
 
-        let index_run = match status.maybe_start_index_run() {
+        let index_run = match status.maybe_start_index_run(&self.cooldowns) {
             Some(index_run) => index_run,
             None => return, // Do not reindex so soon!
         };
@@ -188,6 +424,8 @@ impl PlayerIndexerActor {
         let join_handle = {
             let idx = self.idx;
             let db = Arc::clone(&self.db);
+            let parse_cache = self.parse_cache.clone();
+            let throughput = Arc::clone(&self.throughput);
             let player = player.clone();
 
             task::spawn_blocking(move || {
@@ -202,8 +440,16 @@ impl PlayerIndexerActor {
                 let hash = ByColor::new_with(|color| KeyBuilder::player(&player, color));
 
                 let mut num_games = 0;
-                while let Some(game) = rx_game.blocking_recv() {
-                    PlayerIndexerActor::index_game(idx, &db, &player, &hash, game, &mut status);
+                while let Some((game, _reservation)) = rx_game.blocking_recv() {
+                    PlayerIndexerActor::index_game(
+                        idx,
+                        &db,
+                        &parse_cache,
+                        &player,
+                        &hash,
+                        game,
+                        &mut status,
+                    );
                     num_games += 1;
 
                     if num_games % 1024 == 0 {
@@ -226,6 +472,7 @@ impl PlayerIndexerActor {
                     .expect("put player status");
 
                 let elapsed = started_at.elapsed();
+                throughput.record(num_games, elapsed);
 
                 if num_games > 0 {
                     log::info!(
@@ -254,11 +501,56 @@ impl PlayerIndexerActor {
     fn index_game(
         idx: usize,
         db: &Database,
+        parse_cache: &ParseCache,
         player: &UserId,
         hash: &ByColor<KeyBuilder>,
         game: Game,
         status: &mut PlayerStatus,
     ) {
+        // Skip game if already indexed from this side. Writes of this
+        // indexer for the same player are sequenced by this actor, but
+        // lichess imports may index the game for the player concurrently, so
+        // check again while holding the lock before writing.
+        let lichess_db = db.lichess();
+        let already_indexed = |id, color| {
+            lichess_db
+                .game(id)
+                .expect("get game info")
+                .map_or(false, |info| *info.indexed_player.get(color))
+        };
+
+        if let Some(indexed) = PlayerIndexerActor::prepare_game(
+            idx,
+            parse_cache,
+            player,
+            hash,
+            game,
+            status,
+            already_indexed,
+        ) {
+            let _player_games = db.lock_player_games();
+            if already_indexed(indexed.id, indexed.color()) {
+                return;
+            }
+
+            // Write to database. All writes regarding this game are batched
+            // and atomically committed, so the database will always be in a
+            // consistent state.
+            let mut batch = lichess_db.batch();
+            indexed.merge_into(&mut batch);
+            batch.commit().expect("atomically commit game and moves");
+        }
+    }
+
+    fn prepare_game(
+        idx: usize,
+        parse_cache: &ParseCache,
+        player: &UserId,
+        hash: &ByColor<KeyBuilder>,
+        game: Game,
+        status: &mut PlayerStatus,
+        already_indexed: impl FnOnce(GameId, Color) -> bool,
+    ) -> Option<IndexedGame> {
         status.latest_created_at = game.created_at;
 
         if game.status.is_ongoing() {
@@ -270,11 +562,11 @@ impl PlayerIndexerActor {
                 );
                 status.revisit_ongoing_created_at = Some(game.created_at);
             }
-            return;
+            return None;
         }
 
         if game.status.is_unindexable() {
-            return;
+            return None;
         }
 
         if game
@@ -282,7 +574,7 @@ impl PlayerIndexerActor {
             .iter()
             .any(|p| p.user.is_none() || p.rating.is_none())
         {
-            return;
+            return None;
         }
 
         let color = match game
@@ -297,59 +589,116 @@ impl PlayerIndexerActor {
                     player.as_lowercase_str(),
                     game.id
                 );
-                return;
+                return None;
             }
         };
 
-        // Skip game if already indexed from this side. This cannot race with
-        // writes, because all writes for the same player are sequenced by
-        // this actor. So making a transaction is not required.
-        let lichess_db = db.lichess();
-        if lichess_db
-            .game(game.id)
-            .expect("get game info")
-            .map_or(false, |info| *info.indexed_player.get(color))
-        {
+        if already_indexed(game.id, color) {
             log::debug!("indexer {:02}: {}/{} already indexed", idx, game.id, color);
-            return;
+            return None;
         }
 
-        // Prepare basic information and setup initial position.
+        // Prepare basic information.
         let month = Month::from_time_saturating(game.last_move_at);
         let outcome = Outcome::from_winner(game.winner);
+        let source = game.source();
+        let opponent_rating = match game.players.get(!color).rating {
+            Some(rating) => rating,
+            None => {
+                log::warn!(
+                    "indexer {:02}: skipping {} without opponent rating",
+                    idx,
+                    game.id
+                );
+                return None;
+            }
+        };
+
+        let opponent_title = game
+            .players
+            .get(!color)
+            .user
+            .as_ref()
+            .and_then(|user| Title::from_lila(user.title.as_deref()));
+
+        // Replay the game, unless it was recently replayed while indexing
+        // the opponent.
+        let without_loops = match parse_cache.get(&game.id) {
+            Some(without_loops) => without_loops,
+            None => match PlayerIndexerActor::replay(idx, &game) {
+                Some(without_loops) => {
+                    let without_loops = Arc::new(without_loops);
+                    parse_cache.insert(game.id, Arc::clone(&without_loops));
+                    without_loops
+                }
+                None => return None,
+            },
+        };
+
+        let entries = without_loops
+            .iter()
+            .map(|(zobrist, uci)| {
+                (
+                    hash.get(color)
+                        .with_zobrist(game.variant, *zobrist)
+                        .with_month(month),
+                    PlayerEntry::new_single(
+                        uci.clone(),
+                        game.speed,
+                        Mode::from_rated(game.rated),
+                        Some(source),
+                        Some(TimeBucket::from_unix_millis(game.created_at)),
+                        game.id,
+                        outcome,
+                        game.players.get(color).rating,
+                        opponent_rating,
+                        opponent_title,
+                    ),
+                )
+            })
+            .collect();
+
+        Some(IndexedGame {
+            id: game.id,
+            info: LichessGame {
+                outcome,
+                speed: game.speed,
+                mode: Mode::from_rated(game.rated),
+                month,
+                players: game.players.map(|p| GamePlayer {
+                    name: p.user.map_or(String::new(), |u| u.name.to_string()),
+                    rating: p.rating.unwrap_or_default(),
+                }),
+                indexed_player: ByColor::new_with(|c| color == c),
+                indexed_lichess: false,
+            },
+            entries,
+        })
+    }
+
+    fn replay(idx: usize, game: &Game) -> Option<IntMap<StableZobrist128, UciMove>> {
         let mut pos = match game.initial_fen {
-            Some(fen) => {
+            Some(ref fen) => {
                 match VariantPosition::from_setup(
                     game.variant,
-                    fen.into_setup(),
+                    fen.as_setup().to_owned(),
                     CastlingMode::Chess960,
                 ) {
                     Ok(pos) => pos,
                     Err(err) => {
                         log::warn!("indexer {:02}: not indexing {}: {}", idx, game.id, err);
-                        return;
+                        return None;
                     }
                 }
             }
             None => VariantPosition::new(game.variant),
         };
-        let opponent_rating = match game.players.get(!color).rating {
-            Some(rating) => rating,
-            None => {
-                log::warn!(
-                    "indexer {:02}: skipping {} without opponent rating",
-                    idx,
-                    game.id
-                );
-                return;
-            }
-        };
 
         // Build an intermediate table to remove loops (due to repetitions).
         let mut without_loops: IntMap<StableZobrist128, UciMove> =
             HashMap::with_capacity_and_hasher(game.moves.len(), Default::default());
 
-        for (ply, san) in game.moves.into_iter().enumerate() {
+        for (ply, san) in game.moves.iter().enumerate() {
             if ply >= MAX_PLIES {
                 break;
             }
@@ -375,43 +724,235 @@ impl PlayerIndexerActor {
             pos.play_unchecked(&m);
         }
 
-        // Write to database. All writes regarding this game are batched and
-        // atomically committed, so the database will always be in a consistent
-        // state.
-        let mut batch = lichess_db.batch();
+        Some(without_loops)
+    }
+}
 
-        batch.merge_game(
-            game.id,
-            LichessGame {
-                outcome,
-                speed: game.speed,
-                mode: Mode::from_rated(game.rated),
-                month,
-                players: game.players.map(|p| GamePlayer {
-                    name: p.user.map_or(String::new(), |u| u.name.to_string()),
-                    rating: p.rating.unwrap_or_default(),
-                }),
-                indexed_player: ByColor::new_with(|c| color == c),
-                indexed_lichess: false,
-            },
+/// Stream games of `player` to `tx`. Returns `false` if the games could not
+/// be fetched completely.
+async fn feed_games(
+    idx: usize,
+    lila: &Lila,
+    memory: &Arc<IndexerMemory>,
+    player: &UserId,
+    since: u64,
+    tx: mpsc::Sender<(Game, MemoryReservation)>,
+) -> bool {
+    let mut games = match timeout(Duration::from_secs(60), lila.user_games(player, since)).await {
+        Ok(Ok(games)) => games,
+        Ok(Err(err)) if err.status() == Some(StatusCode::NOT_FOUND) => {
+            log::warn!(
+                "indexer {:02}: did not find player {}",
+                idx,
+                player.as_lowercase_str()
+            );
+            return true;
+        }
+        Ok(Err(err)) => {
+            log::error!("indexer {:02}: request failed: {}", idx, err);
+            sleep(Duration::from_secs(5)).await;
+            return false;
+        }
+        Err(timed_out) => {
+            log::error!("indexer {:02}: request to lila: {}", idx, timed_out);
+            return false;
+        }
+    };
+
+    loop {
+        let game = match timeout(Duration::from_secs(60), games.next()).await {
+            Ok(Some(Ok(game))) => game,
+            Ok(Some(Err(err))) => {
+                log::error!("indexer {:02}: {}", idx, err);
+                continue;
+            }
+            Ok(None) => return true,
+            Err(timed_out) => {
+                log::error!("indexer {:02}: stream from lila: {}", idx, timed_out);
+                return false;
+            }
+        };
+
+        // Wait for memory before reading further, so that lila pauses the
+        // stream.
+        let reservation = memory.reserve(idx, estimate_game_size(&game)).await;
+        if tx.send((game, reservation)).await.is_err() {
+            log::error!("indexer {:02}: game receiver dropped", idx);
+            return false;
+        }
+    }
+}
+
+/// Indexes players leased from a remote coordinator, and posts the
+/// results back instead of writing to a local database.
+pub struct PlayerIndexerWorker {
+    idx: usize,
+    coordinator: Arc<CoordinatorClient>,
+    lila: Lila,
+    parse_cache: ParseCache,
+    memory: Arc<IndexerMemory>,
+}
+
+impl PlayerIndexerWorker {
+    pub async fn run(opt: PlayerIndexerOpt, lila_opt: LilaOpt) {
+        let coordinator = Arc::new(CoordinatorClient::new(
+            opt.coordinator.expect("coordinator url"),
+            opt.coordinator_token,
+        ));
+        let parse_cache = ParseCache::new(opt.parse_cache);
+        let lila = Lila::new(lila_opt);
+        let memory = Arc::new(IndexerMemory::new(opt.indexers, opt.memory_limit));
+
+        let mut join_set = JoinSet::new();
+        for idx in 0..opt.indexers {
+            join_set.spawn(
+                PlayerIndexerWorker {
+                    idx,
+                    coordinator: Arc::clone(&coordinator),
+                    lila: lila.clone(),
+                    parse_cache: parse_cache.clone(),
+                    memory: Arc::clone(&memory),
+                }
+                .work(),
+            );
+        }
+        join_set
+            .join_next()
+            .await
+            .expect("workers")
+            .expect("join worker");
+    }
+
+    async fn work(self) {
+        loop {
+            match self.coordinator.lease().await {
+                Ok(Some(lease)) => self.index_lease(lease).await,
+                Ok(None) => sleep(Duration::from_secs(1)).await,
+                Err(err) => {
+                    log::error!("worker {:02}: failed to lease: {}", self.idx, err);
+                    sleep(Duration::from_secs(5)).await;
+                }
+            }
+        }
+    }
+
+    async fn index_lease(&self, lease: Lease) {
+        let player = match lease.player.parse::<UserName>() {
+            Ok(player) => UserId::from(player),
+            Err(err) => {
+                log::error!("worker {:02}: lease {}: {}", self.idx, lease.id, err);
+                return;
+            }
+        };
+
+        log::info!(
+            "worker {:02}: starting {} (lease {})",
+            self.idx,
+            player.as_lowercase_str(),
+            lease.id
         );
 
-        for (zobrist, uci) in without_loops {
-            batch.merge_player(
-                hash.get(color)
-                    .with_zobrist(game.variant, zobrist)
-                    .with_month(month),
-                PlayerEntry::new_single(
-                    uci.clone(),
-                    game.speed,
-                    Mode::from_rated(game.rated),
-                    game.id,
-                    outcome,
-                    opponent_rating,
-                ),
+        let (tx_game, mut rx_game) = mpsc::channel(100);
+        let (tx_batch, mut rx_batch) = mpsc::channel(4);
+
+        let join_handle = {
+            let idx = self.idx;
+            let parse_cache = self.parse_cache.clone();
+            let player = player.clone();
+            let memory = Arc::clone(&self.memory);
+
+            task::spawn_blocking(move || {
+                let hash = ByColor::new_with(|color| KeyBuilder::player(&player, color));
+                let mut status = PlayerStatus::default();
+                let mut batch = LeaseBatch::default();
+                // Memory stays reserved until the batch is submitted.
+                let mut reservations = Vec::new();
+
+                while let Some((game, reservation)) = rx_game.blocking_recv() {
+                    if let Some(indexed) = PlayerIndexerActor::prepare_game(
+                        idx,
+                        &parse_cache,
+                        &player,
+                        &hash,
+                        game,
+                        &mut status,
+                        |_, _| false, // Checked by the coordinator
+                    ) {
+                        batch.games.push(indexed);
+                        reservations.push(reservation);
+                    }
+
+                    // Submit early rather than holding on to memory that
+                    // others are waiting for, possibly including this feed.
+                    if batch.games.len() >= LEASE_BATCH_SIZE
+                        || (!batch.games.is_empty() && memory.is_contended())
+                    {
+                        batch.latest_created_at = status.latest_created_at;
+                        batch.revisit_ongoing_created_at = status.revisit_ongoing_created_at;
+                        // Fails once the submitter gave up and dropped the
+                        // receiver, instead of waiting for capacity forever.
+                        if tx_batch
+                            .blocking_send((
+                                std::mem::take(&mut batch),
+                                std::mem::take(&mut reservations),
+                            ))
+                            .is_err()
+                        {
+                            return None;
+                        }
+                    }
+                }
+
+                batch.latest_created_at = status.latest_created_at;
+                batch.revisit_ongoing_created_at = status.revisit_ongoing_created_at;
+                Some((batch, reservations))
+            })
+        };
+
+        let feed = feed_games(
+            self.idx,
+            &self.lila,
+            &self.memory,
+            &player,
+            lease.since,
+            tx_game,
+        );
+        let submit = {
+            let (idx, id, coordinator) = (self.idx, lease.id, &self.coordinator);
+            async move {
+                // Owns the receiver, so that it is dropped as soon as
+                // submitting fails. This stops the indexing task, which in
+                // turn stops the feed.
+                while let Some((batch, _reservations)) = rx_batch.recv().await {
+                    if let Err(err) = coordinator.submit(id, &batch, false).await {
+                        // The lease will expire and the player will be
+                        // indexed again eventually.
+                        log::error!("worker {:02}: lease {}: {}", idx, id, err);
+                        return false;
+                    }
+                }
+                true
+            }
+        };
+        let (complete, submitted) = tokio::join!(feed, submit);
+        let last = join_handle.await.expect("join index lease");
+
+        if !complete {
+            // Do not mark the run as done, so that the lease expires and
+            // the missing games are fetched again eventually.
+            log::warn!(
+                "worker {:02}: lease {}: games of {} incomplete",
+                self.idx,
+                lease.id,
+                player.as_lowercase_str()
             );
+            return;
         }
 
-        batch.commit().expect("atomically commit game and moves");
+        if let (true, Some((batch, _reservations))) = (submitted, last) {
+            if let Err(err) = self.coordinator.submit(lease.id, &batch, true).await {
+                log::error!("worker {:02}: lease {}: {}", self.idx, lease.id, err);
+            }
+        }
     }
 }
@@ -1,10 +1,11 @@
 use std::{
     collections::{
         hash_map::{Entry, HashMap},
-        VecDeque,
+        HashSet, VecDeque,
     },
     hash::Hash,
     sync::Mutex,
+    time::SystemTime,
 };
 
 use tokio::sync::{watch, Notify};
@@ -15,9 +16,11 @@ pub struct Queue<T> {
 }
 
 impl<T: Eq + Hash + Clone> Queue<T> {
-    pub fn with_capacity(capacity: usize) -> Queue<T> {
+    /// Create a queue with room for `capacity` waiting tasks, of which each
+    /// source may have at most `source_quota`.
+    pub fn with_capacity(capacity: usize, source_quota: Option<usize>) -> Queue<T> {
         Queue {
-            state: Mutex::new(QueueState::with_capacity(capacity)),
+            state: Mutex::new(QueueState::with_capacity(capacity, source_quota)),
             notify: Notify::new(),
         }
     }
@@ -26,6 +29,12 @@ impl<T: Eq + Hash + Clone> Queue<T> {
         self.state.lock().unwrap().len()
     }
 
+    /// Number of tasks that can still be submitted, ignoring source quotas.
+    pub fn remaining_capacity(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.queue.capacity().saturating_sub(state.queue.len())
+    }
+
     pub fn preceding_tickets(&self, ticket: &Ticket) -> u64 {
         ticket
             .number
@@ -36,14 +45,30 @@ impl<T: Eq + Hash + Clone> Queue<T> {
         self.state.lock().unwrap().watch(task)
     }
 
-    pub fn submit(&self, task: T) -> Result<Ticket, QueueFull<T>> {
-        let result = self.state.lock().unwrap().submit(task);
+    pub fn snapshot(&self) -> Vec<QueueEntry<T>> {
+        self.state.lock().unwrap().snapshot()
+    }
+
+    /// Submit a task, unless it is already queued. `source` identifies who
+    /// requested the task, for per-source quotas.
+    pub fn submit(&self, task: T, source: Option<String>) -> Result<Ticket, SubmitError<T>> {
+        let result = self.state.lock().unwrap().submit(task, source);
         if result.is_ok() {
             self.notify.notify_one();
         }
         result
     }
 
+    /// Take the next task without waiting. The caller is responsible for
+    /// calling [`Queue::release()`] once the task is complete.
+    pub fn try_acquire(&self) -> Option<T> {
+        self.state.lock().unwrap().acquire()
+    }
+
+    pub fn release(&self, task: &T) {
+        self.state.lock().unwrap().complete(task);
+    }
+
     pub async fn acquire(&self) -> QueueItem<T> {
         loop {
             if let Some(task) = self.state.lock().unwrap().acquire() {
@@ -54,22 +79,40 @@ impl<T: Eq + Hash + Clone> Queue<T> {
     }
 }
 
-pub struct QueueFull<T>(pub T);
+pub enum SubmitError<T> {
+    /// The queue is at capacity.
+    QueueFull(T),
+    /// The source already has as many tasks waiting as it may.
+    QuotaExhausted(T),
+}
+
+#[derive(Debug)]
+pub struct QueueEntry<T> {
+    pub task: T,
+    pub number: u64,
+    pub submitted_at: SystemTime,
+    pub acquired: bool,
+}
 
 struct QueueState<T> {
     indexing: HashMap<T, QueuePosition>,
     queue: VecDeque<T>,
     next_number: u64,
     acquired_number: u64,
+    /// Number of waiting tasks by source.
+    sources: HashMap<String, usize>,
+    source_quota: Option<usize>,
 }
 
 impl<T: Eq + Hash + Clone> QueueState<T> {
-    fn with_capacity(capacity: usize) -> QueueState<T> {
+    fn with_capacity(capacity: usize, source_quota: Option<usize>) -> QueueState<T> {
         QueueState {
             indexing: HashMap::with_capacity(capacity),
             queue: VecDeque::with_capacity(capacity),
             next_number: 0,
             acquired_number: 0,
+            sources: HashMap::new(),
+            source_quota,
         }
     }
 
@@ -81,19 +124,43 @@ impl<T: Eq + Hash + Clone> QueueState<T> {
         self.indexing.get(task).map(QueuePosition::ticket)
     }
 
-    fn submit(&mut self, task: T) -> Result<Ticket, QueueFull<T>> {
+    fn snapshot(&self) -> Vec<QueueEntry<T>> {
+        let queued: HashSet<&T> = self.queue.iter().collect();
+        let mut entries: Vec<QueueEntry<T>> = self
+            .indexing
+            .iter()
+            .map(|(task, position)| QueueEntry {
+                task: task.clone(),
+                number: position.number,
+                submitted_at: position.submitted_at,
+                acquired: !queued.contains(task),
+            })
+            .collect();
+        entries.sort_by_key(|entry| entry.number);
+        entries
+    }
+
+    fn submit(&mut self, task: T, source: Option<String>) -> Result<Ticket, SubmitError<T>> {
         let entry = match self.indexing.entry(task) {
             Entry::Occupied(entry) => return Ok(entry.get().ticket()),
             Entry::Vacant(entry) => entry,
         };
 
         if self.queue.len() >= self.queue.capacity() {
-            return Err(QueueFull(entry.into_key()));
+            return Err(SubmitError::QueueFull(entry.into_key()));
+        }
+
+        if let Some(ref source) = source {
+            let waiting = self.sources.get(source).copied().unwrap_or(0);
+            if self.source_quota.is_some_and(|quota| waiting >= quota) {
+                return Err(SubmitError::QuotaExhausted(entry.into_key()));
+            }
+            self.sources.insert(source.clone(), waiting + 1);
         }
 
         self.queue.push_back(entry.key().clone());
 
-        let queue_position = entry.insert(QueuePosition::with_number(self.next_number));
+        let queue_position = entry.insert(QueuePosition::new(self.next_number, source));
         self.next_number += 1;
         Ok(queue_position.ticket())
     }
@@ -106,6 +173,14 @@ impl<T: Eq + Hash + Clone> QueueState<T> {
             };
 
             self.acquired_number = entry.get().number;
+            if let Some(ref source) = entry.get().source {
+                if let Some(waiting) = self.sources.get_mut(source) {
+                    *waiting -= 1;
+                    if *waiting == 0 {
+                        self.sources.remove(source);
+                    }
+                }
+            }
 
             if entry.get().tx.is_closed() {
                 entry.remove();
@@ -124,12 +199,19 @@ impl<T: Eq + Hash + Clone> QueueState<T> {
 struct QueuePosition {
     tx: watch::Sender<()>,
     number: u64,
+    submitted_at: SystemTime,
+    source: Option<String>,
 }
 
 impl QueuePosition {
-    fn with_number(number: u64) -> QueuePosition {
+    fn new(number: u64, source: Option<String>) -> QueuePosition {
         let (tx, _) = watch::channel(());
-        QueuePosition { tx, number }
+        QueuePosition {
+            tx,
+            number,
+            submitted_at: SystemTime::now(),
+            source,
+        }
     }
 
     fn ticket(&self) -> Ticket {
@@ -140,6 +222,7 @@ impl QueuePosition {
     }
 }
 
+#[derive(Clone)]
 pub struct Ticket {
     rx: watch::Receiver<()>,
     number: u64,
@@ -172,3 +255,49 @@ impl<T: Eq + Hash + Clone> Drop for QueueItem<'_, T> {
         self.queue.state.lock().unwrap().complete(&self.task);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queue_snapshot() {
+        let mut state = QueueState::with_capacity(10, None);
+        let _a = state.submit("a", None).ok().unwrap();
+        let _b = state.submit("b", None).ok().unwrap();
+        assert_eq!(state.acquire(), Some("a"));
+
+        let snapshot = state.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[0].task, "a");
+        assert_eq!(snapshot[0].number, 0);
+        assert!(snapshot[0].acquired);
+        assert_eq!(snapshot[1].task, "b");
+        assert_eq!(snapshot[1].number, 1);
+        assert!(!snapshot[1].acquired);
+    }
+
+    #[test]
+    fn test_queue_source_quota() {
+        let mut state = QueueState::with_capacity(10, Some(2));
+        let source = || Some("ip:127.0.0.1".to_owned());
+        let _a = state.submit("a", source()).ok().unwrap();
+        let _b = state.submit("b", source()).ok().unwrap();
+        assert!(matches!(
+            state.submit("c", source()),
+            Err(SubmitError::QuotaExhausted("c"))
+        ));
+
+        // Already queued tasks and other sources are not affected.
+        assert!(state.submit("a", source()).is_ok());
+        let _c = state
+            .submit("c", Some("user:other".to_owned()))
+            .ok()
+            .unwrap();
+        let _d = state.submit("d", None).ok().unwrap();
+
+        // Quota is released once tasks are taken from the queue.
+        assert_eq!(state.acquire(), Some("a"));
+        let _e = state.submit("e", source()).ok().unwrap();
+    }
+}
@@ -0,0 +1,90 @@
+use std::{sync::Mutex, time::Duration};
+
+/// Weight of the most recent index run in the moving averages.
+const ALPHA: f64 = 0.05;
+
+/// Recent throughput of the local player indexers, to estimate how long
+/// players wait in the queue.
+pub struct IndexerThroughput {
+    indexers: usize,
+    averages: Mutex<Option<Averages>>,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Averages {
+    games_per_player: f64,
+    secs_per_player: f64,
+}
+
+impl IndexerThroughput {
+    pub fn new(indexers: usize) -> IndexerThroughput {
+        IndexerThroughput {
+            indexers,
+            averages: Mutex::new(None),
+        }
+    }
+
+    /// Record a finished index run.
+    pub fn record(&self, games: u32, elapsed: Duration) {
+        let (games, secs) = (f64::from(games), elapsed.as_secs_f64());
+        let mut averages = self.averages.lock().expect("lock indexer throughput");
+        *averages = Some(match *averages {
+            Some(avg) => Averages {
+                games_per_player: avg.games_per_player + ALPHA * (games - avg.games_per_player),
+                secs_per_player: avg.secs_per_player + ALPHA * (secs - avg.secs_per_player),
+            },
+            None => Averages {
+                games_per_player: games,
+                secs_per_player: secs,
+            },
+        });
+    }
+
+    fn averages(&self) -> Option<Averages> {
+        *self.averages.lock().expect("lock indexer throughput")
+    }
+
+    /// Games indexed per second by each indexer, on average.
+    pub fn games_per_sec(&self) -> Option<f64> {
+        self.averages()
+            .filter(|avg| avg.secs_per_player > 0.0)
+            .map(|avg| avg.games_per_player / avg.secs_per_player)
+    }
+
+    /// New games per indexed player, on average.
+    pub fn games_per_player(&self) -> Option<f64> {
+        self.averages().map(|avg| avg.games_per_player)
+    }
+
+    /// Estimated time until indexing of a player starts, after the given
+    /// number of preceding players.
+    pub fn estimate_wait(&self, preceding: u64) -> Option<Duration> {
+        if self.indexers == 0 {
+            return None;
+        }
+        self.averages().map(|avg| {
+            Duration::from_secs_f64(preceding as f64 * avg.secs_per_player / self.indexers as f64)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_wait() {
+        let throughput = IndexerThroughput::new(4);
+        assert_eq!(throughput.estimate_wait(10), None);
+
+        throughput.record(200, Duration::from_secs(20));
+        assert_eq!(throughput.games_per_sec(), Some(10.0));
+        assert_eq!(throughput.estimate_wait(0), Some(Duration::ZERO));
+        assert_eq!(throughput.estimate_wait(10), Some(Duration::from_secs(50)));
+
+        // Recent runs move the averages gradually.
+        throughput.record(0, Duration::ZERO);
+        assert_eq!(throughput.games_per_player(), Some(190.0));
+        assert_eq!(throughput.estimate_wait(4), Some(Duration::from_secs(19)));
+    }
+}
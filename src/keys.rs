@@ -0,0 +1,90 @@
+//! Derivation of explorer keys, so that other services can precompute them
+//! (for example to query by `zobrist=<hex>`) and stay in sync with the
+//! database.
+//!
+//! Keys must never change for existing positions, independent of changes to
+//! the hashing of `shakmaty`. This is guarded by the golden vectors in
+//! `tests/zobrist.csv` and `tests/keys.csv`.
+
+use shakmaty::{variant::VariantPosition, zobrist::ZobristHash as _, Color, EnPassantMode};
+
+pub use crate::{
+    model::{Key, KeyBuilder, KeyPrefix, Month, UserId, UserName, Year},
+    zobrist::StableZobrist128,
+};
+
+/// Stable hash of a position, as expected by the `zobrist` query parameter.
+pub fn stable_zobrist(pos: &VariantPosition) -> StableZobrist128 {
+    pos.zobrist_hash(EnPassantMode::Legal)
+}
+
+pub fn lichess_key(pos: &VariantPosition) -> KeyPrefix {
+    KeyBuilder::lichess().with_zobrist(pos.variant(), stable_zobrist(pos))
+}
+
+pub fn masters_key(pos: &VariantPosition) -> KeyPrefix {
+    KeyBuilder::masters().with_zobrist(pos.variant(), stable_zobrist(pos))
+}
+
+pub fn player_key(player: &UserId, color: Color, pos: &VariantPosition) -> KeyPrefix {
+    KeyBuilder::player(player, color).with_zobrist(pos.variant(), stable_zobrist(pos))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use serde_with::{formats::SpaceSeparator, serde_as, DisplayFromStr, StringWithSeparator};
+    use shakmaty::{uci::UciMove, variant::Variant, Position as _};
+
+    use super::*;
+
+    #[test]
+    fn test_keys_reference() {
+        #[serde_as]
+        #[derive(Deserialize)]
+        struct Record {
+            #[serde_as(as = "DisplayFromStr")]
+            variant: Variant,
+            #[serde_as(as = "StringWithSeparator<SpaceSeparator, UciMove>")]
+            uci: Vec<UciMove>,
+            #[serde_as(as = "Option<DisplayFromStr>")]
+            player: Option<UserName>,
+            #[serde_as(as = "Option<DisplayFromStr>")]
+            color: Option<Color>,
+            #[serde_as(as = "DisplayFromStr")]
+            month: Month,
+            key: String,
+        }
+
+        let mut reader = csv::Reader::from_path("tests/keys.csv").expect("reader");
+
+        for (i, record) in reader.deserialize().enumerate() {
+            let record: Record = record.expect("record");
+
+            let mut pos = VariantPosition::new(record.variant);
+            for uci in record.uci {
+                let m = uci.to_move(&pos).expect("legal uci");
+                pos.play_unchecked(&m);
+            }
+
+            let prefix = match (record.player, record.color) {
+                (Some(player), Some(color)) => player_key(&UserId::from(player), color, &pos),
+                _ => {
+                    assert_eq!(
+                        lichess_key(&pos).with_month(record.month),
+                        masters_key(&pos).with_month(record.month),
+                    );
+                    lichess_key(&pos)
+                }
+            };
+
+            let key: String = prefix
+                .with_month(record.month)
+                .into_bytes()
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect();
+            assert_eq!(key, record.key, "line {}", i + 1);
+        }
+    }
+}
@@ -1,10 +1,26 @@
 #![forbid(unsafe_code)]
 
+pub mod aliases;
+pub mod annotation;
 pub mod api;
+pub mod cloud_eval;
 pub mod db;
+pub mod era;
+pub mod explorer;
+pub mod hot_positions;
 pub mod indexer;
+#[cfg(feature = "stable-keys")]
+pub mod keys;
 pub mod lila;
+pub mod metrics;
+pub mod migrate;
 pub mod model;
 pub mod opening;
+pub mod query_log;
+pub mod service;
+pub mod study;
+pub mod tablebase;
+pub mod trace;
+pub mod transposition;
 pub mod util;
 pub mod zobrist;
@@ -1,19 +1,29 @@
-use std::{io, time::SystemTime};
+use std::{
+    cmp::min,
+    io,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use clap::Parser;
 use futures_util::stream::{Stream, StreamExt as _, TryStreamExt as _};
+use reqwest::{header::RETRY_AFTER, RequestBuilder, Response, StatusCode};
 use serde::Deserialize;
 use serde_with::{
     formats::SpaceSeparator, serde_as, DisplayFromStr, StringWithSeparator, TimestampMilliSeconds,
 };
 use shakmaty::{fen::Fen, san::San, variant::Variant, ByColor, Color};
 use time::PrimitiveDateTime;
-use tokio::io::AsyncBufReadExt as _;
+use tokio::{
+    io::AsyncBufReadExt as _,
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::sleep,
+};
 use tokio_stream::wrappers::LinesStream;
 use tokio_util::io::StreamReader;
 
 use crate::{
-    model::{GameId, Speed, UserId, UserName},
+    model::{GameId, GameSource, Speed, UserId, UserName},
     util::ByColorDef,
 };
 
@@ -26,24 +36,116 @@ pub struct LilaOpt {
     /// and allow access to internal endpoints.
     #[arg(long = "bearer", env = "EXPLORER_BEARER")]
     bearer: Option<String>,
+    /// Maximum number of idle connections to keep open to lila.
+    #[arg(long = "lila-pool-max-idle")]
+    pool_max_idle: Option<usize>,
+    /// Close idle connections to lila after this many seconds.
+    #[arg(long = "lila-pool-idle-timeout", default_value = "90")]
+    pool_idle_timeout: u64,
+    /// Send TCP keep-alive probes on connections to lila at this interval in
+    /// seconds.
+    #[arg(long = "lila-tcp-keepalive")]
+    tcp_keepalive: Option<u64>,
+    /// Talk to lila using HTTP/2 without negotiating it first.
+    #[arg(long = "lila-http2")]
+    http2: bool,
+    /// Maximum number of concurrent requests to lila, shared by all indexers.
+    #[arg(long = "lila-concurrency")]
+    concurrency: Option<usize>,
+    /// Number of times to retry a request that was rate limited by lila.
+    #[arg(long = "lila-max-retries", default_value = "5")]
+    max_retries: u32,
+    /// Initial delay in milliseconds before retrying a rate limited request.
+    /// Doubles with every retry, unless lila sends Retry-After.
+    #[arg(long = "lila-retry-backoff", default_value = "1000")]
+    retry_backoff: u64,
 }
 
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Client for lila. Clones share the connection pool and the limit on
+/// concurrent requests.
+#[derive(Clone)]
 pub struct Lila {
     client: reqwest::Client,
+    semaphore: Option<Arc<Semaphore>>,
     opt: LilaOpt,
 }
 
 impl Lila {
     pub fn new(opt: LilaOpt) -> Lila {
+        let mut builder = reqwest::Client::builder()
+            .user_agent("lila-openingexplorer")
+            .pool_idle_timeout(Duration::from_secs(opt.pool_idle_timeout))
+            .tcp_keepalive(opt.tcp_keepalive.map(Duration::from_secs));
+        if let Some(pool_max_idle) = opt.pool_max_idle {
+            builder = builder.pool_max_idle_per_host(pool_max_idle);
+        }
+        if opt.http2 {
+            builder = builder.http2_prior_knowledge();
+        }
+
         Lila {
-            client: reqwest::Client::builder()
-                .user_agent("lila-openingexplorer")
-                .build()
-                .expect("reqwest client"),
+            client: builder.build().expect("reqwest client"),
+            semaphore: opt
+                .concurrency
+                .map(|concurrency| Arc::new(Semaphore::new(concurrency))),
             opt,
         }
     }
 
+    /// Send the request, retrying while rate limited. Also returns the
+    /// permit for the request, which must be held until the response body
+    /// has been read.
+    async fn send(
+        &self,
+        builder: RequestBuilder,
+    ) -> Result<(Response, Option<OwnedSemaphorePermit>), reqwest::Error> {
+        let mut retries = 0;
+        loop {
+            let permit = match self.semaphore {
+                Some(ref semaphore) => Some(
+                    Arc::clone(semaphore)
+                        .acquire_owned()
+                        .await
+                        .expect("semaphore not closed"),
+                ),
+                None => None,
+            };
+            let res = builder
+                .try_clone()
+                .expect("request without streaming body")
+                .send()
+                .await?;
+
+            if res.status() != StatusCode::TOO_MANY_REQUESTS || retries >= self.opt.max_retries {
+                return res.error_for_status().map(|res| (res, permit));
+            }
+            drop(permit);
+
+            let backoff = res
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or_else(|| {
+                    Duration::from_millis(self.opt.retry_backoff)
+                        .saturating_mul(1 << min(retries, 16))
+                });
+            let backoff = min(backoff, MAX_RETRY_BACKOFF);
+            retries += 1;
+            log::warn!(
+                "lila rate limited {}, retry {}/{} in {:?}",
+                res.url(),
+                retries,
+                self.opt.max_retries,
+                backoff
+            );
+            sleep(backoff).await;
+        }
+    }
+
     pub async fn user_games(
         &self,
         user: &UserId,
@@ -64,25 +166,41 @@ impl Lila {
             builder = builder.bearer_auth(bearer);
         }
 
-        let stream = builder
-            .send()
-            .await
-            .and_then(|r| r.error_for_status())?
-            .bytes_stream()
-            .map_err(|err| io::Error::new(io::ErrorKind::Other, err));
-
-        Ok(Box::pin(
-            LinesStream::new(StreamReader::new(stream).lines()).filter_map(|line| async move {
-                match line {
-                    Ok(line) if line.is_empty() => None,
-                    Ok(line) => Some(
-                        serde_json::from_str::<Game>(&line)
-                            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
-                    ),
-                    Err(err) => Some(Err(err)),
-                }
-            }),
-        ))
+        let (res, permit) = self.send(builder).await?;
+
+        Ok(Box::pin(lines(res, permit).filter_map(|line| async move {
+            match line {
+                Ok(line) if line.is_empty() => None,
+                Ok(line) => Some(
+                    serde_json::from_str::<Game>(&line)
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+                ),
+                Err(err) => Some(Err(err)),
+            }
+        })))
+    }
+
+    pub fn is_callback_url(&self, url: &str) -> bool {
+        url.strip_prefix(&self.opt.lila)
+            .map_or(false, |path| path.starts_with('/'))
+    }
+
+    pub async fn notify_indexed(
+        &self,
+        callback_url: &str,
+        user: &UserId,
+    ) -> Result<(), reqwest::Error> {
+        let mut builder = self
+            .client
+            .post(callback_url)
+            .form(&[("player", user.as_lowercase_str())]);
+
+        if let Some(ref bearer) = self.opt.bearer {
+            builder = builder.bearer_auth(bearer);
+        }
+
+        self.send(builder).await?;
+        Ok(())
     }
 
     pub async fn mod_marked_since(
@@ -103,29 +221,39 @@ impl Lila {
             builder = builder.bearer_auth(bearer);
         }
 
-        let stream = builder
-            .send()
-            .await
-            .and_then(|r| r.error_for_status())?
-            .bytes_stream()
-            .map_err(|err| io::Error::new(io::ErrorKind::Other, err));
-
-        Ok(Box::pin(
-            LinesStream::new(StreamReader::new(stream).lines()).filter_map(|line| async move {
-                match line {
-                    Ok(line) if line.is_empty() => None,
-                    Ok(line) => Some(
-                        line.parse::<UserName>()
-                            .map(UserId::from)
-                            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
-                    ),
-                    Err(err) => Some(Err(err)),
-                }
-            }),
-        ))
+        let (res, permit) = self.send(builder).await?;
+
+        Ok(Box::pin(lines(res, permit).filter_map(|line| async move {
+            match line {
+                Ok(line) if line.is_empty() => None,
+                Ok(line) => Some(
+                    line.parse::<UserName>()
+                        .map(UserId::from)
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+                ),
+                Err(err) => Some(Err(err)),
+            }
+        })))
     }
 }
 
+/// Lines of the response body. The permit is released only when the stream
+/// is dropped, so that streamed responses count against the limit on
+/// concurrent requests until they have been read.
+fn lines(
+    res: Response,
+    permit: Option<OwnedSemaphorePermit>,
+) -> impl Stream<Item = Result<String, io::Error>> {
+    let stream = res
+        .bytes_stream()
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+        .map(move |chunk| {
+            let _permit = &permit;
+            chunk
+        });
+    LinesStream::new(StreamReader::new(stream).lines())
+}
+
 #[serde_as]
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -150,6 +278,16 @@ pub struct Game {
     #[serde_as(as = "Option<DisplayFromStr>")]
     #[serde(default)]
     pub initial_fen: Option<Fen>,
+    #[serde(default)]
+    pub tournament: Option<String>,
+    #[serde(default)]
+    pub swiss: Option<String>,
+}
+
+impl Game {
+    pub fn source(&self) -> GameSource {
+        GameSource::from_tournament(self.tournament.is_some(), self.swiss.is_some())
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -165,6 +303,8 @@ pub struct Player {
 pub struct User {
     #[serde_as(as = "DisplayFromStr")]
     pub name: UserName,
+    #[serde(default)]
+    pub title: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Copy, Clone)]
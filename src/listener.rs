@@ -0,0 +1,102 @@
+use std::{
+    fs, io,
+    net::{AddrParseError, SocketAddr},
+    os::unix::fs::FileTypeExt as _,
+    path::PathBuf,
+    str::FromStr,
+};
+
+use axum::Router;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use listenfd::ListenFd;
+use tokio::net::{TcpListener, UnixListener};
+
+/// Address to listen on: `<ip>:<port>` or `unix:<path>`.
+#[derive(Debug, Clone)]
+pub enum Bind {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Bind {
+    type Err = AddrParseError;
+
+    fn from_str(s: &str) -> Result<Bind, AddrParseError> {
+        Ok(match s.strip_prefix("unix:") {
+            Some(path) => Bind::Unix(PathBuf::from(path)),
+            None => Bind::Tcp(s.parse()?),
+        })
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Take over the socket with index `fd` passed by the service manager
+    /// (systemd socket activation with `LISTEN_FDS`), or bind a new one. The
+    /// kind of socket is expected to match `bind`.
+    pub async fn open(bind: &Bind, fd: usize) -> io::Result<Listener> {
+        let mut listenfd = ListenFd::from_env();
+        Ok(match bind {
+            Bind::Tcp(addr) => Listener::Tcp(match listenfd.take_tcp_listener(fd)? {
+                Some(listener) => {
+                    log::info!("using tcp socket from service manager");
+                    listener.set_nonblocking(true)?;
+                    TcpListener::from_std(listener)?
+                }
+                None => TcpListener::bind(addr).await?,
+            }),
+            Bind::Unix(path) => Listener::Unix(match listenfd.take_unix_listener(fd)? {
+                Some(listener) => {
+                    log::info!("using unix socket from service manager");
+                    listener.set_nonblocking(true)?;
+                    UnixListener::from_std(listener)?
+                }
+                None => {
+                    // Remove stale socket from a previous run.
+                    if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                        fs::remove_file(path)?;
+                    }
+                    UnixListener::bind(path)?
+                }
+            }),
+        })
+    }
+
+    pub async fn serve(self, app: Router) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => {
+                axum::serve(
+                    listener,
+                    app.into_make_service_with_connect_info::<SocketAddr>(),
+                )
+                .await
+            }
+            Listener::Unix(listener) => loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(err) => {
+                        log::error!("failed to accept unix connection: {err}");
+                        continue;
+                    }
+                };
+                let service = TowerToHyperService::new(app.clone());
+                tokio::spawn(async move {
+                    if let Err(err) = auto::Builder::new(TokioExecutor::new())
+                        .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                        .await
+                    {
+                        log::debug!("failed to serve unix connection: {err}");
+                    }
+                });
+            },
+        }
+    }
+}
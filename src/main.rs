@@ -1,69 +1,136 @@
 #![forbid(unsafe_code)]
 
+pub mod aliases;
+pub mod annotation;
 pub mod api;
+pub mod cloud_eval;
 pub mod db;
+pub mod era;
+pub mod explorer;
+pub mod hot_positions;
 pub mod indexer;
 pub mod lila;
+pub mod listener;
 pub mod metrics;
+pub mod migrate;
 pub mod model;
 pub mod opening;
+pub mod query_log;
+pub mod study;
+pub mod tablebase;
+pub mod trace;
+pub mod transposition;
 pub mod util;
 pub mod zobrist;
 
+#[cfg(all(test, feature = "golden-tests"))]
+mod golden;
+
 use std::{
-    collections::HashSet,
-    net::SocketAddr,
-    sync::{Arc, RwLock},
+    cmp::Reverse,
+    collections::{BTreeMap, HashSet, VecDeque},
+    hash::Hash,
+    io, iter,
+    num::NonZeroU64,
+    path::{Path as FsPath, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
     time::{Duration, Instant, SystemTime},
 };
 
 use axum::{
-    extract::{FromRef, Path, Query, State},
-    http::StatusCode,
-    routing::{get, post, put},
+    body::{Body, Bytes},
+    extract::{FromRef, Path, Query, RawQuery, State},
+    http::{Request, StatusCode},
+    middleware,
+    response::{IntoResponse as _, Response},
+    routing::{delete, get, post, put},
     Json, Router,
 };
-use clap::Parser;
-use futures_util::{stream::Stream, StreamExt};
+use clap::{Parser, Subcommand};
+use futures_util::{future, stream::Stream, StreamExt, TryStreamExt as _};
 use moka::future::Cache;
 use serde::Deserialize;
+use serde_json::json;
 use serde_with::{serde_as, DisplayFromStr};
 use shakmaty::{
-    san::{San, SanPlus},
+    fen::Fen,
+    san::SanPlus,
     uci::UciMove,
-    variant::VariantPosition,
+    variant::{Variant, VariantPosition},
     zobrist::ZobristHash,
-    Color, EnPassantMode,
+    CastlingMode, Chess, Color, EnPassantMode, Outcome, Position,
 };
 use tikv_jemallocator::Jemalloc;
 use tokio::{
-    net::TcpListener,
+    io::AsyncBufReadExt as _,
+    signal::unix::{signal, SignalKind},
     sync::Semaphore,
     task,
     task::JoinSet,
     time,
     time::{sleep, timeout},
 };
+use tokio_stream::wrappers::LinesStream;
+use tokio_util::io::StreamReader;
+use tower::ServiceExt as _;
 
 use crate::{
+    aliases::{AliasOpt, PlayerAliases},
+    annotation::{AnnotationOpt, MoveAnnotator},
     api::{
-        Error, ExplorerGame, ExplorerGameWithUciMove, ExplorerMove, ExplorerResponse,
-        HistoryWanted, LichessQuery, MastersQuery, NdJson, PlayPosition, PlayerLimits, PlayerQuery,
-        PlayerQueryFilter, WithSource,
+        query_from_json, shed_each, shed_load, AdminActor, AdminToken, AdminTokens, AuditLogEntry,
+        AuditLogResponse, AuditQuery, BatchResponse, CachesMonitorResponse, CorsOpt, Error,
+        ExplorerGame, ExplorerResponse, Formatted, GameSearchQuery, HistoryWanted,
+        HotPositionsQuery, HotPositionsResponse, ImportCompleteQuery, ImportStatusResponse,
+        IndexerMonitorResponse, IndexerQueueEntry, LichessQuery, LichessQueryFilter, Limits,
+        LoadShedder, MastersGamesAtQuery, MastersGamesAtResponse, MastersImportQuery,
+        MastersNoveltyQuery, MastersNoveltyResponse, MastersPgnImportResult, MastersPlayerMove,
+        MastersPlayerQuery, MastersPlayerResponse, MastersQuery, MastersStructureQuery,
+        MastersStructureResponse, MigrateQuery, MoveOrder, NdJson, Novelty, OpeningsStatusResponse,
+        Play, PlayPosition, PlayerExportQuery, PlayerGame, PlayerGamesQuery, PlayerGamesResponse,
+        PlayerImportQuery, PlayerLimits, PlayerQuery, PlayerQueryFilter, PlayerRepertoireQuery,
+        PlayerRepertoireResponse, PlayerStatusQuery, PlayerStatusResponse, Readiness,
+        RepertoireLine, RequestSource, RequireAdmin, RequireImport, ResponseFormat,
+        RocksDbMonitorResponse, RuntimeConfig, Selected, Source, StructurePosition,
+        TranspositionsQuery, TranspositionsResponse, TreeFormat, TreeQuery, TreeRow, VariantsQuery,
+        WithSource,
     },
-    db::{CacheHint, Database, DbOpt, LichessDatabase},
+    cloud_eval::{CloudEval, CloudEvalOpt, PendingEval},
+    db::{
+        CacheHint, CompactAt, Database, DbOpt, DbStats, LichessDatabase, MigrationProgress,
+        MonthDeletion, VariantSplit, COMPACTED_COLUMN_FAMILIES,
+    },
+    era::{EraAdjustment, EraOpt},
+    explorer::{lichess_response, masters_response, player_response, QueriedPosition},
+    hot_positions::HotPositions,
     indexer::{
-        LichessGameImport, LichessImporter, MastersImporter, PlayerIndexerOpt, PlayerIndexerStub,
-        QueueFull, Ticket,
+        Lease, LeaseBatch, LeaseNotFound, LichessGameImport, LichessImporter, LichessImporterOpt,
+        MastersImporter, MastersImporterOpt, PlayerIndexerOpt, PlayerIndexerStub,
+        PlayerIndexerWorker, SubmitError, Ticket,
     },
     lila::{Lila, LilaOpt},
+    listener::{Bind, Listener},
     metrics::Metrics,
+    migrate::MigrateOpt,
     model::{
-        GameId, KeyBuilder, KeyPrefix, MastersGame, MastersGameWithId, PreparedMove, UserId,
-        UserName,
+        search_tokens, AuditEntry, AuditKey, GameId, KeyBuilder, KeyPrefix, LichessGamePgn,
+        MastersGame, MastersGameWithId, Month, PawnStructure, PreparedMove, SearchField,
+        SearchSource, Stats, UserId, UserName, Year,
+    },
+    opening::{
+        retry_delay, Opening, Openings, OpeningsOpt, OpeningsOrigin, OpeningsStatus,
+        REFRESH_INTERVAL,
     },
-    opening::{Opening, Openings},
-    util::{ply, spawn_blocking, DedupStreamExt as _},
+    query_log::{QueryLog, QueryLogEntry, QueryLogOpt},
+    study::{StudyChapter, StudyMove},
+    tablebase::{PendingTablebase, Tablebase, TablebaseOpt},
+    trace::{trace_request, TraceExporter, TraceOpt},
+    transposition::Transpositions,
+    util::{midpoint, ply, spawn_blocking, DedupStreamExt as _},
+    zobrist::StableZobrist128,
 };
 
 #[global_allocator]
@@ -71,43 +138,464 @@ static GLOBAL: Jemalloc = Jemalloc;
 
 #[derive(Parser)]
 struct Opt {
-    /// Binding address. Note that administrative endpoints must be protected
-    /// using a reverse proxy.
+    /// Binding address, either <ip>:<port> or unix:<path>. Note that
+    /// administrative endpoints must be protected using a reverse proxy,
+    /// unless admin tokens are configured. With systemd socket activation,
+    /// the passed socket is used instead (of the same kind).
     #[arg(long, default_value = "127.0.0.1:9002")]
-    bind: SocketAddr,
-    /// Allow access from all origins.
+    bind: Bind,
+    /// Separate binding address for administrative endpoints (/import/*,
+    /// /admin/*, /compact, /stats and /monitor), either <ip>:<port> or
+    /// unix:<path>. They are then no longer served on --bind. With systemd
+    /// socket activation, the second passed socket is used.
     #[arg(long)]
-    cors: bool,
+    bind_admin: Option<Bind>,
+    /// Bearer token required for administrative endpoints. May be repeated.
+    /// Tokens prefixed with `import:` are only allowed to use /import/*
+    /// endpoints.
+    #[arg(
+        long = "admin-token",
+        env = "EXPLORER_ADMIN_TOKEN",
+        value_delimiter = ','
+    )]
+    admin_tokens: Vec<AdminToken>,
     /// Maximum number of cached responses for /masters.
     #[arg(long, default_value = "40000")]
     masters_cache: u64,
     /// Maximum number of cached responses for /lichess.
     #[arg(long, default_value = "40000")]
     lichess_cache: u64,
+    /// Time to live in seconds for responses in the on-disk cache, which is
+    /// used as a second tier for /masters and /lichess when the in-memory
+    /// cache misses. Invalidated by deletions and opening updates, but not
+    /// by imports. Disabled by default.
+    #[arg(long)]
+    response_cache_ttl: Option<u64>,
+    /// JSON file with parameters that can be adjusted without restarting
+    /// (see /admin/config). Applied at startup and reloaded on SIGHUP.
+    #[arg(long)]
+    runtime_config: Option<PathBuf>,
+    /// Warm up the caches with the positions in this JSON file before
+    /// reporting readiness. Use a response of /admin/hot-positions saved
+    /// before restarting, or a list of common openings in the same format.
+    #[arg(long)]
+    warmup: Option<PathBuf>,
+    /// Warm up the caches in the background, after reporting readiness.
+    #[arg(long, requires = "warmup")]
+    warmup_background: bool,
+    /// Players to periodically index even if their personal explorer is not
+    /// requested, for example streamers and titled players. May be repeated.
+    #[arg(long = "pinned-player", value_delimiter = ',')]
+    pinned_players: Vec<UserName>,
+    /// Interval in seconds between indexing runs for pinned players.
+    #[arg(long, default_value = "3600")]
+    pinned_players_interval: u64,
+    /// Maximum number of /masters and /lichess queries in flight. Further
+    /// queries are rejected with 503 Service Unavailable, instead of waiting
+    /// for the blocking pool. Unlimited by default.
+    #[arg(long)]
+    max_queries_in_flight: Option<usize>,
+    /// Only serve /import/* and /monitor, for initial bulk loads. Disables
+    /// caches, opening name downloads and blacklist updates, and tunes the
+    /// database for ingestion. Automatic compactions are deferred until the
+    /// server is started normally again.
+    #[arg(long)]
+    import_only: bool,
+    /// Log the position, ply and database reads of queries slower than
+    /// 500 ms, at most once per second.
+    #[arg(long)]
+    log_slow_queries: bool,
+    /// Time of day (UTC, as HH:MM) to manually compact the column families
+    /// one after another, for example during low-traffic hours. Column
+    /// families are skipped while writes are stalled or delayed by imports.
+    #[arg(long)]
+    compact_at: Option<CompactAt>,
+    /// Delete lichess explorer entries of months older than this many
+    /// months, checked daily, together with games that are only referenced
+    /// by them. Keeps everything by default.
+    #[arg(long)]
+    lichess_retention_months: Option<u16>,
+    #[command(flatten)]
+    cors: CorsOpt,
     #[command(flatten)]
     db: DbOpt,
     #[command(flatten)]
+    lichess_importer: LichessImporterOpt,
+    #[command(flatten)]
+    masters_importer: MastersImporterOpt,
+    #[command(flatten)]
     player_indexer: PlayerIndexerOpt,
     #[command(flatten)]
     lila: LilaOpt,
+    #[command(flatten)]
+    openings: OpeningsOpt,
+    #[command(flatten)]
+    cloud_eval: CloudEvalOpt,
+    #[command(flatten)]
+    era: EraOpt,
+    #[command(flatten)]
+    aliases: AliasOpt,
+    #[command(flatten)]
+    annotation: AnnotationOpt,
+    #[command(flatten)]
+    tablebase: TablebaseOpt,
+    #[command(flatten)]
+    query_log: QueryLogOpt,
+    #[command(flatten)]
+    trace: TraceOpt,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Rewrite all entries of a column family in the current format
+    /// version, instead of serving requests. The server must be stopped.
+    Migrate(MigrateOpt),
 }
 
+/// Response cache keyed by query. Fills go through [`Cache::entry`], which
+/// coalesces concurrent misses for the same query: Only the first request
+/// spawns the blocking task that scans the column family, and all others
+/// await its shared result.
 type ExplorerCache<T> = Cache<T, Result<Json<ExplorerResponse>, Error>>;
 
+/// Holds an explorer cache that can be replaced by one with a different
+/// capacity at runtime.
+struct ReloadableCache<T> {
+    cache: RwLock<ExplorerCache<T>>,
+    time_to_live: Duration,
+    time_to_idle: Duration,
+}
+
+impl<T: Hash + Eq + Send + Sync + 'static> ReloadableCache<T> {
+    fn new(capacity: u64, time_to_live: Duration, time_to_idle: Duration) -> ReloadableCache<T> {
+        ReloadableCache {
+            cache: RwLock::new(Self::build(capacity, time_to_live, time_to_idle)),
+            time_to_live,
+            time_to_idle,
+        }
+    }
+
+    fn build(capacity: u64, time_to_live: Duration, time_to_idle: Duration) -> ExplorerCache<T> {
+        Cache::builder()
+            .max_capacity(capacity)
+            .time_to_live(time_to_live)
+            .time_to_idle(time_to_idle)
+            .build()
+    }
+
+    fn current(&self) -> ExplorerCache<T> {
+        self.cache.read().expect("read cache").clone()
+    }
+
+    fn capacity(&self) -> u64 {
+        self.current().policy().max_capacity().unwrap_or(0)
+    }
+
+    /// Replace the cache, dropping all cached responses.
+    fn resize(&self, capacity: u64) {
+        if capacity != self.capacity() {
+            *self.cache.write().expect("write cache") =
+                Self::build(capacity, self.time_to_live, self.time_to_idle);
+        }
+    }
+}
+
 #[derive(FromRef, Clone)]
 struct AppState {
+    admin_tokens: &'static AdminTokens,
     openings: &'static RwLock<Openings>,
+    openings_opt: &'static OpeningsOpt,
+    openings_status: &'static OpeningsStatus,
+    readiness: &'static Readiness,
     blacklist: &'static RwLock<HashSet<UserId>>,
     db: Arc<Database>,
-    lichess_cache: ExplorerCache<LichessQuery>,
-    masters_cache: ExplorerCache<MastersQuery>,
+    #[from_ref(skip)]
+    lichess_cache: &'static ReloadableCache<LichessQuery>,
+    #[from_ref(skip)]
+    masters_cache: &'static ReloadableCache<MastersQuery>,
+    /// Seconds, or 0 if disabled.
+    #[from_ref(skip)]
+    response_cache_ttl: &'static AtomicU64,
     metrics: &'static Metrics,
+    load_shedder: &'static LoadShedder,
+    cloud_eval: &'static CloudEval,
+    era: &'static EraAdjustment,
+    annotator: &'static MoveAnnotator,
+    player_aliases: &'static PlayerAliases,
+    tablebase: &'static Tablebase,
+    query_log: &'static QueryLog,
+    hot_positions: &'static HotPositions,
+    trace_exporter: &'static TraceExporter,
     lichess_importer: LichessImporter,
     masters_importer: MastersImporter,
     player_indexer: PlayerIndexerStub,
     semaphore: &'static Semaphore,
 }
 
+impl FromRef<AppState> for ExplorerCache<LichessQuery> {
+    fn from_ref(state: &AppState) -> ExplorerCache<LichessQuery> {
+        state.lichess_cache.current()
+    }
+}
+
+impl FromRef<AppState> for ExplorerCache<MastersQuery> {
+    fn from_ref(state: &AppState) -> ExplorerCache<MastersQuery> {
+        state.masters_cache.current()
+    }
+}
+
+impl FromRef<AppState> for Option<Duration> {
+    fn from_ref(state: &AppState) -> Option<Duration> {
+        Some(state.response_cache_ttl.load(Ordering::Relaxed))
+            .filter(|ttl| *ttl > 0)
+            .map(Duration::from_secs)
+    }
+}
+
+impl AppState {
+    fn runtime_config(&self) -> RuntimeConfig {
+        RuntimeConfig {
+            masters_cache: Some(self.masters_cache.capacity()),
+            lichess_cache: Some(self.lichess_cache.capacity()),
+            response_cache_ttl: Some(self.response_cache_ttl.load(Ordering::Relaxed)),
+            max_queries_in_flight: Some(self.load_shedder.limit().unwrap_or(0)),
+            indexer_cooldown: Some(self.player_indexer.cooldowns().index().as_secs()),
+            indexer_revisit_cooldown: Some(self.player_indexer.cooldowns().revisit().as_secs()),
+        }
+    }
+
+    fn apply_runtime_config(&self, config: &RuntimeConfig) {
+        if let Some(capacity) = config.masters_cache {
+            self.masters_cache.resize(capacity);
+        }
+        if let Some(capacity) = config.lichess_cache {
+            self.lichess_cache.resize(capacity);
+        }
+        if let Some(ttl) = config.response_cache_ttl {
+            self.response_cache_ttl.store(ttl, Ordering::Relaxed);
+        }
+        if let Some(limit) = config.max_queries_in_flight {
+            self.load_shedder
+                .set_limit(Some(limit).filter(|limit| *limit > 0));
+        }
+        if let Some(cooldown) = config.indexer_cooldown {
+            self.player_indexer
+                .cooldowns()
+                .set_index(Duration::from_secs(cooldown));
+        }
+        if let Some(cooldown) = config.indexer_revisit_cooldown {
+            self.player_indexer
+                .cooldowns()
+                .set_revisit(Duration::from_secs(cooldown));
+        }
+        log::info!("applied runtime config: {config:?}");
+    }
+}
+
+fn read_runtime_config(path: &FsPath) -> Result<RuntimeConfig, String> {
+    let file = std::fs::read(path).map_err(|err| format!("{}: {err}", path.display()))?;
+    serde_json::from_slice(&file).map_err(|err| format!("{}: {err}", path.display()))
+}
+
+#[derive(Deserialize)]
+struct WarmupFile {
+    positions: Vec<WarmupPosition>,
+}
+
+#[derive(Deserialize)]
+struct WarmupPosition {
+    endpoint: String,
+    variant: String,
+    fen: Option<String>,
+}
+
+/// Number of warmup queries in flight.
+const WARMUP_CONCURRENCY: usize = 8;
+
+/// Queries the positions of a warmup file with default parameters, so that
+/// they are in the caches of the app and RocksDB.
+async fn warmup(app: Router, path: PathBuf) {
+    let file: WarmupFile = match std::fs::read(&path)
+        .map_err(|err| err.to_string())
+        .and_then(|file| serde_json::from_slice(&file).map_err(|err| err.to_string()))
+    {
+        Ok(file) => file,
+        Err(err) => {
+            log::error!("warmup {}: {err}", path.display());
+            return;
+        }
+    };
+
+    let started_at = Instant::now();
+    let total = file.positions.len();
+    let warmed = futures_util::stream::iter(file.positions)
+        .filter_map(|position| {
+            future::ready(
+                position
+                    .fen
+                    .filter(|_| matches!(position.endpoint.as_str(), "masters" | "lichess"))
+                    .map(|fen| {
+                        let mut url =
+                            reqwest::Url::parse("http://localhost/").expect("warmup base url");
+                        url.set_path(&position.endpoint);
+                        url.query_pairs_mut()
+                            .append_pair("variant", &position.variant)
+                            .append_pair("fen", &fen);
+                        format!("{}?{}", url.path(), url.query().unwrap_or_default())
+                    }),
+            )
+        })
+        .map(|uri| {
+            let app = app.clone();
+            async move {
+                let req = Request::get(&uri)
+                    .body(Body::empty())
+                    .expect("warmup request");
+                match app.oneshot(req).await {
+                    Ok(res) if res.status().is_success() => true,
+                    Ok(res) => {
+                        log::warn!("warmup {uri}: status {}", res.status());
+                        false
+                    }
+                    Err(err) => match err {},
+                }
+            }
+        })
+        .buffer_unordered(WARMUP_CONCURRENCY)
+        .filter(|ok| future::ready(*ok))
+        .count()
+        .await;
+
+    log::info!(
+        "warmed up {warmed} of {total} positions in {:.3?}",
+        started_at.elapsed()
+    );
+}
+
+/// Routes of the app. Only /monitor and /import/* with `import_only`.
+fn routes(state: AppState, import_only: bool) -> Router {
+    let app = admin_routes(import_only);
+    let app = if import_only {
+        app
+    } else {
+        app.merge(query_routes(&state))
+    };
+    app.with_state(state)
+}
+
+/// Administrative routes, served on --bind-admin if given.
+fn admin_routes(import_only: bool) -> Router<AppState> {
+    // Available even with --import-only.
+    let app = Router::new()
+        .route("/monitor/cf/:cf/:prop", get(cf_prop))
+        .route("/monitor/db/:prop", get(db_prop))
+        .route("/monitor", get(monitor))
+        .route("/monitor/caches", get(monitor_caches))
+        .route("/monitor/indexer", get(monitor_indexer))
+        .route("/monitor/rocksdb", get(monitor_rocksdb))
+        .route("/import/masters", put(masters_import))
+        .route("/import/masters/pgn", put(masters_import_pgn))
+        .route("/import/lichess", put(lichess_import))
+        .route("/import/lichess/complete", put(lichess_import_complete))
+        .route("/import/player", put(player_import))
+        .route("/import/openings", post(openings_import));
+
+    if import_only {
+        app
+    } else {
+        app.route("/stats", get(stats))
+            .route("/compact", post(compact))
+            .route("/admin/migrate", post(migrate_column))
+            .route("/admin/audit", get(audit_log))
+            .route(
+                "/admin/config",
+                get(runtime_config).post(runtime_config_update),
+            )
+            .route("/admin/indexer/queue", get(indexer_queue))
+            .route("/admin/indexer/lease", get(indexer_lease))
+            .route("/admin/indexer/lease/:id", post(indexer_lease_submit))
+            .route("/admin/import/status", get(import_status))
+            .route("/admin/hot-positions", get(hot_positions))
+            .route("/admin/openings", get(openings_status))
+            .route("/admin/lichess/month/:month", delete(lichess_delete_month))
+            .route(
+                "/admin/lichess/variant/:variant",
+                delete(lichess_delete_variant),
+            )
+            .route(
+                "/admin/lichess/split-variants",
+                post(lichess_split_variants),
+            )
+    }
+}
+
+/// Public query routes.
+fn query_routes(state: &AppState) -> Router<AppState> {
+    let shed = middleware::from_fn_with_state(state.load_shedder, shed_load);
+
+    Router::new()
+        .route("/masters/pgn/:id", get(masters_pgn))
+        .route("/games/search", get(games_search))
+        .route("/masters", get(masters).layer(shed.clone()))
+        .route("/masters/games-at", get(masters_games_at))
+        .route("/masters/novelty", get(masters_novelty))
+        .route("/masters/player/:name", get(masters_player))
+        .route("/masters/structure", get(masters_structure))
+        .route("/lichess", get(lichess_or_variants).layer(shed.clone()))
+        .route("/lichess/batch", post(lichess_batch).layer(shed.clone()))
+        .route("/lichess/history", get(lichess_history).layer(shed.clone())) // bc
+        .route(
+            "/lichess/transpositions",
+            get(lichess_transpositions).layer(shed.clone()),
+        )
+        .route("/lichess/pgn/:id", get(lichess_pgn))
+        .route("/export/tree", get(export_tree))
+        .route("/player", get(player))
+        .route("/player/status", get(player_status))
+        .route("/player/games", get(player_games))
+        .route("/player/repertoire", get(player_repertoire))
+        .route("/player/export", get(player_export))
+        .route("/master/pgn/:id", get(masters_pgn)) // bc
+        .route("/master", get(masters).layer(shed)) // bc
+        .route("/personal", get(player)) // bc
+        .layer(middleware::from_fn_with_state(
+            state.trace_exporter,
+            trace_request,
+        ))
+}
+
+async fn reload_runtime_config_on_sighup(state: AppState, path: PathBuf) {
+    let mut hangup = signal(SignalKind::hangup()).expect("install sighup handler");
+    while hangup.recv().await.is_some() {
+        match read_runtime_config(&path) {
+            Ok(config) => state.apply_runtime_config(&config),
+            Err(err) => log::error!("failed to reload runtime config: {err}"),
+        }
+    }
+}
+
+async fn periodic_lichess_retention(state: AppState, months: u16) {
+    loop {
+        let cutoff = Month::now().sub_months_saturating(months);
+        let db = Arc::clone(&state.db);
+        match spawn_blocking(state.semaphore, move || db.lichess().import_status()).await {
+            Ok(statuses) if statuses.iter().any(|(month, _)| *month < cutoff) => {
+                let importer = state.lichess_importer.clone();
+                spawn_blocking(state.semaphore, move || {
+                    importer.delete_months_before(cutoff)
+                })
+                .await;
+                state.lichess_cache.current().invalidate_all();
+            }
+            Ok(_) => (),
+            Err(err) => log::error!("failed to read lichess import status: {err}"),
+        }
+        time::sleep(Duration::from_secs(24 * 60 * 60)).await;
+    }
+}
+
 fn main() {
     env_logger::Builder::from_env(
         env_logger::Env::new()
@@ -128,82 +616,306 @@ fn main() {
 }
 
 async fn serve() {
-    let opt = Opt::parse();
+    let mut opt = Opt::parse();
+    if opt.import_only {
+        opt.db.bulk_load = true;
+        opt.lichess_cache = 0;
+        opt.masters_cache = 0;
+        opt.response_cache_ttl = None;
+    }
+
+    if let Some(Command::Migrate(migrate_opt)) = opt.command {
+        task::block_in_place(|| migrate::run(opt.db, migrate_opt));
+        return;
+    }
+
+    if opt.player_indexer.coordinator.is_some() {
+        PlayerIndexerWorker::run(opt.player_indexer, opt.lila).await;
+        return;
+    }
 
     let mut join_set = JoinSet::new();
 
-    let openings: &'static RwLock<Openings> = Box::leak(Box::default());
-    join_set.spawn(periodic_openings_import(openings));
+    // Listen early, to answer probes while the database is opened.
+    let readiness: &'static Readiness = Box::leak(Box::default());
+    let listener = Listener::open(&opt.bind, 0).await.expect("bind");
+    let server = tokio::spawn(listener.serve(readiness.router()));
+    let admin_server = match opt.bind_admin {
+        Some(ref bind) => {
+            let listener = Listener::open(bind, 1).await.expect("bind admin");
+            Some(tokio::spawn(listener.serve(readiness.admin_router())))
+        }
+        None => None,
+    };
 
+    let openings: &'static RwLock<Openings> = Box::leak(Box::default());
+    let openings_opt: &'static OpeningsOpt = Box::leak(Box::new(opt.openings));
+    let openings_status: &'static OpeningsStatus =
+        Box::leak(Box::new(OpeningsStatus::new(openings_opt)));
     let blacklist: &'static RwLock<HashSet<UserId>> = Box::leak(Box::default());
-    join_set.spawn(periodic_blacklist_update(blacklist, opt.lila.clone()));
+    if opt.import_only {
+        log::warn!("import only mode: not serving queries, automatic compactions disabled");
+        // Opening names are not needed for imports.
+        readiness.set_openings_loaded();
+    } else {
+        join_set.spawn(periodic_openings_import(
+            openings,
+            openings_opt,
+            openings_status,
+            readiness,
+        ));
+        join_set.spawn(periodic_blacklist_update(blacklist, opt.lila.clone()));
+    }
 
+    let format_upgrade = opt.db.db_format_upgrade && !opt.import_only;
     let db = task::block_in_place(|| Arc::new(Database::open(opt.db).expect("db")));
+    readiness.set_db_opened();
     let player_indexer =
         PlayerIndexerStub::spawn(&mut join_set, Arc::clone(&db), opt.player_indexer, opt.lila);
+    readiness.set_indexer_spawned();
 
-    let app = Router::new()
-        .route("/monitor/cf/:cf/:prop", get(cf_prop))
-        .route("/monitor/db/:prop", get(db_prop))
-        .route("/monitor", get(monitor))
-        .route("/compact", post(compact))
-        .route("/import/masters", put(masters_import))
-        .route("/import/lichess", put(lichess_import))
-        .route("/import/openings", post(openings_import))
-        .route("/masters/pgn/:id", get(masters_pgn))
-        .route("/masters", get(masters))
-        .route("/lichess", get(lichess))
-        .route("/lichess/history", get(lichess_history)) // bc
-        .route("/player", get(player))
-        .route("/master/pgn/:id", get(masters_pgn)) // bc
-        .route("/master", get(masters)) // bc
-        .route("/personal", get(player)) // bc
-        .with_state(AppState {
-            openings,
-            blacklist,
-            lichess_cache: Cache::builder()
-                .max_capacity(opt.lichess_cache)
-                .time_to_live(Duration::from_secs(60 * 60 * 2))
-                .time_to_idle(Duration::from_secs(60 * 10))
-                .build(),
-            masters_cache: Cache::builder()
-                .max_capacity(opt.masters_cache)
-                .time_to_live(Duration::from_secs(60 * 60 * 4))
-                .time_to_idle(Duration::from_secs(60 * 10))
-                .build(),
-            metrics: Box::leak(Box::default()),
-            lichess_importer: LichessImporter::new(Arc::clone(&db)),
-            masters_importer: MastersImporter::new(Arc::clone(&db)),
-            player_indexer,
-            db,
-            semaphore: Box::leak(Box::new(Semaphore::new(128))),
-        });
-
-    let app = if opt.cors {
-        app.layer(tower_http::set_header::SetResponseHeaderLayer::overriding(
-            axum::http::header::ACCESS_CONTROL_ALLOW_ORIGIN,
-            axum::http::HeaderValue::from_static("*"),
-        ))
+    let semaphore: &'static Semaphore = Box::leak(Box::new(Semaphore::new(128)));
+    join_set.spawn(periodic_quarantine_flush(Arc::clone(&db), semaphore));
+    if format_upgrade {
+        join_set.spawn(schedule_format_upgrade(Arc::clone(&db), semaphore));
+    }
+    if let Some(compact_at) = opt.compact_at.filter(|_| !opt.import_only) {
+        join_set.spawn(scheduled_compaction(Arc::clone(&db), compact_at, semaphore));
+    }
+    if !opt.pinned_players.is_empty() && !opt.import_only {
+        join_set.spawn(periodic_pinned_players_index(
+            player_indexer.clone(),
+            opt.pinned_players,
+            Duration::from_secs(opt.pinned_players_interval),
+            semaphore,
+        ));
+    }
+
+    let metrics: &'static Metrics = Box::leak(Box::default());
+    let player_aliases: &'static PlayerAliases = Box::leak(Box::new(
+        PlayerAliases::load(opt.aliases).expect("masters player aliases"),
+    ));
+    if opt.log_slow_queries {
+        metrics.enable_slow_query_log();
+    }
+    let load_shedder: &'static LoadShedder = Box::leak(Box::new(LoadShedder::new(
+        opt.max_queries_in_flight,
+        metrics,
+    )));
+
+    let state = AppState {
+        admin_tokens: Box::leak(Box::new(AdminTokens::new(opt.admin_tokens))),
+        openings,
+        openings_opt,
+        openings_status,
+        readiness,
+        blacklist,
+        lichess_cache: Box::leak(Box::new(ReloadableCache::new(
+            opt.lichess_cache,
+            Duration::from_secs(60 * 60 * 2),
+            Duration::from_secs(60 * 10),
+        ))),
+        masters_cache: Box::leak(Box::new(ReloadableCache::new(
+            opt.masters_cache,
+            Duration::from_secs(60 * 60 * 4),
+            Duration::from_secs(60 * 10),
+        ))),
+        response_cache_ttl: Box::leak(Box::new(AtomicU64::new(
+            opt.response_cache_ttl.unwrap_or(0),
+        ))),
+        metrics,
+        load_shedder,
+        cloud_eval: Box::leak(Box::new(CloudEval::new(opt.cloud_eval))),
+        era: Box::leak(Box::new(
+            EraAdjustment::load(opt.era).expect("masters era adjustment"),
+        )),
+        annotator: Box::leak(Box::new(MoveAnnotator::new(opt.annotation))),
+        player_aliases,
+        tablebase: Box::leak(Box::new(Tablebase::new(opt.tablebase))),
+        query_log: Box::leak(Box::new(QueryLog::new(opt.query_log).expect("query log"))),
+        hot_positions: Box::leak(Box::default()),
+        trace_exporter: Box::leak(Box::new(TraceExporter::new(opt.trace))),
+        lichess_importer: LichessImporter::new(Arc::clone(&db), opt.lichess_importer),
+        masters_importer: MastersImporter::new(
+            Arc::clone(&db),
+            opt.masters_importer,
+            player_aliases,
+        ),
+        player_indexer,
+        db,
+        semaphore,
+    };
+
+    if let Some(path) = opt.runtime_config {
+        state.apply_runtime_config(&read_runtime_config(&path).expect("runtime config"));
+        join_set.spawn(reload_runtime_config_on_sighup(state.clone(), path));
+    }
+
+    if let Some(months) = opt.lichess_retention_months.filter(|_| !opt.import_only) {
+        join_set.spawn(periodic_lichess_retention(state.clone(), months));
+    }
+
+    let app = if opt.bind_admin.is_some() {
+        readiness.set_admin_app(admin_routes(opt.import_only).with_state(state.clone()));
+        if opt.import_only {
+            Router::new()
+        } else {
+            query_routes(&state).with_state(state)
+        }
     } else {
-        app
+        routes(state, opt.import_only)
+    };
+
+    let app = match opt.cors.layer() {
+        Some(cors) => app.layer(cors),
+        None => app,
     };
 
-    let listener = TcpListener::bind(&opt.bind).await.expect("bind");
-    axum::serve(listener, app).await.expect("serve");
+    match opt.warmup.filter(|_| !opt.import_only) {
+        Some(path) if opt.warmup_background => {
+            join_set.spawn(warmup(app.clone(), path));
+        }
+        Some(path) => warmup(app.clone(), path).await,
+        None => (),
+    }
+
+    readiness.set_app(app);
+    match admin_server {
+        Some(admin_server) => {
+            let (res, _) = future::select(server, admin_server).await.factor_first();
+            res.expect("server task").expect("serve");
+        }
+        None => server.await.expect("server task").expect("serve"),
+    }
 }
 
-async fn periodic_openings_import(openings: &'static RwLock<Openings>) {
+async fn periodic_openings_import(
+    openings: &'static RwLock<Openings>,
+    opt: &'static OpeningsOpt,
+    status: &'static OpeningsStatus,
+    readiness: &'static Readiness,
+) {
+    match Openings::load_cached(opt).await {
+        Some(Ok(cached)) => {
+            log::info!("loaded {} cached opening names", cached.len());
+            status.record_loaded(OpeningsOrigin::Cache, cached.len());
+            *openings.write().expect("write openings") = cached;
+            readiness.set_openings_loaded();
+        }
+        Some(Err(err)) => log::error!("failed to load cached opening names: {err}"),
+        None => (),
+    }
+
     loop {
-        match Openings::download().await {
+        let delay = match Openings::download(opt).await {
             Ok(new_openings) => {
                 log::info!("refreshed {} opening names", new_openings.len());
+                status.record_loaded(OpeningsOrigin::Source, new_openings.len());
                 *openings.write().expect("write openings") = new_openings;
+                readiness.set_openings_loaded();
+                REFRESH_INTERVAL
             }
             Err(err) => {
-                log::error!("failed to refresh opening names: {err}");
+                let failures = status.record_failure(&err);
+                let delay = retry_delay(failures);
+                log::error!(
+                    "failed to refresh opening names ({failures} times in a row), retrying in {delay:.0?}: {err}"
+                );
+                delay
+            }
+        };
+        status.record_next_attempt(delay);
+        time::sleep(delay).await;
+    }
+}
+
+async fn periodic_quarantine_flush(db: Arc<Database>, semaphore: &'static Semaphore) {
+    loop {
+        time::sleep(Duration::from_secs(60)).await;
+        let db = Arc::clone(&db);
+        match spawn_blocking(semaphore, move || db.flush_quarantine()).await {
+            Ok(0) => (),
+            Ok(n) => log::warn!("moved {n} corrupt values into the corrupt column family"),
+            Err(err) => log::error!("failed to quarantine corrupt values: {err}"),
+        }
+    }
+}
+
+async fn schedule_format_upgrade(db: Arc<Database>, semaphore: &'static Semaphore) {
+    match spawn_blocking(semaphore, move || db.schedule_format_upgrade()).await {
+        Ok(0) => log::info!("all entries are written in the current format"),
+        Ok(n) => log::warn!("scheduled {n} entries of older formats to be rewritten"),
+        Err(err) => log::error!("failed to schedule format upgrade: {err}"),
+    }
+}
+
+async fn scheduled_compaction(
+    db: Arc<Database>,
+    compact_at: CompactAt,
+    semaphore: &'static Semaphore,
+) {
+    loop {
+        time::sleep(compact_at.duration_until_next(SystemTime::now())).await;
+        let mut skipped = 0;
+        for name in COMPACTED_COLUMN_FAMILIES {
+            let db = Arc::clone(&db);
+            match spawn_blocking(semaphore, move || {
+                if db.is_write_pressured()? {
+                    return Ok(false);
+                }
+                db.compact_cf(name);
+                Ok::<_, rocksdb::Error>(true)
+            })
+            .await
+            {
+                Ok(true) => (),
+                Ok(false) => {
+                    log::warn!("skipping scheduled compaction for {name} under write pressure");
+                    skipped += 1;
+                }
+                Err(err) => {
+                    log::error!("skipping scheduled compaction for {name}: {err}");
+                    skipped += 1;
+                }
+            }
+        }
+        if skipped == 0 {
+            db.finish_compaction();
+        }
+    }
+}
+
+async fn periodic_pinned_players_index(
+    player_indexer: PlayerIndexerStub,
+    players: Vec<UserName>,
+    interval: Duration,
+    semaphore: &'static Semaphore,
+) {
+    loop {
+        let mut tickets = Vec::with_capacity(players.len());
+        for player in &players {
+            match player_indexer
+                .index_player(UserId::from(player.clone()), None, semaphore)
+                .await
+            {
+                Ok(ticket) => tickets.push(ticket),
+                Err(SubmitError::QueueFull(player)) => log::warn!(
+                    "not indexing pinned player {} because queue is full",
+                    player.as_lowercase_str()
+                ),
+                Err(SubmitError::QuotaExhausted(player)) => log::warn!(
+                    "not indexing pinned player {} because the quota is exhausted",
+                    player.as_lowercase_str()
+                ),
             }
         }
-        time::sleep(Duration::from_secs(60 * 167)).await;
+
+        // Hold on to tickets, otherwise queued players would be dropped.
+        for mut ticket in tickets {
+            ticket.completed().await;
+        }
+
+        time::sleep(interval).await;
     }
 }
 
@@ -359,8 +1071,11 @@ async fn monitor(
     State(lichess_cache): State<ExplorerCache<LichessQuery>>,
     State(masters_cache): State<ExplorerCache<MastersQuery>>,
     State(metrics): State<&'static Metrics>,
+    State(load_shedder): State<&'static LoadShedder>,
     State(player_indexer): State<PlayerIndexerStub>,
+    State(lichess_importer): State<LichessImporter>,
     State(blacklist): State<&'static RwLock<HashSet<UserId>>>,
+    State(openings_status): State<&'static OpeningsStatus>,
     State(db): State<Arc<Database>>,
     State(semaphore): State<&'static Semaphore>,
 ) -> String {
@@ -373,15 +1088,27 @@ async fn monitor(
                 format!("masters_cache={}u", masters_cache.entry_count()),
                 // Request metrics
                 metrics.to_influx_string(),
+                format!("in_flight={}u", load_shedder.in_flight()),
                 // Block cache
                 db.metrics().expect("db metrics").to_influx_string(),
                 // Indexer
                 format!("indexing={}u", player_indexer.num_indexing()),
+                format!("indexer_memory={}u", player_indexer.memory().usage()),
+                // Importer
+                format!(
+                    "lichess_import_sampled_out={}u",
+                    lichess_importer.num_sampled_out()
+                ),
                 // Blacklist
                 format!(
                     "blacklist={}u",
                     blacklist.read().expect("read blacklist").len()
                 ),
+                // Opening names
+                format!(
+                    "openings_stale_secs={}u",
+                    openings_status.staleness().as_secs()
+                ),
                 // Column families
                 db.masters()
                     .estimate_metrics()
@@ -391,6 +1118,12 @@ async fn monitor(
                     .estimate_metrics()
                     .expect("lichess metrics")
                     .to_influx_string(),
+                format!(
+                    "response_cache={}u",
+                    db.response_cache()
+                        .estimate_num_keys()
+                        .expect("response cache metrics")
+                ),
                 // Tokio
                 #[cfg(tokio_unstable)]
                 tokio_metrics_to_influx_string(),
@@ -402,85 +1135,341 @@ async fn monitor(
 }
 
 #[axum::debug_handler(state = AppState)]
-async fn compact(State(db): State<Arc<Database>>, State(semaphore): State<&'static Semaphore>) {
-    spawn_blocking(semaphore, move || db.compact()).await
-}
-
-#[axum::debug_handler(state = AppState)]
-async fn openings_import(
-    State(openings): State<&'static RwLock<Openings>>,
+async fn monitor_caches(
     State(lichess_cache): State<ExplorerCache<LichessQuery>>,
     State(masters_cache): State<ExplorerCache<MastersQuery>>,
-) -> Result<(), Error> {
-    let new_openings = Openings::download().await?;
-    log::info!("loaded {} opening names", new_openings.len());
-
-    let mut write_lock = openings.write().expect("write openings");
-    lichess_cache.invalidate_all();
-    masters_cache.invalidate_all();
-    *write_lock = new_openings;
-    Ok(())
+    State(db): State<Arc<Database>>,
+    State(semaphore): State<&'static Semaphore>,
+) -> Json<CachesMonitorResponse> {
+    Json(
+        spawn_blocking(semaphore, move || CachesMonitorResponse {
+            lichess_cache: lichess_cache.entry_count(),
+            masters_cache: masters_cache.entry_count(),
+            response_cache: db
+                .response_cache()
+                .estimate_num_keys()
+                .expect("response cache metrics"),
+        })
+        .await,
+    )
 }
 
-fn finalize_lichess_moves(
-    moves: Vec<PreparedMove>,
-    pos: &VariantPosition,
-    lichess_db: &LichessDatabase,
-    openings: &Openings,
-) -> Vec<ExplorerMove> {
-    moves
-        .into_iter()
-        .map(|p| {
-            let mut pos_after = pos.clone();
-            let san = p.uci.to_move(pos).map_or(
-                SanPlus {
-                    san: San::Null,
-                    suffix: None,
-                },
-                |m| SanPlus::from_move_and_play_unchecked(&mut pos_after, &m),
-            );
-            ExplorerMove {
-                stats: p.stats,
-                san,
-                uci: p.uci,
-                average_rating: p.average_rating,
-                average_opponent_rating: p.average_opponent_rating,
-                performance: p.performance,
-                game: p.game.and_then(|id| {
-                    lichess_db
-                        .game(id)
-                        .expect("get game")
-                        .map(|info| ExplorerGame::from_lichess(id, info))
-                }),
-                opening: openings.classify_exact(&pos_after).cloned(),
-            }
-        })
-        .collect()
+#[axum::debug_handler(state = AppState)]
+async fn monitor_indexer(
+    State(player_indexer): State<PlayerIndexerStub>,
+    State(lichess_importer): State<LichessImporter>,
+) -> Json<IndexerMonitorResponse> {
+    let memory = player_indexer.memory();
+    Json(IndexerMonitorResponse {
+        indexing: player_indexer.num_indexing(),
+        lichess_import_sampled_out: lichess_importer.num_sampled_out(),
+        memory: memory.usage(),
+        memory_by_indexer: memory.usage_by_actor(),
+        memory_limit: memory.limit(),
+        queue_remaining: player_indexer.remaining_capacity(),
+        games_per_second: player_indexer.throughput().games_per_sec(),
+        games_per_player: player_indexer.throughput().games_per_player(),
+    })
 }
 
-fn finalize_lichess_games(
-    games: Vec<(UciMove, GameId)>,
-    lichess_db: &LichessDatabase,
-    blacklist: &HashSet<UserId>,
-) -> Vec<ExplorerGameWithUciMove> {
-    lichess_db
-        .games(games.iter().map(|(_, id)| *id))
-        .expect("get games")
-        .into_iter()
-        .zip(games)
-        .filter_map(|(info, (uci, id))| {
-            info.filter(|info| {
-                info.players
-                    .iter()
-                    .filter_map(|player| player.name.parse::<UserName>().ok().map(UserId::from))
-                    .all(|player_id| !blacklist.contains(&player_id))
-            })
-            .map(|info| ExplorerGameWithUciMove {
-                uci,
-                row: ExplorerGame::from_lichess(id, info),
-            })
+#[axum::debug_handler(state = AppState)]
+async fn monitor_rocksdb(
+    State(db): State<Arc<Database>>,
+    State(semaphore): State<&'static Semaphore>,
+) -> Json<RocksDbMonitorResponse> {
+    Json(
+        spawn_blocking(semaphore, move || RocksDbMonitorResponse {
+            db: db.metrics().expect("db metrics"),
+            masters: db.masters().estimate_metrics().expect("masters metrics"),
+            lichess: db.lichess().estimate_metrics().expect("lichess metrics"),
         })
-        .collect()
+        .await,
+    )
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn stats(
+    _: RequireAdmin,
+    State(db): State<Arc<Database>>,
+    State(semaphore): State<&'static Semaphore>,
+) -> Json<DbStats> {
+    Json(spawn_blocking(semaphore, move || db.stats().expect("db stats")).await)
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn compact(
+    RequireAdmin(actor): RequireAdmin,
+    State(db): State<Arc<Database>>,
+    State(semaphore): State<&'static Semaphore>,
+) {
+    audit(Arc::clone(&db), semaphore, actor, "compact", json!({})).await;
+    spawn_blocking(semaphore, move || db.compact()).await
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn migrate_column(
+    RequireAdmin(actor): RequireAdmin,
+    State(db): State<Arc<Database>>,
+    State(semaphore): State<&'static Semaphore>,
+    Query(query): Query<MigrateQuery>,
+) -> Result<Json<MigrationProgress>, Error> {
+    let version = query
+        .cf
+        .migration_target(query.to_version)
+        .ok_or(Error::MigrationVersion {
+            cf: query.cf.name(),
+            version: query.cf.format_version(),
+        })?;
+    let rate = query.rate.unwrap_or(NonZeroU64::new(20_000).unwrap());
+    audit(
+        Arc::clone(&db),
+        semaphore,
+        actor,
+        "migrate",
+        json!({ "cf": query.cf.name(), "toVersion": query.to_version, "rate": rate }),
+    )
+    .await;
+    Ok(Json(
+        spawn_blocking(semaphore, move || {
+            log::info!("migrating {} to {version} ...", query.cf.name());
+            let progress = db
+                .migrate(query.cf, rate, |progress| {
+                    log::info!(
+                        "{}: scanned {} of ~{} entries, scheduled {}, failed {}",
+                        query.cf.name(),
+                        progress.scanned,
+                        progress.estimated_keys,
+                        progress.scheduled,
+                        progress.failed
+                    );
+                })
+                .expect("migrate");
+            log::info!("finished migrating {} to {version}", query.cf.name());
+            progress
+        })
+        .await,
+    ))
+}
+
+async fn audit(
+    db: Arc<Database>,
+    semaphore: &'static Semaphore,
+    actor: AdminActor,
+    operation: &'static str,
+    params: serde_json::Value,
+) {
+    let entry = AuditEntry {
+        at: SystemTime::now(),
+        operation: operation.to_owned(),
+        ip: actor.ip,
+        token: actor.token,
+        params,
+    };
+    spawn_blocking(semaphore, move || {
+        db.audit().record(&entry).expect("record audit entry")
+    })
+    .await;
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn runtime_config(_: RequireAdmin, State(state): State<AppState>) -> Json<RuntimeConfig> {
+    Json(state.runtime_config())
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn runtime_config_update(
+    RequireAdmin(actor): RequireAdmin,
+    State(state): State<AppState>,
+    Json(config): Json<RuntimeConfig>,
+) -> Json<RuntimeConfig> {
+    let params = serde_json::to_value(&config).expect("serialize runtime config");
+    audit(
+        Arc::clone(&state.db),
+        state.semaphore,
+        actor,
+        "config",
+        params,
+    )
+    .await;
+    state.apply_runtime_config(&config);
+    Json(state.runtime_config())
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn audit_log(
+    _: RequireAdmin,
+    State(db): State<Arc<Database>>,
+    State(semaphore): State<&'static Semaphore>,
+    Query(query): Query<AuditQuery>,
+) -> Json<AuditLogResponse> {
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+    let entries = spawn_blocking(semaphore, move || {
+        db.audit()
+            .entries(query.before.map(AuditKey), limit)
+            .expect("get audit entries")
+    })
+    .await;
+    Json(AuditLogResponse {
+        next: entries
+            .last()
+            .filter(|_| entries.len() >= limit)
+            .map(|(key, _)| key.0),
+        entries: entries
+            .into_iter()
+            .map(|(key, entry)| AuditLogEntry { id: key.0, entry })
+            .collect(),
+    })
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn indexer_queue(
+    _: RequireAdmin,
+    State(player_indexer): State<PlayerIndexerStub>,
+) -> Json<Vec<IndexerQueueEntry>> {
+    Json(
+        player_indexer
+            .queue_snapshot()
+            .into_iter()
+            .map(IndexerQueueEntry::from)
+            .collect(),
+    )
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn hot_positions(
+    _: RequireAdmin,
+    State(hot_positions): State<&'static HotPositions>,
+    Query(query): Query<HotPositionsQuery>,
+) -> Json<HotPositionsResponse> {
+    Json(HotPositionsResponse {
+        window: query.window,
+        positions: hot_positions.top(query.window, query.limit.unwrap_or(50).min(1000)),
+    })
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn openings_status(
+    _: RequireAdmin,
+    State(openings_status): State<&'static OpeningsStatus>,
+) -> Json<OpeningsStatusResponse> {
+    Json(openings_status.to_response())
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn indexer_lease(
+    _: RequireAdmin,
+    State(player_indexer): State<PlayerIndexerStub>,
+    State(semaphore): State<&'static Semaphore>,
+) -> Result<Json<Lease>, StatusCode> {
+    spawn_blocking(semaphore, move || player_indexer.lease())
+        .await
+        .map(Json)
+        .ok_or(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct LeaseSubmitQuery {
+    #[serde(default)]
+    done: bool,
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn indexer_lease_submit(
+    _: RequireAdmin,
+    State(player_indexer): State<PlayerIndexerStub>,
+    State(semaphore): State<&'static Semaphore>,
+    Path(id): Path<u64>,
+    Query(query): Query<LeaseSubmitQuery>,
+    mut body: Bytes,
+) -> Result<(), Error> {
+    let batch = LeaseBatch::read(&mut body).map_err(Error::InvalidLeaseBatch)?;
+    spawn_blocking(semaphore, move || {
+        player_indexer.submit_lease(id, batch, query.done)
+    })
+    .await
+    .map_err(|LeaseNotFound| Error::LeaseNotFound)
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn openings_import(
+    RequireImport(actor): RequireImport,
+    State(openings): State<&'static RwLock<Openings>>,
+    State(openings_opt): State<&'static OpeningsOpt>,
+    State(openings_status): State<&'static OpeningsStatus>,
+    State(readiness): State<&'static Readiness>,
+    State(lichess_cache): State<ExplorerCache<LichessQuery>>,
+    State(masters_cache): State<ExplorerCache<MastersQuery>>,
+    State(db): State<Arc<Database>>,
+    State(semaphore): State<&'static Semaphore>,
+) -> Result<(), Error> {
+    audit(
+        Arc::clone(&db),
+        semaphore,
+        actor,
+        "import_openings",
+        json!({}),
+    )
+    .await;
+    let new_openings = Openings::download(openings_opt).await.inspect_err(|err| {
+        openings_status.record_failure(err);
+    })?;
+    log::info!("loaded {} opening names", new_openings.len());
+    openings_status.record_loaded(OpeningsOrigin::Import, new_openings.len());
+
+    {
+        let mut write_lock = openings.write().expect("write openings");
+        lichess_cache.invalidate_all();
+        masters_cache.invalidate_all();
+        *write_lock = new_openings;
+    }
+    readiness.set_openings_loaded();
+
+    // Persisted responses include opening names, too.
+    spawn_blocking(semaphore, move || {
+        let response_cache = db.response_cache();
+        for namespace in ["lichess", "masters"] {
+            response_cache
+                .invalidate(namespace)
+                .expect("invalidate response cache");
+        }
+    })
+    .await;
+    Ok(())
+}
+
+/// Annotations of the queried position, fetched while the response is
+/// computed.
+struct Prefetched {
+    eval: Option<PendingEval>,
+    tablebase: Option<PendingTablebase>,
+}
+
+/// Resolves the queried position once per request, to count it as hot and
+/// to prefetch annotations for it.
+fn prefetch(
+    openings: &RwLock<Openings>,
+    hot_positions: &HotPositions,
+    cloud_eval: &CloudEval,
+    tablebase: &Tablebase,
+    endpoint: &'static str,
+    key_builder: KeyBuilder,
+    play: &Play,
+) -> Prefetched {
+    let Ok(QueriedPosition { key, pos, .. }) = QueriedPosition::new(
+        play.clone(),
+        key_builder,
+        &openings.read().expect("read openings"),
+    ) else {
+        return Prefetched {
+            eval: None,
+            tablebase: None,
+        };
+    };
+    hot_positions.record(endpoint, play.variant(), &key, pos.as_ref());
+    Prefetched {
+        eval: pos.as_ref().and_then(|pos| cloud_eval.prefetch(pos)),
+        tablebase: pos.as_ref().and_then(|pos| tablebase.prefetch(pos)),
+    }
 }
 
 struct PlayerStreamState {
@@ -504,26 +1493,46 @@ async fn player(
     State(player_indexer): State<PlayerIndexerStub>,
     State(metrics): State<&'static Metrics>,
     State(semaphore): State<&'static Semaphore>,
+    RequestSource(source): RequestSource,
     Query(query): Query<PlayerQuery>,
 ) -> Result<NdJson<impl Stream<Item = ExplorerResponse>>, Error> {
+    if let Some(ref callback_url) = query.callback_url {
+        if !player_indexer.is_callback_url(callback_url) {
+            return Err(Error::InvalidCallbackUrl);
+        }
+    }
+
     let player = UserId::from(query.player);
     let key_builder = KeyBuilder::player(&player, query.color);
     let ticket = player_indexer
-        .index_player(player, semaphore)
+        .index_player(player.clone(), source, semaphore)
         .await
-        .map_err(|QueueFull(player)| {
-            log::error!(
-                "not indexing {} because queue is full",
-                player.as_lowercase_str()
-            );
-            Error::IndexerQueueFull
+        .map_err(|err| match err {
+            SubmitError::QueueFull(player) => {
+                log::error!(
+                    "not indexing {} because queue is full",
+                    player.as_lowercase_str()
+                );
+                Error::IndexerQueueFull
+            }
+            SubmitError::QuotaExhausted(player) => {
+                log::warn!(
+                    "not indexing {} because source quota is exhausted",
+                    player.as_lowercase_str()
+                );
+                Error::IndexerQuotaExhausted
+            }
         })?;
     let PlayPosition { pos, opening } = query
         .play
         .position(&openings.read().expect("read openings"))?;
-    let cache_hint = CacheHint::from_ply(ply(&pos));
     let key = key_builder.with_zobrist(pos.variant(), pos.zobrist_hash(EnPassantMode::Legal));
 
+    let callback = query.callback_url.is_some();
+    if let Some(callback_url) = query.callback_url {
+        player_indexer.notify_on_completion(player, ticket.clone(), callback_url);
+    }
+
     let state = PlayerStreamState {
         player_indexer,
         color: query.color,
@@ -546,13 +1555,17 @@ async fn player(
             }
 
             let first = state.first_response.is_none();
-            state.done = tokio::select! {
+            state.done = callback || tokio::select! {
                 biased;
                 _ = state.ticket.completed() => true,
                 _ = tokio::time::sleep(Duration::from_millis(if first { 0 } else { 1000 })) => false,
             };
 
             let preceding_tickets = state.player_indexer.preceding_tickets(&state.ticket);
+            let queue_eta = (preceding_tickets > 0)
+                .then(|| state.player_indexer.throughput().estimate_wait(preceding_tickets))
+                .flatten()
+                .map(|eta| eta.as_secs());
 
             Some(match state.first_response {
                 Some(ref first_response) if preceding_tickets > 0 => {
@@ -560,6 +1573,7 @@ async fn player(
                     // first response with updated queue position.
                     let response = ExplorerResponse {
                         queue_position: Some(preceding_tickets),
+                        queue_eta,
                         ..first_response.clone()
                     };
                     (response, state)
@@ -568,27 +1582,26 @@ async fn player(
                     spawn_blocking(semaphore, move || {
                         let started_at = Instant::now();
 
-                        let lichess_db = state.db.lichess();
-                        let filtered = lichess_db
-                            .read_player(&state.key, state.filter.since, state.filter.until, cache_hint)
-                            .expect("read player")
-                            .prepare(state.color, &state.filter, &state.limits);
-
                         let response = ExplorerResponse {
-                            total: filtered.total,
-                            moves: finalize_lichess_moves(filtered.moves, &state.pos, &lichess_db, &openings.read().expect("read openings")),
-                            recent_games: Some(finalize_lichess_games(filtered.recent_games, &lichess_db, &HashSet::new())),
-                            top_games: None,
-                            history: None,
-                            opening: state.opening.clone(),
                             queue_position: Some(preceding_tickets),
+                            queue_eta,
+                            ..player_response(
+                                &state.db.lichess(),
+                                &openings.read().expect("read openings"),
+                                &state.key,
+                                &state.pos,
+                                state.opening.clone(),
+                                state.color,
+                                &state.filter,
+                                &state.limits,
+                            )
                         };
 
                         if state.first_response.is_none() {
                             state.first_response = Some(response.clone());
                         }
 
-                        metrics.inc_player(started_at.elapsed(), state.done, ply(&state.pos));
+                        metrics.inc_player(started_at.elapsed(), state.done, &state.pos);
                         (response, state)
                     }).await
                 }
@@ -597,13 +1610,346 @@ async fn player(
     ).dedup_by_key(|res| (res.queue_position, res.total.total()))))
 }
 
+#[axum::debug_handler(state = AppState)]
+async fn player_games(
+    State(openings): State<&'static RwLock<Openings>>,
+    State(db): State<Arc<Database>>,
+    State(semaphore): State<&'static Semaphore>,
+    Query(query): Query<PlayerGamesQuery>,
+) -> Result<Json<PlayerGamesResponse>, Error> {
+    let player = UserId::from(query.player);
+    let PlayPosition { pos, .. } = query
+        .play
+        .position(&openings.read().expect("read openings"))?;
+    let key = KeyBuilder::player(&player, query.color)
+        .with_zobrist(pos.variant(), pos.zobrist_hash(EnPassantMode::Legal));
+    let max_games = query.max.unwrap_or(50).min(500);
+
+    spawn_blocking(semaphore, move || {
+        let (games, next) = db
+            .lichess()
+            .player_games(&key, &query.filter, query.cursor, max_games)
+            .expect("get player games");
+        Ok(Json(PlayerGamesResponse {
+            games: games
+                .into_iter()
+                .map(|(month, uci, id)| PlayerGame { id, uci, month })
+                .collect(),
+            next,
+        }))
+    })
+    .await
+}
+
+/// Maximum depth of a player repertoire.
+const MAX_REPERTOIRE_DEPTH: u8 = 10;
+
+/// Bound on the number of positions read for each color of a player
+/// repertoire.
+const MAX_REPERTOIRE_NODES: usize = 500;
+
+struct RepertoireNode {
+    pos: VariantPosition,
+    uci: Vec<UciMove>,
+    san: Vec<SanPlus>,
+    last: Option<PreparedMove>,
+}
+
+/// Follow the most played moves of the player from the initial position,
+/// breadth first, and return the resulting lines, most played first.
+fn read_repertoire(
+    lichess_db: &LichessDatabase,
+    player: &UserId,
+    color: Color,
+    query: &PlayerRepertoireQuery,
+) -> Vec<RepertoireLine> {
+    let key_builder = KeyBuilder::player(player, color);
+    let limits = PlayerLimits {
+        moves: query.moves,
+        recent_games: 0,
+        time_breakdown: false,
+    };
+
+    let mut lines = Vec::new();
+    let mut visited = 0;
+    let mut queue = VecDeque::from([RepertoireNode {
+        pos: VariantPosition::new(query.variant),
+        uci: Vec::new(),
+        san: Vec::new(),
+        last: None,
+    }]);
+
+    while let Some(node) = queue.pop_front() {
+        let moves = if node.uci.len() < usize::from(query.depth) && visited < MAX_REPERTOIRE_NODES {
+            visited += 1;
+            let key = key_builder.with_zobrist(
+                node.pos.variant(),
+                node.pos.zobrist_hash(EnPassantMode::Legal),
+            );
+            lichess_db
+                .read_player(
+                    &key,
+                    query.filter.since,
+                    query.filter.until,
+                    CacheHint::from_ply(ply(&node.pos)),
+                )
+                .expect("get player")
+                .prepare(color, &query.filter, &limits)
+                .moves
+        } else {
+            Vec::new()
+        };
+
+        if moves.is_empty() {
+            if let Some(last) = node.last {
+                lines.push(RepertoireLine {
+                    uci: node.uci,
+                    san: node.san,
+                    stats: last.stats,
+                    performance: last.performance,
+                });
+            }
+            continue;
+        }
+
+        for p in moves {
+            let Ok(m) = p.uci.to_move(&node.pos) else {
+                continue;
+            };
+            let mut pos = node.pos.clone();
+            let mut san = node.san.clone();
+            san.push(SanPlus::from_move_and_play_unchecked(&mut pos, &m));
+            let mut uci = node.uci.clone();
+            uci.push(p.uci.clone());
+            queue.push_back(RepertoireNode {
+                pos,
+                uci,
+                san,
+                last: Some(p),
+            });
+        }
+    }
+
+    lines.sort_by_key(|line| Reverse(line.stats.total()));
+    lines.truncate(query.lines);
+    lines
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn player_repertoire(
+    State(db): State<Arc<Database>>,
+    State(semaphore): State<&'static Semaphore>,
+    Query(query): Query<PlayerRepertoireQuery>,
+) -> Result<Json<PlayerRepertoireResponse>, Error> {
+    if query.depth > MAX_REPERTOIRE_DEPTH {
+        return Err(Error::InvalidRepertoireQuery("depth is limited to 10"));
+    }
+
+    spawn_blocking(semaphore, move || {
+        let player = UserId::from(query.player.clone());
+        let lichess_db = db.lichess();
+        Ok(Json(PlayerRepertoireResponse {
+            white: read_repertoire(&lichess_db, &player, Color::White, &query),
+            black: read_repertoire(&lichess_db, &player, Color::Black, &query),
+        }))
+    })
+    .await
+}
+
+/// Maximum depth of an exported player study.
+const MAX_STUDY_DEPTH: u8 = 16;
+
+/// Bound on the number of positions read for each chapter of an exported
+/// player study.
+const MAX_STUDY_CHAPTER_NODES: usize = 1_000;
+
+/// Read the most played moves of the player in a position, depth first, with
+/// their continuations.
+fn read_study_moves(
+    lichess_db: &LichessDatabase,
+    key_builder: &KeyBuilder,
+    query: &PlayerExportQuery,
+    pos: &VariantPosition,
+    depth: u8,
+    budget: &mut usize,
+) -> Vec<StudyMove> {
+    if depth == 0 || *budget == 0 {
+        return Vec::new();
+    }
+    *budget -= 1;
+
+    let key = key_builder.with_zobrist(pos.variant(), pos.zobrist_hash(EnPassantMode::Legal));
+    lichess_db
+        .read_player(
+            &key,
+            query.filter.since,
+            query.filter.until,
+            CacheHint::from_ply(ply(pos)),
+        )
+        .expect("get player")
+        .prepare(
+            query.color,
+            &query.filter,
+            &PlayerLimits {
+                moves: query.moves,
+                recent_games: 0,
+                time_breakdown: false,
+            },
+        )
+        .moves
+        .into_iter()
+        .filter_map(|p| {
+            let m = p.uci.to_move(pos).ok()?;
+            let mut after = pos.clone();
+            let san = SanPlus::from_move_and_play_unchecked(&mut after, &m);
+            Some(StudyMove {
+                san,
+                stats: p.stats,
+                performance: p.performance,
+                last_played: p.last_played,
+                children: read_study_moves(
+                    lichess_db,
+                    key_builder,
+                    query,
+                    &after,
+                    depth - 1,
+                    budget,
+                ),
+            })
+        })
+        .collect()
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn player_export(
+    State(db): State<Arc<Database>>,
+    State(semaphore): State<&'static Semaphore>,
+    Query(query): Query<PlayerExportQuery>,
+) -> Result<Response, Error> {
+    if query.depth > MAX_STUDY_DEPTH {
+        return Err(Error::InvalidRepertoireQuery("depth is limited to 16"));
+    }
+
+    let key_builder = Arc::new(KeyBuilder::player(
+        &UserId::from(query.player.clone()),
+        query.color,
+    ));
+    let query = Arc::new(query);
+    let root = VariantPosition::new(query.variant);
+
+    // One chapter for each of the most played moves of the initial
+    // position, streamed as soon as it is complete.
+    let first_moves = spawn_blocking(semaphore, {
+        let db = Arc::clone(&db);
+        let key_builder = Arc::clone(&key_builder);
+        let query = Arc::clone(&query);
+        let root = root.clone();
+        move || {
+            let mut budget = 1;
+            read_study_moves(&db.lichess(), &key_builder, &query, &root, 1, &mut budget)
+        }
+    })
+    .await;
+
+    let chapters = futures_util::stream::iter(first_moves).then(move |mut first_move| {
+        let db = Arc::clone(&db);
+        let key_builder = Arc::clone(&key_builder);
+        let query = Arc::clone(&query);
+        let root = root.clone();
+        spawn_blocking(semaphore, move || {
+            let mut pos = root;
+            let m = first_move.san.san.to_move(&pos).expect("replay first move");
+            pos.play_unchecked(&m);
+            let mut budget = MAX_STUDY_CHAPTER_NODES;
+            first_move.children = read_study_moves(
+                &db.lichess(),
+                &key_builder,
+                &query,
+                &pos,
+                query.depth.saturating_sub(1),
+                &mut budget,
+            );
+
+            let mut buf = Vec::new();
+            StudyChapter {
+                player: &query.player.to_string(),
+                color: query.color,
+                variant: query.variant,
+                first_move: &first_move,
+            }
+            .write_pgn(&mut buf)
+            .map(|_| Bytes::from(buf))
+        })
+    });
+
+    Ok(Response::builder()
+        .header(axum::http::header::CONTENT_TYPE, "application/x-chess-pgn")
+        .body(Body::from_stream(chapters))
+        .unwrap())
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn player_status(
+    State(db): State<Arc<Database>>,
+    State(semaphore): State<&'static Semaphore>,
+    Query(query): Query<PlayerStatusQuery>,
+) -> Result<Json<PlayerStatusResponse>, StatusCode> {
+    let player = UserId::from(query.player);
+    let key = KeyBuilder::player(&player, query.color).with_zobrist(
+        query.variant,
+        VariantPosition::new(query.variant).zobrist_hash(EnPassantMode::Legal),
+    );
+    spawn_blocking(semaphore, move || {
+        let lichess_db = db.lichess();
+        let status = lichess_db
+            .player_status(&player)
+            .expect("get player status")
+            .ok_or(StatusCode::NOT_FOUND)?;
+        let history = lichess_db.player_history(&key).expect("get player history");
+        Ok(Json(PlayerStatusResponse::new(status, history)))
+    })
+    .await
+}
+
 #[axum::debug_handler(state = AppState)]
 async fn masters_import(
+    RequireImport(actor): RequireImport,
     State(importer): State<MastersImporter>,
+    State(db): State<Arc<Database>>,
     State(semaphore): State<&'static Semaphore>,
+    Query(query): Query<MastersImportQuery>,
     Json(body): Json<MastersGameWithId>,
-) -> Result<(), Error> {
-    spawn_blocking(semaphore, move || importer.import(body)).await
+) -> Result<Response, Error> {
+    if !query.dry_run {
+        let params = json!({ "id": body.id.to_string() });
+        audit(db, semaphore, actor, "import_masters", params).await;
+    }
+    let report = spawn_blocking(semaphore, move || importer.import(body, query.dry_run)).await?;
+    // Successful imports respond with an empty body, as before dry runs
+    // were introduced.
+    Ok(if query.dry_run {
+        Json(report).into_response()
+    } else {
+        ().into_response()
+    })
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn masters_import_pgn(
+    RequireImport(actor): RequireImport,
+    State(importer): State<MastersImporter>,
+    State(db): State<Arc<Database>>,
+    State(semaphore): State<&'static Semaphore>,
+    Query(query): Query<MastersImportQuery>,
+    body: Bytes,
+) -> Result<Json<Vec<MastersPgnImportResult>>, Error> {
+    if !query.dry_run {
+        let params = json!({ "bytes": body.len() });
+        audit(db, semaphore, actor, "import_masters_pgn", params).await;
+    }
+    spawn_blocking(semaphore, move || importer.import_pgn(&body, query.dry_run))
+        .await
+        .map(Json)
 }
 
 #[serde_as]
@@ -625,179 +1971,1225 @@ async fn masters_pgn(
     .await
 }
 
+/// Whether every token of `query` is a prefix of some token of `text`.
+fn matches_search(text: &str, query: &[String]) -> bool {
+    let tokens: Vec<String> = search_tokens(text).collect();
+    query
+        .iter()
+        .all(|q| tokens.iter().any(|token| token.starts_with(q.as_str())))
+}
+
 #[axum::debug_handler(state = AppState)]
-async fn masters(
-    State(openings): State<&'static RwLock<Openings>>,
+async fn games_search(
     State(db): State<Arc<Database>>,
-    State(masters_cache): State<ExplorerCache<MastersQuery>>,
-    State(metrics): State<&'static Metrics>,
     State(semaphore): State<&'static Semaphore>,
-    Query(WithSource { query, source }): Query<WithSource<MastersQuery>>,
-) -> Result<Json<ExplorerResponse>, Error> {
-    masters_cache
-        .get_with(query.clone(), async move {
-            spawn_blocking(semaphore, move || {
-                let started_at = Instant::now();
-                let openings = openings.read().expect("read openings");
-                let PlayPosition { pos, opening } = query.play.position(&openings)?;
+    Query(query): Query<GameSearchQuery>,
+) -> Result<Json<Vec<ExplorerGame>>, Error> {
+    let terms: Vec<(SearchField, Vec<String>)> = [
+        (SearchField::White, query.white),
+        (SearchField::Black, query.black),
+        (SearchField::Event, query.event),
+    ]
+    .into_iter()
+    .filter_map(|(field, text)| text.map(|text| (field, search_tokens(&text).collect())))
+    .filter(|(_, tokens): &(SearchField, Vec<String>)| !tokens.is_empty())
+    .collect();
 
-                let key = KeyBuilder::masters()
-                    .with_zobrist(pos.variant(), pos.zobrist_hash(EnPassantMode::Legal));
-                let cache_hint = CacheHint::from_ply(ply(&pos));
+    let Some((field, token)) = terms
+        .first()
+        .map(|(field, tokens)| (*field, tokens[0].clone()))
+    else {
+        return Err(Error::InvalidGameSearch("white, black, or event required"));
+    };
+    if query.db == SearchSource::Lichess && terms.iter().any(|(f, _)| *f == SearchField::Event) {
+        return Err(Error::InvalidGameSearch("lichess games have no event"));
+    }
+
+    let since = query.since.unwrap_or_else(Month::min_value);
+    let until = query.until.unwrap_or_else(Month::max_value);
+    let max = query.max.unwrap_or(20).min(100);
+
+    spawn_blocking(semaphore, move || {
+        let search = db.game_search().ok_or(Error::GameSearchDisabled)?;
+        let ids = search
+            .search(query.db, field, &token, since, until, 10_000)
+            .expect("search games");
+
+        let mut games = Vec::new();
+        match query.db {
+            SearchSource::Masters => {
                 let masters_db = db.masters();
-                let entry = masters_db
-                    .read(key, query.since, query.until, cache_hint)
-                    .expect("get masters")
-                    .prepare(&query.limits);
-
-                let response = Ok(Json(ExplorerResponse {
-                    total: entry.total,
-                    moves: entry
-                        .moves
-                        .into_iter()
-                        .map(|p| {
-                            let mut pos_after = pos.clone();
-                            let san = p.uci.to_move(&pos).map_or(
-                                SanPlus {
-                                    san: San::Null,
-                                    suffix: None,
-                                },
-                                |m| SanPlus::from_move_and_play_unchecked(&mut pos_after, &m),
-                            );
-                            ExplorerMove {
-                                san,
-                                uci: p.uci,
-                                average_rating: p.average_rating,
-                                average_opponent_rating: p.average_opponent_rating,
-                                performance: p.performance,
-                                stats: p.stats,
-                                game: p.game.and_then(|id| {
-                                    masters_db
-                                        .game(id)
-                                        .expect("get masters game")
-                                        .map(|info| ExplorerGame::from_masters(id, info))
-                                }),
-                                opening: openings.classify_exact(&pos_after).cloned(),
-                            }
-                        })
-                        .collect(),
-                    top_games: Some(
-                        masters_db
-                            .games(entry.top_games.iter().map(|(_, id)| *id))
-                            .expect("get masters games")
-                            .into_iter()
-                            .zip(entry.top_games.into_iter())
-                            .filter_map(|(info, (uci, id))| {
-                                info.map(|info| ExplorerGameWithUciMove {
-                                    uci: uci.clone(),
-                                    row: ExplorerGame::from_masters(id, info),
+                for (id, game) in ids.iter().zip(
+                    masters_db
+                        .games(ids.iter().copied())
+                        .expect("get masters games"),
+                ) {
+                    let Some(game) = game else { continue };
+                    if terms.iter().all(|(field, tokens)| {
+                        matches_search(
+                            match field {
+                                SearchField::White => &game.players.white.name,
+                                SearchField::Black => &game.players.black.name,
+                                SearchField::Event => &game.event,
+                            },
+                            tokens,
+                        )
+                    }) {
+                        games.push(ExplorerGame::from_masters(*id, game));
+                        if games.len() >= max {
+                            break;
+                        }
+                    }
+                }
+            }
+            SearchSource::Lichess => {
+                let lichess_db = db.lichess();
+                for (id, game) in ids.iter().zip(
+                    lichess_db
+                        .games(ids.iter().copied())
+                        .expect("get lichess games"),
+                ) {
+                    let Some(game) = game else { continue };
+                    if terms.iter().all(|(field, tokens)| {
+                        matches_search(
+                            &game
+                                .players
+                                .get(match field {
+                                    SearchField::White => Color::White,
+                                    _ => Color::Black,
                                 })
-                            })
-                            .collect(),
-                    ),
-                    opening,
-                    recent_games: None,
-                    queue_position: None,
-                    history: None,
-                }));
-
-                metrics.inc_masters(started_at.elapsed(), source, ply(&pos));
-                response
-            })
-            .await
-        })
-        .await
+                                .name,
+                            tokens,
+                        )
+                    }) {
+                        games.push(ExplorerGame::from_lichess(*id, game));
+                        if games.len() >= max {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(Json(games))
+    })
+    .await
 }
 
+#[serde_as]
+#[derive(Deserialize)]
+struct LichessGameId(#[serde_as(as = "DisplayFromStr")] GameId);
+
 #[axum::debug_handler(state = AppState)]
-async fn lichess_import(
-    State(importer): State<LichessImporter>,
+async fn lichess_pgn(
+    Path(LichessGameId(id)): Path<LichessGameId>,
+    State(db): State<Arc<Database>>,
     State(semaphore): State<&'static Semaphore>,
-    Json(body): Json<Vec<LichessGameImport>>,
-) -> Result<(), Error> {
+) -> Result<LichessGamePgn, StatusCode> {
+    spawn_blocking(semaphore, move || {
+        let lichess_db = db.lichess();
+        match (
+            lichess_db.game(id).expect("get lichess game"),
+            lichess_db.game_moves(id).expect("get lichess game moves"),
+        ) {
+            (Some(info), Some(moves)) => Ok(LichessGamePgn { id, info, moves }),
+            _ => Err(StatusCode::NOT_FOUND),
+        }
+    })
+    .await
+}
+
+/// Answers a query from the response cache or the database. Must be called
+/// from a blocking context.
+#[allow(clippy::too_many_arguments)]
+fn read_masters_response(
+    db: &Database,
+    openings: &RwLock<Openings>,
+    era: &EraAdjustment,
+    annotator: &MoveAnnotator,
+    response_cache_ttl: Option<Duration>,
+    metrics: &Metrics,
+    query: MastersQuery,
+    source: Option<Source>,
+) -> Result<Json<ExplorerResponse>, Error> {
+    let response_cache = db.response_cache();
+    let response_cache_key = response_cache
+        .key("masters", &query.canonical())
+        .expect("get response cache key");
+    if response_cache_ttl.is_some() {
+        if let Some(response) = response_cache.get(&response_cache_key) {
+            metrics.inc_response_cache_hit();
+            return Ok(Json(response));
+        }
+    }
+
+    let started_at = Instant::now();
+    let (response, pos) = masters_response(
+        db,
+        &openings.read().expect("read openings"),
+        era,
+        annotator,
+        query,
+    )?;
+    let truncated = response.truncated;
+
+    if let Some(ttl) = response_cache_ttl.filter(|_| !truncated) {
+        response_cache.put(&response_cache_key, &response, ttl);
+    }
+
+    metrics.inc_masters(started_at.elapsed(), source, pos.as_ref());
+    Ok(Json(response))
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn masters(
+    State(openings): State<&'static RwLock<Openings>>,
+    State(db): State<Arc<Database>>,
+    State(masters_cache): State<ExplorerCache<MastersQuery>>,
+    State(response_cache_ttl): State<Option<Duration>>,
+    State(metrics): State<&'static Metrics>,
+    State(cloud_eval): State<&'static CloudEval>,
+    State(tablebase): State<&'static Tablebase>,
+    State(era): State<&'static EraAdjustment>,
+    State(annotator): State<&'static MoveAnnotator>,
+    State(semaphore): State<&'static Semaphore>,
+    State(query_log): State<&'static QueryLog>,
+    State(hot_positions): State<&'static HotPositions>,
+    format: ResponseFormat,
+    RawQuery(raw_query): RawQuery,
+    Query(WithSource { mut query, source }): Query<WithSource<MastersQuery>>,
+) -> Result<Formatted<Selected<ExplorerResponse>>, Error> {
+    let requested_at = Instant::now();
+    let fields = query.fields;
+    if let Some(fields) = fields {
+        fields.restrict(&mut query.limits);
+    }
+    let prefetched = prefetch(
+        openings,
+        hot_positions,
+        cloud_eval,
+        tablebase,
+        "masters",
+        KeyBuilder::masters(),
+        &query.play,
+    );
+    let cache_key = query.clone();
+    let entry = masters_cache
+        .entry(cache_key.clone())
+        .or_insert_with(async move {
+            spawn_blocking(semaphore, move || {
+                read_masters_response(
+                    &db,
+                    openings,
+                    era,
+                    annotator,
+                    response_cache_ttl,
+                    metrics,
+                    query,
+                    source,
+                )
+            })
+            .await
+        })
+        .await;
+    let cache_hit = !entry.is_fresh();
+    let mut res = entry.into_value();
+
+    if matches!(
+        res,
+        Ok(Json(ExplorerResponse {
+            truncated: true,
+            ..
+        }))
+    ) {
+        // Do not keep serving incomplete counts.
+        masters_cache.invalidate(&cache_key).await;
+    }
+
+    if let (Some(pending_eval), Ok(Json(response))) = (prefetched.eval, &mut res) {
+        cloud_eval.annotate(pending_eval, &mut response.moves).await;
+    }
+
+    if let (Some(pending_tablebase), Ok(Json(response))) = (prefetched.tablebase, &mut res) {
+        tablebase
+            .annotate(pending_tablebase, &mut response.moves)
+            .await;
+    }
+
+    if query_log.sample() {
+        query_log.record(QueryLogEntry::new(
+            "masters",
+            raw_query,
+            cache_hit,
+            requested_at.elapsed(),
+            res.as_ref().map(|Json(response)| response),
+        ));
+    }
+
+    res.map(|Json(response)| Formatted(format, Selected { response, fields }))
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn lichess_import(
+    RequireImport(actor): RequireImport,
+    State(importer): State<LichessImporter>,
+    State(db): State<Arc<Database>>,
+    State(semaphore): State<&'static Semaphore>,
+    Json(body): Json<Vec<LichessGameImport>>,
+) -> Result<(), Error> {
+    let params = json!({ "games": body.len() });
+    audit(db, semaphore, actor, "import_lichess", params).await;
     spawn_blocking(semaphore, move || importer.import_many(body)).await
 }
 
+#[axum::debug_handler(state = AppState)]
+async fn lichess_import_complete(
+    RequireImport(actor): RequireImport,
+    State(importer): State<LichessImporter>,
+    State(db): State<Arc<Database>>,
+    State(semaphore): State<&'static Semaphore>,
+    Query(query): Query<ImportCompleteQuery>,
+) {
+    let params = json!({ "month": query.month.to_string() });
+    audit(db, semaphore, actor, "import_lichess_complete", params).await;
+    spawn_blocking(semaphore, move || importer.complete_month(query.month)).await
+}
+
+#[serde_as]
+#[derive(Deserialize)]
+struct MonthParam(#[serde_as(as = "DisplayFromStr")] Month);
+
+#[axum::debug_handler(state = AppState)]
+async fn lichess_delete_month(
+    RequireAdmin(actor): RequireAdmin,
+    State(importer): State<LichessImporter>,
+    State(lichess_cache): State<ExplorerCache<LichessQuery>>,
+    State(db): State<Arc<Database>>,
+    State(semaphore): State<&'static Semaphore>,
+    Path(MonthParam(month)): Path<MonthParam>,
+) -> Json<MonthDeletion> {
+    let params = json!({ "month": month.to_string() });
+    audit(db, semaphore, actor, "delete_lichess_month", params).await;
+    let deletion = spawn_blocking(semaphore, move || importer.delete_month(month)).await;
+    lichess_cache.invalidate_all();
+    Json(deletion)
+}
+
+#[serde_as]
+#[derive(Deserialize)]
+struct VariantParam(#[serde_as(as = "DisplayFromStr")] Variant);
+
+#[axum::debug_handler(state = AppState)]
+async fn lichess_delete_variant(
+    RequireAdmin(actor): RequireAdmin,
+    State(importer): State<LichessImporter>,
+    State(lichess_cache): State<ExplorerCache<LichessQuery>>,
+    State(db): State<Arc<Database>>,
+    State(semaphore): State<&'static Semaphore>,
+    Path(VariantParam(variant)): Path<VariantParam>,
+) -> Result<(), Error> {
+    let params = json!({ "variant": variant.uci() });
+    audit(db, semaphore, actor, "delete_lichess_variant", params).await;
+    if !spawn_blocking(semaphore, move || importer.delete_variant(variant)).await {
+        return Err(Error::VariantNotSeparate);
+    }
+    lichess_cache.invalidate_all();
+    Ok(())
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn lichess_split_variants(
+    RequireAdmin(actor): RequireAdmin,
+    State(importer): State<LichessImporter>,
+    State(db): State<Arc<Database>>,
+    State(semaphore): State<&'static Semaphore>,
+) -> Json<VariantSplit> {
+    audit(db, semaphore, actor, "split_lichess_variants", json!({})).await;
+    Json(spawn_blocking(semaphore, move || importer.split_variants()).await)
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn import_status(
+    _: RequireAdmin,
+    State(db): State<Arc<Database>>,
+    State(semaphore): State<&'static Semaphore>,
+) -> Json<Vec<ImportStatusResponse>> {
+    let statuses = spawn_blocking(semaphore, move || {
+        db.lichess().import_status().expect("get import status")
+    })
+    .await;
+
+    // Include months without any imported games, so that gaps stand out.
+    let mut response = Vec::with_capacity(statuses.len());
+    let mut next_month: Option<Month> = None;
+    for (month, status) in statuses {
+        while let Some(gap) = next_month.filter(|gap| *gap < month) {
+            response.push(ImportStatusResponse::new(gap, Default::default()));
+            next_month = Some(gap.add_months_saturating(1));
+        }
+        response.push(ImportStatusResponse::new(month, status));
+        next_month = Some(month.add_months_saturating(1));
+    }
+    Json(response)
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn player_import(
+    RequireImport(actor): RequireImport,
+    State(importer): State<LichessImporter>,
+    State(db): State<Arc<Database>>,
+    State(semaphore): State<&'static Semaphore>,
+    Query(query): Query<PlayerImportQuery>,
+    Json(body): Json<Vec<LichessGameImport>>,
+) -> Result<(), Error> {
+    let params = json!({
+        "players": query.players.iter().map(UserName::to_string).collect::<Vec<_>>(),
+        "games": body.len(),
+    });
+    audit(db, semaphore, actor, "import_player", params).await;
+    let players: HashSet<UserId> = query.players.into_iter().map(UserId::from).collect();
+    spawn_blocking(semaphore, move || {
+        importer.import_players_many(body, &players)
+    })
+    .await
+}
+
+/// Answers a query from the response cache or the database. Must be called
+/// from a blocking context.
+#[allow(clippy::too_many_arguments)]
+fn read_lichess_response(
+    db: &Database,
+    openings: &RwLock<Openings>,
+    blacklist: &RwLock<HashSet<UserId>>,
+    annotator: &MoveAnnotator,
+    response_cache_ttl: Option<Duration>,
+    metrics: &Metrics,
+    query: LichessQuery,
+    source: Option<Source>,
+) -> Result<Json<ExplorerResponse>, Error> {
+    let response_cache = db.response_cache();
+    let response_cache_key = response_cache
+        .key("lichess", &query.canonical())
+        .expect("get response cache key");
+    if response_cache_ttl.is_some() {
+        if let Some(response) = response_cache.get(&response_cache_key) {
+            metrics.inc_response_cache_hit();
+            return Ok(Json(response));
+        }
+    }
+
+    let started_at = Instant::now();
+
+    let variant = query.play.variant();
+    let (response, pos) = lichess_response(
+        db,
+        &openings.read().expect("read openings"),
+        &blacklist.read().expect("read blacklist"),
+        annotator,
+        query,
+    )?;
+    let truncated = response.truncated;
+
+    if let Some(ttl) = response_cache_ttl.filter(|_| !truncated) {
+        response_cache.put(&response_cache_key, &response, ttl);
+    }
+
+    metrics.inc_lichess(started_at.elapsed(), source, variant, pos.as_ref());
+    Ok(Json(response))
+}
+
 #[axum::debug_handler(state = AppState)]
 async fn lichess(
     State(openings): State<&'static RwLock<Openings>>,
     State(blacklist): State<&'static RwLock<HashSet<UserId>>>,
+    State(annotator): State<&'static MoveAnnotator>,
     State(db): State<Arc<Database>>,
     State(lichess_cache): State<ExplorerCache<LichessQuery>>,
+    State(response_cache_ttl): State<Option<Duration>>,
     State(metrics): State<&'static Metrics>,
+    State(cloud_eval): State<&'static CloudEval>,
+    State(tablebase): State<&'static Tablebase>,
     State(semaphore): State<&'static Semaphore>,
-    Query(WithSource { query, source }): Query<WithSource<LichessQuery>>,
+    State(query_log): State<&'static QueryLog>,
+    State(hot_positions): State<&'static HotPositions>,
+    RawQuery(raw_query): RawQuery,
+    Query(WithSource { mut query, source }): Query<WithSource<LichessQuery>>,
 ) -> Result<Json<ExplorerResponse>, Error> {
-    lichess_cache
-        .get_with(query.clone(), async move {
+    let requested_at = Instant::now();
+    if let Some(fields) = query.fields {
+        fields.restrict(&mut query.limits);
+    }
+    let prefetched = prefetch(
+        openings,
+        hot_positions,
+        cloud_eval,
+        tablebase,
+        "lichess",
+        KeyBuilder::lichess(),
+        &query.play,
+    );
+    let cache_key = query.clone();
+    let entry = lichess_cache
+        .entry(cache_key.clone())
+        .or_insert_with(async move {
             spawn_blocking(semaphore, move || {
-                let started_at = Instant::now();
+                read_lichess_response(
+                    &db,
+                    openings,
+                    blacklist,
+                    annotator,
+                    response_cache_ttl,
+                    metrics,
+                    query,
+                    source,
+                )
+            })
+            .await
+        })
+        .await;
+    let cache_hit = !entry.is_fresh();
+    let mut res = entry.into_value();
 
-                let openings = openings.read().expect("read openings");
-                let PlayPosition { pos, opening } = query.play.position(&openings)?;
+    if matches!(
+        res,
+        Ok(Json(ExplorerResponse {
+            truncated: true,
+            ..
+        }))
+    ) {
+        // Do not keep serving incomplete counts.
+        lichess_cache.invalidate(&cache_key).await;
+    }
 
-                let key = KeyBuilder::lichess()
-                    .with_zobrist(pos.variant(), pos.zobrist_hash(EnPassantMode::Legal));
-                let cache_hint = CacheHint::from_ply(ply(&pos));
-                let lichess_db = db.lichess();
-                let (filtered, history) = lichess_db
-                    .read_lichess(
-                        &key,
-                        &query.filter,
-                        &query.limits,
-                        query.history,
-                        cache_hint,
-                    )
-                    .expect("get lichess");
-
-                let blacklist = blacklist.read().expect("read blacklist");
-                let response = Ok(Json(ExplorerResponse {
-                    total: filtered.total,
-                    moves: finalize_lichess_moves(filtered.moves, &pos, &lichess_db, &openings),
-                    recent_games: Some(finalize_lichess_games(
-                        filtered.recent_games,
-                        &lichess_db,
-                        &blacklist,
-                    )),
-                    top_games: Some(finalize_lichess_games(
-                        filtered.top_games,
-                        &lichess_db,
-                        &blacklist,
-                    )),
-                    opening,
-                    history,
-                    queue_position: None,
-                }));
-
-                metrics.inc_lichess(started_at.elapsed(), source, ply(&pos));
-                response
-            })
+    if let (Some(pending_eval), Ok(Json(response))) = (prefetched.eval, &mut res) {
+        cloud_eval.annotate(pending_eval, &mut response.moves).await;
+    }
+
+    if let (Some(pending_tablebase), Ok(Json(response))) = (prefetched.tablebase, &mut res) {
+        tablebase
+            .annotate(pending_tablebase, &mut response.moves)
+            .await;
+    }
+
+    if query_log.sample() {
+        query_log.record(QueryLogEntry::new(
+            "lichess",
+            raw_query,
+            cache_hit,
+            requested_at.elapsed(),
+            res.as_ref().map(|Json(response)| response),
+        ));
+    }
+
+    res
+}
+
+/// Answers a `/lichess` query for endpoints that answer several queries per
+/// request.
+async fn lichess_from_state(
+    state: AppState,
+    raw_query: Option<String>,
+    with_source: WithSource<LichessQuery>,
+) -> Result<Json<ExplorerResponse>, Error> {
+    lichess(
+        State(state.openings),
+        State(state.blacklist),
+        State(state.annotator),
+        State(Arc::clone(&state.db)),
+        State(FromRef::from_ref(&state)),
+        State(FromRef::from_ref(&state)),
+        State(state.metrics),
+        State(state.cloud_eval),
+        State(state.tablebase),
+        State(state.semaphore),
+        State(state.query_log),
+        State(state.hot_positions),
+        RawQuery(raw_query),
+        Query(with_source),
+    )
+    .await
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn lichess_or_variants(
+    State(state): State<AppState>,
+    format: ResponseFormat,
+    RawQuery(raw_query): RawQuery,
+    Query(VariantsQuery { variants }): Query<VariantsQuery>,
+    Query(with_source): Query<WithSource<LichessQuery>>,
+) -> Response {
+    let fields = with_source.query.fields;
+    match variants {
+        None => lichess_from_state(state, raw_query, with_source)
             .await
+            .map(|Json(response)| Formatted(format, Selected { response, fields }))
+            .into_response(),
+        Some(variants) => {
+            let mut with_source = with_source;
+            if let Some(fields) = fields {
+                fields.restrict(&mut with_source.query.limits);
+            }
+            lichess_variants(state, raw_query, variants, with_source)
+                .await
+                .map(|Json(responses)| {
+                    let responses: BTreeMap<_, _> = responses
+                        .into_iter()
+                        .map(|(name, res)| {
+                            let res = BatchResponse {
+                                response: res
+                                    .response
+                                    .map(|response| Selected { response, fields }),
+                                error: res.error,
+                            };
+                            (name, res)
+                        })
+                        .collect();
+                    Formatted(format, responses)
+                })
+                .into_response()
+        }
+    }
+}
+
+/// Answers the same query for several variants, keyed by the requested
+/// variant names. Each variant is answered like a single query, sharing its
+/// cache entry.
+async fn lichess_variants(
+    state: AppState,
+    raw_query: Option<String>,
+    variants: Vec<String>,
+    WithSource { query, source }: WithSource<LichessQuery>,
+) -> Result<Json<BTreeMap<String, BatchResponse>>, Error> {
+    let mut queries: Vec<(String, LichessQuery)> = Vec::with_capacity(variants.len());
+    for name in variants {
+        let variant: Variant = name
+            .parse()
+            .map_err(|_| Error::InvalidVariants(format!("unknown variant {name:?}")))?;
+        if queries.iter().all(|(_, q)| q.play.variant() != variant) {
+            let mut query = query.clone();
+            query.play.set_variant(variant);
+            queries.push((name, query));
+        }
+    }
+
+    let results = future::join_all(queries.into_iter().map(|(name, query)| {
+        let state = state.clone();
+        let raw_query = raw_query.clone();
+        async move {
+            let res = match lichess_from_state(state, raw_query, WithSource { query, source }).await
+            {
+                Ok(Json(response)) => BatchResponse {
+                    response: Some(response),
+                    error: None,
+                },
+                Err(err) => BatchResponse {
+                    response: None,
+                    error: Some(err.to_string()),
+                },
+            };
+            (name, res)
+        }
+    }))
+    .await;
+
+    Ok(Json(results.into_iter().collect()))
+}
+
+/// Maximum number of queries of a batch that are answered concurrently.
+const BATCH_CONCURRENCY: usize = 8;
+
+/// Maximum number of queries of a single batch.
+const MAX_BATCH_QUERIES: usize = 1000;
+
+#[axum::debug_handler(state = AppState)]
+async fn lichess_batch(
+    State(state): State<AppState>,
+    body: Body,
+) -> NdJson<impl Stream<Item = BatchResponse>> {
+    let reader = StreamReader::new(body.into_data_stream().map_err(io::Error::other));
+    let load_shedder = state.load_shedder;
+    let lines = LinesStream::new(reader.lines())
+        .try_filter(|line| future::ready(!line.trim().is_empty()))
+        .take(MAX_BATCH_QUERIES + 1)
+        .enumerate();
+    NdJson(
+        shed_each(load_shedder, lines, BATCH_CONCURRENCY, move |(i, line)| {
+            let state = state.clone();
+            async move {
+                let res = if i >= MAX_BATCH_QUERIES {
+                    Err(Error::InvalidBatchQuery(format!(
+                        "at most {MAX_BATCH_QUERIES} queries per batch"
+                    )))
+                } else {
+                    match line {
+                        Ok(line) => match query_from_json(&line) {
+                            Ok(query) => lichess_from_state(state, Some(line), query)
+                                .await
+                                .map(|Json(response)| response),
+                            Err(err) => Err(err),
+                        },
+                        Err(err) => Err(Error::from(err)),
+                    }
+                };
+                match res {
+                    Ok(response) => BatchResponse {
+                        response: Some(response),
+                        error: None,
+                    },
+                    Err(err) => BatchResponse {
+                        response: None,
+                        error: Some(err.to_string()),
+                    },
+                }
+            }
         })
-        .await
+        .map(|res| {
+            res.unwrap_or_else(|| BatchResponse {
+                response: None,
+                error: Some("too many queries in flight".to_owned()),
+            })
+        }),
+    )
+}
+
+/// Maximum depth of an exported opening tree.
+const MAX_TREE_DEPTH: u8 = 6;
+
+/// Bound on the number of positions of an exported opening tree.
+const MAX_TREE_NODES: usize = 10_000;
+
+struct TreeNode {
+    pos: VariantPosition,
+    uci: Vec<UciMove>,
+    san: Vec<SanPlus>,
+}
+
+struct TreeState {
+    db: Arc<Database>,
+    query: TreeQuery,
+    queue: VecDeque<TreeNode>,
+    visited: usize,
+}
+
+fn read_tree_node(
+    db: &Database,
+    openings: &Openings,
+    query: &TreeQuery,
+    node: TreeNode,
+) -> (TreeRow, Vec<TreeNode>) {
+    let key = match query.db {
+        SearchSource::Masters => KeyBuilder::masters(),
+        SearchSource::Lichess => KeyBuilder::lichess(),
+    }
+    .with_zobrist(
+        node.pos.variant(),
+        node.pos.zobrist_hash(EnPassantMode::Legal),
+    );
+    let cache_hint = CacheHint::from_ply(ply(&node.pos));
+    let limits = Limits {
+        top_games: 0,
+        recent_games: 0,
+        moves: query.moves,
+        order_by: MoveOrder::default(),
+        min_games: 0,
+        coverage: None,
+    };
+    let prepared = match query.db {
+        SearchSource::Masters => {
+            let (entry, _) = db
+                .masters()
+                .read(
+                    key,
+                    Year::min_value(),
+                    Year::max_value(),
+                    &EraAdjustment::default(),
+                    cache_hint,
+                )
+                .expect("get masters");
+            entry.prepare(&limits)
+        }
+        SearchSource::Lichess => {
+            let (prepared, _, _, _) = db
+                .lichess()
+                .read_lichess(
+                    node.pos.variant(),
+                    &key,
+                    node.pos.turn(),
+                    &query.filter,
+                    &limits,
+                    HistoryWanted::No,
+                    None,
+                    None,
+                    cache_hint,
+                )
+                .expect("get lichess");
+            prepared
+        }
+    };
+
+    let children = if node.uci.len() < usize::from(query.depth) {
+        prepared
+            .moves
+            .into_iter()
+            .filter_map(|p| {
+                let m = p.uci.to_move(&node.pos).ok()?;
+                let mut pos = node.pos.clone();
+                let mut san = node.san.clone();
+                san.push(SanPlus::from_move_and_play_unchecked(&mut pos, &m));
+                let mut uci = node.uci.clone();
+                uci.push(p.uci);
+                Some(TreeNode { pos, uci, san })
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    let opening = openings.classify_exact(&node.pos);
+    let row = TreeRow {
+        white: prepared.total.white(),
+        draws: prepared.total.draws(),
+        black: prepared.total.black(),
+        average_rating: prepared.total.average_rating(),
+        eco: opening.map(|o| o.eco().to_owned()),
+        opening: opening.map(|o| o.name().to_owned()),
+        uci: node.uci,
+        san: node.san,
+    };
+
+    (row, children)
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn export_tree(
+    State(openings): State<&'static RwLock<Openings>>,
+    State(db): State<Arc<Database>>,
+    State(semaphore): State<&'static Semaphore>,
+    Query(query): Query<TreeQuery>,
+) -> Result<Response, Error> {
+    if query.depth > MAX_TREE_DEPTH {
+        return Err(Error::InvalidTreeQuery("depth is limited to 6"));
+    }
+
+    let PlayPosition { pos, .. } = query
+        .play
+        .clone()
+        .position(&openings.read().expect("read openings"))?;
+    let format = query.format;
+    let state = TreeState {
+        db,
+        query,
+        queue: VecDeque::from([TreeNode {
+            pos,
+            uci: Vec::new(),
+            san: Vec::new(),
+        }]),
+        visited: 0,
+    };
+
+    // Breadth first, reading one position at a time.
+    let rows = futures_util::stream::unfold(state, move |mut state| async move {
+        let node = state.queue.pop_front()?;
+        state.visited += 1;
+        let (row, children, mut state) = spawn_blocking(semaphore, move || {
+            let (row, children) = read_tree_node(
+                &state.db,
+                &openings.read().expect("read openings"),
+                &state.query,
+                node,
+            );
+            (row, children, state)
+        })
+        .await;
+        let budget = MAX_TREE_NODES.saturating_sub(state.visited + state.queue.len());
+        state.queue.extend(children.into_iter().take(budget));
+        Some((row, state))
+    });
+
+    Ok(match format {
+        TreeFormat::Json => NdJson(rows).into_response(),
+        TreeFormat::Csv => Response::builder()
+            .header(axum::http::header::CONTENT_TYPE, "text/csv")
+            .body(Body::from_stream(rows.enumerate().map(|(i, row)| {
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(i == 0)
+                    .from_writer(Vec::new());
+                writer.serialize(row).map_err(io::Error::other)?;
+                writer
+                    .into_inner()
+                    .map(Bytes::from)
+                    .map_err(|err| err.into_error())
+            })))
+            .unwrap(),
+    })
+}
+
+/// Maximum number of move orders in a transposition report.
+const MAX_TRANSPOSITIONS: usize = 12;
+
+#[axum::debug_handler(state = AppState)]
+async fn masters_games_at(
+    State(openings): State<&'static RwLock<Openings>>,
+    State(db): State<Arc<Database>>,
+    State(semaphore): State<&'static Semaphore>,
+    Query(query): Query<MastersGamesAtQuery>,
+) -> Result<Json<MastersGamesAtResponse>, Error> {
+    spawn_blocking(semaphore, move || {
+        let openings = openings.read().expect("read openings");
+        let QueriedPosition { key, .. } =
+            QueriedPosition::new(query.play, KeyBuilder::masters(), &openings)?;
+        Ok(Json(MastersGamesAtResponse {
+            games: db
+                .masters()
+                .games_at(&key)
+                .expect("get masters games at position"),
+        }))
+    })
+    .await
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn masters_novelty(
+    State(openings): State<&'static RwLock<Openings>>,
+    State(db): State<Arc<Database>>,
+    State(semaphore): State<&'static Semaphore>,
+    Query(query): Query<MastersNoveltyQuery>,
+) -> Result<Json<MastersNoveltyResponse>, Error> {
+    spawn_blocking(semaphore, move || {
+        let (start, line) = query.play.split_line();
+        let PlayPosition { mut pos, .. } =
+            start.position(&openings.read().expect("read openings"))?;
+
+        let masters_db = db.masters();
+        let limits = Limits {
+            top_games: 0,
+            recent_games: 0,
+            moves: 0,
+            order_by: MoveOrder::default(),
+            min_games: 0,
+            coverage: None,
+        };
+        let games = |pos: &VariantPosition| {
+            let key = KeyBuilder::masters()
+                .with_zobrist(pos.variant(), pos.zobrist_hash(EnPassantMode::Legal));
+            let (entry, _) = masters_db
+                .read(
+                    key,
+                    Year::min_value(),
+                    Year::max_value(),
+                    &EraAdjustment::default(),
+                    CacheHint::from_ply(ply(pos)),
+                )
+                .expect("get masters");
+            entry.prepare(&limits).total.total()
+        };
+
+        let mut response = MastersNoveltyResponse {
+            ply: 0,
+            games: games(&pos),
+            novelty: None,
+        };
+        if response.games == 0 {
+            return Ok(Json(response));
+        }
+        for (i, uci) in line.into_iter().enumerate() {
+            let m = uci.to_move(&pos).map_err(|_| Error::IllegalMove {
+                ply: i + 1,
+                uci: uci.clone(),
+            })?;
+            let san = SanPlus::from_move_and_play_unchecked(&mut pos, &m);
+            let games = games(&pos);
+            if games == 0 {
+                response.novelty = Some(Novelty {
+                    uci,
+                    san,
+                    fen: Fen::from_position(pos, EnPassantMode::Legal).to_string(),
+                });
+                break;
+            }
+            response.ply = i + 1;
+            response.games = games;
+        }
+        Ok(Json(response))
+    })
+    .await
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn masters_player(
+    State(openings): State<&'static RwLock<Openings>>,
+    State(db): State<Arc<Database>>,
+    State(aliases): State<&'static PlayerAliases>,
+    State(semaphore): State<&'static Semaphore>,
+    Path(name): Path<String>,
+    Query(query): Query<MastersPlayerQuery>,
+) -> Result<Json<MastersPlayerResponse>, Error> {
+    let name = aliases.normalize(&name);
+    // Games imported before the alias was configured are found by any of
+    // the spellings.
+    let tokens: HashSet<String> = iter::once(name.as_str())
+        .chain(aliases.aliases_of(&name))
+        .filter_map(|spelling| search_tokens(spelling).next())
+        .collect();
+    if tokens.is_empty() {
+        return Err(Error::InvalidGameSearch("player name required"));
+    }
+
+    spawn_blocking(semaphore, move || {
+        let PlayPosition { pos, .. } = query
+            .play
+            .position(&openings.read().expect("read openings"))?;
+        let target: StableZobrist128 = pos.zobrist_hash(EnPassantMode::Legal);
+
+        let search = db.game_search().ok_or(Error::GameSearchDisabled)?;
+        let mut ids = Vec::new();
+        for token in &tokens {
+            for field in [SearchField::White, SearchField::Black] {
+                ids.extend(
+                    search
+                        .search(
+                            SearchSource::Masters,
+                            field,
+                            token,
+                            Month::min_value(),
+                            Month::max_value(),
+                            10_000,
+                        )
+                        .expect("search games"),
+                );
+            }
+        }
+        ids.sort_unstable();
+        ids.dedup();
+
+        let mut total = Stats::default();
+        let mut moves: Vec<MastersPlayerMove> = Vec::new();
+        for game in db
+            .masters()
+            .games(ids)
+            .expect("get masters games")
+            .into_iter()
+            .flatten()
+        {
+            if !game
+                .players
+                .iter()
+                .any(|player| aliases.same_player(&player.name, &name))
+            {
+                continue;
+            }
+
+            let stats = Stats::new_single(
+                Outcome::from_winner(game.winner),
+                midpoint(game.players.white.rating, game.players.black.rating),
+            );
+            let mut replay = VariantPosition::new(Variant::Chess);
+            let mut uci_moves = game.moves.iter();
+            loop {
+                if replay.zobrist_hash::<StableZobrist128>(EnPassantMode::Legal) == target {
+                    total += &stats;
+                    let next = uci_moves
+                        .next()
+                        .and_then(|uci| uci.to_move(&replay).ok().map(|m| (uci.clone(), m)));
+                    if let Some((uci, m)) = next {
+                        match moves.iter_mut().find(|candidate| candidate.uci == uci) {
+                            Some(candidate) => candidate.stats += &stats,
+                            None => moves.push(MastersPlayerMove {
+                                uci,
+                                san: SanPlus::from_move(replay, &m),
+                                stats,
+                            }),
+                        }
+                    }
+                    break;
+                }
+                let Some(m) = uci_moves.next().and_then(|uci| uci.to_move(&replay).ok()) else {
+                    break;
+                };
+                replay.play_unchecked(&m);
+            }
+        }
+        moves.sort_by_key(|m| Reverse(m.stats.total()));
+
+        Ok(Json(MastersPlayerResponse { name, total, moves }))
+    })
+    .await
+}
+
+/// Positions with the same pawn structure considered by `/masters/structure`,
+/// before selecting the most common ones.
+const MAX_STRUCTURE_POSITIONS: usize = 50_000;
+
+#[axum::debug_handler(state = AppState)]
+async fn masters_structure(
+    State(openings): State<&'static RwLock<Openings>>,
+    State(db): State<Arc<Database>>,
+    State(semaphore): State<&'static Semaphore>,
+    Query(query): Query<MastersStructureQuery>,
+) -> Result<Json<MastersStructureResponse>, Error> {
+    spawn_blocking(semaphore, move || {
+        let PlayPosition { pos, .. } = query
+            .play
+            .position(&openings.read().expect("read openings"))?;
+
+        let masters_db = db.masters();
+        let (mut entries, truncated) = masters_db
+            .structure_positions(
+                PawnStructure::from_board(pos.board()),
+                MAX_STRUCTURE_POSITIONS,
+            )
+            .expect("get masters structure positions");
+        entries.sort_by_key(|entry| Reverse(entry.games));
+        entries.truncate(query.limit.unwrap_or(12).min(100));
+
+        let limits = Limits {
+            top_games: 0,
+            recent_games: 0,
+            moves: 0,
+            order_by: MoveOrder::default(),
+            min_games: 0,
+            coverage: None,
+        };
+        let positions = entries
+            .into_iter()
+            .filter_map(|entry| {
+                let pos: Chess = match entry
+                    .fen
+                    .parse::<Fen>()
+                    .ok()
+                    .and_then(|fen| fen.into_position(CastlingMode::Chess960).ok())
+                {
+                    Some(pos) => pos,
+                    None => {
+                        log::error!("skipping invalid fen in structure index: {}", entry.fen);
+                        return None;
+                    }
+                };
+                let key = KeyBuilder::masters()
+                    .with_zobrist(Variant::Chess, pos.zobrist_hash(EnPassantMode::Legal));
+                let (masters_entry, _) = masters_db
+                    .read(
+                        key,
+                        Year::min_value(),
+                        Year::max_value(),
+                        &EraAdjustment::default(),
+                        CacheHint::always(),
+                    )
+                    .expect("get masters");
+                Some(StructurePosition {
+                    fen: entry.fen,
+                    indexed_games: entry.games,
+                    stats: masters_entry.prepare(&limits).total,
+                })
+            })
+            .collect();
+
+        Ok(Json(MastersStructureResponse {
+            truncated,
+            positions,
+        }))
+    })
+    .await
+}
+
+#[axum::debug_handler(state = AppState)]
+async fn lichess_transpositions(
+    State(openings): State<&'static RwLock<Openings>>,
+    State(db): State<Arc<Database>>,
+    State(semaphore): State<&'static Semaphore>,
+    Query(query): Query<TranspositionsQuery>,
+) -> Result<Json<TranspositionsResponse>, Error> {
+    spawn_blocking(semaphore, move || {
+        let played = query.play.moves_from_start().map(<[UciMove]>::to_vec);
+        let openings = openings.read().expect("read openings");
+        let PlayPosition { pos, .. } = query.play.position(&openings)?;
+
+        let mut transpositions = Transpositions::new(&pos);
+        for line in openings.move_orders(&pos) {
+            transpositions.add_book_line(line);
+        }
+
+        // Sample the top and recent games of the position.
+        let key = KeyBuilder::lichess()
+            .with_zobrist(pos.variant(), pos.zobrist_hash(EnPassantMode::Legal));
+        let lichess_db = db.lichess();
+        let (prepared, _, _, _) = lichess_db
+            .read_lichess(
+                pos.variant(),
+                &key,
+                pos.turn(),
+                &LichessQueryFilter {
+                    speeds: None,
+                    ratings: None,
+                    modes: None,
+                    min_ply: None,
+                    max_ply: None,
+                    since: None,
+                    until: None,
+                },
+                &Limits {
+                    top_games: usize::MAX,
+                    recent_games: usize::MAX,
+                    moves: 0,
+                    order_by: MoveOrder::default(),
+                    min_games: 0,
+                    coverage: None,
+                },
+                HistoryWanted::No,
+                None,
+                None,
+                CacheHint::from_ply(ply(&pos)),
+            )
+            .expect("get lichess");
+        for (_, id) in prepared.top_games.into_iter().chain(prepared.recent_games) {
+            if let Some(moves) = lichess_db.game_moves(id).expect("get lichess game moves") {
+                transpositions.add_game(&moves);
+            }
+        }
+
+        Ok(Json(TranspositionsResponse {
+            transpositions: transpositions.into_sorted(played.as_deref(), MAX_TRANSPOSITIONS),
+        }))
+    })
+    .await
 }
 
 #[axum::debug_handler(state = AppState)]
 async fn lichess_history(
     openings: State<&'static RwLock<Openings>>,
     blacklist: State<&'static RwLock<HashSet<UserId>>>,
+    annotator: State<&'static MoveAnnotator>,
     db: State<Arc<Database>>,
     lichess_cache: State<ExplorerCache<LichessQuery>>,
+    response_cache_ttl: State<Option<Duration>>,
     metrics: State<&'static Metrics>,
+    cloud_eval: State<&'static CloudEval>,
+    tablebase: State<&'static Tablebase>,
     semaphore: State<&'static Semaphore>,
+    query_log: State<&'static QueryLog>,
+    hot_positions: State<&'static HotPositions>,
+    raw_query: RawQuery,
     Query(mut with_source): Query<WithSource<LichessQuery>>,
 ) -> Result<Json<ExplorerResponse>, Error> {
     with_source.query.history = HistoryWanted::Yes;
     with_source.query.limits.recent_games = 0;
     with_source.query.limits.top_games = 0;
     with_source.query.limits.moves = 0;
+    with_source.query.trend = None;
     lichess(
         openings,
         blacklist,
+        annotator,
         db,
         lichess_cache,
+        response_cache_ttl,
         metrics,
+        cloud_eval,
+        tablebase,
         semaphore,
+        query_log,
+        hot_positions,
+        raw_query,
         Query(with_source),
     )
     .await
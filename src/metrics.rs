@@ -1,46 +1,146 @@
 use std::{
-    sync::atomic::{AtomicU64, Ordering},
-    time::Duration,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use crate::api::Source;
+use shakmaty::{
+    fen::Fen,
+    variant::{Variant, VariantPosition},
+    EnPassantMode, Position,
+};
+
+use crate::{api::Source, trace::Trace, util::ply};
 
 #[derive(Default)]
 pub struct Metrics {
     hit: HitMetrics,
     slow_hit: HitMetrics,
+    response_cache_hit: AtomicU64,
+    shed: AtomicU64,
+    slow_log: SlowQueryLog,
 }
 
 impl Metrics {
     const SLOW_DURATION: Duration = Duration::from_millis(500);
 
+    /// Log details of queries slower than `SLOW_DURATION`, at most once per
+    /// `SlowQueryLog::INTERVAL`.
+    pub fn enable_slow_query_log(&self) {
+        self.slow_log.enabled.store(true, Ordering::Relaxed);
+    }
+
     pub fn to_influx_string(&self) -> String {
         [
             self.hit.to_influx_string(""),
             self.slow_hit.to_influx_string("slow_"),
+            format!(
+                "response_cache_hit={}u",
+                self.response_cache_hit.load(Ordering::Relaxed)
+            ),
+            format!("shed={}u", self.shed.load(Ordering::Relaxed)),
         ]
         .join(",")
     }
 
-    pub fn inc_lichess(&self, duration: Duration, source: Option<Source>, ply: u32) {
-        self.hit.inc_lichess(source, ply);
+    pub fn inc_response_cache_hit(&self) {
+        self.response_cache_hit.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_shed(&self) {
+        self.shed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_lichess(
+        &self,
+        duration: Duration,
+        source: Option<Source>,
+        variant: Variant,
+        pos: Option<&VariantPosition>,
+    ) {
+        let ply = pos.map(ply);
+        self.hit.inc_lichess(source, variant, ply);
         if Metrics::SLOW_DURATION <= duration {
-            self.slow_hit.inc_lichess(source, ply);
+            self.slow_hit.inc_lichess(source, variant, ply);
+            self.slow_log.log("lichess", duration, variant, pos);
         }
     }
 
-    pub fn inc_masters(&self, duration: Duration, source: Option<Source>, ply: u32) {
+    pub fn inc_masters(
+        &self,
+        duration: Duration,
+        source: Option<Source>,
+        pos: Option<&VariantPosition>,
+    ) {
+        let ply = pos.map(ply);
         self.hit.inc_masters(source, ply);
         if Metrics::SLOW_DURATION <= duration {
             self.slow_hit.inc_masters(source, ply);
+            self.slow_log.log("masters", duration, Variant::Chess, pos);
         }
     }
 
-    pub fn inc_player(&self, duration: Duration, done: bool, ply: u32) {
-        self.hit.inc_player(done, ply);
+    pub fn inc_player(&self, duration: Duration, done: bool, pos: &VariantPosition) {
+        let (variant, ply) = (pos.variant(), ply(pos));
+        self.hit.inc_player(done, variant, ply);
         if Metrics::SLOW_DURATION <= duration {
-            self.slow_hit.inc_player(done, ply);
+            self.slow_hit.inc_player(done, variant, ply);
+            self.slow_log.log("player", duration, variant, Some(pos));
+        }
+    }
+}
+
+#[derive(Default)]
+struct SlowQueryLog {
+    enabled: AtomicBool,
+    /// Milliseconds since the Unix epoch.
+    last_logged: AtomicU64,
+    suppressed: AtomicU64,
+}
+
+impl SlowQueryLog {
+    const INTERVAL: Duration = Duration::from_secs(1);
+
+    /// Must be called in the context of the request, to report the keys and
+    /// bytes it scanned.
+    fn log(
+        &self,
+        endpoint: &str,
+        duration: Duration,
+        variant: Variant,
+        pos: Option<&VariantPosition>,
+    ) {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_millis() as u64);
+        let last_logged = self.last_logged.load(Ordering::Relaxed);
+        if now < last_logged + SlowQueryLog::INTERVAL.as_millis() as u64
+            || self
+                .last_logged
+                .compare_exchange(last_logged, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            return;
         }
+
+        let (keys, bytes) = Trace::current()
+            .scan()
+            .map_or(("?".to_owned(), "?".to_owned()), |(keys, bytes)| {
+                (keys.to_string(), bytes.to_string())
+            });
+        log::warn!(
+            "slow {endpoint} query ({duration:.0?}): variant {}, ply {}, {keys} keys and {bytes} bytes scanned, fen {} ({} slow queries not logged)",
+            variant.uci(),
+            pos.map_or("?".to_owned(), |pos| ply(pos).to_string()),
+            pos.map_or("?".to_owned(), |pos| {
+                Fen::from_position(pos.clone(), EnPassantMode::Legal).to_string()
+            }),
+            self.suppressed.swap(0, Ordering::Relaxed),
+        );
     }
 }
 
@@ -61,28 +161,37 @@ struct HitMetrics {
     lichess_ply: PlyMetrics,
     masters_ply: PlyMetrics,
     player_ply: PlyMetrics,
+
+    lichess_variant: VariantMetrics,
+    player_variant: VariantMetrics,
 }
 
 impl HitMetrics {
-    pub fn inc_lichess(&self, source: Option<Source>, ply: u32) {
+    pub fn inc_lichess(&self, source: Option<Source>, variant: Variant, ply: Option<u32>) {
         self.lichess_miss.fetch_add(1, Ordering::Relaxed);
         self.inc_source(source, &self.source_analysis_lichess);
-        self.lichess_ply.inc(ply);
+        if let Some(ply) = ply {
+            self.lichess_ply.inc(ply);
+        }
+        self.lichess_variant.inc(variant);
     }
 
-    pub fn inc_masters(&self, source: Option<Source>, ply: u32) {
+    pub fn inc_masters(&self, source: Option<Source>, ply: Option<u32>) {
         self.masters_miss.fetch_add(1, Ordering::Relaxed);
         self.inc_source(source, &self.source_analysis_masters);
-        self.masters_ply.inc(ply);
+        if let Some(ply) = ply {
+            self.masters_ply.inc(ply);
+        }
     }
 
-    pub fn inc_player(&self, done: bool, ply: u32) {
+    pub fn inc_player(&self, done: bool, variant: Variant, ply: u32) {
         match done {
             false => &self.source_analysis_player_incomplete,
             true => &self.source_analysis_player,
         }
         .fetch_add(1, Ordering::Relaxed);
         self.player_ply.inc(ply);
+        self.player_variant.inc(variant);
     }
 
     fn inc_source(&self, source: Option<Source>, analysis_db: &AtomicU64) {
@@ -145,6 +254,10 @@ impl HitMetrics {
                 .to_influx_string(&format!("{field_prefix}masters_ply_")),
             self.player_ply
                 .to_influx_string(&format!("{field_prefix}player_ply_")),
+            self.lichess_variant
+                .to_influx_string(&format!("{field_prefix}lichess_variant_")),
+            self.player_variant
+                .to_influx_string(&format!("{field_prefix}player_variant_")),
         ]
         .join(",")
     }
@@ -177,3 +290,51 @@ impl PlyMetrics {
             .join(",")
     }
 }
+
+#[derive(Default)]
+struct VariantMetrics {
+    chess: AtomicU64,
+    antichess: AtomicU64,
+    atomic: AtomicU64,
+    crazyhouse: AtomicU64,
+    horde: AtomicU64,
+    king_of_the_hill: AtomicU64,
+    racing_kings: AtomicU64,
+    three_check: AtomicU64,
+}
+
+impl VariantMetrics {
+    fn inc(&self, variant: Variant) {
+        match variant {
+            Variant::Chess => &self.chess,
+            Variant::Antichess => &self.antichess,
+            Variant::Atomic => &self.atomic,
+            Variant::Crazyhouse => &self.crazyhouse,
+            Variant::Horde => &self.horde,
+            Variant::KingOfTheHill => &self.king_of_the_hill,
+            Variant::RacingKings => &self.racing_kings,
+            Variant::ThreeCheck => &self.three_check,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn to_influx_string(&self, field_prefix: &str) -> String {
+        [
+            ("chess", &self.chess),
+            ("antichess", &self.antichess),
+            ("atomic", &self.atomic),
+            ("crazyhouse", &self.crazyhouse),
+            ("horde", &self.horde),
+            ("king_of_the_hill", &self.king_of_the_hill),
+            ("racing_kings", &self.racing_kings),
+            ("three_check", &self.three_check),
+        ]
+        .into_iter()
+        .map(|(name, counter)| {
+            let num = counter.load(Ordering::Relaxed);
+            format!("{field_prefix}{name}={num}u")
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+    }
+}
@@ -0,0 +1,61 @@
+use std::{num::NonZeroU64, process, time::Instant};
+
+use clap::Parser;
+
+use crate::db::{Database, DbOpt, EntryColumn};
+
+#[derive(Parser)]
+pub struct MigrateOpt {
+    /// Column family to migrate: masters, lichess (including the column
+    /// families of variants) or player.
+    #[arg(long)]
+    cf: EntryColumn,
+    /// Format version to rewrite entries in. Must be the version written by
+    /// this release, so that the migration can not be started by accident
+    /// with an outdated binary.
+    #[arg(long)]
+    to_version: u16,
+    /// Maximum number of entries to rewrite per second.
+    #[arg(long, default_value = "20000")]
+    rate: NonZeroU64,
+}
+
+/// Rewrite all entries of a column family in the current format version.
+/// Opens the database, so the server must be stopped. To migrate while the
+/// server is running, use `POST /admin/migrate` instead.
+pub fn run(db: DbOpt, opt: MigrateOpt) {
+    let Some(version) = opt.cf.migration_target(opt.to_version) else {
+        log::error!(
+            "can only migrate {} to {}, the version written by this release",
+            opt.cf.name(),
+            opt.cf.format_version()
+        );
+        process::exit(1);
+    };
+
+    let db = Database::open(db).expect("db");
+
+    log::info!("migrating {} to {version} ...", opt.cf.name());
+    let started_at = Instant::now();
+    let progress = db
+        .migrate(opt.cf, opt.rate, |progress| {
+            log::info!(
+                "{}: scanned {} of ~{} entries, scheduled {}, failed {} ({:.0?})",
+                opt.cf.name(),
+                progress.scanned,
+                progress.estimated_keys,
+                progress.scheduled,
+                progress.failed,
+                started_at.elapsed()
+            );
+        })
+        .expect("migrate");
+
+    if progress.failed > 0 {
+        log::warn!(
+            "{} values could not be decoded and were moved into the corrupt column family",
+            progress.failed
+        );
+    }
+    log::info!("finished migrating {} to {version}", opt.cf.name());
+}
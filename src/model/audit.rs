@@ -0,0 +1,43 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, TimestampMilliSeconds};
+
+/// Administrative operation, as recorded in the audit column family.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEntry {
+    #[serde_as(as = "TimestampMilliSeconds")]
+    pub at: SystemTime,
+    pub operation: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    /// Fingerprint of the admin token that authorized the operation, if
+    /// tokens are configured.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    pub params: serde_json::Value,
+}
+
+/// Key in the audit column family. Microseconds since the epoch, made
+/// unique by the writer, so that iteration is in chronological order.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct AuditKey(pub u64);
+
+impl AuditKey {
+    pub fn from_time(at: SystemTime) -> AuditKey {
+        AuditKey(
+            at.duration_since(UNIX_EPOCH)
+                .unwrap_or(Duration::ZERO)
+                .as_micros() as u64,
+        )
+    }
+
+    pub fn to_bytes(self) -> [u8; 8] {
+        self.0.to_be_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<AuditKey> {
+        Some(AuditKey(u64::from_be_bytes(bytes.try_into().ok()?)))
+    }
+}
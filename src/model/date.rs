@@ -1,4 +1,9 @@
-use std::{cmp::min, convert::TryFrom, fmt, str::FromStr};
+use std::{
+    cmp::{max, min},
+    convert::TryFrom,
+    fmt,
+    str::FromStr,
+};
 
 use thiserror::Error;
 use time::{OffsetDateTime, PrimitiveDateTime};
@@ -138,6 +143,12 @@ impl TryFrom<u16> for Year {
     }
 }
 
+impl fmt::Display for Year {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 impl FromStr for Year {
     type Err = InvalidDate;
 
@@ -164,11 +175,21 @@ impl Month {
         Month(year * 12 + month0)
     }
 
+    pub fn now() -> Month {
+        let now = OffsetDateTime::now_utc();
+        Month::from_time_saturating(PrimitiveDateTime::new(now.date(), now.time()))
+    }
+
     #[must_use]
     pub fn add_months_saturating(self, months: u16) -> Month {
         min(Month(self.0.saturating_add(months)), Month::max_value())
     }
 
+    #[must_use]
+    pub fn sub_months_saturating(self, months: u16) -> Month {
+        max(Month(self.0.saturating_sub(months)), Month::min_value())
+    }
+
     pub fn year(self) -> Year {
         Year(self.0 / 12)
     }
@@ -198,6 +219,35 @@ impl fmt::Display for Month {
     }
 }
 
+/// When a move was last played, as precise as the database records it:
+/// by month for lichess games, by year for masters games.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LastPlayed {
+    Year(Year),
+    Month(Month),
+}
+
+impl fmt::Display for LastPlayed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LastPlayed::Year(year) => year.fmt(f),
+            LastPlayed::Month(month) => month.fmt(f),
+        }
+    }
+}
+
+impl FromStr for LastPlayed {
+    type Err = InvalidDate;
+
+    fn from_str(s: &str) -> Result<LastPlayed, InvalidDate> {
+        Ok(if s.contains('-') {
+            LastPlayed::Month(s.parse()?)
+        } else {
+            LastPlayed::Year(s.parse()?)
+        })
+    }
+}
+
 impl FromStr for Month {
     type Err = InvalidDate;
 
@@ -0,0 +1,111 @@
+use std::fmt;
+
+use bytes::{Buf, BufMut};
+
+use crate::model::ReadError;
+
+/// Version of the binary encoding of an entry.
+///
+/// Entries of the first version have no header. Later versions are prefixed
+/// with an envelope: a little-endian `u16` with all bits of the top nibble
+/// set, which can not be the first move of an entry (roles fit into 3 bits),
+/// and the version in the remaining 12 bits.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct FormatVersion(u16);
+
+impl FormatVersion {
+    pub const V1: FormatVersion = FormatVersion(1);
+    pub const V2: FormatVersion = FormatVersion(2);
+    pub const V3: FormatVersion = FormatVersion(3);
+
+    const ENVELOPE: u16 = 0xf000;
+
+    pub const fn new(version: u16) -> Option<FormatVersion> {
+        if version >= 1 && version & FormatVersion::ENVELOPE == 0 {
+            Some(FormatVersion(version))
+        } else {
+            None
+        }
+    }
+
+    /// Version of a stored value, without consuming it.
+    pub fn of(value: &[u8]) -> FormatVersion {
+        match value {
+            [lo, hi, ..] => {
+                let n = u16::from_le_bytes([*lo, *hi]);
+                if n & FormatVersion::ENVELOPE == FormatVersion::ENVELOPE {
+                    FormatVersion(n & !FormatVersion::ENVELOPE)
+                } else {
+                    FormatVersion::V1
+                }
+            }
+            _ => FormatVersion::V1,
+        }
+    }
+
+    /// Consume the envelope, if any. Versions newer than `supported` are
+    /// rejected, so that entries are never rewritten by a reader that does
+    /// not understand them.
+    pub fn read<B: Buf>(buf: &mut B, supported: FormatVersion) -> Result<FormatVersion, ReadError> {
+        let version = FormatVersion::of(buf.chunk());
+        if version != FormatVersion::V1 {
+            buf.advance(2);
+        }
+        if version > supported {
+            return Err(ReadError::Invalid("format version"));
+        }
+        Ok(version)
+    }
+
+    pub fn write<B: BufMut>(self, buf: &mut B) {
+        if self != FormatVersion::V1 {
+            buf.put_u16_le(FormatVersion::ENVELOPE | self.0);
+        }
+    }
+}
+
+impl From<FormatVersion> for u16 {
+    fn from(FormatVersion(version): FormatVersion) -> u16 {
+        version
+    }
+}
+
+impl fmt::Display for FormatVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shakmaty::{uci::UciMove, Role, Square};
+
+    use super::*;
+    use crate::model::RawUciMove;
+
+    #[test]
+    fn test_format_version_envelope() {
+        let v2 = FormatVersion::new(2).unwrap();
+
+        let mut buf = Vec::new();
+        FormatVersion::V1.write(&mut buf);
+        assert!(buf.is_empty(), "first version has no envelope");
+
+        v2.write(&mut buf);
+        RawUciMove::from(UciMove::Normal {
+            from: Square::H7,
+            to: Square::H8,
+            promotion: Some(Role::King),
+        })
+        .write(&mut buf);
+        assert_eq!(FormatVersion::of(&buf), v2);
+        assert_eq!(FormatVersion::of(&buf[2..]), FormatVersion::V1);
+
+        let mut reader = &buf[..];
+        assert_eq!(FormatVersion::read(&mut reader, v2), Ok(v2));
+        assert_eq!(reader.len(), 2);
+
+        let mut reader = &buf[..];
+        assert!(FormatVersion::read(&mut reader, FormatVersion::V1).is_err());
+    }
+}
@@ -4,13 +4,16 @@ use std::{
 };
 
 use bytes::{Buf, BufMut};
+use sha1::{Digest, Sha1};
 use thiserror::Error;
 
+use crate::model::{ensure_remaining, ReadError};
+
 #[derive(Error, Debug)]
 #[error("invalid game id")]
 pub struct InvalidGameId;
 
-#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct GameId(u64);
 
 impl GameId {
@@ -26,10 +29,22 @@ impl GameId {
         buf.put_uint_le(self.0, Self::SIZE);
     }
 
-    pub fn read<B: Buf>(buf: &mut B) -> GameId {
+    pub fn read<B: Buf>(buf: &mut B) -> Result<GameId, ReadError> {
+        ensure_remaining(buf, Self::SIZE)?;
         let n = buf.get_uint_le(Self::SIZE);
-        assert!(n < 62u64.pow(8), "invalid game id");
-        GameId(n)
+        if n >= 62u64.pow(8) {
+            return Err(ReadError::Invalid("game id"));
+        }
+        Ok(GameId(n))
+    }
+
+    /// Derive a game id from the content of a game, for sources that do not
+    /// come with their own ids.
+    pub fn from_content(content: &[u8]) -> GameId {
+        let digest = Sha1::digest(content);
+        let mut n = [0; 8];
+        n.copy_from_slice(&digest[..8]);
+        GameId(u64::from_le_bytes(n) % 62u64.pow(8))
     }
 }
 
@@ -0,0 +1,43 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// How the players of a game were paired.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum GameSource {
+    /// Lobby, challenges, and all other pairings outside of tournaments.
+    Pairing,
+    Arena,
+    Swiss,
+}
+
+impl GameSource {
+    pub fn from_tournament(arena: bool, swiss: bool) -> GameSource {
+        if swiss {
+            GameSource::Swiss
+        } else if arena {
+            GameSource::Arena
+        } else {
+            GameSource::Pairing
+        }
+    }
+}
+
+impl FromStr for GameSource {
+    type Err = InvalidGameSource;
+
+    fn from_str(s: &str) -> Result<GameSource, InvalidGameSource> {
+        Ok(match s {
+            "pairing" => GameSource::Pairing,
+            "arena" => GameSource::Arena,
+            "swiss" => GameSource::Swiss,
+            _ => return Err(InvalidGameSource),
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("invalid game source")]
+pub struct InvalidGameSource;
@@ -1,12 +1,19 @@
-use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+use bytes::Buf;
+use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
+use shakmaty::{uci::UciMove, Color};
 
-use crate::model::{Month, Stats};
+use crate::{
+    api::{LichessQueryFilter, Limits},
+    model::{LichessEntry, Month, PreparedMove, PreparedResponse, ReadError, Stats},
+};
 
 pub type History = Vec<HistorySegment>;
 
 #[serde_as]
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct HistorySegment {
     #[serde_as(as = "DisplayFromStr")]
     pub month: Month,
@@ -63,3 +70,82 @@ impl HistoryBuilder {
         self.segments
     }
 }
+
+#[derive(Debug)]
+pub struct TrendBuilder {
+    since: Month,
+    until: Month,
+    trends: HashMap<UciMove, Vec<u64>>,
+}
+
+impl TrendBuilder {
+    pub const MAX_MONTHS: u16 = 36;
+
+    pub fn new_until(until: Option<Month>, months: u16) -> TrendBuilder {
+        // By default, end with the last month that may be completely indexed.
+        let until = until.unwrap_or_else(|| Month::now().sub_months_saturating(1));
+        let months = months.clamp(1, TrendBuilder::MAX_MONTHS);
+        TrendBuilder {
+            since: until.sub_months_saturating(months - 1),
+            until,
+            trends: HashMap::new(),
+        }
+    }
+
+    pub fn wants(&self, month: Month) -> bool {
+        self.since <= month && month <= self.until
+    }
+
+    fn len(&self) -> usize {
+        usize::from(u16::from(self.until) - u16::from(self.since)) + 1
+    }
+
+    pub fn record(&mut self, month: Month, entry: &LichessEntry, filter: &LichessQueryFilter) {
+        let len = self.len();
+        let idx = usize::from(u16::from(month) - u16::from(self.since));
+        for (uci, total) in entry.move_totals(filter) {
+            self.trends.entry(uci).or_insert_with(|| vec![0; len])[idx] += total;
+        }
+    }
+
+    /// Set game counts of each month, oldest first.
+    pub fn annotate(mut self, moves: &mut [PreparedMove]) {
+        let len = self.len();
+        for m in moves {
+            m.trend = Some(self.trends.remove(&m.uci).unwrap_or_else(|| vec![0; len]));
+        }
+    }
+}
+
+/// Entries of each month, to be prepared separately.
+#[derive(Debug, Default)]
+pub struct MonthsBuilder {
+    entries: BTreeMap<Month, LichessEntry>,
+}
+
+impl MonthsBuilder {
+    pub fn record<B: Buf>(&mut self, month: Month, buf: &mut B) -> Result<(), ReadError> {
+        self.entries
+            .entry(month)
+            .or_default()
+            .extend_from_reader(buf)
+    }
+
+    /// Responses of each month, oldest first, without games.
+    pub fn build(
+        self,
+        color: Color,
+        filter: &LichessQueryFilter,
+        limits: &Limits,
+    ) -> Vec<(Month, PreparedResponse)> {
+        let limits = Limits {
+            top_games: 0,
+            recent_games: 0,
+            ..limits.clone()
+        };
+        self.entries
+            .into_iter()
+            .map(|(month, entry)| (month, entry.prepare(color, filter, &limits)))
+            .collect()
+    }
+}
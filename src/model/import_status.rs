@@ -0,0 +1,28 @@
+use bytes::{Buf, BufMut};
+
+use crate::model::{read_uint, write_uint};
+
+/// Import progress of a month of lichess games. Stored in the meta column
+/// family, where concurrent updates are combined by adding all fields.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct ImportStatus {
+    pub accepted: u64,
+    pub skipped: u64,
+    pub completed: bool,
+}
+
+impl ImportStatus {
+    pub fn write<B: BufMut>(&self, buf: &mut B) {
+        write_uint(buf, self.accepted);
+        write_uint(buf, self.skipped);
+        write_uint(buf, u64::from(self.completed));
+    }
+
+    pub fn read<B: Buf>(buf: &mut B) -> ImportStatus {
+        ImportStatus {
+            accepted: read_uint(buf),
+            skipped: read_uint(buf),
+            completed: read_uint(buf) > 0,
+        }
+    }
+}
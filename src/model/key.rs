@@ -5,7 +5,7 @@ use sha1::{Digest, Sha1};
 use shakmaty::{variant::Variant, Color};
 
 use crate::{
-    model::{InvalidDate, Month, UserId, Year},
+    model::{GameId, InvalidDate, Month, UserId, Year},
     zobrist::StableZobrist128,
 };
 
@@ -79,6 +79,18 @@ impl KeyPrefix {
         (&mut buf[KeyPrefix::SIZE..]).put_u16(u16::from(year));
         Key(buf)
     }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.prefix[..KeyPrefix::SIZE]
+    }
+
+    /// Key of a game in the posting list of the position.
+    pub fn with_game(&self, id: GameId) -> [u8; KeyPrefix::SIZE + GameId::SIZE] {
+        let mut buf = [0; KeyPrefix::SIZE + GameId::SIZE];
+        buf[..KeyPrefix::SIZE].clone_from_slice(&self.prefix[..KeyPrefix::SIZE]);
+        id.write(&mut &mut buf[KeyPrefix::SIZE..]);
+        buf
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
@@ -94,6 +106,10 @@ impl Key {
     pub fn month(&self) -> Result<Month, InvalidDate> {
         (&mut &self.0[KeyPrefix::SIZE..]).get_u16().try_into()
     }
+
+    pub fn year(&self) -> Result<Year, InvalidDate> {
+        (&mut &self.0[KeyPrefix::SIZE..]).get_u16().try_into()
+    }
 }
 
 impl TryFrom<&'_ [u8]> for Key {
@@ -6,12 +6,15 @@ use std::{
 
 use bytes::{Buf, BufMut};
 use nohash_hasher::IntMap;
-use shakmaty::{uci::UciMove, Outcome};
+use shakmaty::{uci::UciMove, Color, Outcome};
 use thin_vec::{thin_vec, ThinVec};
 
 use crate::{
-    api::{LichessQueryFilter, Limits},
-    model::{read_uint, write_uint, BySpeed, GameId, RawUciMove, Speed, Stats},
+    api::{LichessQueryFilter, Limits, MoveOrder},
+    model::{
+        try_get_u8, try_read_uint, write_uint, ByMode, BySpeed, FormatVersion, GameId, LastPlayed,
+        Mode, Month, PlayersSketch, RawUciMove, ReadError, Speed, Stats,
+    },
     util::{midpoint, sort_by_key_and_truncate},
 };
 
@@ -29,12 +32,13 @@ pub enum RatingGroup {
     Group2000,
     Group2200,
     Group2500,
-    Group2800, // TODO: Tweak rating groups for better top game selection
+    Group2800,
+    Group3000,
     Group3200,
 }
 
 impl RatingGroup {
-    pub const ALL: [RatingGroup; 11] = [
+    pub const ALL: [RatingGroup; 12] = [
         RatingGroup::GroupLow,
         RatingGroup::Group1000,
         RatingGroup::Group1200,
@@ -45,6 +49,7 @@ impl RatingGroup {
         RatingGroup::Group2200,
         RatingGroup::Group2500,
         RatingGroup::Group2800,
+        RatingGroup::Group3000,
         RatingGroup::Group3200,
     ];
 
@@ -67,6 +72,10 @@ impl RatingGroup {
             RatingGroup::Group2200
         } else if avg < 2800 {
             RatingGroup::Group2500
+        } else if avg < 3000 {
+            RatingGroup::Group2800
+        } else if avg < 3200 {
+            RatingGroup::Group3000
         } else {
             RatingGroup::Group3200
         }
@@ -88,6 +97,7 @@ impl RatingGroup {
             RatingGroup::Group2200 => 2200,
             RatingGroup::Group2500 => 2500,
             RatingGroup::Group2800 => 2800,
+            RatingGroup::Group3000 => 3000,
             RatingGroup::Group3200 => 3200,
         }
     }
@@ -113,6 +123,7 @@ struct ByRatingGroup<T> {
     group_2200: T,
     group_2500: T,
     group_2800: T,
+    group_3000: T,
     group_3200: T,
 }
 
@@ -129,6 +140,7 @@ impl<T> ByRatingGroup<T> {
             RatingGroup::Group2200 => &mut self.group_2200,
             RatingGroup::Group2500 => &mut self.group_2500,
             RatingGroup::Group2800 => &mut self.group_2800,
+            RatingGroup::Group3000 => &mut self.group_3000,
             RatingGroup::Group3200 => &mut self.group_3200,
         }
     }
@@ -145,6 +157,7 @@ impl<T> ByRatingGroup<T> {
             group_2200: &self.group_2200,
             group_2500: &self.group_2500,
             group_2800: &self.group_2800,
+            group_3000: &self.group_3000,
             group_3200: &self.group_3200,
         }
     }
@@ -161,6 +174,7 @@ impl<T> ByRatingGroup<T> {
             group_2200: (RatingGroup::Group2200, self.group_2200),
             group_2500: (RatingGroup::Group2500, self.group_2500),
             group_2800: (RatingGroup::Group2800, self.group_2800),
+            group_3000: (RatingGroup::Group3000, self.group_3000),
             group_3200: (RatingGroup::Group3200, self.group_3200),
         }
     }
@@ -168,7 +182,7 @@ impl<T> ByRatingGroup<T> {
 
 impl<T> IntoIterator for ByRatingGroup<T> {
     type Item = T;
-    type IntoIter = array::IntoIter<T, 11>;
+    type IntoIter = array::IntoIter<T, 12>;
 
     fn into_iter(self) -> Self::IntoIter {
         [
@@ -182,33 +196,95 @@ impl<T> IntoIterator for ByRatingGroup<T> {
             self.group_2200,
             self.group_2500,
             self.group_2800,
+            self.group_3000,
             self.group_3200,
         ]
         .into_iter()
     }
 }
 
+/// Groups of a sub entry by the ply at which the position occurred. Sparse,
+/// because most positions can only be reached at one or few plies. Groups
+/// written before plies were recorded have no ply.
+#[derive(Debug)]
+struct ByPly<T> {
+    groups: ThinVec<(Option<u8>, T)>,
+}
+
+impl<T> Default for ByPly<T> {
+    fn default() -> ByPly<T> {
+        ByPly {
+            groups: ThinVec::new(),
+        }
+    }
+}
+
+impl<T: Default> ByPly<T> {
+    fn by_ply_mut(&mut self, ply: Option<u8>) -> &mut T {
+        let idx = match self.groups.iter().position(|(p, _)| *p == ply) {
+            Some(idx) => idx,
+            None => {
+                self.groups.push((ply, T::default()));
+                self.groups.len() - 1
+            }
+        };
+        &mut self.groups[idx].1
+    }
+}
+
+impl<T> ByPly<T> {
+    fn iter(&self) -> impl Iterator<Item = (Option<u8>, &T)> {
+        self.groups.iter().map(|(ply, group)| (*ply, group))
+    }
+}
+
+type SubEntry = BySpeed<ByMode<ByRatingGroup<ByPly<LichessGroup>>>>;
+
 enum LichessHeader {
     Group {
         rating_group: RatingGroup,
         speed: Speed,
+        mode: Mode,
+        ply: Option<u8>,
         num_games: usize,
     },
     End,
 }
 
 impl LichessHeader {
-    fn read<B: Buf>(buf: &mut B) -> LichessHeader {
-        let n = buf.get_u8();
+    // Not a valid header byte (speed bits 7), so it can prefix the header of
+    // a casual group. Groups without the prefix are rated, which keeps
+    // entries written before the split by mode readable.
+    const CASUAL_PREFIX: u8 = 7;
+
+    // Also not a valid header byte. Followed by the ply at which the
+    // position occurred, then the remaining header.
+    const PLY_PREFIX: u8 = 7 | (1 << 3);
+
+    fn read<B: Buf>(buf: &mut B, version: FormatVersion) -> Result<LichessHeader, ReadError> {
+        let mut n = try_get_u8(buf)?;
+        let ply = if n == LichessHeader::PLY_PREFIX {
+            let ply = try_get_u8(buf)?;
+            n = try_get_u8(buf)?;
+            Some(ply)
+        } else {
+            None
+        };
+        let mode = if n == LichessHeader::CASUAL_PREFIX {
+            n = try_get_u8(buf)?;
+            Mode::Casual
+        } else {
+            Mode::Rated
+        };
         let speed = match n & 7 {
-            0 => return LichessHeader::End,
+            0 => return Ok(LichessHeader::End),
             1 => Speed::UltraBullet,
             2 => Speed::Bullet,
             3 => Speed::Blitz,
             4 => Speed::Rapid,
             5 => Speed::Classical,
             6 => Speed::Correspondence,
-            _ => panic!("invalid speed"),
+            _ => return Err(ReadError::Invalid("speed")),
         };
         let rating_group = match (n >> 3) & 15 {
             0 => RatingGroup::GroupLow,
@@ -221,19 +297,25 @@ impl LichessHeader {
             7 => RatingGroup::Group2200,
             8 => RatingGroup::Group2500,
             9 => RatingGroup::Group2800,
+            // Before the second version, this was the group of all games
+            // from 2800, which can not be split after the fact.
+            10 if version < FormatVersion::V2 => RatingGroup::Group2800,
             10 => RatingGroup::Group3200,
-            _ => panic!("invalid rating group"),
+            11 => RatingGroup::Group3000,
+            _ => return Err(ReadError::Invalid("rating group")),
         };
         let single_game = (n >> 7) != 0;
-        LichessHeader::Group {
+        Ok(LichessHeader::Group {
             speed,
+            mode,
             rating_group,
+            ply,
             num_games: if single_game {
                 1
             } else {
-                read_uint(buf) as usize
+                try_read_uint(buf)? as usize
             },
-        }
+        })
     }
 
     fn write<B: BufMut>(&self, buf: &mut B) {
@@ -241,9 +323,18 @@ impl LichessHeader {
             LichessHeader::End => buf.put_u8(0),
             LichessHeader::Group {
                 speed,
+                mode,
                 rating_group,
+                ply,
                 num_games,
             } => {
+                if let Some(ply) = ply {
+                    buf.put_u8(LichessHeader::PLY_PREFIX);
+                    buf.put_u8(ply);
+                }
+                if !mode.is_rated() {
+                    buf.put_u8(LichessHeader::CASUAL_PREFIX);
+                }
                 let single_game = num_games == 1;
                 buf.put_u8(
                     (match speed {
@@ -265,6 +356,7 @@ impl LichessHeader {
                         RatingGroup::Group2500 => 8,
                         RatingGroup::Group2800 => 9,
                         RatingGroup::Group3200 => 10,
+                        RatingGroup::Group3000 => 11,
                     } << 3)
                         | (u8::from(single_game) << 7),
                 );
@@ -276,79 +368,173 @@ impl LichessHeader {
     }
 }
 
+fn sub_entry_total(sub_entry: &SubEntry, filter: &LichessQueryFilter) -> Stats {
+    let mut stats = Stats::default();
+
+    for (speed, by_mode) in sub_entry.as_ref().zip_speed() {
+        if filter.contains_speed(speed) {
+            for (mode, by_rating_group) in by_mode.as_ref().zip_mode() {
+                if filter.contains_mode(mode) {
+                    for (rating_group, by_ply) in by_rating_group.as_ref().zip_rating_group() {
+                        if filter.contains_rating_group(rating_group) {
+                            for (ply, group) in by_ply.iter() {
+                                if filter.contains_ply(ply) {
+                                    stats += &group.stats;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    stats
+}
+
 #[derive(Default, Debug)]
 pub struct LichessGroup {
     pub stats: Stats,
     pub games: ThinVec<(u64, GameId)>,
+    /// Latest month that contributed to the group, if known. Not persisted.
+    pub last_month: Option<Month>,
 }
 
 #[derive(Default, Debug)]
 pub struct LichessEntry {
-    sub_entries: IntMap<RawUciMove, BySpeed<ByRatingGroup<LichessGroup>>>,
+    sub_entries: IntMap<RawUciMove, SubEntry>,
     min_game_idx: Option<u64>,
     max_game_idx: Option<u64>,
+    players: Option<PlayersSketch>,
 }
 
 impl LichessEntry {
-    pub const SIZE_HINT: usize = 13;
+    pub const SIZE_HINT: usize = 17;
+
+    /// Version written by [`LichessEntry::write()`]. Older versions are
+    /// still readable. The second version splits games from 2800 into
+    /// [`RatingGroup::Group2800`], [`RatingGroup::Group3000`] and
+    /// [`RatingGroup::Group3200`]. The third version can start with a
+    /// sketch of the players who reached the position.
+    pub const FORMAT_VERSION: FormatVersion = FormatVersion::V3;
+
+    // Not a valid move (role 14), so it can introduce the players sketch.
+    const PLAYERS_PREFIX: u16 = 0xe000;
 
+    #[allow(clippy::too_many_arguments)]
     pub fn new_single(
         uci: UciMove,
         speed: Speed,
+        mode: Mode,
+        ply: u8,
         game_id: GameId,
         outcome: Outcome,
         mover_rating: u16,
         opponent_rating: u16,
     ) -> LichessEntry {
-        let mut sub_entry: BySpeed<ByRatingGroup<LichessGroup>> = Default::default();
+        let mut sub_entry: SubEntry = Default::default();
         *sub_entry
             .by_speed_mut(speed)
-            .by_rating_group_mut(RatingGroup::select(mover_rating, opponent_rating)) =
-            LichessGroup {
-                stats: Stats::new_single(outcome, mover_rating),
-                games: thin_vec![(0, game_id)],
-            };
+            .by_mode_mut(mode)
+            .by_rating_group_mut(RatingGroup::select(mover_rating, opponent_rating))
+            .by_ply_mut(Some(ply)) = LichessGroup {
+            stats: Stats::new_single(outcome, mover_rating),
+            games: thin_vec![(0, game_id)],
+            last_month: None,
+        };
         LichessEntry {
             sub_entries: [(RawUciMove::from(uci), sub_entry)].into_iter().collect(),
             min_game_idx: Some(0),
             max_game_idx: Some(0),
+            players: None,
         }
     }
 
-    pub fn extend_from_reader<B: Buf>(&mut self, buf: &mut B) {
+    /// Also record the players of the game, to estimate the number of
+    /// distinct players who reached the position.
+    pub fn with_players(mut self, players: PlayersSketch) -> LichessEntry {
+        self.players = Some(players);
+        self
+    }
+
+    /// Merge an entry from the reader. On malformed data, an error is
+    /// returned and the entry may have been partially extended.
+    pub fn extend_from_reader<B: Buf>(&mut self, buf: &mut B) -> Result<(), ReadError> {
+        self.extend(buf, None)
+    }
+
+    /// Like [`LichessEntry::extend_from_reader()`], but also remembers that
+    /// the moves were played in the given month.
+    pub fn extend_from_month<B: Buf>(
+        &mut self,
+        month: Month,
+        buf: &mut B,
+    ) -> Result<(), ReadError> {
+        self.extend(buf, Some(month))
+    }
+
+    fn extend<B: Buf>(&mut self, buf: &mut B, month: Option<Month>) -> Result<(), ReadError> {
+        let version = FormatVersion::read(buf, LichessEntry::FORMAT_VERSION)?;
+        if version >= FormatVersion::V3
+            && buf
+                .chunk()
+                .starts_with(&LichessEntry::PLAYERS_PREFIX.to_le_bytes())
+        {
+            buf.advance(2);
+            let players = PlayersSketch::read(buf)?;
+            match self.players {
+                Some(ref mut sketch) => sketch.merge(&players),
+                None => self.players = Some(players),
+            }
+        }
         let base_game_idx = self.max_game_idx.map_or(0, |idx| idx + 1);
 
         while buf.has_remaining() {
-            let uci = RawUciMove::read(buf);
+            let uci = RawUciMove::read(buf)?;
             let sub_entry = self.sub_entries.entry(uci).or_default();
 
             while buf.has_remaining() {
-                match LichessHeader::read(buf) {
+                match LichessHeader::read(buf, version)? {
                     LichessHeader::End => break,
                     LichessHeader::Group {
                         speed,
+                        mode,
                         rating_group,
+                        ply,
                         num_games,
                     } => {
                         let group = sub_entry
                             .by_speed_mut(speed)
-                            .by_rating_group_mut(rating_group);
-                        group.stats += &Stats::read(buf);
-                        group.games.extend((0..num_games).map(|_| {
-                            let game_idx = base_game_idx + read_uint(buf);
+                            .by_mode_mut(mode)
+                            .by_rating_group_mut(rating_group)
+                            .by_ply_mut(ply);
+                        group.stats += &Stats::read(buf)?;
+                        group.last_month = max(group.last_month, month);
+                        for _ in 0..num_games {
+                            let game_idx = base_game_idx
+                                .checked_add(try_read_uint(buf)?)
+                                .ok_or(ReadError::Invalid("game index"))?;
                             self.min_game_idx =
                                 Some(min(self.min_game_idx.unwrap_or(u64::MAX), game_idx));
                             self.max_game_idx = Some(max(self.max_game_idx.unwrap_or(0), game_idx));
-                            let game = GameId::read(buf);
-                            (game_idx, game)
-                        }));
+                            group.games.push((game_idx, GameId::read(buf)?));
+                        }
                     }
                 }
             }
         }
+
+        Ok(())
     }
 
     pub fn write<B: BufMut>(&self, buf: &mut B) {
+        LichessEntry::FORMAT_VERSION.write(buf);
+
+        if let Some(ref players) = self.players {
+            buf.put_u16_le(LichessEntry::PLAYERS_PREFIX);
+            players.write(buf);
+        }
+
         for (i, (uci, sub_entry)) in self.sub_entries.iter().enumerate() {
             if i > 0 {
                 LichessHeader::End.write(buf);
@@ -356,22 +542,30 @@ impl LichessEntry {
 
             uci.write(buf);
 
-            for (speed, by_rating_group) in sub_entry.as_ref().zip_speed() {
-                for (rating_group, group) in by_rating_group.as_ref().zip_rating_group() {
-                    if !group.stats.is_empty() {
-                        let num_games = min(group.games.len(), MAX_LICHESS_GAMES);
-                        LichessHeader::Group {
-                            speed,
-                            rating_group,
-                            num_games,
-                        }
-                        .write(buf);
+            for (speed, by_mode) in sub_entry.as_ref().zip_speed() {
+                for (mode, by_rating_group) in by_mode.as_ref().zip_mode() {
+                    for (rating_group, by_ply) in by_rating_group.as_ref().zip_rating_group() {
+                        for (ply, group) in by_ply.iter() {
+                            if !group.stats.is_empty() {
+                                let num_games = min(group.games.len(), MAX_LICHESS_GAMES);
+                                LichessHeader::Group {
+                                    speed,
+                                    mode,
+                                    rating_group,
+                                    ply,
+                                    num_games,
+                                }
+                                .write(buf);
 
-                        group.stats.write(buf);
+                                group.stats.write(buf);
 
-                        for (game_idx, game) in &group.games[group.games.len() - num_games..] {
-                            write_uint(buf, *game_idx - self.min_game_idx.unwrap_or(0));
-                            game.write(buf);
+                                for (game_idx, game) in
+                                    &group.games[group.games.len() - num_games..]
+                                {
+                                    write_uint(buf, *game_idx - self.min_game_idx.unwrap_or(0));
+                                    game.write(buf);
+                                }
+                            }
                         }
                     }
                 }
@@ -381,23 +575,29 @@ impl LichessEntry {
 
     pub fn total(&self, filter: &LichessQueryFilter) -> Stats {
         let mut stats = Stats::default();
-
         for sub_entry in self.sub_entries.values() {
-            for (speed, group) in sub_entry.as_ref().zip_speed() {
-                if filter.contains_speed(speed) {
-                    for (rating_group, group) in group.as_ref().zip_rating_group() {
-                        if filter.contains_rating_group(rating_group) {
-                            stats += &group.stats;
-                        }
-                    }
-                }
-            }
+            stats += &sub_entry_total(sub_entry, filter);
         }
-
         stats
     }
 
-    pub fn prepare(self, filter: &LichessQueryFilter, limits: &Limits) -> PreparedResponse {
+    /// Number of games for each move.
+    pub fn move_totals<'a>(
+        &'a self,
+        filter: &'a LichessQueryFilter,
+    ) -> impl Iterator<Item = (UciMove, u64)> + 'a {
+        self.sub_entries.iter().filter_map(|(uci, sub_entry)| {
+            let total = sub_entry_total(sub_entry, filter).total();
+            (total > 0).then(|| (UciMove::from(*uci), total))
+        })
+    }
+
+    pub fn prepare(
+        self,
+        color: Color,
+        filter: &LichessQueryFilter,
+        limits: &Limits,
+    ) -> PreparedResponse {
         let mut total = Stats::default();
         let mut moves = Vec::with_capacity(self.sub_entries.len());
         let mut games: Vec<(RatingGroup, Speed, u64, UciMove, GameId)> = Vec::new();
@@ -406,13 +606,28 @@ impl LichessEntry {
             let uci = UciMove::from(uci);
 
             let mut latest_game: Option<(u64, GameId)> = None;
+            let mut last_month: Option<Month> = None;
             let mut stats = Stats::default();
 
-            for (speed, group) in sub_entry.as_ref().zip_speed() {
-                if filter.contains_speed(speed) {
-                    for (rating_group, group) in group.as_ref().zip_rating_group() {
-                        if filter.contains_rating_group(rating_group) {
+            for (speed, by_mode) in sub_entry.as_ref().zip_speed() {
+                if !filter.contains_speed(speed) {
+                    continue;
+                }
+                for (mode, by_rating_group) in by_mode.as_ref().zip_mode() {
+                    if !filter.contains_mode(mode) {
+                        continue;
+                    }
+                    for (rating_group, by_ply) in by_rating_group.as_ref().zip_rating_group() {
+                        if !filter.contains_rating_group(rating_group) {
+                            continue;
+                        }
+                        for (ply, group) in by_ply.iter() {
+                            if !filter.contains_ply(ply) {
+                                continue;
+                            }
+
                             stats += &group.stats;
+                            last_month = max(last_month, group.last_month);
 
                             if limits.games_wanted() {
                                 for (idx, game) in group.games.iter().copied() {
@@ -439,14 +654,25 @@ impl LichessEntry {
                     uci,
                     average_rating: stats.average_rating(),
                     average_opponent_rating: None,
-                    performance: None,
+                    // Groups are selected by the average rating of both
+                    // players, so the mover's average rating doubles as an
+                    // estimate of the opponent's.
+                    performance: stats.performance(color),
                     game: latest_game.filter(|_| stats.is_single()).map(|(_, id)| id),
                     stats,
+                    trend: None,
+                    last_played: last_month.map(LastPlayed::Month),
+                    score_delta: None,
                 });
             }
         }
 
-        sort_by_key_and_truncate(&mut moves, limits.moves, |row| Reverse(row.stats.total()));
+        moves.retain(|m| m.stats.total() >= limits.min_games);
+        sort_moves(&mut moves, color, limits);
+        if let Some(coverage) = limits.coverage {
+            moves
+                .truncate(coverage.num_moves(moves.iter().map(|m| m.stats.total()), total.total()));
+        }
 
         // Split games into top and recent.
         let (mut top_games, mut recent_games) = if let Some(top_group) = filter.top_group() {
@@ -495,6 +721,7 @@ impl LichessEntry {
                 .into_iter()
                 .map(|(_, _, _, uci, game)| (uci, game))
                 .collect(),
+            unique_players: self.players.map(|players| players.estimate()),
         }
     }
 }
@@ -505,6 +732,30 @@ pub struct PreparedResponse {
     pub moves: Vec<PreparedMove>,
     pub recent_games: Vec<(UciMove, GameId)>,
     pub top_games: Vec<(UciMove, GameId)>,
+    /// Estimated number of distinct players, independent of filters.
+    pub unique_players: Option<u64>,
+}
+
+/// Order moves as requested, breaking ties by the number of games, and keep
+/// only the requested number of moves.
+fn sort_moves(moves: &mut Vec<PreparedMove>, color: Color, limits: &Limits) {
+    let num = limits.moves_wanted();
+    match limits.order_by {
+        MoveOrder::Total => sort_by_key_and_truncate(moves, num, |row| Reverse(row.stats.total())),
+        MoveOrder::Score => sort_by_key_and_truncate(moves, num, |row| {
+            (Reverse(row.score(color)), Reverse(row.stats.total()))
+        }),
+        MoveOrder::Performance => sort_by_key_and_truncate(moves, num, |row| {
+            (Reverse(row.performance), Reverse(row.stats.total()))
+        }),
+        MoveOrder::RecentPopularity => sort_by_key_and_truncate(moves, num, |row| {
+            let last_month = match row.last_played {
+                Some(LastPlayed::Month(month)) => Some(month),
+                _ => None,
+            };
+            (Reverse(last_month), Reverse(row.stats.total()))
+        }),
+    }
 }
 
 #[derive(Debug)]
@@ -515,14 +766,126 @@ pub struct PreparedMove {
     pub average_rating: Option<u16>,
     pub average_opponent_rating: Option<u16>,
     pub performance: Option<i32>,
+    pub trend: Option<Vec<u64>>,
+    pub last_played: Option<LastPlayed>,
+    /// Score in percentage points above the expectation from the ratings of
+    /// the player and the opponents.
+    pub score_delta: Option<f64>,
+}
+
+impl PreparedMove {
+    /// Score of the move for the given side, in permille.
+    fn score(&self, color: Color) -> u64 {
+        let total = self.stats.total();
+        if total == 0 {
+            return 0;
+        }
+        let wins = color.fold_wb(self.stats.white(), self.stats.black());
+        (2 * wins + self.stats.draws()) * 500 / total
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use shakmaty::{Color, Square};
+    use quickcheck::quickcheck;
+    use shakmaty::Square;
 
     use super::*;
 
+    quickcheck! {
+        fn test_lichess_entry_arbitrary_bytes(data: Vec<u8>) -> bool {
+            // Must not panic, no matter the result.
+            let mut entry = LichessEntry::default();
+            let _ = entry.extend_from_reader(&mut &data[..]);
+            let mut buf = Vec::new();
+            entry.write(&mut buf);
+            true
+        }
+    }
+
+    #[test]
+    fn test_lichess_entry_malformed() {
+        let mut buf = Vec::new();
+        LichessEntry::new_single(
+            UciMove::Normal {
+                from: Square::E2,
+                to: Square::E4,
+                promotion: None,
+            },
+            Speed::Blitz,
+            Mode::Rated,
+            0,
+            "aaaaaaaa".parse().unwrap(),
+            Outcome::Draw,
+            1500,
+            1500,
+        )
+        .write(&mut buf);
+
+        // Cutting right after the envelope or the move would leave a valid
+        // entry without groups.
+        for len in (1..buf.len()).filter(|len| *len != 2 && *len != 4) {
+            assert_eq!(
+                LichessEntry::default().extend_from_reader(&mut &buf[..len]),
+                Err(ReadError::UnexpectedEnd),
+                "truncated to {len} bytes"
+            );
+        }
+
+        // Rating group out of range.
+        buf[6] = 3 | (15 << 3);
+        assert_eq!(
+            LichessEntry::default().extend_from_reader(&mut &buf[..]),
+            Err(ReadError::Invalid("rating group"))
+        );
+    }
+
+    #[test]
+    fn test_rating_groups() {
+        assert_eq!(RatingGroup::select_avg(2799), RatingGroup::Group2500);
+        assert_eq!(RatingGroup::select_avg(2800), RatingGroup::Group2800);
+        assert_eq!(RatingGroup::select_avg(3100), RatingGroup::Group3000);
+        assert_eq!(RatingGroup::select_avg(3300), RatingGroup::Group3200);
+
+        for rating_group in [RatingGroup::Group3000, RatingGroup::Group3200] {
+            let mut buf = Vec::new();
+            LichessHeader::Group {
+                rating_group,
+                speed: Speed::Bullet,
+                mode: Mode::Rated,
+                ply: None,
+                num_games: 2,
+            }
+            .write(&mut buf);
+
+            let LichessHeader::Group {
+                rating_group: read_group,
+                ..
+            } = LichessHeader::read(&mut &buf[..], FormatVersion::V2).unwrap()
+            else {
+                panic!("expected group");
+            };
+            assert_eq!(read_group, rating_group);
+        }
+
+        // The first version did not distinguish groups from 2800.
+        let mut buf = Vec::new();
+        LichessHeader::Group {
+            rating_group: RatingGroup::Group3200,
+            speed: Speed::Bullet,
+            mode: Mode::Rated,
+            ply: None,
+            num_games: 2,
+        }
+        .write(&mut buf);
+        let LichessHeader::Group { rating_group, .. } =
+            LichessHeader::read(&mut &buf[..], FormatVersion::V1).unwrap()
+        else {
+            panic!("expected group");
+        };
+        assert_eq!(rating_group, RatingGroup::Group2800);
+    }
+
     #[test]
     fn test_lichess_entry() {
         // Roundtrip with a single entry.
@@ -535,6 +898,8 @@ mod tests {
         let a = LichessEntry::new_single(
             uci_a.clone(),
             Speed::Blitz,
+            Mode::Rated,
+            2,
             "aaaaaaaa".parse().unwrap(),
             Outcome::Draw,
             2000,
@@ -550,7 +915,7 @@ mod tests {
         );
 
         let mut deserialized = LichessEntry::default();
-        deserialized.extend_from_reader(&mut &buf[..]);
+        deserialized.extend_from_reader(&mut &buf[..]).unwrap();
 
         assert_eq!(deserialized.sub_entries.len(), 1);
         assert_eq!(deserialized.max_game_idx, Some(0));
@@ -565,6 +930,8 @@ mod tests {
         let b = LichessEntry::new_single(
             uci_b.clone(),
             Speed::Blitz,
+            Mode::Rated,
+            2,
             "bbbbbbbb".parse().unwrap(),
             Outcome::Decisive {
                 winner: Color::White,
@@ -575,7 +942,7 @@ mod tests {
 
         let mut buf = Vec::new();
         b.write(&mut buf);
-        deserialized.extend_from_reader(&mut &buf[..]);
+        deserialized.extend_from_reader(&mut &buf[..]).unwrap();
 
         assert_eq!(deserialized.sub_entries.len(), 2);
         assert_eq!(deserialized.max_game_idx, Some(1));
@@ -584,16 +951,20 @@ mod tests {
         let mut buf = Vec::new();
         deserialized.write(&mut buf);
         let mut deserialized = LichessEntry::default();
-        deserialized.extend_from_reader(&mut &buf[..]);
+        deserialized.extend_from_reader(&mut &buf[..]).unwrap();
 
         assert_eq!(deserialized.sub_entries.len(), 2);
         assert_eq!(deserialized.max_game_idx, Some(1));
 
         // Run query.
         let res = deserialized.prepare(
+            Color::White,
             &LichessQueryFilter {
                 speeds: None,
                 ratings: Some([RatingGroup::Group2000].into()),
+                modes: None,
+                min_ply: None,
+                max_ply: None,
                 since: None,
                 until: None,
             },
@@ -601,6 +972,9 @@ mod tests {
                 recent_games: usize::MAX,
                 top_games: usize::MAX,
                 moves: Limits::default_moves(),
+                order_by: MoveOrder::default(),
+                min_games: 0,
+                coverage: None,
             },
         );
         assert_eq!(
@@ -611,4 +985,310 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_lichess_performance() {
+        let uci = UciMove::Normal {
+            from: Square::E2,
+            to: Square::E4,
+            promotion: None,
+        };
+
+        let mut entry = LichessEntry::default();
+        for (game, outcome) in [
+            (
+                "aaaaaaaa",
+                Outcome::Decisive {
+                    winner: Color::White,
+                },
+            ),
+            ("bbbbbbbb", Outcome::Draw),
+        ] {
+            let mut buf = Vec::new();
+            LichessEntry::new_single(
+                uci.clone(),
+                Speed::Blitz,
+                Mode::Rated,
+                0,
+                game.parse().unwrap(),
+                outcome,
+                2000,
+                2000,
+            )
+            .write(&mut buf);
+            entry.extend_from_reader(&mut &buf[..]).unwrap();
+        }
+
+        let filter = LichessQueryFilter {
+            speeds: None,
+            ratings: None,
+            modes: None,
+            min_ply: None,
+            max_ply: None,
+            since: None,
+            until: None,
+        };
+        let limits = Limits {
+            recent_games: 0,
+            top_games: 0,
+            moves: Limits::default_moves(),
+            order_by: MoveOrder::default(),
+            min_games: 0,
+            coverage: None,
+        };
+
+        // Scored 75% as white.
+        let res = entry.prepare(Color::White, &filter, &limits);
+        assert_eq!(res.moves[0].average_rating, Some(2000));
+        assert_eq!(res.moves[0].performance, Some(2193));
+    }
+
+    #[test]
+    fn test_lichess_move_order() {
+        let e4 = UciMove::Normal {
+            from: Square::E2,
+            to: Square::E4,
+            promotion: None,
+        };
+        let d4 = UciMove::Normal {
+            from: Square::D2,
+            to: Square::D4,
+            promotion: None,
+        };
+        let black_wins = Outcome::Decisive {
+            winner: Color::Black,
+        };
+
+        let read_entry = || {
+            let mut entry = LichessEntry::default();
+            for (uci, game, outcome) in [
+                (&e4, "aaaaaaaa", Outcome::Draw),
+                (&e4, "bbbbbbbb", black_wins),
+                (&e4, "cccccccc", black_wins),
+                (&d4, "dddddddd", Outcome::Draw),
+            ] {
+                let mut buf = Vec::new();
+                LichessEntry::new_single(
+                    uci.clone(),
+                    Speed::Blitz,
+                    Mode::Rated,
+                    0,
+                    game.parse().unwrap(),
+                    outcome,
+                    2000,
+                    2000,
+                )
+                .write(&mut buf);
+                entry.extend_from_reader(&mut &buf[..]).unwrap();
+            }
+            entry
+        };
+
+        let filter = LichessQueryFilter {
+            speeds: None,
+            ratings: None,
+            modes: None,
+            min_ply: None,
+            max_ply: None,
+            since: None,
+            until: None,
+        };
+        let ordered = |color: Color, order_by: MoveOrder, min_games: u64| {
+            let limits = Limits {
+                recent_games: 0,
+                top_games: 0,
+                moves: 1,
+                order_by,
+                min_games,
+                coverage: None,
+            };
+            read_entry().prepare(color, &filter, &limits).moves[0]
+                .uci
+                .clone()
+        };
+
+        assert_eq!(ordered(Color::White, MoveOrder::Total, 0), e4);
+        assert_eq!(ordered(Color::White, MoveOrder::Score, 0), d4);
+        assert_eq!(ordered(Color::Black, MoveOrder::Score, 0), e4);
+        assert_eq!(ordered(Color::White, MoveOrder::Performance, 0), d4);
+
+        // Rarely played moves are omitted before truncation.
+        assert_eq!(ordered(Color::White, MoveOrder::Score, 2), e4);
+    }
+
+    #[test]
+    fn test_lichess_mode_filter() {
+        let uci = UciMove::Normal {
+            from: Square::E2,
+            to: Square::E4,
+            promotion: None,
+        };
+
+        let mut entry = LichessEntry::default();
+        for (game, mode) in [("aaaaaaaa", Mode::Rated), ("bbbbbbbb", Mode::Casual)] {
+            let mut buf = Vec::new();
+            LichessEntry::new_single(
+                uci.clone(),
+                Speed::Blitz,
+                mode,
+                0,
+                game.parse().unwrap(),
+                Outcome::Draw,
+                1500,
+                1500,
+            )
+            .write(&mut buf);
+            entry.extend_from_reader(&mut &buf[..]).unwrap();
+        }
+
+        // Roundtrip.
+        let mut buf = Vec::new();
+        entry.write(&mut buf);
+        let mut entry = LichessEntry::default();
+        entry.extend_from_reader(&mut &buf[..]).unwrap();
+
+        let mut filter = LichessQueryFilter {
+            speeds: None,
+            ratings: None,
+            modes: None,
+            min_ply: None,
+            max_ply: None,
+            since: None,
+            until: None,
+        };
+        assert_eq!(entry.total(&filter).total(), 2);
+
+        filter.modes = Some([Mode::Casual].into());
+        assert_eq!(entry.total(&filter).total(), 1);
+
+        let res = entry.prepare(
+            Color::White,
+            &filter,
+            &Limits {
+                recent_games: usize::MAX,
+                top_games: usize::MAX,
+                moves: Limits::default_moves(),
+                order_by: MoveOrder::default(),
+                min_games: 0,
+                coverage: None,
+            },
+        );
+        assert_eq!(res.recent_games, &[(uci, "bbbbbbbb".parse().unwrap())]);
+    }
+
+    #[test]
+    fn test_lichess_last_played() {
+        let uci = UciMove::Normal {
+            from: Square::D2,
+            to: Square::D4,
+            promotion: None,
+        };
+
+        let read_entry = || {
+            let mut entry = LichessEntry::default();
+            for (game, mode, month) in [
+                ("aaaaaaaa", Mode::Rated, "2020-01"),
+                ("bbbbbbbb", Mode::Casual, "2021-06"),
+                ("cccccccc", Mode::Rated, "2020-03"),
+            ] {
+                let mut buf = Vec::new();
+                LichessEntry::new_single(
+                    uci.clone(),
+                    Speed::Blitz,
+                    mode,
+                    0,
+                    game.parse().unwrap(),
+                    Outcome::Draw,
+                    1500,
+                    1500,
+                )
+                .write(&mut buf);
+                entry
+                    .extend_from_month(month.parse().unwrap(), &mut &buf[..])
+                    .unwrap();
+            }
+            entry
+        };
+
+        let mut filter = LichessQueryFilter {
+            speeds: None,
+            ratings: None,
+            modes: None,
+            min_ply: None,
+            max_ply: None,
+            since: None,
+            until: None,
+        };
+        let limits = Limits {
+            recent_games: 0,
+            top_games: 0,
+            moves: Limits::default_moves(),
+            order_by: MoveOrder::default(),
+            min_games: 0,
+            coverage: None,
+        };
+
+        let res = read_entry().prepare(Color::White, &filter, &limits);
+        assert_eq!(
+            res.moves[0].last_played.map(|d| d.to_string()),
+            Some("2021-06".to_owned())
+        );
+
+        filter.modes = Some([Mode::Rated].into());
+        let res = read_entry().prepare(Color::White, &filter, &limits);
+        assert_eq!(
+            res.moves[0].last_played.map(|d| d.to_string()),
+            Some("2020-03".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_lichess_ply_filter() {
+        let uci = UciMove::Normal {
+            from: Square::G1,
+            to: Square::F3,
+            promotion: None,
+        };
+
+        let mut entry = LichessEntry::default();
+        for (game, ply) in [("aaaaaaaa", 2), ("bbbbbbbb", 6), ("cccccccc", 6)] {
+            let mut buf = Vec::new();
+            LichessEntry::new_single(
+                uci.clone(),
+                Speed::Blitz,
+                Mode::Rated,
+                ply,
+                game.parse().unwrap(),
+                Outcome::Draw,
+                1500,
+                1500,
+            )
+            .write(&mut buf);
+            entry.extend_from_reader(&mut &buf[..]).unwrap();
+        }
+
+        // Roundtrip.
+        let mut buf = Vec::new();
+        entry.write(&mut buf);
+        let mut entry = LichessEntry::default();
+        entry.extend_from_reader(&mut &buf[..]).unwrap();
+
+        let mut filter = LichessQueryFilter {
+            speeds: None,
+            ratings: None,
+            modes: None,
+            min_ply: None,
+            max_ply: None,
+            since: None,
+            until: None,
+        };
+        assert_eq!(entry.total(&filter).total(), 3);
+
+        filter.max_ply = Some(4);
+        assert_eq!(entry.total(&filter).total(), 1);
+
+        filter.max_ply = None;
+        filter.min_ply = Some(6);
+        assert_eq!(entry.total(&filter).total(), 2);
+    }
 }
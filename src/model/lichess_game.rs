@@ -1,10 +1,33 @@
-use std::convert::{TryFrom, TryInto};
+use std::{
+    convert::{TryFrom, TryInto},
+    io,
+    io::{Cursor, Write},
+};
 
+use axum::{
+    body::Body,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
 use bytes::{Buf, BufMut};
 use serde::{Deserialize, Serialize};
-use shakmaty::{ByColor, Color, Outcome};
+use shakmaty::{
+    fen::Fen,
+    san::SanPlus,
+    uci::UciMove,
+    variant::{Variant, VariantPosition},
+    ByColor, CastlingMode, Color, Outcome, Position,
+};
 
-use crate::model::{read_uint, write_uint, Mode, Month, Speed};
+use crate::model::{
+    ensure_remaining, read_uint, try_get_u8, try_read_uint, write_uint, GameId, Mode, Month,
+    RawUciMove, ReadError, Speed,
+};
+
+/// Last byte of a game that is no longer imported into the lichess
+/// explorer. Read like an unset flag, but as a merge operand it also clears
+/// the flag of the values merged before it.
+const UNINDEXED_LICHESS: u8 = 2;
 
 #[derive(Debug)]
 pub struct LichessGame {
@@ -21,6 +44,22 @@ impl LichessGame {
     pub const SIZE_HINT: usize = 1 + 2 * (1 + 20 + 2) + 2;
 
     pub fn write<B: BufMut>(&self, buf: &mut B) {
+        self.write_with_indexed_lichess(buf, u8::from(self.indexed_lichess));
+    }
+
+    /// Write the game as no longer imported into the lichess explorer, so
+    /// that merging it clears the flag instead of keeping it.
+    pub fn write_unindexed_lichess<B: BufMut>(&self, buf: &mut B) {
+        self.write_with_indexed_lichess(buf, UNINDEXED_LICHESS);
+    }
+
+    /// Whether the value was written by
+    /// [`LichessGame::write_unindexed_lichess()`].
+    pub fn clears_indexed_lichess(value: &[u8]) -> bool {
+        value.last() == Some(&UNINDEXED_LICHESS)
+    }
+
+    fn write_with_indexed_lichess<B: BufMut>(&self, buf: &mut B, indexed_lichess: u8) {
         buf.put_u8(
             match self.speed {
                 Speed::UltraBullet => 0,
@@ -45,11 +84,15 @@ impl LichessGame {
         self.players.white.write(buf);
         self.players.black.write(buf);
         buf.put_u16_le(u16::from(self.month));
-        buf.put_u8(u8::from(self.indexed_lichess));
+        buf.put_u8(indexed_lichess);
     }
 
     pub fn read<B: Buf>(buf: &mut B) -> LichessGame {
-        let byte = buf.get_u8();
+        LichessGame::try_read(buf).expect("read lichess game")
+    }
+
+    pub fn try_read<B: Buf>(buf: &mut B) -> Result<LichessGame, ReadError> {
+        let byte = try_get_u8(buf)?;
         let speed = match byte & 7 {
             0 => Speed::UltraBullet,
             1 => Speed::Bullet,
@@ -57,7 +100,7 @@ impl LichessGame {
             3 => Speed::Rapid,
             4 => Speed::Classical,
             5 => Speed::Correspondence,
-            _ => panic!("invalid speed"),
+            _ => return Err(ReadError::Invalid("speed")),
         };
         let outcome = match (byte >> 3) & 3 {
             0 => Outcome::Decisive {
@@ -67,7 +110,7 @@ impl LichessGame {
                 winner: Color::White,
             },
             2 => Outcome::Draw,
-            _ => panic!("invalid outcome"),
+            _ => return Err(ReadError::Invalid("outcome")),
         };
         let mode = Mode::from_rated((byte >> 5) & 1 == 1);
         let indexed_player = ByColor {
@@ -75,12 +118,16 @@ impl LichessGame {
             black: (byte >> 7) & 1 == 1,
         };
         let players = ByColor {
-            white: GamePlayer::read(buf),
-            black: GamePlayer::read(buf),
+            white: GamePlayer::try_read(buf)?,
+            black: GamePlayer::try_read(buf)?,
         };
-        let month = buf.get_u16_le().try_into().expect("month");
-        let indexed_lichess = buf.get_u8() != 0;
-        LichessGame {
+        ensure_remaining(buf, 3)?;
+        let month = buf
+            .get_u16_le()
+            .try_into()
+            .map_err(|_| ReadError::Invalid("month"))?;
+        let indexed_lichess = buf.get_u8() == 1;
+        Ok(LichessGame {
             outcome,
             speed,
             mode,
@@ -88,7 +135,7 @@ impl LichessGame {
             month,
             indexed_player,
             indexed_lichess,
-        }
+        })
     }
 }
 
@@ -105,13 +152,277 @@ impl GamePlayer {
         buf.put_u16_le(self.rating);
     }
 
-    fn read<B: Buf>(buf: &mut B) -> GamePlayer {
-        let len = usize::try_from(read_uint(buf)).expect("player name len");
+    fn try_read<B: Buf>(buf: &mut B) -> Result<GamePlayer, ReadError> {
+        let len = usize::try_from(try_read_uint(buf)?)
+            .map_err(|_| ReadError::Invalid("player name len"))?;
+        ensure_remaining(buf, len)?;
         let mut name = vec![0; len];
         buf.copy_to_slice(&mut name);
-        GamePlayer {
-            name: String::from_utf8(name).expect("name utf-8"),
+        ensure_remaining(buf, 2)?;
+        Ok(GamePlayer {
+            name: String::from_utf8(name).map_err(|_| ReadError::Invalid("player name"))?,
             rating: buf.get_u16_le(),
+        })
+    }
+}
+
+/// Moves of a lichess game, truncated to the plies that were indexed.
+#[derive(Debug)]
+pub struct LichessGameMoves {
+    pub variant: Variant,
+    pub fen: Option<Fen>,
+    pub moves: Vec<UciMove>,
+}
+
+impl LichessGameMoves {
+    pub fn write<B: BufMut>(&self, buf: &mut B) {
+        write_str(buf, self.variant.uci());
+        write_str(
+            buf,
+            &self.fen.as_ref().map_or(String::new(), ToString::to_string),
+        );
+        for uci in &self.moves {
+            RawUciMove::from(uci.clone()).write(buf);
+        }
+    }
+
+    pub fn read<B: Buf>(buf: &mut B) -> LichessGameMoves {
+        let variant = Variant::from_uci(&read_str(buf)).expect("variant");
+        let fen = Some(read_str(buf))
+            .filter(|fen| !fen.is_empty())
+            .map(|fen| fen.parse().expect("fen"));
+        let mut moves = Vec::new();
+        while buf.has_remaining() {
+            moves.push(UciMove::from(RawUciMove::read(buf).expect("uci move")));
+        }
+        LichessGameMoves {
+            variant,
+            fen,
+            moves,
+        }
+    }
+}
+
+fn write_str<B: BufMut>(buf: &mut B, s: &str) {
+    write_uint(buf, s.len() as u64);
+    buf.put_slice(s.as_bytes());
+}
+
+fn read_str<B: Buf>(buf: &mut B) -> String {
+    let len = usize::try_from(read_uint(buf)).expect("str len");
+    let mut s = vec![0; len];
+    buf.copy_to_slice(&mut s);
+    String::from_utf8(s).expect("str utf-8")
+}
+
+/// Lichess game reconstructed from its metadata and truncated moves.
+pub struct LichessGamePgn {
+    pub id: GameId,
+    pub info: LichessGame,
+    pub moves: LichessGameMoves,
+}
+
+impl LichessGamePgn {
+    fn write_pgn<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writeln!(
+            writer,
+            "[Event \"{} {} game\"]",
+            if self.info.mode.is_rated() {
+                "Rated"
+            } else {
+                "Casual"
+            },
+            match self.info.speed {
+                Speed::UltraBullet => "UltraBullet",
+                Speed::Bullet => "Bullet",
+                Speed::Blitz => "Blitz",
+                Speed::Rapid => "Rapid",
+                Speed::Classical => "Classical",
+                Speed::Correspondence => "Correspondence",
+            }
+        )?;
+        writeln!(writer, "[Site \"https://lichess.org/{}\"]", self.id)?;
+        writeln!(
+            writer,
+            "[Date \"{}.??\"]",
+            self.info.month.to_string().replace('-', ".")
+        )?;
+        writeln!(writer, "[White \"{}\"]", self.info.players.white.name)?;
+        writeln!(writer, "[Black \"{}\"]", self.info.players.black.name)?;
+        writeln!(writer, "[Result \"{}\"]", self.info.outcome)?;
+        writeln!(writer, "[WhiteElo \"{}\"]", self.info.players.white.rating)?;
+        writeln!(writer, "[BlackElo \"{}\"]", self.info.players.black.rating)?;
+        if self.moves.variant != Variant::Chess {
+            writeln!(writer, "[Variant \"{}\"]", self.moves.variant.uci())?;
+        }
+        if let Some(ref fen) = self.moves.fen {
+            writeln!(writer, "[FEN \"{fen}\"]")?;
+            writeln!(writer, "[SetUp \"1\"]")?;
+        }
+        writeln!(writer)?;
+
+        let mut pos = match self.moves.fen {
+            Some(ref fen) => VariantPosition::from_setup(
+                self.moves.variant,
+                fen.as_setup().to_owned(),
+                CastlingMode::Chess960,
+            )
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?,
+            None => VariantPosition::new(self.moves.variant),
+        };
+
+        for (i, uci) in self.moves.moves.iter().enumerate() {
+            let m = uci
+                .to_move(&pos)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            let fullmoves = pos.fullmoves();
+            if pos.turn().is_white() {
+                if i > 0 {
+                    write!(writer, " ")?;
+                }
+                write!(writer, "{fullmoves}.")?;
+            } else if i == 0 {
+                write!(writer, "{fullmoves}...")?;
+            }
+            let san = SanPlus::from_move_and_play_unchecked(&mut pos, &m);
+            write!(writer, " {san}")?;
+        }
+
+        if !self.moves.moves.is_empty() {
+            write!(writer, " ")?;
+        }
+        writeln!(writer, "{}", self.info.outcome)
+    }
+}
+
+impl IntoResponse for LichessGamePgn {
+    fn into_response(self) -> Response {
+        let mut buf = Cursor::new(Vec::new());
+        if let Err(err) = self.write_pgn(&mut buf) {
+            // The stored moves do not replay, so the game is corrupt.
+            log::error!("failed to write pgn of lichess game {}: {err}", self.id);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+
+        Response::builder()
+            .header(axum::http::header::CONTENT_TYPE, "application/x-chess-pgn")
+            .body(Body::from(buf.into_inner()))
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn game_pgn(fen: Option<&str>, moves: &[&str], outcome: Outcome) -> LichessGamePgn {
+        LichessGamePgn {
+            id: "aaaaaaaa".parse().unwrap(),
+            info: LichessGame {
+                outcome,
+                speed: Speed::Blitz,
+                mode: Mode::Rated,
+                players: ByColor {
+                    white: GamePlayer {
+                        name: "foo".to_owned(),
+                        rating: 1600,
+                    },
+                    black: GamePlayer {
+                        name: "bar".to_owned(),
+                        rating: 1500,
+                    },
+                },
+                month: "2024-05".parse().unwrap(),
+                indexed_player: ByColor::new_with(|_| false),
+                indexed_lichess: true,
+            },
+            moves: LichessGameMoves {
+                variant: Variant::Chess,
+                fen: fen.map(|fen| fen.parse().unwrap()),
+                moves: moves.iter().map(|uci| uci.parse().unwrap()).collect(),
+            },
         }
     }
+
+    fn pgn(game: &LichessGamePgn) -> String {
+        let mut buf = Vec::new();
+        game.write_pgn(&mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn test_lichess_game_moves_roundtrip() {
+        let moves = LichessGameMoves {
+            variant: Variant::Antichess,
+            fen: Some("8/P7/8/8/8/8/8/k6K w - - 0 1".parse().unwrap()),
+            moves: vec!["a7a8q".parse().unwrap(), "a1b1".parse().unwrap()],
+        };
+
+        let mut buf = Vec::new();
+        moves.write(&mut buf);
+        let deserialized = LichessGameMoves::read(&mut &buf[..]);
+        assert_eq!(deserialized.variant, Variant::Antichess);
+        assert_eq!(
+            deserialized.fen.map(|fen| fen.to_string()).as_deref(),
+            Some("8/P7/8/8/8/8/8/k6K w - - 0 1")
+        );
+        assert_eq!(deserialized.moves, moves.moves);
+
+        let moves = LichessGameMoves {
+            variant: Variant::Chess,
+            fen: None,
+            moves: Vec::new(),
+        };
+        let mut buf = Vec::new();
+        moves.write(&mut buf);
+        let deserialized = LichessGameMoves::read(&mut &buf[..]);
+        assert_eq!(deserialized.variant, Variant::Chess);
+        assert!(deserialized.fen.is_none());
+        assert!(deserialized.moves.is_empty());
+    }
+
+    #[test]
+    fn test_lichess_game_pgn() {
+        let game = game_pgn(
+            None,
+            &["e2e4", "e7e5", "g1f3"],
+            Outcome::Decisive {
+                winner: Color::White,
+            },
+        );
+        assert_eq!(
+            pgn(&game),
+            "[Event \"Rated Blitz game\"]\n\
+             [Site \"https://lichess.org/aaaaaaaa\"]\n\
+             [Date \"2024.05.??\"]\n\
+             [White \"foo\"]\n\
+             [Black \"bar\"]\n\
+             [Result \"1-0\"]\n\
+             [WhiteElo \"1600\"]\n\
+             [BlackElo \"1500\"]\n\
+             \n\
+             1. e4 e5 2. Nf3 1-0\n"
+        );
+
+        let game = game_pgn(
+            Some("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1"),
+            &["c7c5", "g1f3"],
+            Outcome::Draw,
+        );
+        assert!(pgn(&game).ends_with(
+            "[FEN \"rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1\"]\n\
+             [SetUp \"1\"]\n\
+             \n\
+             1... c5 2. Nf3 1/2-1/2\n"
+        ));
+    }
+
+    #[test]
+    fn test_lichess_game_pgn_corrupt_moves() {
+        let game = game_pgn(None, &["e2e5"], Outcome::Draw);
+        assert_eq!(
+            game.into_response().status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
 }
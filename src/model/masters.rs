@@ -1,5 +1,5 @@
 use std::{
-    cmp::{min, Reverse},
+    cmp::{max, min, Reverse},
     io,
     io::{Cursor, Write},
 };
@@ -12,12 +12,16 @@ use bytes::{Buf, BufMut};
 use nohash_hasher::IntMap;
 use serde::{Deserialize, Serialize};
 use serde_with::{formats::SpaceSeparator, serde_as, DisplayFromStr, StringWithSeparator};
+use sha1::{Digest, Sha1};
 use shakmaty::{san::SanPlus, uci::UciMove, ByColor, Chess, Color, Outcome};
 use thin_vec::{thin_vec, ThinVec};
 
 use crate::{
     api::Limits,
-    model::{GameId, GamePlayer, LaxDate, PreparedMove, PreparedResponse, RawUciMove, Stats},
+    model::{
+        ensure_remaining, try_get_u8, FormatVersion, GameId, GamePlayer, LastPlayed, LaxDate,
+        PreparedMove, PreparedResponse, RawUciMove, ReadError, Stats, Year,
+    },
     util::{sort_by_key_and_truncate, ByColorDef},
 };
 
@@ -49,6 +53,31 @@ pub struct MastersGame {
 }
 
 impl MastersGame {
+    pub const CONTENT_HASH_SIZE: usize = 16;
+
+    /// Hash of the players, year and moves, identifying the same game
+    /// imported from different sources under different ids. Player names
+    /// are compared ignoring case and repeated whitespace. The rest of the
+    /// date is not included, because sources differ in its precision.
+    pub fn content_hash(&self) -> [u8; MastersGame::CONTENT_HASH_SIZE] {
+        let mut hash = Sha1::new();
+        for player in self.players.iter() {
+            for word in player.name.split_whitespace() {
+                hash.update(word.to_lowercase());
+                hash.update(b" ");
+            }
+            hash.update(b"\0");
+        }
+        hash.update(u16::from(self.date.year()).to_be_bytes());
+        for uci in &self.moves {
+            hash.update(uci.to_string());
+            hash.update(b" ");
+        }
+        let mut buf = [0; MastersGame::CONTENT_HASH_SIZE];
+        buf.copy_from_slice(&hash.finalize()[..MastersGame::CONTENT_HASH_SIZE]);
+        buf
+    }
+
     fn outcome(&self) -> Outcome {
         Outcome::from_winner(self.winner)
     }
@@ -103,7 +132,11 @@ impl IntoResponse for MastersGame {
 #[derive(Debug, Default)]
 pub struct MastersGroup {
     stats: Stats,
+    /// Games by sort key, which is the combined rating of both players plus
+    /// a bonus for recent games.
     games: ThinVec<(u16, GameId)>,
+    /// Latest year that contributed to the group, if known. Not persisted.
+    last_year: Option<Year>,
 }
 
 #[derive(Default, Debug)]
@@ -114,19 +147,30 @@ pub struct MastersEntry {
 impl MastersEntry {
     pub const SIZE_HINT: usize = 14;
 
+    /// Version written by [`MastersEntry::write()`]. Older versions are still
+    /// readable.
+    pub const FORMAT_VERSION: FormatVersion = FormatVersion::V1;
+
     pub fn new_single(
         uci: UciMove,
         id: GameId,
         outcome: Outcome,
         mover_rating: u16,
         opponent_rating: u16,
+        recency_bonus: u16,
     ) -> MastersEntry {
         MastersEntry {
             groups: [(
                 RawUciMove::from(uci),
                 MastersGroup {
                     stats: Stats::new_single(outcome, mover_rating),
-                    games: thin_vec![(mover_rating.saturating_add(opponent_rating), id)],
+                    games: thin_vec![(
+                        mover_rating
+                            .saturating_add(opponent_rating)
+                            .saturating_add(recency_bonus),
+                        id,
+                    )],
+                    last_year: None,
                 },
             )]
             .into_iter()
@@ -134,16 +178,46 @@ impl MastersEntry {
         }
     }
 
-    pub fn extend_from_reader<B: Buf>(&mut self, buf: &mut B) {
+    /// Merge an entry from the reader. On malformed data, an error is
+    /// returned and the entry may have been partially extended.
+    pub fn extend_from_reader<B: Buf>(&mut self, buf: &mut B) -> Result<(), ReadError> {
+        self.extend(buf, None, 0)
+    }
+
+    /// Like [`MastersEntry::extend_from_reader()`], but also remembers that
+    /// the moves were played in the given year, and shifts the ratings of
+    /// the games by `rating_delta`.
+    pub fn extend_from_year<B: Buf>(
+        &mut self,
+        year: Year,
+        rating_delta: i32,
+        buf: &mut B,
+    ) -> Result<(), ReadError> {
+        self.extend(buf, Some(year), rating_delta)
+    }
+
+    fn extend<B: Buf>(
+        &mut self,
+        buf: &mut B,
+        year: Option<Year>,
+        rating_delta: i32,
+    ) -> Result<(), ReadError> {
+        FormatVersion::read(buf, MastersEntry::FORMAT_VERSION)?;
         while buf.has_remaining() {
-            let uci = RawUciMove::read(buf);
+            let uci = RawUciMove::read(buf)?;
             let group = self.groups.entry(uci).or_default();
-            group.stats += &Stats::read(buf);
-            let num_games = usize::from(buf.get_u8());
-            group
-                .games
-                .extend((0..num_games).map(|_| (buf.get_u16_le(), GameId::read(buf))));
+            let mut stats = Stats::read(buf)?;
+            stats.adjust_ratings(rating_delta);
+            group.stats += &stats;
+            group.last_year = max(group.last_year, year);
+            let num_games = usize::from(try_get_u8(buf)?);
+            for _ in 0..num_games {
+                ensure_remaining(buf, 2)?;
+                let rating_sum = buf.get_u16_le();
+                group.games.push((rating_sum, GameId::read(buf)?));
+            }
         }
+        Ok(())
     }
 
     pub fn write<B: BufMut>(&self, buf: &mut B) {
@@ -163,6 +237,8 @@ impl MastersEntry {
             return;
         };
 
+        MastersEntry::FORMAT_VERSION.write(buf);
+
         for (uci, group) in &self.groups {
             uci.write(buf);
             group.stats.write(buf);
@@ -207,6 +283,9 @@ impl MastersEntry {
                 performance: None,
                 game: single_game,
                 stats: group.stats,
+                trend: None,
+                last_played: group.last_year.map(LastPlayed::Year),
+                score_delta: None,
             });
 
             top_games.extend(
@@ -224,7 +303,14 @@ impl MastersEntry {
             |(sort_key, _, _)| Reverse(*sort_key),
         );
 
-        sort_by_key_and_truncate(&mut moves, limits.moves, |m| Reverse(m.stats.total()));
+        moves.retain(|m| m.stats.total() >= limits.min_games);
+        sort_by_key_and_truncate(&mut moves, limits.moves_wanted(), |m| {
+            Reverse(m.stats.total())
+        });
+        if let Some(coverage) = limits.coverage {
+            moves
+                .truncate(coverage.num_moves(moves.iter().map(|m| m.stats.total()), total.total()));
+        }
 
         PreparedResponse {
             total,
@@ -234,16 +320,45 @@ impl MastersEntry {
                 .map(|(_, uci, game)| (uci, game))
                 .collect(),
             recent_games: Vec::new(),
+            unique_players: None,
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use serde_json::json;
     use shakmaty::Square;
 
     use super::*;
 
+    fn masters_game(id: &str, white: &str, date: &str, moves: &str) -> MastersGameWithId {
+        serde_json::from_value(json!({
+            "id": id,
+            "event": "Tata Steel Masters",
+            "site": "Wijk aan Zee NED",
+            "date": date,
+            "round": "1",
+            "white": { "name": white, "rating": 2830 },
+            "black": { "name": "Giri, Anish", "rating": 2760 },
+            "winner": "white",
+            "moves": moves,
+        }))
+        .expect("masters game")
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let a = masters_game("aaaaaaaa", "Carlsen, Magnus", "2023.01.14", "e2e4 e7e5");
+        let b = masters_game("bbbbbbbb", "carlsen,  Magnus", "2023.??.??", "e2e4 e7e5");
+        assert_eq!(a.game.content_hash(), b.game.content_hash());
+
+        let other_moves = masters_game("aaaaaaaa", "Carlsen, Magnus", "2023.01.14", "e2e4 c7c5");
+        assert_ne!(a.game.content_hash(), other_moves.game.content_hash());
+        let other_year = masters_game("aaaaaaaa", "Carlsen, Magnus", "2022.01.14", "e2e4 e7e5");
+        assert_ne!(a.game.content_hash(), other_year.game.content_hash());
+    }
+
     #[test]
     fn test_masters_entry() {
         let uci = UciMove::Normal {
@@ -252,7 +367,7 @@ mod tests {
             promotion: None,
         };
         let game = "aaaaaaaa".parse().unwrap();
-        let a = MastersEntry::new_single(uci.clone(), game, Outcome::Draw, 1600, 1700);
+        let a = MastersEntry::new_single(uci.clone(), game, Outcome::Draw, 1600, 1700, 0);
 
         let mut buf = Vec::with_capacity(MastersEntry::SIZE_HINT);
         a.write(&mut buf);
@@ -264,7 +379,7 @@ mod tests {
 
         let mut reader = &buf[..];
         let mut deserialized = MastersEntry::default();
-        deserialized.extend_from_reader(&mut reader);
+        deserialized.extend_from_reader(&mut reader).unwrap();
 
         let group = deserialized.groups.get(&RawUciMove::from(uci)).unwrap();
         assert_eq!(group.stats.draws(), 1);
@@ -1,29 +1,50 @@
+mod audit;
 mod date;
+mod format;
 mod game_id;
+mod game_source;
 mod history;
+mod import_status;
 mod key;
 mod lichess;
 mod lichess_game;
 mod masters;
 mod mode;
 mod player;
+mod players_sketch;
+mod read_error;
+mod search;
 mod speed;
 mod stats;
+mod structure;
+mod title;
 mod uci;
 mod uint;
 mod user;
 
-pub use date::{InvalidDate, LaxDate, Month, Year};
+pub use audit::{AuditEntry, AuditKey};
+pub use date::{InvalidDate, LastPlayed, LaxDate, Month, Year};
+pub use format::FormatVersion;
 pub use game_id::{GameId, InvalidGameId};
-pub use history::{History, HistoryBuilder, HistorySegment};
+pub use game_source::{GameSource, InvalidGameSource};
+pub use history::{History, HistoryBuilder, HistorySegment, MonthsBuilder, TrendBuilder};
+pub use import_status::ImportStatus;
 pub use key::{Key, KeyBuilder, KeyPrefix};
 pub use lichess::{LichessEntry, LichessGroup, PreparedMove, PreparedResponse, RatingGroup};
-pub use lichess_game::{GamePlayer, LichessGame};
+pub use lichess_game::{GamePlayer, LichessGame, LichessGameMoves, LichessGamePgn};
 pub use masters::{MastersEntry, MastersGame, MastersGameWithId};
 pub use mode::{ByMode, Mode};
-pub use player::{IndexRun, PlayerEntry, PlayerStatus};
+pub use player::{
+    IndexCooldowns, IndexRun, InvalidPlayerGamesCursor, PlayerEntry, PlayerGamesCursor,
+    PlayerRating, PlayerStatus, TimeBucket,
+};
+pub use players_sketch::PlayersSketch;
+pub use read_error::{ensure_remaining, try_get_u8, ReadError};
+pub use search::{search_tokens, SearchField, SearchKey, SearchSource};
 pub use speed::{BySpeed, Speed};
 pub use stats::Stats;
+pub use structure::{PawnStructure, StructureEntry};
+pub use title::{InvalidTitle, Title, TitleFilter};
 pub use uci::RawUciMove;
-pub use uint::{read_uint, write_uint};
+pub use uint::{read_uint, try_read_uint, write_uint};
 pub use user::{UserId, UserName};
@@ -1,98 +1,319 @@
 use std::{
     cmp::{max, min, Reverse},
     fmt,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
     time::{Duration, SystemTime},
 };
 
 use bytes::{Buf, BufMut};
 use nohash_hasher::IntMap;
 use shakmaty::{uci::UciMove, Color, Outcome};
-use thin_vec::thin_vec;
+use thin_vec::{thin_vec, ThinVec};
+use thiserror::Error;
 
 use crate::{
-    api::{PlayerLimits, PlayerQueryFilter},
+    api::{PlayerLimits, PlayerQueryFilter, TimeBreakdown},
     model::{
-        read_uint, write_uint, ByMode, BySpeed, GameId, LichessGroup, Mode, PreparedMove,
-        PreparedResponse, RawUciMove, Speed, Stats,
+        read_uint, try_get_u8, try_read_uint, write_uint, ByMode, BySpeed, FormatVersion, GameId,
+        GameSource, LastPlayed, LichessGroup, Mode, Month, PreparedMove, PreparedResponse,
+        RawUciMove, ReadError, Speed, Stats, Title,
     },
     util::sort_by_key_and_truncate,
 };
 
 const MAX_PLAYER_GAMES: usize = 8; // must fit into 4 bits
 
+/// Rating of the player at the time of the game, rounded down to a
+/// multiple of 100.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct PlayerRating(u8);
+
+impl PlayerRating {
+    pub fn from_rating(rating: u16) -> PlayerRating {
+        PlayerRating(u8::try_from(rating / 100).unwrap_or(u8::MAX))
+    }
+
+    pub fn rating(self) -> u16 {
+        u16::from(self.0) * 100
+    }
+}
+
+/// Weekday and hour (UTC) at which a game started.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TimeBucket(u8);
+
+impl TimeBucket {
+    const COUNT: u8 = 7 * 24;
+
+    pub fn from_unix_millis(millis: u64) -> TimeBucket {
+        let hours = millis / (60 * 60 * 1000);
+        // 1970-01-01 was a Thursday.
+        let weekday = (hours / 24 + 3) % 7;
+        TimeBucket((weekday * 24 + hours % 24) as u8)
+    }
+
+    /// Day of the week, starting with 0 for Monday.
+    pub fn weekday(self) -> usize {
+        usize::from(self.0 / 24)
+    }
+
+    pub fn hour(self) -> usize {
+        usize::from(self.0 % 24)
+    }
+}
+
+/// Details that are recorded only for some games, distinguishing groups of
+/// a sub entry.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+struct GroupKey {
+    source: Option<GameSource>,
+    rating: Option<PlayerRating>,
+    time: Option<TimeBucket>,
+    opponent_title: Option<Title>,
+}
+
+/// Actual and expected score against the opposition, over games where the
+/// rating of the player is known.
+#[derive(Default)]
+struct ScoreExpectation {
+    games: u64,
+    actual: f64,
+    expected: f64,
+}
+
+impl ScoreExpectation {
+    fn add(&mut self, rating: Option<PlayerRating>, stats: &Stats, color: Color) {
+        let (Some(rating), Some(opponent_rating), Some(score)) =
+            (rating, stats.average_rating(), stats.score(color))
+        else {
+            return;
+        };
+        // Ratings are grouped by hundreds, so take the middle of the range.
+        let rating_diff = f64::from(rating.rating() + 50) - f64::from(opponent_rating);
+        let games = stats.total();
+        self.games += games;
+        self.actual += score * games as f64;
+        self.expected += Stats::expected_score(rating_diff) * games as f64;
+    }
+
+    /// Actual minus expected score, in percentage points, rounded to one
+    /// decimal.
+    fn delta(&self) -> Option<f64> {
+        (self.games > 0)
+            .then(|| ((self.actual - self.expected) * 1000.0 / self.games as f64).round() / 10.0)
+    }
+}
+
+impl GroupKey {
+    fn matches(&self, filter: &PlayerQueryFilter) -> bool {
+        filter.contains_source(self.source)
+            && filter.contains_rating(self.rating)
+            && filter.contains_opponent_title(self.opponent_title)
+    }
+}
+
 #[derive(Debug, Eq, PartialEq)]
 enum Header {
     Group {
         mode: Mode,
         speed: Speed,
+        key: GroupKey,
         num_games: usize,
     },
     End,
 }
 
 impl Header {
-    fn read<B: Buf>(buf: &mut B) -> Header {
-        let n = buf.get_u8();
-        Header::Group {
+    // Not a valid header byte (speed bits 7), so it can prefix the header of
+    // a group, with the source of the game in the following bits. Groups
+    // without the prefix were indexed before sources were recorded.
+    const SOURCE_PREFIX: u8 = 7;
+
+    // Like the source prefix, but followed by a byte with the rating of the
+    // player. Groups without the prefix were indexed before ratings were
+    // recorded.
+    const RATING_PREFIX: u8 = Header::SOURCE_PREFIX | (3 << 3);
+
+    // Followed by a byte with the time bucket of the games, since the second
+    // format version.
+    const TIME_PREFIX: u8 = Header::SOURCE_PREFIX | (4 << 3);
+
+    // Followed by a byte with the title of the opponent. Groups without the
+    // prefix were indexed before titles were recorded.
+    const OPPONENT_TITLE_PREFIX: u8 = Header::SOURCE_PREFIX | (5 << 3);
+
+    fn read<B: Buf>(buf: &mut B, version: FormatVersion) -> Result<Header, ReadError> {
+        let mut n = try_get_u8(buf)?;
+        let mut key = GroupKey::default();
+        if n & 7 == Header::SOURCE_PREFIX && n >> 3 <= 2 {
+            key.source = Some(match n >> 3 {
+                0 => GameSource::Pairing,
+                1 => GameSource::Arena,
+                _ => GameSource::Swiss,
+            });
+            n = try_get_u8(buf)?;
+        }
+        if n == Header::RATING_PREFIX {
+            key.rating = Some(PlayerRating(try_get_u8(buf)?));
+            n = try_get_u8(buf)?;
+        }
+        if n == Header::TIME_PREFIX && version >= FormatVersion::V2 {
+            let bucket = try_get_u8(buf)?;
+            if bucket >= TimeBucket::COUNT {
+                return Err(ReadError::Invalid("player time bucket"));
+            }
+            key.time = Some(TimeBucket(bucket));
+            n = try_get_u8(buf)?;
+        }
+        if n == Header::OPPONENT_TITLE_PREFIX {
+            key.opponent_title = Some(
+                Title::from_u8(try_get_u8(buf)?)
+                    .ok_or(ReadError::Invalid("player opponent title"))?,
+            );
+            n = try_get_u8(buf)?;
+        }
+        Ok(Header::Group {
             speed: match n & 7 {
-                0 => return Header::End,
+                0 => return Ok(Header::End),
                 1 => Speed::UltraBullet,
                 2 => Speed::Bullet,
                 3 => Speed::Blitz,
                 4 => Speed::Rapid,
                 5 => Speed::Classical,
                 6 => Speed::Correspondence,
-                _ => panic!("invalid player header"),
+                _ => return Err(ReadError::Invalid("player header")),
             },
             mode: Mode::from_rated((n >> 3) & 1 == 1),
+            key,
             num_games: usize::from(n >> 4),
-        }
+        })
     }
 
     fn write<B: BufMut>(&self, buf: &mut B) {
-        buf.put_u8(match *self {
-            Header::End => 0,
+        match *self {
+            Header::End => buf.put_u8(0),
             Header::Group {
                 mode,
                 speed,
+                key,
                 num_games,
             } => {
-                (match speed {
-                    Speed::UltraBullet => 1,
-                    Speed::Bullet => 2,
-                    Speed::Blitz => 3,
-                    Speed::Rapid => 4,
-                    Speed::Classical => 5,
-                    Speed::Correspondence => 6,
-                }) | (u8::from(mode.is_rated()) << 3)
-                    | ((num_games as u8) << 4)
+                if let Some(source) = key.source {
+                    buf.put_u8(
+                        Header::SOURCE_PREFIX
+                            | (match source {
+                                GameSource::Pairing => 0,
+                                GameSource::Arena => 1,
+                                GameSource::Swiss => 2,
+                            } << 3),
+                    );
+                }
+                if let Some(PlayerRating(rating)) = key.rating {
+                    buf.put_u8(Header::RATING_PREFIX);
+                    buf.put_u8(rating);
+                }
+                if let Some(TimeBucket(bucket)) = key.time {
+                    buf.put_u8(Header::TIME_PREFIX);
+                    buf.put_u8(bucket);
+                }
+                if let Some(title) = key.opponent_title {
+                    buf.put_u8(Header::OPPONENT_TITLE_PREFIX);
+                    buf.put_u8(title.to_u8());
+                }
+                buf.put_u8(
+                    (match speed {
+                        Speed::UltraBullet => 1,
+                        Speed::Bullet => 2,
+                        Speed::Blitz => 3,
+                        Speed::Rapid => 4,
+                        Speed::Classical => 5,
+                        Speed::Correspondence => 6,
+                    }) | (u8::from(mode.is_rated()) << 3)
+                        | ((num_games as u8) << 4),
+                );
             }
-        });
+        }
+    }
+}
+
+/// Groups of a sub entry by the source of the games, the rating of the
+/// player, and so on. Sparse, because most players only play in few kinds
+/// of events, within a few hundred rating points.
+#[derive(Debug)]
+struct ByKey<T> {
+    groups: ThinVec<(GroupKey, T)>,
+}
+
+impl<T> Default for ByKey<T> {
+    fn default() -> ByKey<T> {
+        ByKey {
+            groups: ThinVec::new(),
+        }
     }
 }
 
+impl<T: Default> ByKey<T> {
+    fn by_key_mut(&mut self, key: GroupKey) -> &mut T {
+        let idx = match self.groups.iter().position(|(k, _)| *k == key) {
+            Some(idx) => idx,
+            None => {
+                self.groups.push((key, T::default()));
+                self.groups.len() - 1
+            }
+        };
+        &mut self.groups[idx].1
+    }
+}
+
+impl<T> ByKey<T> {
+    fn iter(&self) -> impl Iterator<Item = (GroupKey, &T)> {
+        self.groups.iter().map(|(key, group)| (*key, group))
+    }
+}
+
+type SubEntry = BySpeed<ByMode<ByKey<LichessGroup>>>;
+
 #[derive(Default, Debug)]
 pub struct PlayerEntry {
-    sub_entries: IntMap<RawUciMove, BySpeed<ByMode<LichessGroup>>>,
+    sub_entries: IntMap<RawUciMove, SubEntry>,
     min_game_idx: Option<u64>,
     max_game_idx: Option<u64>,
 }
 
 impl PlayerEntry {
-    pub const SIZE_HINT: usize = 13;
+    pub const SIZE_HINT: usize = 16;
 
+    /// Version written by [`PlayerEntry::write()`]. Older versions are still
+    /// readable. The second version can record the time bucket of games.
+    pub const FORMAT_VERSION: FormatVersion = FormatVersion::V2;
+
+    #[allow(clippy::too_many_arguments)]
     pub fn new_single(
         uci: UciMove,
         speed: Speed,
         mode: Mode,
+        source: Option<GameSource>,
+        time: Option<TimeBucket>,
         game_id: GameId,
         outcome: Outcome,
+        rating: Option<u16>,
         opponent_rating: u16,
+        opponent_title: Option<Title>,
     ) -> PlayerEntry {
-        let mut sub_entry: BySpeed<ByMode<LichessGroup>> = Default::default();
-        *sub_entry.by_speed_mut(speed).by_mode_mut(mode) = LichessGroup {
+        let mut sub_entry: SubEntry = Default::default();
+        *sub_entry
+            .by_speed_mut(speed)
+            .by_mode_mut(mode)
+            .by_key_mut(GroupKey {
+                source,
+                rating: rating.map(PlayerRating::from_rating),
+                time,
+                opponent_title,
+            }) = LichessGroup {
             stats: Stats::new_single(outcome, opponent_rating),
             games: thin_vec![(0, game_id)],
+            last_month: None,
         };
         PlayerEntry {
             sub_entries: [(RawUciMove::from(uci), sub_entry)].into_iter().collect(),
@@ -101,38 +322,65 @@ impl PlayerEntry {
         }
     }
 
-    pub fn extend_from_reader<B: Buf>(&mut self, buf: &mut B) {
+    /// Merge an entry from the reader. On malformed data, an error is
+    /// returned and the entry may have been partially extended.
+    pub fn extend_from_reader<B: Buf>(&mut self, buf: &mut B) -> Result<(), ReadError> {
+        self.extend(buf, None)
+    }
+
+    /// Like [`PlayerEntry::extend_from_reader()`], but also remembers that
+    /// the moves were played in the given month.
+    pub fn extend_from_month<B: Buf>(
+        &mut self,
+        month: Month,
+        buf: &mut B,
+    ) -> Result<(), ReadError> {
+        self.extend(buf, Some(month))
+    }
+
+    fn extend<B: Buf>(&mut self, buf: &mut B, month: Option<Month>) -> Result<(), ReadError> {
+        let version = FormatVersion::read(buf, PlayerEntry::FORMAT_VERSION)?;
         let base_game_idx = self.max_game_idx.map_or(0, |idx| idx + 1);
 
         while buf.has_remaining() {
-            let uci = RawUciMove::read(buf);
+            let uci = RawUciMove::read(buf)?;
             let sub_entry = self.sub_entries.entry(uci).or_default();
 
             while buf.has_remaining() {
-                match Header::read(buf) {
+                match Header::read(buf, version)? {
                     Header::End => break,
                     Header::Group {
                         speed,
                         mode,
+                        key,
                         num_games,
                     } => {
-                        let group = sub_entry.by_speed_mut(speed).by_mode_mut(mode);
-                        group.stats += &Stats::read(buf);
-                        group.games.extend((0..num_games).map(|_| {
-                            let game_idx = base_game_idx + read_uint(buf);
+                        let group = sub_entry
+                            .by_speed_mut(speed)
+                            .by_mode_mut(mode)
+                            .by_key_mut(key);
+                        group.stats += &Stats::read(buf)?;
+                        group.last_month = max(group.last_month, month);
+                        for _ in 0..num_games {
+                            let game_idx = base_game_idx
+                                .checked_add(try_read_uint(buf)?)
+                                .ok_or(ReadError::Invalid("game index"))?;
                             self.min_game_idx =
                                 Some(min(self.min_game_idx.unwrap_or(u64::MAX), game_idx));
                             self.max_game_idx = Some(max(self.max_game_idx.unwrap_or(0), game_idx));
-                            let game = GameId::read(buf);
-                            (game_idx, game)
-                        }));
+                            group.games.push((game_idx, GameId::read(buf)?));
+                        }
                     }
                 }
             }
         }
+
+        Ok(())
     }
 
     pub fn write<B: BufMut>(&self, buf: &mut B) {
+        PlayerEntry::FORMAT_VERSION.write(buf);
+
         for (i, (uci, sub_entry)) in self.sub_entries.iter().enumerate() {
             if i > 0 {
                 Header::End.write(buf);
@@ -141,27 +389,114 @@ impl PlayerEntry {
             uci.write(buf);
 
             for (speed, by_mode) in sub_entry.as_ref().zip_speed() {
-                for (mode, group) in by_mode.as_ref().zip_mode() {
-                    if !group.stats.is_empty() {
-                        Header::Group {
-                            speed,
-                            mode,
-                            num_games: min(group.games.len(), MAX_PLAYER_GAMES),
+                for (mode, by_key) in by_mode.as_ref().zip_mode() {
+                    for (key, group) in by_key.iter() {
+                        if !group.stats.is_empty() {
+                            Header::Group {
+                                speed,
+                                mode,
+                                key,
+                                num_games: min(group.games.len(), MAX_PLAYER_GAMES),
+                            }
+                            .write(buf);
+
+                            group.stats.write(buf);
+
+                            for (game_idx, game) in
+                                &group.games[group.games.len().saturating_sub(MAX_PLAYER_GAMES)..]
+                            {
+                                write_uint(buf, *game_idx - self.min_game_idx.unwrap_or(0));
+                                game.write(buf);
+                            }
                         }
-                        .write(buf);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn total(&self) -> Stats {
+        let mut stats = Stats::default();
+        for sub_entry in self.sub_entries.values() {
+            for by_mode in sub_entry.as_ref() {
+                for by_key in by_mode.as_ref() {
+                    for (_, group) in by_key.iter() {
+                        stats += &group.stats;
+                    }
+                }
+            }
+        }
+        stats
+    }
 
-                        group.stats.write(buf);
+    /// Results of games matching the filter, by the weekday and hour at
+    /// which they started. Games indexed before times were recorded are not
+    /// included.
+    pub fn time_breakdown(&self, filter: &PlayerQueryFilter) -> TimeBreakdown {
+        let mut breakdown = TimeBreakdown::default();
+        for sub_entry in self.sub_entries.values() {
+            for (speed, by_mode) in sub_entry.as_ref().zip_speed() {
+                if !filter
+                    .speeds
+                    .as_ref()
+                    .map_or(true, |speeds| speeds.contains(&speed))
+                {
+                    continue;
+                }
+                for (mode, by_key) in by_mode.as_ref().zip_mode() {
+                    if !filter
+                        .modes
+                        .as_ref()
+                        .map_or(true, |modes| modes.contains(&mode))
+                    {
+                        continue;
+                    }
+                    for (key, group) in by_key.iter() {
+                        if let Some(time) = key.time.filter(|_| key.matches(filter)) {
+                            breakdown.weekdays[time.weekday()] += &group.stats;
+                            breakdown.hours[time.hour()] += &group.stats;
+                        }
+                    }
+                }
+            }
+        }
+        breakdown
+    }
 
-                        for (game_idx, game) in
-                            &group.games[group.games.len().saturating_sub(MAX_PLAYER_GAMES)..]
-                        {
-                            write_uint(buf, *game_idx - self.min_game_idx.unwrap_or(0));
-                            game.write(buf);
+    /// Games matching the filter, most recent first. Only the most recent
+    /// games of each group are stored.
+    pub fn games(&self, filter: &PlayerQueryFilter) -> Vec<(UciMove, GameId)> {
+        let mut games: Vec<(u64, RawUciMove, GameId)> = Vec::new();
+        for (uci, sub_entry) in &self.sub_entries {
+            for (speed, by_mode) in sub_entry.as_ref().zip_speed() {
+                if !filter
+                    .speeds
+                    .as_ref()
+                    .map_or(true, |speeds| speeds.contains(&speed))
+                {
+                    continue;
+                }
+                for (mode, by_key) in by_mode.as_ref().zip_mode() {
+                    if !filter
+                        .modes
+                        .as_ref()
+                        .map_or(true, |modes| modes.contains(&mode))
+                    {
+                        continue;
+                    }
+                    for (key, group) in by_key.iter() {
+                        if key.matches(filter) {
+                            games.extend(group.games.iter().map(|(idx, id)| (*idx, *uci, *id)));
                         }
                     }
                 }
             }
         }
+        games.sort_by_key(|(idx, _, _)| Reverse(*idx));
+        games
+            .into_iter()
+            .map(|(_, uci, id)| (UciMove::from(uci), id))
+            .collect()
     }
 
     pub fn prepare(
@@ -176,7 +511,9 @@ impl PlayerEntry {
 
         for (uci, sub_entry) in self.sub_entries {
             let mut latest_game: Option<(u64, GameId)> = None;
+            let mut last_month: Option<Month> = None;
             let mut stats = Stats::default();
+            let mut expectation = ScoreExpectation::default();
 
             for (speed, group) in sub_entry.as_ref().zip_speed() {
                 if filter
@@ -184,28 +521,37 @@ impl PlayerEntry {
                     .as_ref()
                     .map_or(true, |speeds| speeds.contains(&speed))
                 {
-                    for (mode, group) in group.as_ref().zip_mode() {
+                    for (mode, by_key) in group.as_ref().zip_mode() {
                         if filter
                             .modes
                             .as_ref()
                             .map_or(true, |modes| modes.contains(&mode))
                         {
-                            stats += &group.stats;
+                            for (key, group) in by_key.iter() {
+                                if !key.matches(filter) {
+                                    continue;
+                                }
+
+                                stats += &group.stats;
+                                last_month = max(last_month, group.last_month);
+                                expectation.add(key.rating, &group.stats, color);
 
-                            for (idx, game) in group.games.iter().copied() {
-                                if latest_game.map_or(true, |(latest_idx, _game)| latest_idx < idx)
-                                {
-                                    latest_game = Some((idx, game));
+                                for (idx, game) in group.games.iter().copied() {
+                                    if latest_game
+                                        .map_or(true, |(latest_idx, _game)| latest_idx < idx)
+                                    {
+                                        latest_game = Some((idx, game));
+                                    }
                                 }
-                            }
 
-                            recent_games.extend(
-                                group
-                                    .games
-                                    .iter()
-                                    .copied()
-                                    .map(|(idx, game)| (idx, uci, game)),
-                            );
+                                recent_games.extend(
+                                    group
+                                        .games
+                                        .iter()
+                                        .copied()
+                                        .map(|(idx, game)| (idx, uci, game)),
+                                );
+                            }
                         }
                     }
                 }
@@ -221,6 +567,9 @@ impl PlayerEntry {
                     performance: stats.performance(color),
                     game: latest_game.filter(|_| stats.is_single()).map(|(_, id)| id),
                     stats,
+                    trend: None,
+                    last_played: last_month.map(LastPlayed::Month),
+                    score_delta: expectation.delta(),
                 });
             }
         }
@@ -240,8 +589,84 @@ impl PlayerEntry {
                 .map(|(_, uci, game)| (UciMove::from(uci), game))
                 .collect(),
             top_games: Vec::new(),
+            unique_players: None,
+        }
+    }
+}
+
+/// Position in the list of games of a player, given as the month and the
+/// number of games of that month that were already returned, for example
+/// `2023-11:20`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PlayerGamesCursor {
+    pub month: Month,
+    pub skip: usize,
+}
+
+impl fmt::Display for PlayerGamesCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.month, self.skip)
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("invalid player games cursor")]
+pub struct InvalidPlayerGamesCursor;
+
+impl FromStr for PlayerGamesCursor {
+    type Err = InvalidPlayerGamesCursor;
+
+    fn from_str(s: &str) -> Result<PlayerGamesCursor, InvalidPlayerGamesCursor> {
+        let (month, skip) = s.split_once(':').ok_or(InvalidPlayerGamesCursor)?;
+        Ok(PlayerGamesCursor {
+            month: month.parse().map_err(|_| InvalidPlayerGamesCursor)?,
+            skip: skip.parse().map_err(|_| InvalidPlayerGamesCursor)?,
+        })
+    }
+}
+
+/// Minimum time between index runs of the same player. Shared by all
+/// indexers, and adjustable at runtime.
+#[derive(Debug)]
+pub struct IndexCooldowns {
+    index: AtomicU64,
+    revisit: AtomicU64,
+}
+
+impl Default for IndexCooldowns {
+    fn default() -> IndexCooldowns {
+        IndexCooldowns::new(
+            Duration::from_secs(2 * 60),
+            Duration::from_secs(24 * 60 * 60),
+        )
+    }
+}
+
+impl IndexCooldowns {
+    pub fn new(index: Duration, revisit: Duration) -> IndexCooldowns {
+        IndexCooldowns {
+            index: AtomicU64::new(index.as_secs()),
+            revisit: AtomicU64::new(revisit.as_secs()),
         }
     }
+
+    /// Before indexing new games.
+    pub fn index(&self) -> Duration {
+        Duration::from_secs(self.index.load(Ordering::Relaxed))
+    }
+
+    pub fn set_index(&self, cooldown: Duration) {
+        self.index.store(cooldown.as_secs(), Ordering::Relaxed);
+    }
+
+    /// Before revisiting games that were ongoing in a previous run.
+    pub fn revisit(&self) -> Duration {
+        Duration::from_secs(self.revisit.load(Ordering::Relaxed))
+    }
+
+    pub fn set_revisit(&self, cooldown: Duration) {
+        self.revisit.store(cooldown.as_secs(), Ordering::Relaxed);
+    }
 }
 
 #[derive(Debug)]
@@ -266,15 +691,16 @@ impl Default for PlayerStatus {
 impl PlayerStatus {
     pub const SIZE_HINT: usize = 3 * 8;
 
-    pub fn maybe_start_index_run(&self) -> Option<IndexRun> {
-        self.maybe_revisit_ongoing().or_else(|| self.maybe_index())
+    pub fn maybe_start_index_run(&self, cooldowns: &IndexCooldowns) -> Option<IndexRun> {
+        self.maybe_revisit_ongoing(cooldowns.revisit())
+            .or_else(|| self.maybe_index(cooldowns.index()))
     }
 
-    fn maybe_revisit_ongoing(&self) -> Option<IndexRun> {
+    fn maybe_revisit_ongoing(&self, cooldown: Duration) -> Option<IndexRun> {
         if SystemTime::now()
             .duration_since(self.revisited_at)
             .unwrap_or_default()
-            > Duration::from_secs(24 * 60 * 60)
+            > cooldown
         {
             self.revisit_ongoing_created_at
                 .map(|since| IndexRun::Revisit { since })
@@ -283,10 +709,10 @@ impl PlayerStatus {
         }
     }
 
-    fn maybe_index(&self) -> Option<IndexRun> {
+    fn maybe_index(&self, cooldown: Duration) -> Option<IndexRun> {
         SystemTime::now()
             .duration_since(self.indexed_at)
-            .map_or(false, |cooldown| cooldown > Duration::from_secs(2 * 60))
+            .map_or(false, |elapsed| elapsed > cooldown)
             .then_some(IndexRun::Index {
                 after: self.latest_created_at,
             })
@@ -328,7 +754,7 @@ impl PlayerStatus {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub enum IndexRun {
     Index { after: u64 },
     Revisit { since: u64 },
@@ -359,18 +785,64 @@ impl fmt::Display for IndexRun {
 
 #[cfg(test)]
 mod tests {
+    use quickcheck::quickcheck;
     use shakmaty::{Color, Square};
 
     use super::*;
 
+    quickcheck! {
+        fn test_player_entry_arbitrary_bytes(data: Vec<u8>) -> bool {
+            // Must not panic, no matter the result.
+            let mut entry = PlayerEntry::default();
+            let _ = entry.extend_from_reader(&mut &data[..]);
+            let mut buf = Vec::new();
+            entry.write(&mut buf);
+            true
+        }
+    }
+
     #[test]
     fn test_header_roundtrip() {
         let headers = [
             Header::Group {
                 mode: Mode::Rated,
                 speed: Speed::Correspondence,
+                key: GroupKey::default(),
                 num_games: 15,
             },
+            Header::Group {
+                mode: Mode::Casual,
+                speed: Speed::Blitz,
+                key: GroupKey {
+                    source: Some(GameSource::Swiss),
+                    rating: None,
+                    time: None,
+                    opponent_title: None,
+                },
+                num_games: 8,
+            },
+            Header::Group {
+                mode: Mode::Rated,
+                speed: Speed::Rapid,
+                key: GroupKey {
+                    source: Some(GameSource::Arena),
+                    rating: Some(PlayerRating::from_rating(1850)),
+                    time: Some(TimeBucket::from_unix_millis(1_700_000_000_000)),
+                    opponent_title: Some(Title::Bot),
+                },
+                num_games: 1,
+            },
+            Header::Group {
+                mode: Mode::Rated,
+                speed: Speed::Bullet,
+                key: GroupKey {
+                    source: None,
+                    rating: Some(PlayerRating::from_rating(u16::MAX)),
+                    time: None,
+                    opponent_title: Some(Title::Untitled),
+                },
+                num_games: 0,
+            },
             Header::End,
         ];
 
@@ -381,10 +853,21 @@ mod tests {
 
         let mut reader = &buf[..];
         for header in headers {
-            assert_eq!(Header::read(&mut reader), header);
+            assert_eq!(
+                Header::read(&mut reader, PlayerEntry::FORMAT_VERSION),
+                Ok(header)
+            );
         }
     }
 
+    #[test]
+    fn test_time_bucket() {
+        // 2023-11-14 22:13:20 UTC, a Tuesday.
+        let bucket = TimeBucket::from_unix_millis(1_700_000_000_000);
+        assert_eq!(bucket.weekday(), 1);
+        assert_eq!(bucket.hour(), 22);
+    }
+
     #[test]
     fn test_merge_player() {
         // Merge three entries, two of which are for the same move in the same
@@ -399,22 +882,30 @@ mod tests {
             uci_ab.clone(),
             Speed::Bullet,
             Mode::Rated,
+            Some(GameSource::Arena),
+            None,
             "aaaaaaaa".parse().unwrap(),
             Outcome::Decisive {
                 winner: Color::White,
             },
+            None,
             1600,
+            None,
         );
 
         let b = PlayerEntry::new_single(
             uci_ab.clone(),
             Speed::Bullet,
             Mode::Rated,
+            Some(GameSource::Arena),
+            None,
             "bbbbbbbb".parse().unwrap(),
             Outcome::Decisive {
                 winner: Color::Black,
             },
+            None,
             1800,
+            None,
         );
 
         let uci_c = UciMove::Normal {
@@ -427,9 +918,13 @@ mod tests {
             uci_c,
             Speed::Bullet,
             Mode::Rated,
+            Some(GameSource::Pairing),
+            Some(TimeBucket::from_unix_millis(1_700_000_000_000)),
             "cccccccc".parse().unwrap(),
             Outcome::Draw,
+            Some(1920),
             1700,
+            Some(Title::Gm),
         );
 
         let mut buf = Vec::new();
@@ -441,24 +936,30 @@ mod tests {
         );
 
         let mut deserialized = PlayerEntry::default();
-        deserialized.extend_from_reader(&mut &buf[..]);
+        deserialized.extend_from_reader(&mut &buf[..]).unwrap();
 
         let mut buf = Vec::new();
         b.write(&mut buf);
-        deserialized.extend_from_reader(&mut &buf[..]);
+        deserialized.extend_from_reader(&mut &buf[..]).unwrap();
 
         let mut buf = Vec::new();
         c.write(&mut buf);
-        deserialized.extend_from_reader(&mut &buf[..]);
+        deserialized.extend_from_reader(&mut &buf[..]).unwrap();
 
         assert_eq!(deserialized.sub_entries.len(), 2);
         assert_eq!(deserialized.max_game_idx, Some(2));
-        let group = &deserialized
+        let group = deserialized
             .sub_entries
-            .get(&RawUciMove::from(uci_ab))
+            .get_mut(&RawUciMove::from(uci_ab))
             .unwrap()
             .bullet
-            .rated;
+            .rated
+            .by_key_mut(GroupKey {
+                source: Some(GameSource::Arena),
+                rating: None,
+                time: None,
+                opponent_title: None,
+            });
         assert_eq!(group.stats.white(), 1);
         assert_eq!(group.stats.draws(), 0);
         assert_eq!(group.stats.black(), 1);
@@ -469,7 +970,7 @@ mod tests {
         let mut buf = Vec::new();
         deserialized.write(&mut buf);
         let mut deserialized = PlayerEntry::default();
-        deserialized.extend_from_reader(&mut &buf[..]);
+        deserialized.extend_from_reader(&mut &buf[..]).unwrap();
         assert_eq!(deserialized.sub_entries.len(), 2);
         assert_eq!(deserialized.max_game_idx, Some(2));
     }
@@ -0,0 +1,213 @@
+use std::cmp::{max, min};
+
+use bytes::{Buf, BufMut};
+use sha1::{Digest, Sha1};
+use thin_vec::ThinVec;
+
+use crate::model::{ensure_remaining, try_read_uint, write_uint, ReadError};
+
+const PRECISION: u32 = 10;
+const REGISTERS: usize = 1 << PRECISION;
+const MAX_RANK: u8 = (64 - PRECISION + 1) as u8;
+
+// Beyond this many sparse registers, the dense representation is smaller.
+const MAX_SPARSE: usize = REGISTERS / 2;
+
+// Number of sparse registers that announces the dense representation
+// instead.
+const DENSE: u64 = REGISTERS as u64;
+
+#[derive(Debug, Clone)]
+enum Registers {
+    /// Non-empty registers, encoded as `index << 6 | rank`, sorted by index.
+    Sparse(ThinVec<u16>),
+    Dense(Box<[u8; REGISTERS]>),
+}
+
+/// HyperLogLog sketch of the players who reached a position, to estimate
+/// their number with an error of about 3%. Small sketches are stored
+/// sparsely, so that the sketches of single games remain small.
+#[derive(Debug, Clone)]
+pub struct PlayersSketch {
+    registers: Registers,
+}
+
+impl Default for PlayersSketch {
+    fn default() -> PlayersSketch {
+        PlayersSketch {
+            registers: Registers::Sparse(ThinVec::new()),
+        }
+    }
+}
+
+impl PlayersSketch {
+    /// Record a player by name. Anonymous players (empty name) are ignored.
+    pub fn insert(&mut self, name: &str) {
+        if name.is_empty() {
+            return;
+        }
+        let digest = Sha1::digest(name.to_ascii_lowercase().as_bytes());
+        let hash = u64::from_be_bytes(digest[..8].try_into().expect("8 bytes"));
+        let index = (hash >> (64 - PRECISION)) as u16;
+        let rank = min((hash << PRECISION).leading_zeros() + 1, u32::from(MAX_RANK)) as u8;
+        self.set(index, rank);
+    }
+
+    pub fn merge(&mut self, other: &PlayersSketch) {
+        match other.registers {
+            Registers::Sparse(ref registers) => {
+                for register in registers {
+                    self.set(register >> 6, (register & 63) as u8);
+                }
+            }
+            Registers::Dense(ref registers) => {
+                for (index, rank) in registers.iter().enumerate() {
+                    if *rank > 0 {
+                        self.set(index as u16, *rank);
+                    }
+                }
+            }
+        }
+    }
+
+    fn set(&mut self, index: u16, rank: u8) {
+        let registers = match self.registers {
+            Registers::Dense(ref mut registers) => {
+                let register = &mut registers[usize::from(index)];
+                *register = max(*register, rank);
+                return;
+            }
+            Registers::Sparse(ref mut registers) => registers,
+        };
+
+        let encoded = (index << 6) | u16::from(rank);
+        match registers.binary_search_by_key(&index, |register| register >> 6) {
+            Ok(i) => registers[i] = max(registers[i], encoded),
+            Err(i) => registers.insert(i, encoded),
+        }
+
+        if registers.len() > MAX_SPARSE {
+            let mut dense = Box::new([0; REGISTERS]);
+            for register in registers.iter() {
+                dense[usize::from(register >> 6)] = (register & 63) as u8;
+            }
+            self.registers = Registers::Dense(dense);
+        }
+    }
+
+    /// Estimated number of distinct players.
+    pub fn estimate(&self) -> u64 {
+        let (sum, zeros) = match self.registers {
+            Registers::Sparse(ref registers) => (
+                registers
+                    .iter()
+                    .map(|register| 0.5f64.powi(i32::from(register & 63)))
+                    .sum::<f64>()
+                    + (REGISTERS - registers.len()) as f64,
+                REGISTERS - registers.len(),
+            ),
+            Registers::Dense(ref registers) => (
+                registers
+                    .iter()
+                    .map(|rank| 0.5f64.powi(i32::from(*rank)))
+                    .sum(),
+                registers.iter().filter(|rank| **rank == 0).count(),
+            ),
+        };
+
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let raw = alpha * m * m / sum;
+        let estimate = if raw <= 2.5 * m && zeros > 0 {
+            // Linear counting for small cardinalities.
+            m * (m / zeros as f64).ln()
+        } else {
+            raw
+        };
+        estimate.round() as u64
+    }
+
+    pub fn read<B: Buf>(buf: &mut B) -> Result<PlayersSketch, ReadError> {
+        let n = try_read_uint(buf)?;
+        let registers = if n == DENSE {
+            ensure_remaining(buf, REGISTERS)?;
+            let mut registers = Box::new([0; REGISTERS]);
+            buf.copy_to_slice(&mut registers[..]);
+            if registers.iter().any(|rank| *rank > MAX_RANK) {
+                return Err(ReadError::Invalid("players sketch"));
+            }
+            Registers::Dense(registers)
+        } else if n <= MAX_SPARSE as u64 {
+            ensure_remaining(buf, 2 * n as usize)?;
+            let mut registers = ThinVec::with_capacity(n as usize);
+            for _ in 0..n {
+                let register = buf.get_u16_le();
+                let rank = (register & 63) as u8;
+                if rank == 0
+                    || rank > MAX_RANK
+                    || registers
+                        .last()
+                        .is_some_and(|last: &u16| last >> 6 >= register >> 6)
+                {
+                    return Err(ReadError::Invalid("players sketch"));
+                }
+                registers.push(register);
+            }
+            Registers::Sparse(registers)
+        } else {
+            return Err(ReadError::Invalid("players sketch"));
+        };
+        Ok(PlayersSketch { registers })
+    }
+
+    pub fn write<B: BufMut>(&self, buf: &mut B) {
+        match self.registers {
+            Registers::Sparse(ref registers) => {
+                write_uint(buf, registers.len() as u64);
+                for register in registers {
+                    buf.put_u16_le(*register);
+                }
+            }
+            Registers::Dense(ref registers) => {
+                write_uint(buf, DENSE);
+                buf.put_slice(&registers[..]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_players_sketch() {
+        let mut a = PlayersSketch::default();
+        let mut b = PlayersSketch::default();
+        for i in 0..3000 {
+            a.insert(&format!("player{i}"));
+            b.insert(&format!("Player{}", i + 2000));
+        }
+        assert!(matches!(b.registers, Registers::Dense(_)));
+
+        a.merge(&b);
+        let estimate = a.estimate();
+        assert!((4750..5250).contains(&estimate), "estimate {estimate}");
+
+        let mut small = PlayersSketch::default();
+        small.insert("alice");
+        small.insert("bob");
+        small.insert("ALICE");
+        small.insert("");
+        assert_eq!(small.estimate(), 2);
+
+        for sketch in [a, small] {
+            let mut buf = Vec::new();
+            sketch.write(&mut buf);
+            let mut reader = &buf[..];
+            let read = PlayersSketch::read(&mut reader).unwrap();
+            assert!(reader.is_empty());
+            assert_eq!(read.estimate(), sketch.estimate());
+        }
+    }
+}
@@ -0,0 +1,24 @@
+use bytes::Buf;
+use thiserror::Error;
+
+/// Malformed data encountered while reading a stored value.
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+pub enum ReadError {
+    #[error("unexpected end of data")]
+    UnexpectedEnd,
+    #[error("invalid {0}")]
+    Invalid(&'static str),
+}
+
+pub fn ensure_remaining<B: Buf>(buf: &B, n: usize) -> Result<(), ReadError> {
+    if buf.remaining() < n {
+        Err(ReadError::UnexpectedEnd)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn try_get_u8<B: Buf>(buf: &mut B) -> Result<u8, ReadError> {
+    ensure_remaining(buf, 1)?;
+    Ok(buf.get_u8())
+}
@@ -0,0 +1,104 @@
+use bytes::BufMut;
+use serde::Deserialize;
+
+use crate::model::{GameId, Month};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SearchSource {
+    #[default]
+    Masters = 0,
+    Lichess = 1,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SearchField {
+    White = 0,
+    Black = 1,
+    Event = 2,
+}
+
+/// Split text into normalized search tokens, so that for example
+/// `Carlsen, Magnus` can be found by `carlsen` as well as `magnus`.
+pub fn search_tokens(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(|token| token.to_lowercase())
+}
+
+/// Key in the secondary game search index:
+///
+/// | source | field | token | 0 | month | game id |
+///
+/// Values are empty. Tokens never contain NUL, so lookups can seek to
+/// the start of a token and then scan all tokens sharing the prefix.
+#[derive(Debug, Clone)]
+pub struct SearchKey {
+    pub source: SearchSource,
+    pub field: SearchField,
+    pub token: String,
+    pub month: Month,
+    pub id: GameId,
+}
+
+impl SearchKey {
+    pub fn prefix(source: SearchSource, field: SearchField, token: &str) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(2 + token.len());
+        buf.put_u8(source as u8);
+        buf.put_u8(field as u8);
+        buf.put_slice(token.as_bytes());
+        buf
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        let mut buf = SearchKey::prefix(self.source, self.field, &self.token);
+        buf.put_u8(0);
+        buf.put_u16(u16::from(self.month));
+        self.id.write(&mut buf);
+        buf
+    }
+
+    /// Extract month and game id from a key found in the index.
+    pub fn read_suffix(key: &[u8]) -> Option<(Month, GameId)> {
+        let suffix = key.get(key.len().checked_sub(2 + GameId::SIZE)?..)?;
+        let month = Month::try_from(u16::from_be_bytes([suffix[0], suffix[1]])).ok()?;
+        Some((month, GameId::read(&mut &suffix[2..]).ok()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search_tokens() {
+        assert_eq!(
+            search_tokens("Carlsen, Magnus").collect::<Vec<_>>(),
+            vec!["carlsen", "magnus"]
+        );
+        assert_eq!(
+            search_tokens("Tata Steel-Masters 2023").collect::<Vec<_>>(),
+            vec!["tata", "steel", "masters", "2023"]
+        );
+    }
+
+    #[test]
+    fn test_search_key_suffix() {
+        let month: Month = "2023-01".parse().unwrap();
+        let id: GameId = "aaaaaaaa".parse().unwrap();
+        let key = SearchKey {
+            source: SearchSource::Masters,
+            field: SearchField::White,
+            token: "carlsen".to_owned(),
+            month,
+            id,
+        }
+        .into_bytes();
+        assert!(key.starts_with(&SearchKey::prefix(
+            SearchSource::Masters,
+            SearchField::White,
+            "carl"
+        )));
+        assert_eq!(SearchKey::read_suffix(&key), Some((month, id)));
+    }
+}
@@ -1,12 +1,12 @@
 use std::ops::{AddAssign, Sub};
 
 use bytes::{Buf, BufMut};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use shakmaty::{Color, Outcome};
 
-use crate::model::{read_uint, write_uint};
+use crate::model::{try_read_uint, write_uint, ReadError};
 
-#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize)]
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Stats {
     #[serde(skip)]
     rating_sum: u64,
@@ -26,12 +26,26 @@ impl Stats {
     }
 }
 
+/// Rating differences by the percentage score that they predict.
+/// https://handbook.fide.com/chapter/B022017
+const DELTAS: [f64; 101] = [
+    -800.0, -677.0, -589.0, -538.0, -501.0, -470.0, -444.0, -422.0, -401.0, -383.0, -366.0, -351.0,
+    -336.0, -322.0, -309.0, -296.0, -284.0, -273.0, -262.0, -251.0, -240.0, -230.0, -220.0, -211.0,
+    -202.0, -193.0, -184.0, -175.0, -166.0, -158.0, -149.0, -141.0, -133.0, -125.0, -117.0, -110.0,
+    -102.0, -95.0, -87.0, -80.0, -72.0, -65.0, -57.0, -50.0, -43.0, -36.0, -29.0, -21.0, -14.0,
+    -7.0, 0.0, 7.0, 14.0, 21.0, 29.0, 36.0, 43.0, 50.0, 57.0, 65.0, 72.0, 80.0, 87.0, 95.0, 102.0,
+    110.0, 117.0, 125.0, 133.0, 141.0, 149.0, 158.0, 166.0, 175.0, 184.0, 193.0, 202.0, 211.0,
+    220.0, 230.0, 240.0, 251.0, 262.0, 273.0, 284.0, 296.0, 309.0, 322.0, 336.0, 351.0, 366.0,
+    383.0, 401.0, 422.0, 444.0, 470.0, 501.0, 538.0, 589.0, 677.0, 800.0,
+];
+
 impl AddAssign<&Stats> for Stats {
     fn add_assign(&mut self, rhs: &Stats) {
-        self.rating_sum += rhs.rating_sum;
-        self.white += rhs.white;
-        self.draws += rhs.draws;
-        self.black += rhs.black;
+        // Saturating, so that malformed values cannot overflow.
+        self.rating_sum = self.rating_sum.saturating_add(rhs.rating_sum);
+        self.white = self.white.saturating_add(rhs.white);
+        self.draws = self.draws.saturating_add(rhs.draws);
+        self.black = self.black.saturating_add(rhs.black);
     }
 }
 
@@ -85,20 +99,18 @@ impl Stats {
         self.average_rating_f64().map(|avg| avg.round() as u16)
     }
 
-    pub fn performance(&self, color: Color) -> Option<i32> {
-        // https://handbook.fide.com/chapter/B022017
-        const DELTAS: [f64; 101] = [
-            -800.0, -677.0, -589.0, -538.0, -501.0, -470.0, -444.0, -422.0, -401.0, -383.0, -366.0,
-            -351.0, -336.0, -322.0, -309.0, -296.0, -284.0, -273.0, -262.0, -251.0, -240.0, -230.0,
-            -220.0, -211.0, -202.0, -193.0, -184.0, -175.0, -166.0, -158.0, -149.0, -141.0, -133.0,
-            -125.0, -117.0, -110.0, -102.0, -95.0, -87.0, -80.0, -72.0, -65.0, -57.0, -50.0, -43.0,
-            -36.0, -29.0, -21.0, -14.0, -7.0, 0.0, 7.0, 14.0, 21.0, 29.0, 36.0, 43.0, 50.0, 57.0,
-            65.0, 72.0, 80.0, 87.0, 95.0, 102.0, 110.0, 117.0, 125.0, 133.0, 141.0, 149.0, 158.0,
-            166.0, 175.0, 184.0, 193.0, 202.0, 211.0, 220.0, 230.0, 240.0, 251.0, 262.0, 273.0,
-            284.0, 296.0, 309.0, 322.0, 336.0, 351.0, 366.0, 383.0, 401.0, 422.0, 444.0, 470.0,
-            501.0, 538.0, 589.0, 677.0, 800.0,
-        ];
+    /// Shift the rating of every game by `delta` points, without going below
+    /// zero.
+    pub fn adjust_ratings(&mut self, delta: i32) {
+        let shift = u64::from(delta.unsigned_abs()).saturating_mul(self.total());
+        self.rating_sum = if delta < 0 {
+            self.rating_sum.saturating_sub(shift)
+        } else {
+            self.rating_sum.saturating_add(shift)
+        };
+    }
 
+    pub fn performance(&self, color: Color) -> Option<i32> {
         self.average_rating_f64().map(|avg_opponent_rating| {
             let score = 100 * color.fold_wb(self.white, self.black) + 50 * self.draws;
             let p = (score as f64) / (self.total() as f64);
@@ -110,9 +122,47 @@ impl Stats {
         })
     }
 
-    pub fn read<B: Buf>(buf: &mut B) -> Stats {
-        let rating_sum = read_uint(buf);
-        match read_uint(buf) {
+    /// Expected score for a rating difference to the opponent, from 0 to 1,
+    /// by inverting the same table.
+    pub fn expected_score(rating_diff: f64) -> f64 {
+        let idx = DELTAS.partition_point(|delta| *delta < rating_diff);
+        if idx == 0 {
+            0.0
+        } else if idx >= DELTAS.len() {
+            1.0
+        } else {
+            let (lo, hi) = (DELTAS[idx - 1], DELTAS[idx]);
+            ((idx - 1) as f64 + (rating_diff - lo) / (hi - lo)) / 100.0
+        }
+    }
+
+    /// Score of the given side, from 0 to 1, counting draws as half a point.
+    pub fn score(&self, color: Color) -> Option<f64> {
+        let total = self.total();
+        (total > 0).then(|| {
+            (2 * color.fold_wb(self.white, self.black) + self.draws) as f64 / (2 * total) as f64
+        })
+    }
+
+    /// Wilson score interval at 95% confidence for the score of the given
+    /// side, counting draws as half a point.
+    pub fn score_interval(&self, color: Color) -> Option<(f64, f64)> {
+        const Z: f64 = 1.96;
+
+        let n = self.total() as f64;
+        if n == 0.0 {
+            return None;
+        }
+        let p = (2 * color.fold_wb(self.white, self.black) + self.draws) as f64 / (2.0 * n);
+        let denominator = 1.0 + Z * Z / n;
+        let center = (p + Z * Z / (2.0 * n)) / denominator;
+        let margin = Z / denominator * (p * (1.0 - p) / n + Z * Z / (4.0 * n * n)).sqrt();
+        Some(((center - margin).max(0.0), (center + margin).min(1.0)))
+    }
+
+    pub fn read<B: Buf>(buf: &mut B) -> Result<Stats, ReadError> {
+        let rating_sum = try_read_uint(buf)?;
+        Ok(match try_read_uint(buf)? {
             0 => Stats {
                 rating_sum,
                 white: 1,
@@ -134,10 +184,10 @@ impl Stats {
             white_plus_three => Stats {
                 rating_sum,
                 white: white_plus_three - 3,
-                draws: read_uint(buf),
-                black: read_uint(buf),
+                draws: try_read_uint(buf)?,
+                black: try_read_uint(buf)?,
             },
-        }
+        })
     }
 
     pub fn write<B: BufMut>(&self, buf: &mut B) {
@@ -198,7 +248,7 @@ mod tests {
             stats.write(&mut buf);
 
             let mut reader = &buf[..];
-            Stats::read(&mut reader) == stats
+            Stats::read(&mut reader) == Ok(stats)
         }
     }
 
@@ -231,4 +281,57 @@ mod tests {
         assert_eq!(p5.performance(Color::White), Some(-470));
         assert_eq!(p5.performance(Color::Black), Some(470));
     }
+
+    #[test]
+    fn test_expected_score() {
+        assert_eq!(Stats::expected_score(0.0), 0.5);
+        assert_eq!(Stats::expected_score(-7.0), 0.49);
+        assert_eq!(Stats::expected_score(3.5), 0.505);
+        assert_eq!(Stats::expected_score(-470.0), 0.05);
+        assert_eq!(Stats::expected_score(-1000.0), 0.0);
+        assert_eq!(Stats::expected_score(800.0), 1.0);
+        assert_eq!(Stats::expected_score(1000.0), 1.0);
+    }
+
+    #[test]
+    fn test_score_interval() {
+        assert_eq!(Stats::default().score_interval(Color::White), None);
+
+        let even = Stats {
+            white: 5,
+            draws: 0,
+            black: 5,
+            rating_sum: 0,
+        };
+        let (low, high) = even.score_interval(Color::White).unwrap();
+        assert!((low - 0.2366).abs() < 0.0001);
+        assert!((high - 0.7634).abs() < 0.0001);
+
+        let single = Stats {
+            white: 1,
+            draws: 0,
+            black: 0,
+            rating_sum: 0,
+        };
+        let (low, high) = single.score_interval(Color::White).unwrap();
+        assert!((low - 0.2065).abs() < 0.0001);
+        assert_eq!(high, 1.0);
+        let (low, high) = single.score_interval(Color::Black).unwrap();
+        assert_eq!(low, 0.0);
+        assert!((high - 0.7935).abs() < 0.0001);
+    }
+
+    #[test]
+    fn test_adjust_ratings() {
+        let mut stats = Stats {
+            white: 2,
+            draws: 1,
+            black: 0,
+            rating_sum: 3 * 2400,
+        };
+        stats.adjust_ratings(100);
+        assert_eq!(stats.average_rating(), Some(2500));
+        stats.adjust_ratings(-3000);
+        assert_eq!(stats.average_rating(), Some(0));
+    }
 }
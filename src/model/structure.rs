@@ -0,0 +1,97 @@
+use bytes::{Buf, BufMut};
+use shakmaty::{Bitboard, Board};
+
+use crate::model::{try_read_uint, write_uint, KeyPrefix, ReadError};
+
+/// Placement of the pawns of both sides, by which positions are grouped in
+/// the structure index.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PawnStructure {
+    white: Bitboard,
+    black: Bitboard,
+}
+
+impl PawnStructure {
+    pub const SIZE: usize = 16;
+
+    pub fn from_board(board: &Board) -> PawnStructure {
+        PawnStructure {
+            white: board.pawns() & board.white(),
+            black: board.pawns() & board.black(),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; PawnStructure::SIZE] {
+        let mut buf = [0; PawnStructure::SIZE];
+        let mut writer = &mut buf[..];
+        writer.put_u64(u64::from(self.white));
+        writer.put_u64(u64::from(self.black));
+        buf
+    }
+
+    /// Key of a position in the structure index:
+    ///
+    /// | pawn structure | position key prefix |
+    pub fn with_position(self, key: &KeyPrefix) -> [u8; PawnStructure::SIZE + KeyPrefix::SIZE] {
+        let mut buf = [0; PawnStructure::SIZE + KeyPrefix::SIZE];
+        buf[..PawnStructure::SIZE].clone_from_slice(&self.to_bytes());
+        buf[PawnStructure::SIZE..].clone_from_slice(key.as_bytes());
+        buf
+    }
+}
+
+/// Value in the structure index: the number of games in which the position
+/// occurred, and its FEN.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct StructureEntry {
+    pub games: u64,
+    pub fen: String,
+}
+
+impl StructureEntry {
+    pub fn new_single(fen: String) -> StructureEntry {
+        StructureEntry { games: 1, fen }
+    }
+
+    pub fn extend_from_reader<B: Buf>(&mut self, buf: &mut B) -> Result<(), ReadError> {
+        let games = try_read_uint(buf)?;
+        let fen = String::from_utf8(buf.copy_to_bytes(buf.remaining()).to_vec())
+            .map_err(|_| ReadError::Invalid("structure fen"))?;
+        self.games = self.games.saturating_add(games);
+        if !fen.is_empty() {
+            self.fen = fen;
+        }
+        Ok(())
+    }
+
+    pub fn write<B: BufMut>(&self, buf: &mut B) {
+        write_uint(buf, self.games);
+        buf.put_slice(self.fen.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shakmaty::{Chess, Position};
+
+    use super::*;
+
+    #[test]
+    fn test_structure_entry() {
+        let fen = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq -";
+        let mut entry = StructureEntry::default();
+        for _ in 0..3 {
+            let mut buf = Vec::new();
+            StructureEntry::new_single(fen.to_owned()).write(&mut buf);
+            entry.extend_from_reader(&mut &buf[..]).unwrap();
+        }
+        assert_eq!(entry.games, 3);
+        assert_eq!(entry.fen, fen);
+
+        let structure = PawnStructure::from_board(Chess::default().board());
+        assert_eq!(
+            structure.to_bytes(),
+            [0, 0, 0, 0, 0, 0, 0xff, 0, 0, 0xff, 0, 0, 0, 0, 0, 0]
+        );
+    }
+}
@@ -0,0 +1,140 @@
+use std::str::FromStr;
+
+use thiserror::Error;
+
+/// Title of a lichess player, as recorded for opponents in the player
+/// explorer.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Title {
+    Untitled,
+    Gm,
+    Wgm,
+    Im,
+    Wim,
+    Fm,
+    Wfm,
+    Cm,
+    Wcm,
+    Nm,
+    Wnm,
+    Lm,
+    Bot,
+}
+
+impl Title {
+    const ALL: [Title; 13] = [
+        Title::Untitled,
+        Title::Gm,
+        Title::Wgm,
+        Title::Im,
+        Title::Wim,
+        Title::Fm,
+        Title::Wfm,
+        Title::Cm,
+        Title::Wcm,
+        Title::Nm,
+        Title::Wnm,
+        Title::Lm,
+        Title::Bot,
+    ];
+
+    /// The title of a user in the lila API, or `None` if it is not known.
+    pub fn from_lila(title: Option<&str>) -> Option<Title> {
+        match title {
+            Some(title) => title.parse().ok(),
+            None => Some(Title::Untitled),
+        }
+    }
+
+    pub fn from_u8(n: u8) -> Option<Title> {
+        Title::ALL.get(usize::from(n)).copied()
+    }
+
+    pub fn to_u8(self) -> u8 {
+        self as u8
+    }
+}
+
+impl FromStr for Title {
+    type Err = InvalidTitle;
+
+    fn from_str(s: &str) -> Result<Title, InvalidTitle> {
+        Ok(match s.to_ascii_uppercase().as_str() {
+            "GM" => Title::Gm,
+            "WGM" => Title::Wgm,
+            "IM" => Title::Im,
+            "WIM" => Title::Wim,
+            "FM" => Title::Fm,
+            "WFM" => Title::Wfm,
+            "CM" => Title::Cm,
+            "WCM" => Title::Wcm,
+            "NM" => Title::Nm,
+            "WNM" => Title::Wnm,
+            "LM" => Title::Lm,
+            "BOT" => Title::Bot,
+            _ => return Err(InvalidTitle),
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("invalid title")]
+pub struct InvalidTitle;
+
+/// Matches titles in queries: `titled` (any title other than `BOT`),
+/// `untitled`, or a specific title like `GM` or `BOT`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum TitleFilter {
+    Titled,
+    Untitled,
+    Title(Title),
+}
+
+impl TitleFilter {
+    pub fn matches(self, title: Title) -> bool {
+        match self {
+            TitleFilter::Titled => !matches!(title, Title::Untitled | Title::Bot),
+            TitleFilter::Untitled => title == Title::Untitled,
+            TitleFilter::Title(t) => title == t,
+        }
+    }
+}
+
+impl FromStr for TitleFilter {
+    type Err = InvalidTitle;
+
+    fn from_str(s: &str) -> Result<TitleFilter, InvalidTitle> {
+        Ok(match s {
+            "titled" => TitleFilter::Titled,
+            "untitled" => TitleFilter::Untitled,
+            title => TitleFilter::Title(title.parse()?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_title_filter() {
+        let titled: TitleFilter = "titled".parse().unwrap();
+        assert!(titled.matches(Title::Gm));
+        assert!(titled.matches(Title::Lm));
+        assert!(!titled.matches(Title::Bot));
+        assert!(!titled.matches(Title::Untitled));
+
+        let bot: TitleFilter = "BOT".parse().unwrap();
+        assert!(bot.matches(Title::Bot));
+        assert_eq!(
+            "im".parse::<TitleFilter>().ok(),
+            Some(TitleFilter::Title(Title::Im))
+        );
+        assert!("grandmaster".parse::<TitleFilter>().is_err());
+
+        for title in Title::ALL {
+            assert_eq!(Title::from_u8(title.to_u8()), Some(title));
+        }
+        assert_eq!(Title::from_u8(13), None);
+    }
+}
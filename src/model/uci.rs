@@ -3,12 +3,15 @@ use std::{convert::TryFrom, fmt};
 use bytes::{Buf, BufMut};
 use shakmaty::{uci::UciMove, Role, Square};
 
+use crate::model::{ensure_remaining, ReadError};
+
 #[derive(Copy, Clone, PartialEq, Eq, Hash)]
 pub struct RawUciMove(u16);
 
 impl RawUciMove {
-    pub fn read<B: Buf>(buf: &mut B) -> RawUciMove {
-        RawUciMove(buf.get_u16_le())
+    pub fn read<B: Buf>(buf: &mut B) -> Result<RawUciMove, ReadError> {
+        ensure_remaining(buf, 2)?;
+        Ok(RawUciMove(buf.get_u16_le()))
     }
 
     pub fn write<B: BufMut>(&self, buf: &mut B) {
@@ -94,7 +97,7 @@ mod tests {
 
         let mut reader = &buf[..];
         for uci in moves {
-            assert_eq!(uci, UciMove::from(RawUciMove::read(&mut reader)));
+            assert_eq!(uci, UciMove::from(RawUciMove::read(&mut reader).unwrap()));
         }
     }
 }
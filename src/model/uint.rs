@@ -1,13 +1,22 @@
 use bytes::{Buf, BufMut};
 
+use crate::model::{try_get_u8, ReadError};
+
 pub fn read_uint<B: Buf>(buf: &mut B) -> u64 {
+    try_read_uint(buf).expect("read uint")
+}
+
+pub fn try_read_uint<B: Buf>(buf: &mut B) -> Result<u64, ReadError> {
     let mut n = 0;
     let mut shift = 0;
     loop {
-        let byte = buf.get_u8();
+        let byte = try_get_u8(buf)?;
+        if shift > 63 || (shift == 63 && byte & 127 > 1) {
+            return Err(ReadError::Invalid("uint"));
+        }
         if byte & 128 == 0 {
             n |= u64::from(byte) << shift;
-            return n;
+            return Ok(n);
         } else {
             n |= u64::from(byte & 127) << shift;
         }
@@ -37,5 +46,20 @@ mod tests {
             let mut reader = &buf[..];
             read_uint(&mut reader) == n
         }
+
+        fn test_try_read_uint_truncated(n: u64) -> bool {
+            let mut buf = Vec::new();
+            write_uint(&mut buf, n);
+            buf.pop();
+
+            let mut reader = &buf[..];
+            try_read_uint(&mut reader) == Err(ReadError::UnexpectedEnd)
+        }
+    }
+
+    #[test]
+    fn test_try_read_uint_overflow() {
+        let mut reader = &[0xff; 11][..];
+        assert_eq!(try_read_uint(&mut reader), Err(ReadError::Invalid("uint")));
     }
 }
@@ -1,5 +1,13 @@
-use std::time::Duration;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
 
+use clap::Parser;
 use nohash_hasher::IntMap;
 use serde::{Deserialize, Serialize};
 use shakmaty::{
@@ -7,15 +15,142 @@ use shakmaty::{
     uci::UciMove,
     variant::{Variant, VariantPosition},
     zobrist::{Zobrist64, ZobristHash},
-    Chess, EnPassantMode, Position,
+    EnPassantMode, Position,
 };
+use thiserror::Error;
+
+use crate::api::{Error, OpeningsStatusResponse};
+
+#[derive(Parser, Clone)]
+pub struct OpeningsOpt {
+    /// Where to load the standard opening names from: a base URL, or a local
+    /// directory, with the files `a.tsv` to `e.tsv` of
+    /// https://github.com/lichess-org/chess-openings.
+    #[arg(
+        long = "openings-source",
+        default_value = "https://raw.githubusercontent.com/lichess-org/chess-openings/master"
+    )]
+    openings_source: String,
+    /// Directory to keep the last successfully loaded standard opening names
+    /// in. They are loaded from there at startup, before trying the source,
+    /// so that opening names are available even if the source is
+    /// unreachable.
+    #[arg(long = "openings-cache")]
+    openings_cache: Option<PathBuf>,
+    /// Opening names for a variant, given as `<variant>=<path>` to a TSV file
+    /// with the same columns as https://github.com/lichess-org/chess-openings.
+    /// Replaces the standard opening names for that variant. May be repeated.
+    #[arg(long = "variant-openings")]
+    variant_openings: Vec<VariantOpenings>,
+}
+
+impl OpeningsOpt {
+    pub fn source(&self) -> &str {
+        &self.openings_source
+    }
+}
+
+#[derive(Clone, Debug)]
+struct VariantOpenings {
+    variant: Variant,
+    path: PathBuf,
+}
 
-use crate::api::Error;
+impl FromStr for VariantOpenings {
+    type Err = InvalidVariantOpenings;
+
+    fn from_str(s: &str) -> Result<VariantOpenings, InvalidVariantOpenings> {
+        let (variant, path) = s.split_once('=').ok_or(InvalidVariantOpenings)?;
+        Ok(VariantOpenings {
+            variant: variant.parse().map_err(|_| InvalidVariantOpenings)?,
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("invalid variant openings, expected <variant>=<path>")]
+pub struct InvalidVariantOpenings;
+
+/// An ECO code, like `B90`, or an inclusive range of ECO codes, like
+/// `B90-B99`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct EcoRange {
+    from: u16,
+    to: u16,
+}
+
+fn parse_eco(s: &str) -> Option<u16> {
+    match *s.as_bytes() {
+        [letter @ b'A'..=b'E', tens @ b'0'..=b'9', ones @ b'0'..=b'9'] => Some(
+            u16::from(letter - b'A') * 100 + u16::from(tens - b'0') * 10 + u16::from(ones - b'0'),
+        ),
+        _ => None,
+    }
+}
 
-#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+fn fmt_eco(eco: u16, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+        f,
+        "{}{:02}",
+        char::from(b'A' + (eco / 100) as u8),
+        eco % 100
+    )
+}
+
+impl FromStr for EcoRange {
+    type Err = InvalidEco;
+
+    fn from_str(s: &str) -> Result<EcoRange, InvalidEco> {
+        let (from, to) = s.split_once('-').unwrap_or((s, s));
+        let range = EcoRange {
+            from: parse_eco(from).ok_or(InvalidEco)?,
+            to: parse_eco(to).ok_or(InvalidEco)?,
+        };
+        if range.from > range.to {
+            return Err(InvalidEco);
+        }
+        Ok(range)
+    }
+}
+
+impl fmt::Display for EcoRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt_eco(self.from, f)?;
+        if self.from != self.to {
+            f.write_str("-")?;
+            fmt_eco(self.to, f)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("invalid eco, expected a code like B90 or a range like B90-B99")]
+pub struct InvalidEco;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct Opening {
     eco: String,
     name: String,
+    /// Whether the position itself is named, rather than having arisen from
+    /// the named position.
+    #[serde(default = "Opening::default_exact")]
+    exact: bool,
+}
+
+impl Opening {
+    fn default_exact() -> bool {
+        true
+    }
+
+    pub fn eco(&self) -> &str {
+        &self.eco
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
 }
 
 #[derive(Deserialize)]
@@ -28,6 +163,12 @@ struct OpeningRecord {
 #[derive(Default)]
 pub struct Openings {
     data: IntMap<Zobrist64, Opening>,
+    variants: HashMap<Variant, IntMap<Zobrist64, Opening>>,
+    /// Distinct move orders of the standard opening lines, by each position
+    /// they pass through.
+    move_orders: IntMap<Zobrist64, Vec<Vec<UciMove>>>,
+    /// Shortest standard opening line of each ECO code.
+    eco_lines: BTreeMap<u16, Vec<UciMove>>,
 }
 
 impl Openings {
@@ -35,24 +176,66 @@ impl Openings {
         Openings::default()
     }
 
-    pub async fn download() -> Result<Openings, Error> {
+    /// Load opening names from the configured source, and keep a copy in
+    /// the configured cache directory.
+    pub async fn download(opt: &OpeningsOpt) -> Result<Openings, Error> {
+        let source = &opt.openings_source;
+        let tsvs = if source.starts_with("http://") || source.starts_with("https://") {
+            let client = reqwest::Client::builder()
+                .user_agent("lila-openingexplorer")
+                .timeout(Duration::from_secs(60))
+                .build()
+                .expect("reqwest client");
+            let mut tsvs = Vec::with_capacity(PARTS.len());
+            for part in PARTS {
+                tsvs.push(
+                    client
+                        .get(format!("{}/{part}.tsv", source.trim_end_matches('/')))
+                        .send()
+                        .await?
+                        .error_for_status()?
+                        .text()
+                        .await?,
+                );
+            }
+            tsvs
+        } else {
+            read_parts(Path::new(source)).await?
+        };
+
+        // Only keep opening names that could be loaded.
+        let openings = Openings::from_parts(opt, &tsvs).await?;
+        if let Some(cache) = &opt.openings_cache {
+            if let Err(err) = write_parts(cache, &tsvs).await {
+                log::error!("failed to keep opening names in {}: {err}", cache.display());
+            }
+        }
+        Ok(openings)
+    }
+
+    /// Load the opening names kept by the last successful download, if any.
+    pub async fn load_cached(opt: &OpeningsOpt) -> Option<Result<Openings, Error>> {
+        let cache = opt.openings_cache.as_ref()?;
+        if !tokio::fs::try_exists(cache.join(format!("{}.tsv", PARTS[0])))
+            .await
+            .unwrap_or(false)
+        {
+            return None;
+        }
+        Some(match read_parts(cache).await {
+            Ok(tsvs) => Openings::from_parts(opt, &tsvs).await,
+            Err(err) => Err(err),
+        })
+    }
+
+    async fn from_parts(opt: &OpeningsOpt, tsvs: &[String]) -> Result<Openings, Error> {
         let mut openings = Openings::new();
-        let client = reqwest::Client::builder()
-            .user_agent("lila-openingexplorer")
-            .timeout(Duration::from_secs(60))
-            .build()
-            .expect("reqwest client");
-        for part in ["a", "b", "c", "d", "e"] {
-            let tsv = client
-                .get(format!(
-                    "https://raw.githubusercontent.com/lichess-org/chess-openings/master/{part}.tsv"
-                ))
-                .send()
-                .await?
-                .error_for_status()?
-                .text()
-                .await?;
-            openings.load_tsv(&tsv)?;
+        for tsv in tsvs {
+            openings.load_tsv(tsv)?;
+        }
+        for VariantOpenings { variant, path } in &opt.variant_openings {
+            let tsv = tokio::fs::read_to_string(path).await?;
+            openings.load_variant_tsv(*variant, &tsv)?;
         }
         Ok(openings)
     }
@@ -62,71 +245,398 @@ impl Openings {
     }
 
     pub fn len(&self) -> usize {
-        self.data.len()
+        self.data.len() + self.variants.values().map(|data| data.len()).sum::<usize>()
     }
 
     pub fn load_tsv(&mut self, tsv: &str) -> Result<(), Error> {
-        let mut tsv = csv::ReaderBuilder::new()
-            .delimiter(b'\t')
-            .from_reader(tsv.as_bytes());
-
-        for record in tsv.deserialize() {
-            let record: OpeningRecord = record?;
-
-            let mut pos = Chess::default();
-            for token in record.pgn.split(' ') {
-                if let Ok(san) = token.parse::<San>() {
-                    pos.play_unchecked(&san.to_move(&pos)?);
-                }
-            }
-
-            if self
-                .data
-                .insert(
-                    pos.zobrist_hash(EnPassantMode::Legal),
-                    Opening {
-                        eco: record.eco,
-                        name: record.name,
-                    },
-                )
-                .is_some()
-            {
-                return Err(Error::DuplicateOpening);
-            }
-        }
+        load_tsv_into(
+            &mut self.data,
+            Some(&mut self.move_orders),
+            Some(&mut self.eco_lines),
+            Variant::Chess,
+            tsv,
+        )
+    }
 
-        Ok(())
+    /// Loads opening names that are specific to a variant. Once loaded, they
+    /// are used instead of the standard opening names for positions of that
+    /// variant.
+    pub fn load_variant_tsv(&mut self, variant: Variant, tsv: &str) -> Result<(), Error> {
+        load_tsv_into(
+            self.variants.entry(variant).or_default(),
+            None,
+            None,
+            variant,
+            tsv,
+        )
     }
 
+    /// Classify the position after playing the given moves, falling back to
+    /// the last named position along the way.
     pub fn classify_and_play(
         &self,
         root: &mut VariantPosition,
         play: Vec<UciMove>,
     ) -> Result<Option<Opening>, Error> {
         let mut opening = self.classify_exact(root);
+        let mut exact = true;
 
-        for uci in play {
-            let m = uci.to_move(root)?;
+        for (i, uci) in play.into_iter().enumerate() {
+            let m = uci
+                .to_move(root)
+                .map_err(|_| Error::IllegalMove { ply: i + 1, uci })?;
             root.play_unchecked(&m);
 
-            opening = self.classify_exact(root).or(opening);
+            match self.classify_exact(root) {
+                Some(named) => {
+                    opening = Some(named);
+                    exact = true;
+                }
+                None => exact = false,
+            }
         }
 
-        Ok(opening.cloned())
+        Ok(opening.map(|opening| Opening {
+            exact,
+            ..opening.clone()
+        }))
+    }
+
+    /// Move orders of standard opening lines that pass through the position.
+    pub fn move_orders(&self, pos: &VariantPosition) -> &[Vec<UciMove>] {
+        if self.variants.contains_key(&pos.variant()) || !opening_sensible(pos.variant()) {
+            return &[];
+        }
+        self.move_orders
+            .get(&pos.zobrist_hash(EnPassantMode::Legal))
+            .map_or(&[], Vec::as_slice)
+    }
+
+    /// Moves to the position from which all standard openings of the ECO
+    /// range arise, i.e., the longest common prefix of their shortest lines.
+    pub fn eco_line(&self, range: EcoRange) -> Option<Vec<UciMove>> {
+        let mut lines = self
+            .eco_lines
+            .range(range.from..=range.to)
+            .map(|(_, line)| line);
+        let mut prefix = lines.next()?.clone();
+        for line in lines {
+            let common = prefix.iter().zip(line).take_while(|(a, b)| a == b).count();
+            prefix.truncate(common);
+        }
+        Some(prefix)
     }
 
     pub fn classify_exact(&self, pos: &VariantPosition) -> Option<&Opening> {
-        if opening_sensible(pos.variant()) {
-            self.data.get(&pos.zobrist_hash(EnPassantMode::Legal))
-        } else {
-            None
+        match self.variants.get(&pos.variant()) {
+            Some(data) => data.get(&pos.zobrist_hash(EnPassantMode::Legal)),
+            None if opening_sensible(pos.variant()) => {
+                self.data.get(&pos.zobrist_hash(EnPassantMode::Legal))
+            }
+            None => None,
         }
     }
 }
 
+fn load_tsv_into(
+    data: &mut IntMap<Zobrist64, Opening>,
+    mut move_orders: Option<&mut IntMap<Zobrist64, Vec<Vec<UciMove>>>>,
+    mut eco_lines: Option<&mut BTreeMap<u16, Vec<UciMove>>>,
+    variant: Variant,
+    tsv: &str,
+) -> Result<(), Error> {
+    let mut tsv = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_reader(tsv.as_bytes());
+
+    for record in tsv.deserialize() {
+        let record: OpeningRecord = record?;
+
+        let mut pos = VariantPosition::new(variant);
+        let mut line = Vec::new();
+        for token in record.pgn.split(' ') {
+            if let Ok(san) = token.parse::<San>() {
+                let m = san.to_move(&pos)?;
+                line.push(UciMove::from_standard(&m));
+                pos.play_unchecked(&m);
+
+                if let Some(ref mut move_orders) = move_orders {
+                    let orders = move_orders
+                        .entry(pos.zobrist_hash(EnPassantMode::Legal))
+                        .or_default();
+                    if !orders.contains(&line) {
+                        orders.push(line.clone());
+                    }
+                }
+            }
+        }
+
+        if let (Some(eco_lines), Some(eco)) = (eco_lines.as_deref_mut(), parse_eco(&record.eco)) {
+            let shortest = eco_lines.entry(eco).or_insert_with(|| line.clone());
+            if line.len() < shortest.len() {
+                *shortest = line;
+            }
+        }
+
+        if data
+            .insert(
+                pos.zobrist_hash(EnPassantMode::Legal),
+                Opening {
+                    eco: record.eco,
+                    name: record.name,
+                    exact: true,
+                },
+            )
+            .is_some()
+        {
+            return Err(Error::DuplicateOpening);
+        }
+    }
+
+    Ok(())
+}
+
+/// Files of https://github.com/lichess-org/chess-openings.
+const PARTS: [&str; 5] = ["a", "b", "c", "d", "e"];
+
+async fn read_parts(dir: &Path) -> Result<Vec<String>, Error> {
+    let mut tsvs = Vec::with_capacity(PARTS.len());
+    for part in PARTS {
+        tsvs.push(tokio::fs::read_to_string(dir.join(format!("{part}.tsv"))).await?);
+    }
+    Ok(tsvs)
+}
+
+async fn write_parts(dir: &Path, tsvs: &[String]) -> std::io::Result<()> {
+    tokio::fs::create_dir_all(dir).await?;
+    for (part, tsv) in PARTS.into_iter().zip(tsvs) {
+        // Replace atomically, so that an interrupted write does not leave a
+        // truncated file behind.
+        let tmp = dir.join(format!("{part}.tsv.tmp"));
+        tokio::fs::write(&tmp, tsv).await?;
+        tokio::fs::rename(&tmp, dir.join(format!("{part}.tsv"))).await?;
+    }
+    Ok(())
+}
+
+/// Interval between refreshes of opening names that were loaded
+/// successfully.
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 167);
+
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Delay before retrying after the given number of consecutive failures:
+/// exponential, up to the refresh interval, and randomized so that a fleet
+/// of servers does not retry in lockstep.
+pub fn retry_delay(failures: u32) -> Duration {
+    let base = MIN_RETRY_DELAY
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(REFRESH_INTERVAL);
+    base / 2 + base.mul_f64(fastrand::f64() / 2.0)
+}
+
+/// Where the current opening names were loaded from.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum OpeningsOrigin {
+    /// The cache directory, at startup.
+    Cache,
+    /// The configured source, periodically.
+    Source,
+    /// The configured source, on request via `/import/openings`.
+    Import,
+}
+
+/// Outcomes of loading opening names, for monitoring.
+pub struct OpeningsStatus {
+    started_at: SystemTime,
+    inner: Mutex<OpeningsStatusResponse>,
+}
+
+impl OpeningsStatus {
+    pub fn new(opt: &OpeningsOpt) -> OpeningsStatus {
+        OpeningsStatus {
+            started_at: SystemTime::now(),
+            inner: Mutex::new(OpeningsStatusResponse {
+                source: opt.source().to_owned(),
+                origin: None,
+                names: 0,
+                loaded_at: None,
+                stale_secs: 0,
+                failures: 0,
+                last_error: None,
+                last_failure_at: None,
+                next_attempt_at: None,
+            }),
+        }
+    }
+
+    pub fn record_loaded(&self, origin: OpeningsOrigin, names: usize) {
+        let mut inner = self.inner.lock().expect("lock openings status");
+        inner.origin = Some(origin);
+        inner.names = names;
+        inner.loaded_at = Some(SystemTime::now());
+        if origin != OpeningsOrigin::Cache {
+            inner.failures = 0;
+        }
+    }
+
+    /// Returns the number of consecutive failures.
+    pub fn record_failure(&self, err: &Error) -> u32 {
+        let mut inner = self.inner.lock().expect("lock openings status");
+        inner.failures += 1;
+        inner.last_error = Some(err.to_string());
+        inner.last_failure_at = Some(SystemTime::now());
+        inner.failures
+    }
+
+    pub fn record_next_attempt(&self, delay: Duration) {
+        self.inner
+            .lock()
+            .expect("lock openings status")
+            .next_attempt_at = Some(SystemTime::now() + delay);
+    }
+
+    /// Time since opening names were last loaded, or since startup if they
+    /// were never loaded.
+    pub fn staleness(&self) -> Duration {
+        let loaded_at = self.inner.lock().expect("lock openings status").loaded_at;
+        loaded_at
+            .unwrap_or(self.started_at)
+            .elapsed()
+            .unwrap_or_default()
+    }
+
+    pub fn to_response(&self) -> OpeningsStatusResponse {
+        let mut response = self.inner.lock().expect("lock openings status").clone();
+        response.stale_secs = self.staleness().as_secs();
+        response
+    }
+}
+
 fn opening_sensible(variant: Variant) -> bool {
     matches!(
         variant,
         Variant::Chess | Variant::Crazyhouse | Variant::ThreeCheck | Variant::KingOfTheHill
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_delay() {
+        for _ in 0..100 {
+            let first = retry_delay(1);
+            assert!(Duration::from_secs(15) <= first && first <= Duration::from_secs(30));
+            let third = retry_delay(3);
+            assert!(Duration::from_secs(60) <= third && third <= Duration::from_secs(120));
+            assert!(retry_delay(u32::MAX) <= REFRESH_INTERVAL);
+        }
+    }
+
+    #[test]
+    fn test_variant_openings() {
+        let mut openings = Openings::new();
+        openings
+            .load_tsv("eco\tname\tpgn\nB00\tKing's Pawn Game\t1. e4\n")
+            .unwrap();
+        openings
+            .load_variant_tsv(
+                Variant::Antichess,
+                "eco\tname\tpgn\nA00\tAntichess Opening\t1. e3\n",
+            )
+            .unwrap();
+
+        let mut chess = VariantPosition::new(Variant::Chess);
+        let opening = openings
+            .classify_and_play(&mut chess, vec!["e2e4".parse().unwrap()])
+            .unwrap();
+        assert_eq!(
+            opening.map(|o| (o.name, o.exact)),
+            Some(("King's Pawn Game".to_owned(), true))
+        );
+
+        let mut chess = VariantPosition::new(Variant::Chess);
+        let opening = openings
+            .classify_and_play(
+                &mut chess,
+                vec!["e2e4".parse().unwrap(), "e7e5".parse().unwrap()],
+            )
+            .unwrap();
+        assert_eq!(
+            opening.map(|o| (o.name, o.exact)),
+            Some(("King's Pawn Game".to_owned(), false))
+        );
+
+        let mut antichess = VariantPosition::new(Variant::Antichess);
+        let opening = openings
+            .classify_and_play(&mut antichess, vec!["e2e3".parse().unwrap()])
+            .unwrap();
+        assert_eq!(
+            opening.map(|o| o.name),
+            Some("Antichess Opening".to_owned())
+        );
+
+        let mut atomic = VariantPosition::new(Variant::Atomic);
+        let opening = openings
+            .classify_and_play(&mut atomic, vec!["e2e4".parse().unwrap()])
+            .unwrap();
+        assert_eq!(opening, None);
+    }
+
+    #[test]
+    fn test_eco_line() {
+        let mut openings = Openings::new();
+        openings
+            .load_tsv(
+                "eco\tname\tpgn\n\
+                 B90\tSicilian Defense: Najdorf Variation\t1. e4 c5 2. Nf3 d6 3. d4 cxd4 4. Nxd4 Nf6 5. Nc3 a6\n\
+                 B92\tSicilian Defense: Najdorf Variation, Opocensky Variation\t1. e4 c5 2. Nf3 d6 3. d4 cxd4 4. Nxd4 Nf6 5. Nc3 a6 6. Be2\n\
+                 B90\tSicilian Defense: Najdorf Variation, English Attack\t1. e4 c5 2. Nf3 d6 3. d4 cxd4 4. Nxd4 Nf6 5. Nc3 a6 6. Be3\n\
+                 B50\tSicilian Defense: Modern Variations\t1. e4 c5 2. Nf3 d6\n",
+            )
+            .unwrap();
+
+        let range: EcoRange = "B90".parse().unwrap();
+        assert_eq!(openings.eco_line(range).map(|line| line.len()), Some(10));
+
+        let range: EcoRange = "B90-B99".parse().unwrap();
+        assert_eq!(range.to_string(), "B90-B99");
+        assert_eq!(openings.eco_line(range).map(|line| line.len()), Some(10));
+
+        let range: EcoRange = "B00-B99".parse().unwrap();
+        assert_eq!(openings.eco_line(range).map(|line| line.len()), Some(4));
+
+        let range: EcoRange = "C00-E99".parse().unwrap();
+        assert_eq!(openings.eco_line(range), None);
+
+        assert!("B99-B90".parse::<EcoRange>().is_err());
+        assert!("F00".parse::<EcoRange>().is_err());
+    }
+
+    #[test]
+    fn test_move_orders() {
+        let mut openings = Openings::new();
+        openings
+            .load_tsv(
+                "eco\tname\tpgn\n\
+                 E00\tQueen's Pawn Game\t1. d4 Nf6 2. c4 e6\n\
+                 A15\tEnglish Opening\t1. c4 Nf6 2. d4 e6 3. Nc3\n",
+            )
+            .unwrap();
+
+        let mut pos = VariantPosition::new(Variant::Chess);
+        openings
+            .classify_and_play(
+                &mut pos,
+                ["d2d4", "e7e6", "c2c4", "g8f6"]
+                    .into_iter()
+                    .map(|uci| uci.parse().unwrap())
+                    .collect(),
+            )
+            .unwrap();
+        assert_eq!(openings.move_orders(&pos).len(), 2);
+    }
+}
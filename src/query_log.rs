@@ -0,0 +1,162 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, SyncSender},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use clap::Parser;
+use serde::Serialize;
+
+use crate::api::{Error, ExplorerResponse};
+
+#[derive(Parser, Clone)]
+pub struct QueryLogOpt {
+    /// Append a random sample of /masters and /lichess queries to this file
+    /// as newline-delimited JSON, for offline analysis of query patterns and
+    /// cache behavior. Disabled by default.
+    #[arg(long = "query-log")]
+    query_log: Option<PathBuf>,
+    /// Percentage of queries to sample for the query log.
+    #[arg(long = "query-log-sample", default_value = "1")]
+    query_log_sample: f64,
+    /// Rotate the query log to <path>.1 once it grows beyond this many
+    /// bytes, replacing the previous rotated file.
+    #[arg(long = "query-log-max-bytes", default_value = "104857600")]
+    query_log_max_bytes: u64,
+}
+
+/// Number of entries waiting to be written. Further entries are dropped
+/// rather than slowing down queries.
+const QUEUE_CAPACITY: usize = 1024;
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryLogEntry {
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub endpoint: &'static str,
+    /// Raw query string, or the JSON line of a batch query.
+    pub query: Option<String>,
+    /// Answered from the in-memory cache.
+    pub cache_hit: bool,
+    pub latency_us: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub total_games: u64,
+    pub moves: usize,
+    pub top_games: usize,
+    pub recent_games: usize,
+}
+
+impl QueryLogEntry {
+    pub fn new(
+        endpoint: &'static str,
+        query: Option<String>,
+        cache_hit: bool,
+        latency: Duration,
+        res: Result<&ExplorerResponse, &Error>,
+    ) -> QueryLogEntry {
+        let mut entry = QueryLogEntry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |t| t.as_millis() as u64),
+            endpoint,
+            query,
+            cache_hit,
+            latency_us: latency.as_micros() as u64,
+            error: None,
+            total_games: 0,
+            moves: 0,
+            top_games: 0,
+            recent_games: 0,
+        };
+        match res {
+            Ok(response) => {
+                entry.total_games = response.total.total();
+                entry.moves = response.moves.len();
+                entry.top_games = response.top_games.as_ref().map_or(0, Vec::len);
+                entry.recent_games = response.recent_games.as_ref().map_or(0, Vec::len);
+            }
+            Err(err) => entry.error = Some(err.to_string()),
+        }
+        entry
+    }
+}
+
+pub struct QueryLog {
+    tx: Option<SyncSender<QueryLogEntry>>,
+    percent: f64,
+}
+
+impl QueryLog {
+    pub fn new(opt: QueryLogOpt) -> io::Result<QueryLog> {
+        let Some(path) = opt.query_log else {
+            return Ok(QueryLog {
+                tx: None,
+                percent: 0.0,
+            });
+        };
+
+        let file = open_append(&path)?;
+        let (tx, rx) = mpsc::sync_channel(QUEUE_CAPACITY);
+        let max_bytes = opt.query_log_max_bytes;
+        thread::Builder::new()
+            .name("query-log".to_owned())
+            .spawn(move || {
+                if let Err(err) = write_entries(rx, &path, file, max_bytes) {
+                    log::error!("query log {}: {err}", path.display());
+                }
+            })?;
+
+        Ok(QueryLog {
+            tx: Some(tx),
+            percent: opt.query_log_sample,
+        })
+    }
+
+    /// Decide if the current query should be logged.
+    pub fn sample(&self) -> bool {
+        self.tx.is_some() && fastrand::f64() * 100.0 < self.percent
+    }
+
+    pub fn record(&self, entry: QueryLogEntry) {
+        if let Some(ref tx) = self.tx {
+            let _ = tx.try_send(entry);
+        }
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn write_entries(
+    rx: Receiver<QueryLogEntry>,
+    path: &Path,
+    mut file: File,
+    max_bytes: u64,
+) -> io::Result<()> {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(".1");
+
+    let mut len = file.metadata()?.len();
+    let mut line = Vec::new();
+    for entry in rx {
+        line.clear();
+        serde_json::to_writer(&mut line, &entry).map_err(io::Error::other)?;
+        line.push(b'\n');
+
+        if len > 0 && len + line.len() as u64 > max_bytes {
+            fs::rename(path, &rotated)?;
+            file = open_append(path)?;
+            len = 0;
+        }
+
+        file.write_all(&line)?;
+        len += line.len() as u64;
+    }
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use std::collections::HashSet;
+
+use shakmaty::{zobrist::ZobristHash, EnPassantMode, Position};
+
+use crate::{
+    annotation::MoveAnnotator,
+    api::{Error, ExplorerResponse, LichessQuery, MastersQuery, PlayPosition, PlayerQuery},
+    db::{Database, DbOpt},
+    era::EraAdjustment,
+    explorer::{lichess_response, masters_response, player_response},
+    model::{KeyBuilder, UserId},
+    opening::Openings,
+};
+
+/// Explorer queries against a database opened in-process, for batch jobs
+/// that run next to the data. Unlike the HTTP server, there are no caches,
+/// cloud evaluations or tablebase lookups, and player queries only read
+/// games that have already been indexed.
+pub struct ExplorerService {
+    db: Database,
+    openings: Openings,
+    era: EraAdjustment,
+    annotator: MoveAnnotator,
+}
+
+impl ExplorerService {
+    /// Open the database. Needs exclusive access, so the server must not be
+    /// running on the same database.
+    pub fn open(opt: DbOpt) -> Result<ExplorerService, rocksdb::Error> {
+        Ok(ExplorerService {
+            db: Database::open(opt)?,
+            openings: Openings::default(),
+            era: EraAdjustment::default(),
+            annotator: MoveAnnotator::default(),
+        })
+    }
+
+    /// Name the openings of positions and moves.
+    pub fn with_openings(self, openings: Openings) -> ExplorerService {
+        ExplorerService { openings, ..self }
+    }
+
+    /// Normalize ratings of masters games across rating eras.
+    pub fn with_era_adjustment(self, era: EraAdjustment) -> ExplorerService {
+        ExplorerService { era, ..self }
+    }
+
+    /// Thresholds for annotating moves with `annotate=true`.
+    pub fn with_move_annotator(self, annotator: MoveAnnotator) -> ExplorerService {
+        ExplorerService { annotator, ..self }
+    }
+
+    pub fn database(&self) -> &Database {
+        &self.db
+    }
+
+    pub fn masters(&self, query: MastersQuery) -> Result<ExplorerResponse, Error> {
+        masters_response(&self.db, &self.openings, &self.era, &self.annotator, query)
+            .map(|(response, _)| response)
+    }
+
+    pub fn lichess(&self, query: LichessQuery) -> Result<ExplorerResponse, Error> {
+        lichess_response(
+            &self.db,
+            &self.openings,
+            &HashSet::new(),
+            &self.annotator,
+            query,
+        )
+        .map(|(response, _)| response)
+    }
+
+    pub fn player(&self, query: PlayerQuery) -> Result<ExplorerResponse, Error> {
+        let player = UserId::from(query.player);
+        let PlayPosition { pos, opening } = query.play.position(&self.openings)?;
+        let key = KeyBuilder::player(&player, query.color)
+            .with_zobrist(pos.variant(), pos.zobrist_hash(EnPassantMode::Legal));
+        Ok(player_response(
+            &self.db.lichess(),
+            &self.openings,
+            &key,
+            &pos,
+            opening,
+            query.color,
+            &query.filter,
+            &query.limits,
+        ))
+    }
+}
@@ -0,0 +1,199 @@
+use std::io::{self, Write};
+
+use shakmaty::{san::SanPlus, variant::Variant, Color};
+
+use crate::model::{LastPlayed, Stats};
+
+/// A move of an exported repertoire, with the most played continuations
+/// first.
+#[derive(Debug)]
+pub struct StudyMove {
+    pub san: SanPlus,
+    pub stats: Stats,
+    pub performance: Option<i32>,
+    pub last_played: Option<LastPlayed>,
+    pub children: Vec<StudyMove>,
+}
+
+/// A chapter of a PGN study, exploring the repertoire of a player after one
+/// of the moves of the initial position.
+pub struct StudyChapter<'a> {
+    pub player: &'a str,
+    pub color: Color,
+    pub variant: Variant,
+    pub first_move: &'a StudyMove,
+}
+
+impl StudyChapter<'_> {
+    pub fn write_pgn<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writeln!(
+            writer,
+            "[Event \"{} as {}: {}\"]",
+            self.player, self.color, self.first_move.san
+        )?;
+        writeln!(writer, "[Site \"https://lichess.org/@/{}\"]", self.player)?;
+        writeln!(
+            writer,
+            "[White \"{}\"]",
+            self.color.fold_wb(self.player, "?")
+        )?;
+        writeln!(
+            writer,
+            "[Black \"{}\"]",
+            self.color.fold_wb("?", self.player)
+        )?;
+        writeln!(writer, "[Result \"*\"]")?;
+        if self.variant != Variant::Chess {
+            writeln!(writer, "[Variant \"{}\"]", self.variant.uci())?;
+        }
+        writeln!(writer, "[Orientation \"{}\"]", self.color)?;
+        writeln!(writer)?;
+
+        let mut tokens = Vec::new();
+        self.write_moves(&mut tokens, std::slice::from_ref(self.first_move), 0);
+        tokens.push("*".to_owned());
+        writeln!(writer, "{}", tokens.join(" "))?;
+        writeln!(writer)
+    }
+
+    fn write_moves(&self, tokens: &mut Vec<String>, moves: &[StudyMove], ply: u32) {
+        let Some((main, alternatives)) = moves.split_first() else {
+            return;
+        };
+        self.write_move(tokens, main, ply);
+        for alternative in alternatives {
+            tokens.push("(".to_owned());
+            self.write_move(tokens, alternative, ply);
+            self.write_moves(tokens, &alternative.children, ply + 1);
+            tokens.push(")".to_owned());
+        }
+        self.write_moves(tokens, &main.children, ply + 1);
+    }
+
+    /// Every move is followed by a comment, so moves of black also need a
+    /// move number.
+    fn write_move(&self, tokens: &mut Vec<String>, m: &StudyMove, ply: u32) {
+        if ply % 2 == 0 {
+            tokens.push(format!("{}.", ply / 2 + 1));
+        } else {
+            tokens.push(format!("{}...", ply / 2 + 1));
+        }
+        tokens.push(m.san.to_string());
+        tokens.push(format!("{{ {} }}", self.comment(m)));
+    }
+
+    /// Results from the point of view of the player.
+    fn comment(&self, m: &StudyMove) -> String {
+        let total = m.stats.total();
+        let wins = self.color.fold_wb(m.stats.white(), m.stats.black());
+        let losses = self.color.fold_wb(m.stats.black(), m.stats.white());
+        let mut comment = format!(
+            "{total} {}, +{wins} ={} -{losses}",
+            if total == 1 { "game" } else { "games" },
+            m.stats.draws()
+        );
+        if total > 0 {
+            let score = (2 * wins + m.stats.draws()) as f64 * 50.0 / total as f64;
+            comment.push_str(&format!(", score {score:.0}%"));
+        }
+        if let Some(performance) = m.performance {
+            comment.push_str(&format!(", performance {performance}"));
+        }
+        if let Some(last_played) = m.last_played {
+            comment.push_str(&format!(", last played {last_played}"));
+        }
+        comment
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shakmaty::Outcome;
+
+    use super::*;
+
+    fn study_move(san: &str, outcome: Outcome, children: Vec<StudyMove>) -> StudyMove {
+        StudyMove {
+            san: san.parse().unwrap(),
+            stats: Stats::new_single(outcome, 1500),
+            performance: None,
+            last_played: None,
+            children,
+        }
+    }
+
+    #[test]
+    fn test_study_chapter() {
+        let first_move = study_move(
+            "e4",
+            Outcome::Draw,
+            vec![
+                study_move(
+                    "c5",
+                    Outcome::Decisive {
+                        winner: Color::White,
+                    },
+                    vec![study_move("Nf3", Outcome::Draw, Vec::new())],
+                ),
+                study_move(
+                    "e5",
+                    Outcome::Decisive {
+                        winner: Color::Black,
+                    },
+                    Vec::new(),
+                ),
+            ],
+        );
+        let chapter = StudyChapter {
+            player: "foo",
+            color: Color::White,
+            variant: Variant::Chess,
+            first_move: &first_move,
+        };
+
+        let mut buf = Vec::new();
+        chapter.write_pgn(&mut buf).unwrap();
+        let pgn = String::from_utf8(buf).unwrap();
+        assert!(pgn.starts_with("[Event \"foo as white: e4\"]\n"));
+        assert!(pgn.ends_with(
+            "\n1. e4 { 1 game, +0 =1 -0, score 50% } \
+             1... c5 { 1 game, +1 =0 -0, score 100% } \
+             ( 1... e5 { 1 game, +0 =0 -1, score 0% } ) \
+             2. Nf3 { 1 game, +0 =1 -0, score 50% } *\n\n"
+        ));
+    }
+
+    #[test]
+    fn test_study_move_numbers() {
+        let draw = |san: &str, children| study_move(san, Outcome::Draw, children);
+        let first_move = draw(
+            "d4",
+            vec![
+                draw(
+                    "Nf6",
+                    vec![
+                        draw("c4", vec![draw("e6", Vec::new())]),
+                        draw("Nf3", vec![draw("g6", Vec::new())]),
+                    ],
+                ),
+                draw("d5", vec![draw("c4", Vec::new())]),
+            ],
+        );
+        let chapter = StudyChapter {
+            player: "foo",
+            color: Color::Black,
+            variant: Variant::Chess,
+            first_move: &first_move,
+        };
+
+        let mut buf = Vec::new();
+        chapter.write_pgn(&mut buf).unwrap();
+        let pgn = String::from_utf8(buf).unwrap();
+        let c = "{ 1 game, +0 =1 -0, score 50% }";
+        assert!(pgn.contains("[White \"?\"]\n[Black \"foo\"]\n"));
+        assert!(pgn.ends_with(&format!(
+            "\n1. d4 {c} 1... Nf6 {c} ( 1... d5 {c} 2. c4 {c} ) \
+             2. c4 {c} ( 2. Nf3 {c} 2... g6 {c} ) 2... e6 {c} *\n\n"
+        )));
+    }
+}
@@ -0,0 +1,196 @@
+use std::{sync::Arc, time::Duration};
+
+use clap::Parser;
+use moka::future::Cache;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use shakmaty::{
+    fen::Fen,
+    uci::UciMove,
+    variant::{Variant, VariantPosition},
+    EnPassantMode, Move, Position,
+};
+use tokio::{task::JoinHandle, time::timeout};
+
+use crate::api::ExplorerMove;
+
+#[derive(Parser, Clone)]
+pub struct TablebaseOpt {
+    /// Tablebase endpoint, for example http://tablebase.lichess.ovh. If
+    /// configured, moves of positions with few pieces are annotated with
+    /// tablebase results.
+    #[arg(long = "tablebase")]
+    tablebase: Option<String>,
+    /// Only annotate positions with at most this many pieces.
+    #[arg(long = "tablebase-max-pieces", default_value = "7")]
+    tablebase_max_pieces: usize,
+    /// Milliseconds to wait for tablebase results before responding without
+    /// annotations. The results are still fetched and cached in the
+    /// background.
+    #[arg(long = "tablebase-timeout", default_value = "200")]
+    tablebase_timeout: u64,
+}
+
+/// Tablebase result of a move, from the point of view of the player making
+/// the move.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TablebaseCategory {
+    Win,
+    SyzygyWin,
+    MaybeWin,
+    CursedWin,
+    Draw,
+    BlessedLoss,
+    MaybeLoss,
+    SyzygyLoss,
+    Loss,
+    #[serde(other)]
+    Unknown,
+}
+
+impl TablebaseCategory {
+    /// The same result from the point of view of the opponent.
+    fn flip(self) -> TablebaseCategory {
+        match self {
+            TablebaseCategory::Win => TablebaseCategory::Loss,
+            TablebaseCategory::SyzygyWin => TablebaseCategory::SyzygyLoss,
+            TablebaseCategory::MaybeWin => TablebaseCategory::MaybeLoss,
+            TablebaseCategory::CursedWin => TablebaseCategory::BlessedLoss,
+            TablebaseCategory::Draw => TablebaseCategory::Draw,
+            TablebaseCategory::BlessedLoss => TablebaseCategory::CursedWin,
+            TablebaseCategory::MaybeLoss => TablebaseCategory::MaybeWin,
+            TablebaseCategory::SyzygyLoss => TablebaseCategory::SyzygyWin,
+            TablebaseCategory::Loss => TablebaseCategory::Win,
+            TablebaseCategory::Unknown => TablebaseCategory::Unknown,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TablebaseResponse {
+    moves: Vec<TablebaseMove>,
+}
+
+#[serde_as]
+#[derive(Deserialize)]
+struct TablebaseMove {
+    #[serde_as(as = "DisplayFromStr")]
+    uci: UciMove,
+    /// From the point of view of the side to move after the move.
+    category: TablebaseCategory,
+}
+
+type MoveResults = Arc<[(UciMove, TablebaseCategory)]>;
+
+pub struct Tablebase {
+    client: reqwest::Client,
+    cache: Cache<String, MoveResults>,
+    opt: TablebaseOpt,
+}
+
+/// Tablebase results being fetched in the background.
+pub struct PendingTablebase {
+    pos: VariantPosition,
+    handle: JoinHandle<Option<MoveResults>>,
+}
+
+impl Tablebase {
+    pub fn new(opt: TablebaseOpt) -> Tablebase {
+        Tablebase {
+            client: reqwest::Client::builder()
+                .user_agent("lila-openingexplorer")
+                .timeout(Duration::from_secs(10))
+                .build()
+                .expect("reqwest client"),
+            cache: Cache::builder()
+                .max_capacity(50_000)
+                .time_to_live(Duration::from_secs(60 * 60 * 24))
+                .build(),
+            opt,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.opt.tablebase.is_some()
+    }
+
+    /// Start fetching tablebase results for the moves of a position, if
+    /// configured and the position can be in the tablebase.
+    pub fn prefetch(&self, pos: &VariantPosition) -> Option<PendingTablebase> {
+        let endpoint = self.opt.tablebase.as_ref()?;
+        let variant = match pos.variant() {
+            Variant::Chess => "standard",
+            Variant::Atomic => "atomic",
+            Variant::Antichess => "antichess",
+            _ => return None,
+        };
+        if pos.board().occupied().count() > self.opt.tablebase_max_pieces
+            || !pos.castles().is_empty()
+        {
+            return None;
+        }
+
+        let fen = Fen::from_position(pos.clone(), EnPassantMode::Legal).to_string();
+        let url = format!("{}/{variant}", endpoint.trim_end_matches('/'));
+        let key = format!("{variant}:{fen}");
+        let client = self.client.clone();
+        let cache = self.cache.clone();
+
+        Some(PendingTablebase {
+            pos: pos.clone(),
+            handle: tokio::spawn(async move {
+                cache
+                    .optionally_get_with(key, async move {
+                        let res: TablebaseResponse = client
+                            .get(url)
+                            .query(&[("fen", fen.as_str())])
+                            .send()
+                            .await
+                            .and_then(|res| res.error_for_status())
+                            .map_err(|err| log::warn!("tablebase: {err}"))
+                            .ok()?
+                            .json()
+                            .await
+                            .map_err(|err| log::warn!("tablebase: {err}"))
+                            .ok()?;
+                        Some(
+                            res.moves
+                                .into_iter()
+                                .map(|m| (m.uci, m.category.flip()))
+                                .collect(),
+                        )
+                    })
+                    .await
+            }),
+        })
+    }
+
+    /// Annotate moves with tablebase results, if they become available in
+    /// time.
+    pub async fn annotate(&self, pending: PendingTablebase, moves: &mut [ExplorerMove]) {
+        let results = match timeout(
+            Duration::from_millis(self.opt.tablebase_timeout),
+            pending.handle,
+        )
+        .await
+        {
+            Ok(Ok(Some(results))) => results,
+            _ => return,
+        };
+
+        let tablebase_moves: Vec<(Move, TablebaseCategory)> = results
+            .iter()
+            .filter_map(|(uci, category)| uci.to_move(&pending.pos).ok().map(|m| (m, *category)))
+            .collect();
+
+        for explorer_move in moves {
+            if let Ok(m) = explorer_move.uci.to_move(&pending.pos) {
+                explorer_move.tablebase = tablebase_moves
+                    .iter()
+                    .find(|(tablebase_move, _)| *tablebase_move == m)
+                    .map(|(_, category)| *category);
+            }
+        }
+    }
+}
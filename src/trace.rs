@@ -0,0 +1,377 @@
+use std::{
+    mem,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use clap::Parser;
+use serde_json::{json, Value};
+use tokio::{sync::mpsc, time};
+
+#[derive(Parser, Clone)]
+pub struct TraceOpt {
+    /// OTLP/HTTP endpoint to export traces of queries to, for example
+    /// http://127.0.0.1:4318/v1/traces. Spans are sent as JSON in batches.
+    /// Disabled by default.
+    #[arg(long = "otlp-endpoint")]
+    otlp_endpoint: Option<String>,
+    /// Percentage of queries to export to the OTLP endpoint.
+    #[arg(long = "otlp-sample", default_value = "100")]
+    otlp_sample: f64,
+}
+
+/// Number of finished traces waiting to be exported. Further traces are
+/// dropped rather than slowing down queries.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// Collect finished traces for this long before sending them in one batch.
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+static X_RESPONSE_TIME: HeaderName = HeaderName::from_static("x-response-time");
+static X_SCAN_STATS: HeaderName = HeaderName::from_static("x-scan-stats");
+static SERVER_TIMING: HeaderName = HeaderName::from_static("server-timing");
+
+tokio::task_local! {
+    static CURRENT: Trace;
+}
+
+/// Timings of the processing stages of a request. Disabled outside of
+/// requests, so that recording is always cheap.
+#[derive(Clone, Default)]
+pub struct Trace {
+    inner: Option<Arc<Mutex<TraceData>>>,
+}
+
+#[derive(Default)]
+struct TraceData {
+    stages: Vec<Stage>,
+    scan: Option<ScanStats>,
+}
+
+struct Stage {
+    name: &'static str,
+    start: SystemTime,
+    duration: Duration,
+}
+
+#[derive(Default, Copy, Clone)]
+struct ScanStats {
+    keys: u64,
+    bytes: u64,
+}
+
+impl Trace {
+    /// The trace of the request being handled.
+    pub fn current() -> Trace {
+        CURRENT.try_with(Trace::clone).unwrap_or_default()
+    }
+
+    /// Run `f` as part of this trace, for example on a blocking thread.
+    pub fn enter<R>(self, f: impl FnOnce() -> R) -> R {
+        if self.inner.is_some() {
+            CURRENT.sync_scope(self, f)
+        } else {
+            f()
+        }
+    }
+
+    /// Time `f` as a stage of the request.
+    pub fn stage<R>(&self, name: &'static str, f: impl FnOnce() -> R) -> R {
+        let started_at = Instant::now();
+        let res = f();
+        self.record(name, started_at);
+        res
+    }
+
+    /// Record a stage that started at `started_at` and ends now.
+    pub fn record(&self, name: &'static str, started_at: Instant) {
+        if let Some(ref inner) = self.inner {
+            let duration = started_at.elapsed();
+            inner.lock().expect("lock trace").stages.push(Stage {
+                name,
+                start: SystemTime::now() - duration,
+                duration,
+            });
+        }
+    }
+
+    /// Count keys and bytes read from the database.
+    pub fn add_scan(&self, keys: u64, bytes: u64) {
+        if let Some(ref inner) = self.inner {
+            let mut inner = inner.lock().expect("lock trace");
+            let scan = inner.scan.get_or_insert_with(ScanStats::default);
+            scan.keys += keys;
+            scan.bytes += bytes;
+        }
+    }
+
+    /// Keys and bytes read from the database so far, if any.
+    pub fn scan(&self) -> Option<(u64, u64)> {
+        self.inner.as_ref().and_then(|inner| {
+            inner
+                .lock()
+                .expect("lock trace")
+                .scan
+                .map(|scan| (scan.keys, scan.bytes))
+        })
+    }
+
+    fn take(&self) -> TraceData {
+        self.inner
+            .as_ref()
+            .map(|inner| mem::take(&mut *inner.lock().expect("lock trace")))
+            .unwrap_or_default()
+    }
+}
+
+impl TraceData {
+    /// Durations of stages with the same name are summed up, in the order
+    /// they first occurred.
+    fn server_timing(&self, total: Duration) -> String {
+        let mut totals: Vec<(&'static str, Duration)> = Vec::new();
+        for stage in &self.stages {
+            match totals.iter_mut().find(|(name, _)| *name == stage.name) {
+                Some((_, duration)) => *duration += stage.duration,
+                None => totals.push((stage.name, stage.duration)),
+            }
+        }
+        totals.push(("total", total));
+        totals
+            .into_iter()
+            .map(|(name, duration)| format!("{name};dur={:.3}", millis(duration)))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+struct FinishedTrace {
+    name: String,
+    start: SystemTime,
+    duration: Duration,
+    status: StatusCode,
+    data: TraceData,
+}
+
+pub struct TraceExporter {
+    tx: Option<mpsc::Sender<FinishedTrace>>,
+    percent: f64,
+}
+
+impl TraceExporter {
+    /// Must be called from within the runtime, to spawn the export task.
+    pub fn new(opt: TraceOpt) -> TraceExporter {
+        let Some(endpoint) = opt.otlp_endpoint else {
+            return TraceExporter {
+                tx: None,
+                percent: 0.0,
+            };
+        };
+
+        let client = reqwest::Client::builder()
+            .user_agent("lila-openingexplorer")
+            .timeout(Duration::from_secs(10))
+            .build()
+            .expect("reqwest client");
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(export_traces(client, endpoint, rx));
+
+        TraceExporter {
+            tx: Some(tx),
+            percent: opt.otlp_sample,
+        }
+    }
+
+    fn export(&self, trace: FinishedTrace) {
+        if let Some(ref tx) = self.tx {
+            if fastrand::f64() * 100.0 < self.percent {
+                let _ = tx.try_send(trace);
+            }
+        }
+    }
+}
+
+/// Times requests, and reports the stages in `Server-Timing`, the total in
+/// `X-Response-Time`, and database reads in `X-Scan-Stats` response headers.
+pub async fn trace_request(
+    State(exporter): State<&'static TraceExporter>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let start = SystemTime::now();
+    let started_at = Instant::now();
+    let name = format!(
+        "{} {}",
+        req.method(),
+        req.extensions()
+            .get::<MatchedPath>()
+            .map_or(req.uri().path(), MatchedPath::as_str)
+    );
+
+    let trace = Trace {
+        inner: Some(Arc::default()),
+    };
+    let mut res = CURRENT.scope(trace.clone(), next.run(req)).await;
+    let duration = started_at.elapsed();
+    let data = trace.take();
+
+    let headers = res.headers_mut();
+    if let Ok(value) = HeaderValue::from_str(&format!("{:.3}ms", millis(duration))) {
+        headers.insert(X_RESPONSE_TIME.clone(), value);
+    }
+    if let Ok(value) = HeaderValue::from_str(&data.server_timing(duration)) {
+        headers.insert(SERVER_TIMING.clone(), value);
+    }
+    if let Some(scan) = data.scan {
+        if let Ok(value) =
+            HeaderValue::from_str(&format!("keys={}, bytes={}", scan.keys, scan.bytes))
+        {
+            headers.insert(X_SCAN_STATS.clone(), value);
+        }
+    }
+
+    exporter.export(FinishedTrace {
+        name,
+        start,
+        duration,
+        status: res.status(),
+        data,
+    });
+
+    res
+}
+
+async fn export_traces(
+    client: reqwest::Client,
+    endpoint: String,
+    mut rx: mpsc::Receiver<FinishedTrace>,
+) {
+    while let Some(trace) = rx.recv().await {
+        time::sleep(EXPORT_INTERVAL).await;
+        let mut spans = trace.to_otlp_spans();
+        while let Ok(trace) = rx.try_recv() {
+            spans.extend(trace.to_otlp_spans());
+        }
+
+        let body = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [attribute("service.name", json!({ "stringValue": "lila-openingexplorer" }))],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "lila-openingexplorer" },
+                    "spans": spans,
+                }],
+            }],
+        });
+
+        match client.post(&endpoint).json(&body).send().await {
+            Ok(res) if res.status().is_success() => (),
+            Ok(res) => log::warn!("otlp export: status {}", res.status()),
+            Err(err) => log::warn!("otlp export: {err}"),
+        }
+    }
+}
+
+impl FinishedTrace {
+    /// A server span for the request, with a child span for each stage.
+    fn to_otlp_spans(&self) -> Vec<Value> {
+        let trace_id = random_hex_id(16);
+        let span_id = random_hex_id(8);
+
+        let mut attributes = vec![attribute(
+            "http.response.status_code",
+            json!({ "intValue": self.status.as_u16().to_string() }),
+        )];
+        if let Some(scan) = self.data.scan {
+            attributes.push(attribute(
+                "db.scan.keys",
+                json!({ "intValue": scan.keys.to_string() }),
+            ));
+            attributes.push(attribute(
+                "db.scan.bytes",
+                json!({ "intValue": scan.bytes.to_string() }),
+            ));
+        }
+
+        let mut spans = vec![json!({
+            "traceId": trace_id,
+            "spanId": span_id,
+            "name": self.name,
+            "kind": 2, // server
+            "startTimeUnixNano": unix_nanos(self.start),
+            "endTimeUnixNano": unix_nanos(self.start + self.duration),
+            "attributes": attributes,
+            "status": { "code": if self.status.is_server_error() { 2 } else { 0 } },
+        })];
+        spans.extend(self.data.stages.iter().map(|stage| {
+            json!({
+                "traceId": trace_id,
+                "spanId": random_hex_id(8),
+                "parentSpanId": span_id,
+                "name": stage.name,
+                "kind": 1, // internal
+                "startTimeUnixNano": unix_nanos(stage.start),
+                "endTimeUnixNano": unix_nanos(stage.start + stage.duration),
+            })
+        }));
+        spans
+    }
+}
+
+fn attribute(key: &str, value: Value) -> Value {
+    json!({ "key": key, "value": value })
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos())
+        .to_string()
+}
+
+fn random_hex_id(bytes: usize) -> String {
+    (0..bytes)
+        .map(|_| format!("{:02x}", fastrand::u8(..)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_timing() {
+        let data = TraceData {
+            stages: ["parse", "queue", "scan", "queue"]
+                .into_iter()
+                .map(|name| Stage {
+                    name,
+                    start: UNIX_EPOCH,
+                    duration: Duration::from_micros(1500),
+                })
+                .collect(),
+            scan: None,
+        };
+        assert_eq!(
+            data.server_timing(Duration::from_millis(10)),
+            "parse;dur=1.500, queue;dur=3.000, scan;dur=1.500, total;dur=10.000"
+        );
+    }
+
+    #[test]
+    fn test_disabled_trace() {
+        let trace = Trace::current();
+        trace.stage("parse", || ());
+        trace.add_scan(1, 2);
+        assert!(trace.take().stages.is_empty());
+    }
+}
@@ -0,0 +1,126 @@
+use std::{cmp::Reverse, collections::HashMap};
+
+use shakmaty::{
+    san::SanPlus,
+    uci::UciMove,
+    variant::{Variant, VariantPosition},
+    zobrist::ZobristHash,
+    EnPassantMode, Position,
+};
+
+use crate::{api::Transposition, model::LichessGameMoves, zobrist::StableZobrist128};
+
+#[derive(Default)]
+struct MoveOrder {
+    games: u64,
+    book: bool,
+}
+
+/// Collects distinct move orders from the initial position that reach the
+/// same position.
+pub struct Transpositions {
+    variant: Variant,
+    target: StableZobrist128,
+    orders: HashMap<Vec<UciMove>, MoveOrder>,
+}
+
+impl Transpositions {
+    pub fn new(pos: &VariantPosition) -> Transpositions {
+        Transpositions {
+            variant: pos.variant(),
+            target: pos.zobrist_hash(EnPassantMode::Legal),
+            orders: HashMap::new(),
+        }
+    }
+
+    pub fn add_book_line(&mut self, moves: &[UciMove]) {
+        self.orders.entry(moves.to_vec()).or_default().book = true;
+    }
+
+    /// Replay the game up to the first time it reaches the position.
+    pub fn add_game(&mut self, game: &LichessGameMoves) {
+        if game.variant != self.variant || game.fen.is_some() {
+            return;
+        }
+
+        let mut pos = VariantPosition::new(self.variant);
+        for (i, uci) in game.moves.iter().enumerate() {
+            let Ok(m) = uci.to_move(&pos) else {
+                return;
+            };
+            pos.play_unchecked(&m);
+
+            if pos.zobrist_hash::<StableZobrist128>(EnPassantMode::Legal) == self.target {
+                self.orders
+                    .entry(game.moves[..=i].to_vec())
+                    .or_default()
+                    .games += 1;
+                return;
+            }
+        }
+    }
+
+    /// Move orders other than `exclude`, most frequently played first.
+    pub fn into_sorted(self, exclude: Option<&[UciMove]>, limit: usize) -> Vec<Transposition> {
+        let mut orders: Vec<_> = self
+            .orders
+            .into_iter()
+            .filter(|(moves, _)| Some(moves.as_slice()) != exclude)
+            .collect();
+        orders.sort_by_key(|(moves, order)| (Reverse(order.games), !order.book, moves.len()));
+        orders.truncate(limit);
+
+        orders
+            .into_iter()
+            .map(|(uci, order)| {
+                let mut pos = VariantPosition::new(self.variant);
+                let san = uci
+                    .iter()
+                    .map(|uci| {
+                        let m = uci.to_move(&pos).expect("replay move order");
+                        SanPlus::from_move_and_play_unchecked(&mut pos, &m)
+                    })
+                    .collect();
+                Transposition {
+                    uci,
+                    san,
+                    games: order.games,
+                    book: order.book,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn moves(uci: &str) -> Vec<UciMove> {
+        uci.split(' ').map(|uci| uci.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_transpositions() {
+        let mut pos = VariantPosition::new(Variant::Chess);
+        for uci in moves("d2d4 g8f6 c2c4 e7e6") {
+            let m = uci.to_move(&pos).unwrap();
+            pos.play_unchecked(&m);
+        }
+
+        let mut transpositions = Transpositions::new(&pos);
+        transpositions.add_book_line(&moves("d2d4 g8f6 c2c4 e7e6"));
+        for game in ["c2c4 e7e6 d2d4 g8f6 b1c3", "c2c4 g8f6 d2d4 e7e6 g1f3"] {
+            transpositions.add_game(&LichessGameMoves {
+                variant: Variant::Chess,
+                fen: None,
+                moves: moves(game),
+            });
+        }
+
+        let res = transpositions.into_sorted(Some(&moves("d2d4 g8f6 c2c4 e7e6")), 10);
+        assert_eq!(res.len(), 2);
+        assert!(res.iter().all(|t| t.games == 1 && !t.book));
+        assert!(res.iter().any(|t| t.uci == moves("c2c4 e7e6 d2d4 g8f6")));
+    }
+}
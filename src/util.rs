@@ -2,6 +2,7 @@ use std::{
     cmp::min,
     pin::Pin,
     task::{Context, Poll},
+    time::Instant,
 };
 
 use futures_util::{ready, stream::Stream};
@@ -11,6 +12,8 @@ use serde::{Deserialize, Serialize};
 use shakmaty::{variant::VariantPosition, ByColor, Position};
 use tokio::{sync::Semaphore, task};
 
+use crate::trace::Trace;
+
 #[derive(Serialize, Deserialize)]
 #[serde(remote = "ByColor")]
 pub struct ByColorDef<T> {
@@ -88,11 +91,18 @@ pub fn midpoint(a: u16, b: u16) -> u16 {
     ((u32::from(a) + u32::from(b)) / 2) as u16
 }
 
+/// Run blocking work with a permit of the semaphore. Waiting for the permit
+/// is recorded as the queue stage of the current request.
 pub async fn spawn_blocking<F, R>(semaphore: &Semaphore, f: F) -> R
 where
     F: FnOnce() -> R + Send + 'static,
     R: Send + 'static,
 {
+    let trace = Trace::current();
+    let queued_at = Instant::now();
     let _permit = semaphore.acquire().await.expect("semaphore not closed");
-    task::spawn_blocking(f).await.expect("blocking task")
+    trace.record("queue", queued_at);
+    task::spawn_blocking(move || trace.enter(f))
+        .await
+        .expect("blocking task")
 }